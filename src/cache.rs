@@ -0,0 +1,63 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::{Course, User};
+use crate::state::state_dir;
+
+pub const CACHE_FILE: &str = "cache.json";
+// Long enough for a few runs while tweaking filters, short enough to pick up new favourites
+pub const TTL_MINUTES: i64 = 10;
+
+// /users/self and course listings of the last run, so rapid successive runs skip straight to course selection
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cache {
+    // See key(), a cache written for other credentials is never served
+    pub key: String,
+    pub fetched_at: String,
+    pub api_base: String,
+    pub user: User,
+    // Courses link --> listing, favourites and --all-courses are cached separately
+    #[serde(default)]
+    pub courses: BTreeMap<String, Vec<Course>>,
+}
+
+// Identifies the instance and token without storing the token
+pub fn key(canvas_url: &str, canvas_token: &str) -> String {
+    let mut h = DefaultHasher::new();
+    canvas_url.trim_end_matches('/').hash(&mut h);
+    canvas_token.hash(&mut h);
+    format!("{:016x}", h.finish())
+}
+
+impl Cache {
+    pub fn new(key: String, api_base: String, user: User) -> Cache {
+        Cache {
+            key,
+            fetched_at: Local::now().to_rfc3339(),
+            api_base,
+            user,
+            courses: BTreeMap::new(),
+        }
+    }
+
+    // None when missing, unreadable, expired or written for other credentials
+    pub fn load(destination: &Path, key: &str) -> Option<Cache> {
+        let file = std::fs::File::open(state_dir(destination).join(CACHE_FILE)).ok()?;
+        let cache: Cache = serde_json::from_reader(file).ok()?;
+        let fetched_at = DateTime::parse_from_rfc3339(&cache.fetched_at).ok()?;
+        let age = Local::now().signed_duration_since(fetched_at);
+        (cache.key == key && age >= chrono::Duration::zero() && age < chrono::Duration::minutes(TTL_MINUTES)).then_some(cache)
+    }
+
+    pub fn save(&self, destination: &Path) -> Result<()> {
+        let dir = state_dir(destination);
+        std::fs::create_dir_all(&dir)?;
+        crate::write_artifact(&dir.join(CACHE_FILE), serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::state::STATE_DIR;
+
+// Every download writes to its tmp file all the time, one untouched for this long belongs to no running instance
+const ABANDONED_AFTER: Duration = Duration::from_secs(10 * 60);
+// Partial downloads are kept this long for a later run to resume, see download_file()
+const RESUMABLE_FOR: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Downloads of atomic_download_file(), see tmp_file_name()
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TmpFile {
+    // .canvasdl-<hash>.tmp
+    Resumable,
+    // .canvasdl-<hash>.<pid>.tmp, and <hash>.tmp of earlier versions that no run picks up anymore
+    Abandoned,
+}
+
+fn tmp_file(file_name: &str) -> Option<TmpFile> {
+    let resumable = Regex::new(r"^\.canvasdl-[0-9]+\.tmp$").expect("tmp file regex is valid");
+    let per_run = Regex::new(r"^(\.canvasdl-[0-9]+\.[0-9]+|[0-9]+)\.tmp$").expect("tmp file regex is valid");
+    if resumable.is_match(file_name) {
+        Some(TmpFile::Resumable)
+    } else if per_run.is_match(file_name) {
+        Some(TmpFile::Abandoned)
+    } else {
+        None
+    }
+}
+
+fn unmodified_for(path: &Path) -> Duration {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .unwrap_or_default()
+}
+
+// Run before downloading: removes the tmp files of crashed runs, keeping recent partial downloads to resume unless
+// --no-resume. Files still being written by another instance are left alone. Returns files and bytes removed
+pub fn remove_stale_tmp_files(tmp_dir: &Path, resume: bool) -> Result<(usize, u64)> {
+    if !tmp_dir.is_dir() {
+        return Ok((0, 0));
+    }
+    let mut files_removed = 0;
+    let mut bytes_removed = 0;
+    for entry in std::fs::read_dir(tmp_dir).with_context(|| format!("Could not read directory {tmp_dir:?}"))? {
+        let entry = entry?;
+        let Some(kind) = entry.file_name().to_str().and_then(tmp_file) else {
+            continue;
+        };
+        let path = entry.path();
+        let age = unmodified_for(&path);
+        let stale = match kind {
+            TmpFile::Resumable if resume => age > RESUMABLE_FOR,
+            _ => age > ABANDONED_AFTER,
+        };
+        if !stale {
+            continue;
+        }
+        let size = entry.metadata().map_or(0, |metadata| metadata.len());
+        std::fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+        files_removed += 1;
+        bytes_removed += size;
+    }
+    Ok((files_removed, bytes_removed))
+}
+
+// Removes what interrupted runs leave behind: downloads of atomic_download_file() in the tmp folder, and
+// ".<name>.<pid>.part" artifacts of write_artifact() anywhere in the destination
+pub fn run(destination: &Path, tmp_dir: &Path, empty_folders: bool) -> Result<()> {
+    if !destination.is_dir() {
+        println!("Nothing to clean, {} does not exist", destination.to_string_lossy());
+        return Ok(());
+    }
+    let part_name = Regex::new(r"^\..+\.[0-9]+\.part$").expect("part file regex is valid");
+
+    let mut files = Vec::new();
+    crate::storage_report::walk(destination, &mut files)?;
+    // --tmp-dir may be outside the destination
+    if tmp_dir.is_dir() && !tmp_dir.starts_with(destination) {
+        crate::storage_report::walk(tmp_dir, &mut files)?;
+    }
+
+    let mut files_removed = 0;
+    let mut bytes_removed = 0;
+    for (path, size) in files {
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let in_tmp_dir = path.parent() == Some(tmp_dir);
+        let leftover = (in_tmp_dir && tmp_file(file_name).is_some()) || part_name.is_match(file_name);
+        if !leftover {
+            continue;
+        }
+        // Most likely a download of a sync that is running right now
+        if unmodified_for(&path) < ABANDONED_AFTER {
+            continue;
+        }
+        std::fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+        files_removed += 1;
+        bytes_removed += size;
+    }
+    println!(
+        "Removed {files_removed} leftover file(s), {}",
+        indicatif::HumanBytes(bytes_removed)
+    );
+
+    if empty_folders {
+        let mut folders_removed = 0;
+        for entry in std::fs::read_dir(destination)? {
+            let entry = entry?;
+            // The state folder is not course content
+            if entry.file_name() != STATE_DIR {
+                remove_empty_folders(&entry.path(), &mut folders_removed)?;
+            }
+        }
+        println!("Removed {folders_removed} empty folder(s)");
+    }
+    Ok(())
+}
+
+// Bottom-up, so a folder holding only empty folders is removed too. Returns whether `folder` was removed
+fn remove_empty_folders(folder: &Path, folders_removed: &mut usize) -> Result<bool> {
+    // Symlinks are left alone, like in storage_report::walk()
+    if !std::fs::symlink_metadata(folder)?.is_dir() {
+        return Ok(false);
+    }
+    let mut empty = true;
+    for entry in std::fs::read_dir(folder).with_context(|| format!("Could not read directory {folder:?}"))? {
+        let entry = entry?;
+        if !remove_empty_folders(&entry.path(), folders_removed)? {
+            empty = false;
+        }
+    }
+    if empty {
+        std::fs::remove_dir(folder).with_context(|| format!("Could not remove {folder:?}"))?;
+        *folders_removed += 1;
+    }
+    Ok(empty)
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches};
+use serde_json::{Map, Value};
+
+use crate::canvas::Credentials;
+use crate::CommandLineOptions;
+
+// Config file keys that fill in the credential file instead of an option
+const CREDENTIAL_KEYS: [(&str, &str); 3] = [
+    ("canvas_url", "canvasUrl"),
+    ("canvas_token", "canvasToken"),
+    ("api_prefix", "apiPrefix"),
+];
+
+// Options that [role.<role>] tables may turn on per course, eg [role.teacher] include_unpublished = true
+pub const ROLE_OPTIONS: [&str; 2] = ["include_unpublished", "all_submissions"];
+pub const ROLES: [&str; 5] = ["student", "ta", "teacher", "designer", "observer"];
+
+// Role --> option --> value
+pub type RoleRules = BTreeMap<String, BTreeMap<String, bool>>;
+
+// $XDG_CONFIG_HOME/canvas-downloader/config.toml, falling back to ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|config_dir| config_dir.join("canvas-downloader").join("config.toml"))
+}
+
+pub struct Loaded {
+    pub args: CommandLineOptions,
+    pub credentials: Option<Credentials>,
+    pub role_rules: RoleRules,
+    pub path: Option<PathBuf>,
+}
+
+// The command line of this process, with the config file it names or the default one when that exists
+pub fn load() -> Result<Loaded> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    // --help, --version and invalid flags exit here
+    let cli_matches = CommandLineOptions::command().get_matches_from(&argv);
+    let cli_args = CommandLineOptions::from_arg_matches(&cli_matches).unwrap_or_else(|e| e.exit());
+
+    let path = match &cli_args.config {
+        Some(path) => Some(path.clone()),
+        None => default_path().filter(|path| path.exists()),
+    };
+    let Some(path) = path else {
+        return load_from(&argv, None);
+    };
+    let body = std::fs::read_to_string(&path)
+        .with_context(|| format!("Could not open config file {path:?}"))?;
+    load_from(&argv, Some((path, &body)))
+}
+
+// Command line flags take precedence over the config file, which takes precedence over the defaults.
+// Config values are passed through clap as if typed before the flags, so they are validated the same way
+pub fn load_from(argv: &[OsString], config: Option<(PathBuf, &str)>) -> Result<Loaded> {
+    let cli_matches = CommandLineOptions::command().try_get_matches_from(argv)?;
+    let Some((path, body)) = config else {
+        let args = CommandLineOptions::from_arg_matches(&cli_matches)?;
+        return Ok(Loaded { args, credentials: None, role_rules: RoleRules::new(), path: None });
+    };
+    let table: toml::Table = toml::from_str(body)
+        .with_context(|| format!("Config file {path:?} is not valid toml"))?;
+
+    let command = CommandLineOptions::command();
+    let mut config_argv = Vec::new();
+    let mut credentials = Map::new();
+    let mut role_rules = RoleRules::new();
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        if id == "role" {
+            role_rules = parse_role_rules(value).with_context(|| format!("Invalid [role] table in config file {path:?}"))?;
+            continue;
+        }
+        if let Some((_, credential_key)) = CREDENTIAL_KEYS.iter().find(|(config_key, _)| *config_key == id) {
+            credentials.insert(credential_key.to_string(), serde_json::to_value(value)?);
+            continue;
+        }
+        // Renamed options keep their old name as an alias, eg concurrency for api_concurrency
+        let arg = command.get_arguments().find(|arg| {
+            arg.get_id().as_str() == id
+                || arg
+                    .get_all_aliases()
+                    .is_some_and(|aliases| aliases.contains(&id.replace('_', "-").as_str()))
+        });
+        let long = arg
+            .and_then(|arg| arg.get_long())
+            .filter(|long| !matches!(*long, "config" | "print-config"))
+            .ok_or_else(|| anyhow!("Unknown option {key} in config file {path:?}"))?;
+        let id = arg.map_or(id.clone(), |arg| arg.get_id().to_string());
+        if cli_matches.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Boolean(true) => vec![None],
+            toml::Value::Boolean(false) => vec![],
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| config_value(&key, item).map(Some))
+                .collect::<Result<_>>()?,
+            value => vec![Some(config_value(&key, value)?)],
+        };
+        for value in values {
+            config_argv.push(OsString::from(match value {
+                Some(value) => format!("--{long}={value}"),
+                None => format!("--{long}"),
+            }));
+        }
+    }
+
+    // Binary name, then the config file, then what was typed
+    let merged_argv = argv.iter().take(1).cloned().chain(config_argv).chain(argv.iter().skip(1).cloned());
+    let matches = CommandLineOptions::command()
+        .try_get_matches_from(merged_argv)
+        .with_context(|| format!("Invalid value in config file {path:?}"))?;
+    let args = CommandLineOptions::from_arg_matches(&matches)?;
+
+    let credentials = if credentials.is_empty() {
+        None
+    } else {
+        Some(
+            serde_json::from_value(Value::Object(credentials))
+                .with_context(|| format!("Config file {path:?} needs both canvas_url and canvas_token"))?,
+        )
+    };
+    Ok(Loaded { args, credentials, role_rules, path: Some(path) })
+}
+
+fn parse_role_rules(value: toml::Value) -> Result<RoleRules> {
+    let toml::Value::Table(roles) = value else {
+        return Err(anyhow!("expected tables like [role.teacher]"));
+    };
+    let mut role_rules = RoleRules::new();
+    for (role, options) in roles {
+        if !ROLES.contains(&role.as_str()) {
+            return Err(anyhow!("unknown role {role}, expected one of {}", ROLES.join(", ")));
+        }
+        let toml::Value::Table(options) = options else {
+            return Err(anyhow!("expected [role.{role}] to be a table"));
+        };
+        for (option, value) in options {
+            let id = option.replace('-', "_");
+            if !ROLE_OPTIONS.contains(&id.as_str()) {
+                return Err(anyhow!(
+                    "{option} can not be set per role, expected one of {}",
+                    ROLE_OPTIONS.join(", ")
+                ));
+            }
+            let toml::Value::Boolean(value) = value else {
+                return Err(anyhow!("expected true or false for role.{role}.{option}"));
+            };
+            role_rules.entry(role.clone()).or_default().insert(id, value);
+        }
+    }
+    Ok(role_rules)
+}
+
+fn config_value(key: &str, value: toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(expand_home(value)),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        value => Err(anyhow!("Unsupported value {value} for {key} in config file")),
+    }
+}
+
+// "~/uni" --> "/home/me/uni", the shell does this for flags but not for config values
+fn expand_home(value: String) -> String {
+    match (value.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{home}/{rest}"),
+        _ => value,
+    }
+}
+
+// Effective configuration, with the token redacted
+pub fn print(loaded: &Loaded) -> Result<()> {
+    match &loaded.path {
+        Some(path) => println!("# Config file: {}", path.to_string_lossy()),
+        None => println!("# No config file"),
+    }
+    if let Some(credentials) = &loaded.credentials {
+        println!("canvas_url = {:?}", credentials.canvas_url);
+        if let Some(api_prefix) = &credentials.api_prefix {
+            println!("api_prefix = {api_prefix:?}");
+        }
+        println!("canvas_token = \"<redacted>\"");
+    }
+    print!("{}", toml::to_string(&loaded.args)?);
+    for (role, options) in &loaded.role_rules {
+        println!("\n[role.{role}]");
+        for (option, value) in options {
+            println!("{option} = {value}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_with(argv: &[&str], config: &str) -> Result<Loaded> {
+        let argv: Vec<OsString> = ["canvas-downloader"].iter().chain(argv).map(OsString::from).collect();
+        load_from(&argv, Some((PathBuf::from("config.toml"), config)))
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_config_file() {
+        let config = "api_concurrency = 4\nretries = 5\n";
+        let loaded = load_with(&["--api-concurrency", "16"], config).expect("load config");
+        assert_eq!(loaded.args.api_concurrency, 16);
+        assert_eq!(loaded.args.retries, 5);
+        assert_eq!(loaded.path, Some(PathBuf::from("config.toml")));
+    }
+
+    #[test]
+    fn options_missing_from_the_config_file_keep_their_default() {
+        let loaded = load_with(&[], "flat = true\nexclude_folders = [\"Recordings\", \"Week 1/Old\"]\n").expect("load config");
+        assert!(loaded.args.flat);
+        assert_eq!(loaded.args.exclude_folders, ["Recordings", "Week 1/Old"]);
+        assert_eq!(loaded.args.api_concurrency, 8);
+        assert_eq!(loaded.args.download_concurrency, 4);
+        assert!(loaded.credentials.is_none());
+        assert!(loaded.role_rules.is_empty());
+    }
+
+    #[test]
+    fn credentials_come_from_the_config_file() {
+        let config = "canvas_url = \"https://canvas.example.edu\"\ncanvas_token = \"1234~token\"\n\
+            [role.teacher]\ninclude_unpublished = true\n";
+        let loaded = load_with(&[], config).expect("load config");
+        let credentials = loaded.credentials.expect("credentials");
+        assert_eq!(credentials.canvas_url, "https://canvas.example.edu");
+        assert_eq!(credentials.canvas_token, "1234~token");
+        assert_eq!(credentials.api_prefix, None);
+        assert!(loaded.role_rules["teacher"]["include_unpublished"]);
+
+        let error = load_with(&[], "canvas_url = \"https://canvas.example.edu\"\n").err().expect("token missing");
+        assert!(format!("{error:#}").contains("needs both canvas_url and canvas_token"), "{error:#}");
+    }
+
+    #[test]
+    fn unknown_config_keys_are_refused() {
+        for config in ["no_such_option = true\n", "print_config = true\n", "[role.principal]\nflat = true\n"] {
+            assert!(load_with(&[], config).is_err(), "{config}");
+        }
+        let error = load_with(&[], "no_such_option = true\n").err().expect("unknown option");
+        assert!(format!("{error:#}").contains("Unknown option no_such_option"), "{error:#}");
+    }
+}
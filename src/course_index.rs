@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::canvas::{self, Course, ProcessOptions};
+use crate::ContentType;
+
+pub const COURSE_JSON: &str = "course.json";
+pub const INDEX_HTML: &str = "index.html";
+const SYLLABUS_HTML: &str = "syllabus.html";
+const FRONT_PAGE_HTML: &str = "front_page.html";
+
+// A section of index.html and the artifacts it points at, relative to the course folder
+struct Section {
+    title: &'static str,
+    links: Vec<(String, String)>, // href, text
+}
+
+// Writes course.json and an index.html that opens with the section the course used as its landing view on Canvas
+pub async fn write(course: &Course, course_folder_path: &Path, options: &ProcessOptions) -> Result<()> {
+    options
+        .write_artifact(&course_folder_path.join(COURSE_JSON), serde_json::to_string_pretty(course)?.as_bytes())
+        .await?;
+
+    // Canvas default_view --> what the archive shows first, announcements stand in for the activity stream
+    let landing = match course.default_view.as_deref() {
+        Some("modules") => "modules",
+        Some("syllabus") => "syllabus",
+        Some("wiki") => "front page",
+        Some("assignments") => "assignments",
+        _ => "announcements",
+    };
+    let mut sections = Vec::new();
+    match landing {
+        "syllabus" => sections.extend(syllabus(course, course_folder_path, options).await),
+        "front page" => sections.extend(front_page(course, course_folder_path, options).await),
+        _ => {}
+    }
+    if options.content.contains(&ContentType::Modules) {
+        sections.extend(listing("modules", course_folder_path, "modules"));
+    }
+    if options.content.contains(&ContentType::Announcements) {
+        sections.extend(listing("announcements", course_folder_path, "announcements"));
+    }
+    if options.content.contains(&ContentType::Assignments) {
+        sections.extend(listing("assignments", course_folder_path, "assignments"));
+    }
+    if options.content.contains(&ContentType::Discussions) {
+        sections.extend(listing("discussions", course_folder_path, "discussions"));
+    }
+    if options.content.contains(&ContentType::Files) {
+        sections.extend(listing("files", course_folder_path, "files"));
+    }
+    if options.content.contains(&ContentType::Videos) {
+        sections.extend(listing("videos", course_folder_path, "videos"));
+    }
+
+    // Skipped by --content or empty on Canvas, the remaining sections keep their usual order
+    let landing_found = match sections.iter().position(|section| section.title == landing) {
+        Some(position) => {
+            let section = sections.remove(position);
+            sections.insert(0, section);
+            true
+        }
+        None => false,
+    };
+
+    let mut html = format!(
+        "<html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\n<h1>{0}</h1>\n",
+        escape(&format!("{} - {}", course.course_code, course.name))
+    );
+    if !landing_found {
+        html.push_str(&format!(
+            "<p>This course opens with its {landing} on Canvas, which was not synced.</p>\n"
+        ));
+    }
+    for section in sections {
+        html.push_str(&format!("<h2>{}</h2>\n<ul>\n", capitalize(section.title)));
+        for (href, text) in section.links {
+            html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", escape(&href), escape(&text)));
+        }
+        html.push_str("</ul>\n");
+    }
+    html.push_str("</body></html>\n");
+    options.write_artifact(&course_folder_path.join(INDEX_HTML), html.as_bytes()).await
+}
+
+// Subfolders and files of a content folder, eg modules/12_Week 1
+fn listing(title: &'static str, course_folder_path: &Path, folder: &str) -> Option<Section> {
+    let mut entries: Vec<String> = std::fs::read_dir(course_folder_path.join(folder))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+    entries.sort();
+    let links = entries
+        .into_iter()
+        .map(|name| (href(&format!("{folder}/{name}")), name))
+        .collect();
+    Some(Section { title, links })
+}
+
+// Not crawled otherwise, only fetched for courses that open with it
+async fn syllabus(course: &Course, course_folder_path: &Path, options: &ProcessOptions) -> Option<Section> {
+    let url = options.api_url(&format!("courses/{}?include[]=syllabus_body", course.id));
+    let resp = crate::get_canvas_api(url, options).await.ok()?;
+    let status = resp.status();
+    let body = resp.text().await.ok()?;
+    let syllabus = canvas::parse_api_body::<serde_json::Value>(status, &body, None).ok()?;
+    let syllabus_body = syllabus.get("syllabus_body")?.as_str()?;
+    let html = format!("<html><head><title>Syllabus</title></head><body>{syllabus_body}</body></html>");
+    options.write_artifact(&course_folder_path.join(SYLLABUS_HTML), html.as_bytes()).await.ok()?;
+    Some(Section { title: "syllabus", links: vec![(SYLLABUS_HTML.to_string(), "Syllabus".to_string())] })
+}
+
+async fn front_page(course: &Course, course_folder_path: &Path, options: &ProcessOptions) -> Option<Section> {
+    let url = options.api_url(&format!("courses/{}/front_page", course.id));
+    let resp = crate::get_canvas_api(url, options).await.ok()?;
+    let status = resp.status();
+    let body = resp.text().await.ok()?;
+    let page = canvas::parse_api_body::<canvas::PageBody>(status, &body, None).ok()?;
+    let html = format!("<html><head><title>{}</title></head><body>{}</body></html>", page.title, page.body);
+    options.write_artifact(&course_folder_path.join(FRONT_PAGE_HTML), html.as_bytes()).await.ok()?;
+    Some(Section { title: "front page", links: vec![(FRONT_PAGE_HTML.to_string(), page.title)] })
+}
+
+// Relative links to local files, only characters with a meaning in URLs are encoded
+pub fn href(path: &str) -> String {
+    path.replace('%', "%25").replace('#', "%23").replace('?', "%3F")
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+}
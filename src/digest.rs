@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+
+use crate::course_index::{escape, href};
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:50em;margin:2em auto;padding:0 1em;color:#222}\
+h2{border-bottom:1px solid #ccc;padding-bottom:.2em}\
+.announcement{border-left:3px solid #4a90d9;padding:.2em 1em;margin:1em 0}\
+.announcement img{max-width:100%}\
+.date{color:#777;font-size:.9em}\
+del{color:#a33}";
+
+// What this run added or changed, collected while crawling and downloading
+#[derive(Default)]
+pub struct Changes {
+    pub announcements: Vec<NewAnnouncement>,
+    pub files: Vec<ChangedFile>,
+    pub due_dates: Vec<DueDateChange>,
+}
+
+pub struct NewAnnouncement {
+    pub course_folder: PathBuf,
+    pub folder: PathBuf,
+    pub title: String,
+    pub posted_at: Option<String>,
+    // Canvas HTML, shown as is
+    pub message: String,
+}
+
+pub struct ChangedFile {
+    pub course_folder: PathBuf,
+    pub path: PathBuf,
+    // Replaced an earlier download instead of being new
+    pub updated: bool,
+}
+
+pub struct DueDateChange {
+    pub course_folder: PathBuf,
+    pub folder: PathBuf,
+    pub name: String,
+    // Canvas due_at, None without a due date
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.announcements.is_empty() && self.files.is_empty() && self.due_dates.is_empty()
+    }
+}
+
+// Course folder --> its share of the changes, in the order they were found
+#[derive(Default)]
+struct CourseChanges<'a> {
+    announcements: Vec<&'a NewAnnouncement>,
+    files: Vec<&'a ChangedFile>,
+    due_dates: Vec<&'a DueDateChange>,
+}
+
+// Writes digest-<date>.html into the destination, with a second run on the same day getting the time appended.
+// Returns None when nothing changed and no "no changes" page was asked for
+pub fn write(destination: &Path, changes: &Changes, write_when_empty: bool) -> Result<Option<PathBuf>> {
+    if changes.is_empty() && !write_when_empty {
+        return Ok(None);
+    }
+    let now = Local::now();
+    let mut path = destination.join(format!("digest-{}.html", now.format("%Y-%m-%d")));
+    if path.exists() {
+        path = destination.join(format!("digest-{}.html", now.format("%Y-%m-%d-%H%M%S")));
+    }
+
+    let title = format!("Canvas digest {}", now.format("%Y-%m-%d %H:%M"));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title><style>{STYLE}</style></head><body>\n<h1>{0}</h1>\n",
+        escape(&title)
+    );
+    if changes.is_empty() {
+        html.push_str("<p>No changes since the last run.</p>\n");
+    }
+
+    let mut courses: BTreeMap<&Path, CourseChanges> = BTreeMap::new();
+    for announcement in &changes.announcements {
+        courses.entry(&announcement.course_folder).or_default().announcements.push(announcement);
+    }
+    for file in &changes.files {
+        courses.entry(&file.course_folder).or_default().files.push(file);
+    }
+    for due_date in &changes.due_dates {
+        courses.entry(&due_date.course_folder).or_default().due_dates.push(due_date);
+    }
+
+    for (course_folder, course) in courses {
+        html.push_str(&format!("<h2>{}</h2>\n", escape(&relative(destination, course_folder))));
+        if !course.announcements.is_empty() {
+            html.push_str("<h3>New announcements</h3>\n");
+            for announcement in course.announcements {
+                html.push_str(&format!(
+                    "<div class=\"announcement\"><h4><a href=\"{}\">{}</a></h4>",
+                    escape(&href(&relative(destination, &announcement.folder))),
+                    escape(&announcement.title)
+                ));
+                if let Some(posted_at) = &announcement.posted_at {
+                    html.push_str(&format!("<p class=\"date\">{}</p>", escape(&local_time(posted_at))));
+                }
+                html.push_str(&format!("\n{}\n</div>\n", announcement.message));
+            }
+        }
+        for (updated, heading) in [(false, "New files"), (true, "Updated files")] {
+            let files: Vec<&&ChangedFile> = course.files.iter().filter(|file| file.updated == updated).collect();
+            if files.is_empty() {
+                continue;
+            }
+            html.push_str(&format!("<h3>{heading}</h3>\n<ul>\n"));
+            for file in files {
+                let file_path = relative(destination, &file.path);
+                let name = relative(course_folder, &file.path);
+                html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", escape(&href(&file_path)), escape(&name)));
+            }
+            html.push_str("</ul>\n");
+        }
+        if !course.due_dates.is_empty() {
+            html.push_str("<h3>Changed due dates</h3>\n<ul>\n");
+            for due_date in course.due_dates {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a>: <del>{}</del> &rarr; <strong>{}</strong></li>\n",
+                    escape(&href(&relative(destination, &due_date.folder))),
+                    escape(&due_date.name),
+                    escape(&due_date.old.as_deref().map_or_else(|| "no due date".to_string(), local_time)),
+                    escape(&due_date.new.as_deref().map_or_else(|| "no due date".to_string(), local_time)),
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+    }
+    html.push_str("</body></html>\n");
+
+    crate::write_artifact(&path, html.as_bytes())?;
+    Ok(Some(path))
+}
+
+// Runs `command` through the shell with the digest path as its last argument, eg --notify-command "xdg-open"
+pub fn notify(command: &str, digest_path: &Path) -> Result<()> {
+    let status = if cfg!(windows) {
+        std::process::Command::new("cmd")
+            .arg("/C")
+            .arg(format!("{command} \"{}\"", digest_path.to_string_lossy()))
+            .status()
+    } else {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{command} \"$1\""))
+            .arg("sh")
+            .arg(digest_path)
+            .status()
+    }
+    .with_context(|| format!("Could not run notify command {command:?}"))?;
+    if !status.success() {
+        return Err(anyhow!("Notify command {command:?} failed with {status}"));
+    }
+    Ok(())
+}
+
+// Forward slashes, so links work on every platform
+fn relative(base: &Path, path: &Path) -> String {
+    let relative_path = path.strip_prefix(base).unwrap_or(path);
+    relative_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+// Canvas timestamps are UTC, shown in local time
+fn local_time(timestamp: &str) -> String {
+    DateTime::parse_from_rfc3339(timestamp).map_or_else(
+        |_| timestamp.to_string(),
+        |timestamp| timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+    )
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::canvas::File;
+
+// Kept free on top of what the downloads need, for artifacts, the state and other programs
+pub const SPACE_MARGIN: u64 = 256 * 1024 * 1024;
+
+// Bytes the files need once downloaded, and how many have no known size and were counted as 0
+pub fn required_space(files: &[File]) -> (u64, usize) {
+    let bytes = files.iter().map(|file| file.size).sum();
+    let n_unknown_size = files.iter().filter(|file| file.size == 0).count();
+    (bytes, n_unknown_size)
+}
+
+// Free space for this user on the filesystem of `path`, None when it can not be told
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is NUL-terminated and stat is a valid statvfs to write to
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // the field types differ between platforms
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_bytes_available: *mut u64,
+            total_bytes: *mut u64,
+            total_free_bytes: *mut u64,
+        ) -> i32;
+    }
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free_bytes_available = 0;
+    // SAFETY: path is NUL-terminated, the totals are optional and left out
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(path.as_ptr(), &mut free_bytes_available, std::ptr::null_mut(), std::ptr::null_mut())
+    };
+    (ok != 0).then_some(free_bytes_available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+// Creation time of a file, on Windows and macOS. Other platforms have none that can be set, the file keeps the time
+// it was written
+#[cfg(any(windows, target_os = "macos"))]
+pub fn set_created(path: &Path, created: SystemTime) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    // Times left unset in FileTimes, like the modified time, are not touched
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+pub fn set_created(_path: &Path, _created: SystemTime) -> std::io::Result<()> {
+    Ok(())
+}
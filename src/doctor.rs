@@ -0,0 +1,88 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::canvas::{self, ApiError, ProcessOptions};
+
+// Read-only endpoints probed on the sample course, as (label, path relative to the course)
+const COURSE_PROBES: [(&str, &str); 6] = [
+    ("files", "folders?per_page=1"),
+    ("modules", "modules?per_page=1"),
+    ("discussions", "discussion_topics?per_page=1"),
+    ("assignments", "assignments?per_page=1"),
+    ("pages", "pages?per_page=1"),
+    ("users", "users?per_page=1"),
+];
+
+// Prints what the credentials can reach. Never creates folders or downloads files
+pub async fn run(options: &ProcessOptions) -> Result<()> {
+    println!("Canvas Downloader doctor, paste this report when opening an issue\n");
+    println!("Canvas instance:    {}", options.canvas_url);
+    println!("API base:           {}", options.api_base);
+    println!("Token:              valid, signed in as {} (id {})", options.user.name, options.user.id);
+
+    let favourites = crate::get_courses(options.api_url("users/self/favorites/courses?include[]=term"), options).await;
+    let active = crate::get_courses(
+        options.api_url("courses?enrollment_state=active&include[]=term"),
+        options,
+    )
+    .await;
+    println!(
+        "Courses:            {} favourite, {} active enrollment(s)",
+        count_or_error(&favourites),
+        count_or_error(&active)
+    );
+
+    let sample = favourites
+        .ok()
+        .and_then(|courses| courses.into_iter().next())
+        .or_else(|| active.ok().and_then(|courses| courses.into_iter().next()));
+    match sample {
+        Some(course) => {
+            println!("Sample course:      {} - {} (id {})", course.course_code, course.name, course.id);
+            for (label, path) in COURSE_PROBES {
+                let url = options.api_url(&format!("courses/{}/{path}", course.id));
+                println!("  {:<17} {}", format!("{label}:"), probe(url, options).await);
+            }
+            let panopto = match crate::launch_panopto(&options.canvas_url, course.id, options).await {
+                Ok(Some((_, host, _))) => format!("LTI launch succeeded ({host})"),
+                Ok(None) => "no Panopto tool in this course".to_string(),
+                Err(e) => format!("not available ({e})"),
+            };
+            println!("  {:<17} {panopto}", "videos:");
+        }
+        None => println!("Sample course:      none, no course to probe"),
+    }
+
+    let rate_limit = match options.metrics.snapshot().rate_limit_remaining_min {
+        Some(remaining) => format!("exposed, {remaining} remaining"),
+        None => "not exposed, throttling can only be detected from 403 responses".to_string(),
+    };
+    println!("Rate limit headers: {rate_limit}");
+    Ok(())
+}
+
+fn count_or_error(courses: &Result<Vec<canvas::Course>>) -> String {
+    match courses {
+        Ok(courses) => courses.len().to_string(),
+        Err(e) => format!("? ({e})"),
+    }
+}
+
+async fn probe(url: String, options: &ProcessOptions) -> String {
+    let resp = match crate::get_canvas_api(url, options).await {
+        Ok(resp) => resp,
+        Err(e) => return format!("request failed ({e})"),
+    };
+    let status = resp.status();
+    let body = match resp.text().await {
+        Ok(body) => body,
+        Err(e) => return format!("request failed ({e})"),
+    };
+    match canvas::parse_api_body::<Vec<Value>>(status, &body, None) {
+        Ok(items) if items.is_empty() => "ok, but empty".to_string(),
+        Ok(_) => "ok".to_string(),
+        Err(ApiError::Unauthorized) => format!("forbidden ({status}), a permissions limitation"),
+        Err(ApiError::NotFound) => format!("not found ({status}), likely disabled for this course"),
+        Err(e) => format!("error, {e}"),
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::canvas::ApiError;
+
+pub const ERRORS_LOG: &str = "errors.log";
+// Every failure of the last run as JSON, for scripts
+pub const FAILURES_JSON: &str = "failures.json";
+
+tokio::task_local! {
+    // Course folder a task works on, inherited by the tasks it forks, see fork!()
+    pub static COURSE: Option<String>;
+}
+
+pub fn current_course() -> Option<String> {
+    COURSE.try_with(Clone::clone).ok().flatten()
+}
+
+// Failures with the same key are printed once, e.g. an expired token fails every request of a course the same way.
+// Course and content type are part of the key so that unrelated failures are never folded together
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct FailureKey {
+    course: Option<String>,
+    content: String,
+    kind: String,
+    host: Option<String>,
+    status: Option<u16>,
+}
+
+struct FailureGroup {
+    messages: Vec<String>,
+    // Live "(...and N more like this)" line, added on the first repeat
+    counter: Option<ProgressBar>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    // Finding files through the Canvas API, Panopto and pages
+    Crawl,
+    Download,
+}
+
+// One failure, as written to failures.json
+#[derive(Clone, Debug, Serialize)]
+pub struct Failure {
+    pub phase: Phase,
+    pub course: Option<String>,
+    pub content: String,
+    pub url: Option<String>,
+    pub path: Option<PathBuf>,
+    // Outermost context first
+    pub error: Vec<String>,
+}
+
+// A task stopped by the first Ctrl-C, not a failure, see ProcessOptions.cancel
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[derive(Default)]
+pub struct ErrorReporter {
+    groups: Mutex<BTreeMap<FailureKey, FailureGroup>>,
+    failures: Mutex<Vec<Failure>>,
+}
+
+impl ErrorReporter {
+    // Prints the first failure of its kind, later ones only bump the counter below it
+    pub fn report(&self, content: &str, error: &anyhow::Error, progress_bars: &MultiProgress) {
+        if error.chain().any(|cause| cause.is::<Cancelled>()) {
+            return;
+        }
+        let url = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>()?.url().map(ToString::to_string));
+        self.report_failure(Phase::Crawl, content, url, None, error, progress_bars);
+    }
+
+    // Failed download of a file, see atomic_download_file()
+    pub fn report_download(&self, url: &str, path: PathBuf, error: &anyhow::Error, progress_bars: &MultiProgress) {
+        if error.chain().any(|cause| cause.is::<Cancelled>()) {
+            return;
+        }
+        self.report_failure(Phase::Download, "download", Some(url.to_string()), Some(path), error, progress_bars);
+    }
+
+    fn report_failure(
+        &self,
+        phase: Phase,
+        content: &str,
+        url: Option<String>,
+        path: Option<PathBuf>,
+        error: &anyhow::Error,
+        progress_bars: &MultiProgress,
+    ) {
+        let (kind, host, status) = classify(error);
+        let failure = Failure {
+            phase,
+            course: current_course(),
+            content: content.to_string(),
+            url,
+            path,
+            error: error.chain().map(ToString::to_string).collect(),
+        };
+        self.record(
+            FailureKey { course: current_course(), content: content.to_string(), kind, host, status },
+            format!("{error:?}"),
+            failure,
+            progress_bars,
+        );
+    }
+
+    pub fn report_api(&self, content: &str, uri: &str, error: &ApiError, message: String, progress_bars: &MultiProgress) {
+        let (kind, status) = api_kind(error);
+        let host = reqwest::Url::parse(uri).ok().and_then(|url| url.host_str().map(str::to_string));
+        let failure = Failure {
+            phase: Phase::Crawl,
+            course: current_course(),
+            content: content.to_string(),
+            url: Some(uri.to_string()),
+            path: None,
+            error: vec![error.to_string()],
+        };
+        self.record(
+            FailureKey { course: current_course(), content: content.to_string(), kind, host, status },
+            message,
+            failure,
+            progress_bars,
+        );
+    }
+
+    fn record(&self, key: FailureKey, message: String, failure: Failure, progress_bars: &MultiProgress) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.push(failure);
+        }
+        let Ok(mut groups) = self.groups.lock() else {
+            eprintln!("{message}");
+            return;
+        };
+        match groups.get_mut(&key) {
+            None => {
+                eprintln!("{message}");
+                groups.insert(key, FailureGroup { messages: vec![message], counter: None });
+            }
+            Some(group) => {
+                group.messages.push(message);
+                let counter = group.counter.get_or_insert_with(|| {
+                    let counter = progress_bars.add(ProgressBar::new_spinner());
+                    counter.set_style(ProgressStyle::with_template("{msg}").unwrap_or_else(|_| ProgressStyle::default_spinner()));
+                    counter
+                });
+                counter.set_message(format!(
+                    "  ({} failed: ...and {} more like this)",
+                    describe(&key),
+                    group.messages.len() - 1
+                ));
+            }
+        }
+    }
+
+    // Clears the live counters and prints one line per repeated failure
+    pub fn print_summary(&self) {
+        let Ok(groups) = self.groups.lock() else {
+            return;
+        };
+        for (key, group) in groups.iter() {
+            if let Some(counter) = &group.counter {
+                counter.finish_and_clear();
+            }
+            if group.messages.len() > 1 {
+                eprintln!("{} failed {} times the same way", describe(key), group.messages.len());
+            }
+        }
+    }
+
+    // "Failed downloads" section of the summary, by course
+    pub fn print_failed_downloads(&self) {
+        let Ok(failures) = self.failures.lock() else {
+            return;
+        };
+        let mut courses: BTreeMap<Option<&String>, Vec<&Failure>> = BTreeMap::new();
+        for failure in failures.iter().filter(|failure| failure.phase == Phase::Download) {
+            courses.entry(failure.course.as_ref()).or_default().push(failure);
+        }
+        if courses.is_empty() {
+            return;
+        }
+        eprintln!("Failed downloads:");
+        for (course, failures) in courses {
+            eprintln!("  {}", course.map_or("Other", String::as_str));
+            for failure in failures {
+                eprintln!(
+                    "    * {}: {}",
+                    failure.path.as_ref().map_or_else(String::new, |path| path.to_string_lossy().to_string()),
+                    failure.error.last().map_or("", String::as_str)
+                );
+            }
+        }
+    }
+
+    // failures.json, an empty list when nothing failed
+    pub fn failures_json(&self) -> serde_json::Result<String> {
+        match self.failures.lock() {
+            Ok(failures) => serde_json::to_string_pretty(&*failures),
+            Err(_) => Ok("[]".to_string()),
+        }
+    }
+
+    pub fn failures(&self) -> Vec<Failure> {
+        self.failures.lock().map(|failures| failures.clone()).unwrap_or_default()
+    }
+
+    // Whether anything of the course, by its course_label(), failed in this run
+    pub fn course_failed(&self, course: &str) -> bool {
+        self.failures
+            .lock()
+            .is_ok_and(|failures| failures.iter().any(|failure| failure.course.as_deref() == Some(course)))
+    }
+
+    pub fn count(&self) -> usize {
+        self.groups
+            .lock()
+            .map_or(0, |groups| groups.values().map(|group| group.messages.len()).sum())
+    }
+
+    // Every failure, grouped. None when nothing failed
+    pub fn log(&self) -> Option<String> {
+        let groups = self.groups.lock().ok()?;
+        if groups.is_empty() {
+            return None;
+        }
+        let mut log = String::new();
+        for (key, group) in groups.iter() {
+            log.push_str(&format!("== {} ({} failure(s))\n", describe(key), group.messages.len()));
+            for message in &group.messages {
+                log.push_str(message);
+                log.push_str("\n\n");
+            }
+        }
+        Some(log)
+    }
+}
+
+// "CS101 discussions on canvas.example.edu, timeout"
+fn describe(key: &FailureKey) -> String {
+    let mut description = match &key.course {
+        Some(course) => format!("{course} {}", key.content),
+        None => key.content.clone(),
+    };
+    if let Some(host) = &key.host {
+        description.push_str(&format!(" on {host}"));
+    }
+    description.push_str(&format!(", {}", key.kind));
+    if let Some(status) = key.status {
+        if !key.kind.contains(&status.to_string()) {
+            description.push_str(&format!(" ({status})"));
+        }
+    }
+    description
+}
+
+// Without the saved body path of ParseFailed, which differs for every request
+fn api_kind(error: &ApiError) -> (String, Option<u16>) {
+    match error {
+        ApiError::Unauthorized => ("unauthorized".to_string(), Some(401)),
+        ApiError::NotFound => ("not found".to_string(), Some(404)),
+        ApiError::RateLimited => ("rate limited".to_string(), Some(403)),
+        ApiError::Status(status) => (format!("status {status}"), None),
+        ApiError::ParseFailed { .. } => ("unexpected response".to_string(), None),
+    }
+}
+
+// "process_discussions" --> "discussions", as named by fork!()
+pub fn task_content(task: &str) -> String {
+    match task.rsplit("::").next().unwrap_or(task).trim() {
+        "atomic_download_file" => "download".to_string(),
+        task => task.trim_start_matches("process_").replace('_', " "),
+    }
+}
+
+// Error kind, host and HTTP status of the first cause that tells them
+fn classify(error: &anyhow::Error) -> (String, Option<String>, Option<u16>) {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            let host = e.url().and_then(|url| url.host_str()).map(str::to_string);
+            let status = e.status().map(|status| status.as_u16());
+            let kind = if e.is_timeout() {
+                "timeout"
+            } else if e.is_connect() {
+                "connection failed"
+            } else if e.is_status() {
+                "http error"
+            } else if e.is_decode() || e.is_body() {
+                "bad response"
+            } else {
+                "request failed"
+            };
+            return (kind.to_string(), host, status);
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return (format!("io {:?}", e.kind()), None, None);
+        }
+        if let Some(e) = cause.downcast_ref::<ApiError>() {
+            let (kind, status) = api_kind(e);
+            return (kind, None, status);
+        }
+    }
+    // Numbers are usually IDs, sizes or line numbers
+    let numbers = Regex::new(r"[0-9]+").expect("number regex is valid");
+    let root_cause = error.root_cause().to_string();
+    let kind = numbers.replace_all(root_cause.lines().next().unwrap_or_default(), "#");
+    (kind.chars().take(80).collect(), None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::ProgressDrawTarget;
+
+    #[test]
+    fn api_error_classes_are_grouped_apart() {
+        let reporter = ErrorReporter::default();
+        let progress_bars = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let uri = "https://canvas.example.edu/api/v1/courses/1/folders";
+        let errors = [
+            ApiError::Unauthorized,
+            ApiError::Unauthorized,
+            ApiError::NotFound,
+            ApiError::RateLimited,
+            ApiError::Status("500 Internal Server Error".to_string()),
+            ApiError::ParseFailed { body_path: Some(PathBuf::from("/tmp/body-1.json")), error: "expected a sequence".into() },
+            ApiError::ParseFailed { body_path: Some(PathBuf::from("/tmp/body-2.json")), error: "expected a sequence".into() },
+        ];
+        for error in &errors {
+            reporter.report_api("folders", uri, error, error.to_string(), &progress_bars);
+        }
+        assert_eq!(reporter.count(), errors.len());
+        let log = reporter.log().expect("failures logged");
+        // Repeats of a class fold into its group, whatever the saved body path
+        assert!(log.contains("== folders on canvas.example.edu, unauthorized (401) (2 failure(s))\n"), "{log}");
+        assert!(log.contains("== folders on canvas.example.edu, not found (404) (1 failure(s))\n"), "{log}");
+        assert!(log.contains("== folders on canvas.example.edu, rate limited (403) (1 failure(s))\n"), "{log}");
+        let server_error = "== folders on canvas.example.edu, status 500 Internal Server Error (1 failure(s))\n";
+        assert!(log.contains(server_error), "{log}");
+        assert!(log.contains("== folders on canvas.example.edu, unexpected response (2 failure(s))\n"), "{log}");
+        reporter.print_summary();
+    }
+
+    #[test]
+    fn failures_json_lists_every_failure() {
+        let reporter = ErrorReporter::default();
+        assert_eq!(reporter.failures_json().expect("serialize failures"), "[]");
+        let progress_bars = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let uri = "https://canvas.example.edu/api/v1/courses/1/modules";
+        reporter.report_api("modules", uri, &ApiError::NotFound, "not found".to_string(), &progress_bars);
+        let failures: serde_json::Value =
+            serde_json::from_str(&reporter.failures_json().expect("serialize failures")).expect("JSON");
+        assert_eq!(failures[0]["phase"], "crawl");
+        assert_eq!(failures[0]["content"], "modules");
+        assert_eq!(failures[0]["url"], uri);
+        assert_eq!(failures[0]["error"][0], "not found");
+    }
+
+    #[test]
+    fn failures_are_attributed_to_their_course() {
+        let reporter = ErrorReporter::default();
+        let progress_bars = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let error = anyhow::anyhow!("403 Forbidden");
+        COURSE.sync_scope(Some("CS101".to_string()), || {
+            let url = "https://canvas.example.edu/files/2/download";
+            reporter.report_download(url, PathBuf::from("CS101/notes.pdf"), &error, &progress_bars);
+        });
+        // Listing the courses belongs to none of them
+        reporter.report("courses", &error, &progress_bars);
+        assert!(reporter.course_failed("CS101"));
+        assert!(!reporter.course_failed("MATH201"));
+    }
+}
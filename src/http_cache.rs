@@ -0,0 +1,155 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::state::state_dir;
+
+pub const HTTP_CACHE_DIR: &str = "http-cache";
+// Listings not asked for this long are removed, eg of courses no longer synced
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// Beyond this, the least recently used listings are removed first
+const MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+// Canvas API responses by URL, sent again with If-None-Match/If-Modified-Since so an unchanged listing is answered
+// with 304 Not Modified instead of its body, see send_canvas_api()
+pub struct HttpCache {
+    dir: PathBuf,
+    // See cache::key(), responses for other credentials are never served
+    key: String,
+    read_only: bool,
+    revalidated: AtomicUsize,
+    hits: AtomicUsize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    // Pagination of the listing, see get_pages()
+    pub link: Option<String>,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+impl HttpCache {
+    pub fn new(destination: &Path, key: String, read_only: bool) -> HttpCache {
+        HttpCache {
+            dir: state_dir(destination).join(HTTP_CACHE_DIR),
+            key,
+            read_only,
+            revalidated: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let mut h = DefaultHasher::new();
+        self.key.hash(&mut h);
+        url.hash(&mut h);
+        self.dir.join(format!("{:016x}.json", h.finish()))
+    }
+
+    // None when never stored or unreadable, a response is then fetched in full
+    pub fn lookup(&self, url: &str) -> Option<Entry> {
+        let file = std::fs::File::open(self.path(url)).ok()?;
+        let entry: Entry = serde_json::from_reader(std::io::BufReader::new(file)).ok()?;
+        // Two URLs with the same hash
+        if entry.url != url {
+            return None;
+        }
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+        Some(entry)
+    }
+
+    // Canvas answered 304, the modified time tells evict() the entry is still in use
+    pub fn hit(&self, url: &str) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if !self.read_only {
+            let _ = filetime::set_file_mtime(self.path(url), filetime::FileTime::now());
+        }
+    }
+
+    pub fn store(&self, entry: &Entry) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        crate::write_artifact(&self.path(&entry.url), serde_json::to_string(entry)?.as_bytes())
+    }
+
+    // (requests sent with a cached response to compare against, answered from the cache)
+    pub fn counts(&self) -> (usize, usize) {
+        (self.revalidated.load(Ordering::Relaxed), self.hits.load(Ordering::Relaxed))
+    }
+
+    // Every stored response by file name, for `state export`. The names only hold for the same credentials
+    pub fn entries(destination: &Path) -> Result<BTreeMap<String, Entry>> {
+        let dir = state_dir(destination).join(HTTP_CACHE_DIR);
+        let mut entries = BTreeMap::new();
+        if !dir.is_dir() {
+            return Ok(entries);
+        }
+        for dir_entry in std::fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let Ok(file) = std::fs::File::open(dir_entry.path()) else {
+                continue;
+            };
+            // Unreadable ones are fetched again
+            if let Ok(entry) = serde_json::from_reader(std::io::BufReader::new(file)) {
+                entries.insert(dir_entry.file_name().to_string_lossy().to_string(), entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Stores the responses of entries(), skipping names that are not of this cache
+    pub fn restore(destination: &Path, entries: &BTreeMap<String, Entry>) -> Result<usize> {
+        let dir = state_dir(destination).join(HTTP_CACHE_DIR);
+        let file_name = regex::Regex::new(r"^[0-9a-f]{16}\.json$").expect("cache file regex is valid");
+        let mut restored = 0;
+        for (name, entry) in entries.iter().filter(|(name, _)| file_name.is_match(name)) {
+            std::fs::create_dir_all(&dir)?;
+            crate::write_artifact(&dir.join(name), serde_json::to_string(entry)?.as_bytes())?;
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    // Removes entries unused for MAX_AGE, then the least recently used ones until the cache fits in MAX_BYTES.
+    // Returns the number of entries removed
+    pub fn evict(&self) -> Result<usize> {
+        if self.read_only || !self.dir.is_dir() {
+            return Ok(0);
+        }
+        let mut entries = Vec::new();
+        let mut removed = 0;
+        let mut total_bytes = 0;
+        for dir_entry in std::fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let metadata = dir_entry.metadata()?;
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, metadata.len(), dir_entry.path()));
+            total_bytes += metadata.len();
+        }
+        // Least recently used first
+        entries.sort();
+        let now = SystemTime::now();
+        for (used, size, path) in entries {
+            let expired = now.duration_since(used).unwrap_or_default() > MAX_AGE;
+            if !expired && total_bytes <= MAX_BYTES {
+                break;
+            }
+            std::fs::remove_file(&path)?;
+            total_bytes -= size;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
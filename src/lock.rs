@@ -0,0 +1,197 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::state::state_dir;
+
+pub const LOCK_FILE: &str = "lock";
+// No sync takes this long, the lock of a run that could not remove it, eg after a reboot with the PID reused since
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Held by the run syncing into a destination, so a second run (eg cron and a manual one) does not download into the
+// same tmp files and state. Removed when dropped, also when the run is interrupted
+pub struct DestinationLock {
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Holder {
+    pid: u32,
+    started_at: String,
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Fails when another run holds the lock, unless `force`. Locks of runs that are gone are taken over with a warning
+pub fn acquire(destination: &Path, force: bool) -> Result<DestinationLock> {
+    let dir = state_dir(destination);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create state directory {dir:?}"))?;
+    let path = dir.join(LOCK_FILE);
+    if let Some(lock) = try_create(&path)? {
+        return Ok(lock);
+    }
+
+    let holder = std::fs::read_to_string(&path).ok().and_then(|body| serde_json::from_str::<Holder>(&body).ok());
+    let started_at = holder
+        .as_ref()
+        .and_then(|holder| DateTime::parse_from_rfc3339(&holder.started_at).ok())
+        .map(|started_at| started_at.with_timezone(&Local));
+    let age = started_at.map(|started_at| Local::now().signed_duration_since(started_at).to_std().unwrap_or_default());
+    match &holder {
+        Some(holder) if !force && process_alive(holder.pid) && age.is_some_and(|age| age < STALE_AFTER) => {
+            return Err(anyhow!(
+                "Another canvas-downloader (PID {}, started {}) is syncing into {}. Wait for it to finish, or pass \
+                 --force-lock if it is not running",
+                holder.pid,
+                started_at.map_or_else(String::new, |started_at| started_at.format("%Y-%m-%d %H:%M").to_string()),
+                destination.to_string_lossy()
+            ));
+        }
+        Some(holder) if force => eprintln!("Warning: taking over the lock of PID {} (--force-lock)", holder.pid),
+        Some(holder) => eprintln!(
+            "Warning: taking over the lock of PID {} started {}, which is no longer running",
+            holder.pid, holder.started_at
+        ),
+        None => eprintln!("Warning: taking over an unreadable lock {path:?}"),
+    }
+    std::fs::remove_file(&path).with_context(|| format!("Could not remove lock {path:?}"))?;
+    // Another run may have taken it over first
+    try_create(&path)?.ok_or_else(|| anyhow!("Another canvas-downloader took over the lock {path:?} just now"))
+}
+
+// None when the lock file exists already. The holder is written to a file of this process first and linked into
+// place, a second run never reads a lock that is only half written and takes it over as unreadable
+fn try_create(path: &Path) -> Result<Option<DestinationLock>> {
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let holder = Holder { pid: std::process::id(), started_at: Local::now().to_rfc3339() };
+    std::fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Could not write lock {tmp_path:?}"))?;
+    let linked = std::fs::hard_link(&tmp_path, path);
+    let _ = std::fs::remove_file(&tmp_path);
+    match linked {
+        Ok(()) => Ok(Some(DestinationLock { path: path.to_path_buf() })),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Could not create lock {path:?}")),
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // Exists, but belongs to another user
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(desired_access: u32, inherit_handle: i32, process_id: u32) -> isize;
+        fn GetExitCodeProcess(process: isize, exit_code: *mut u32) -> i32;
+        fn CloseHandle(handle: isize) -> i32;
+    }
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+    const STILL_ACTIVE: u32 = 259;
+    // SAFETY: the handle is checked before use and closed after
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process == 0 {
+            return false;
+        }
+        let mut exit_code = 0;
+        let alive = GetExitCodeProcess(process, &mut exit_code) != 0 && exit_code == STILL_ACTIVE;
+        CloseHandle(process);
+        alive
+    }
+}
+
+// Can not be told, only the age of the lock makes it stale
+#[cfg(not(any(unix, windows)))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn lock_path(dest: &TestDir) -> PathBuf {
+        state_dir(dest.path()).join(LOCK_FILE)
+    }
+
+    fn hold(dest: &TestDir, pid: u32, started_at: DateTime<Local>) {
+        let holder = Holder { pid, started_at: started_at.to_rfc3339() };
+        std::fs::create_dir_all(state_dir(dest.path())).expect("create state dir");
+        std::fs::write(lock_path(dest), serde_json::to_string(&holder).expect("holder")).expect("write lock");
+    }
+
+    fn holder_pid(dest: &TestDir) -> u32 {
+        let body = std::fs::read_to_string(lock_path(dest)).expect("read lock");
+        serde_json::from_str::<Holder>(&body).expect("complete holder").pid
+    }
+
+    // Above any pid_max, so never a running process
+    const DEAD_PID: u32 = i32::MAX as u32;
+
+    #[test]
+    fn lock_is_released_when_dropped() {
+        let dest = TestDir::new("release");
+        let lock = acquire(dest.path(), false).expect("free lock");
+        assert_eq!(holder_pid(&dest), std::process::id());
+        drop(lock);
+        assert!(!lock_path(&dest).exists());
+        acquire(dest.path(), false).expect("released lock");
+    }
+
+    #[test]
+    fn contended_lock_is_refused() {
+        let dest = TestDir::new("contended");
+        let _lock = acquire(dest.path(), false).expect("free lock");
+        let e = acquire(dest.path(), false).err().expect("lock held by a running process");
+        assert!(e.to_string().contains("--force-lock"), "{e}");
+        // Still held by the first
+        assert!(lock_path(&dest).exists());
+    }
+
+    #[test]
+    fn forced_lock_is_taken_over() {
+        let dest = TestDir::new("forced");
+        hold(&dest, std::process::id(), Local::now());
+        let _lock = acquire(dest.path(), true).expect("forced");
+        assert_eq!(holder_pid(&dest), std::process::id());
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let dest = TestDir::new("stale");
+        hold(&dest, DEAD_PID, Local::now());
+        drop(acquire(dest.path(), false).expect("lock of a process that is gone"));
+
+        // Running, but the PID may have been reused since
+        hold(&dest, std::process::id(), Local::now() - chrono::Duration::hours(25));
+        drop(acquire(dest.path(), false).expect("lock older than a day"));
+
+        std::fs::write(lock_path(&dest), "{\"pid\": ").expect("write lock");
+        let _lock = acquire(dest.path(), false).expect("unreadable lock");
+        assert_eq!(holder_pid(&dest), std::process::id());
+    }
+}
@@ -1,26 +1,25 @@
 #![deny(clippy::unwrap_used)]
 
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::ops::Add;
 use std::time::Duration;
 use std::{
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
 };
 
 use anyhow::{anyhow, Context, Error, Result};
-use chrono::{DateTime, Local, Utc, TimeZone};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Offset, Utc, TimeZone};
 use clap::Parser;
 use futures::future::{ready, join_all};
 use futures::{stream, StreamExt, TryStreamExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use m3u8_rs::Playlist;
 use rand::Rng;
 use regex::Regex;
@@ -28,184 +27,1009 @@ use reqwest::{header, Response, Url};
 use select::document::Document;
 use select::predicate::Name;
 use serde_json::{json, Value};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 
 use canvas::{File, ProcessOptions};
-
-#[derive(Parser)]
+use metrics::{Metrics, RetryCause};
+use plan_tree::PlanStatus;
+use state::State;
+
+mod cache;
+mod clean;
+mod config;
+mod course_index;
+mod digest;
+mod disk;
+mod doctor;
+mod errors;
+mod http_cache;
+mod lock;
+mod manifest;
+mod metrics;
+mod mirror;
+mod plan_tree;
+mod report;
+mod state;
+mod state_bundle;
+mod storage_report;
+mod studio;
+#[cfg(test)]
+mod test_util;
+
+#[derive(Parser, serde::Serialize)]
 #[command(name = "Canvas Downloader")]
 #[command(version)]
 struct CommandLineOptions {
-    #[arg(short = 'c', long, value_name = "FILE")]
-    credential_file: PathBuf,
-    #[arg(short = 'd', long, value_name = "FOLDER", default_value = ".")]
+    // Required unless the config file has canvas_url and canvas_token
+    #[arg(short = 'c', long, value_name = "FILE", global = true)]
+    credential_file: Option<PathBuf>,
+    // Defaults for any option, ~/.config/canvas-downloader/config.toml when it exists
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+    #[arg(long)]
+    print_config: bool,
+    // Ignore user and course listings cached by a run in the last few minutes, and fetch every listing in full instead
+    // of asking Canvas whether it changed
+    #[arg(long)]
+    no_cache: bool,
+    #[arg(short = 'd', long, value_name = "FOLDER", default_value = ".", global = true)]
     destination_folder: PathBuf,
+    // Put every file of a course directly into the course folder, without Canvas' folder structure
+    #[arg(long)]
+    flat: bool,
+    // Course folder inside the destination, eg "{term_name}/{course_code} - {course_name}", see LAYOUT_PLACEHOLDERS
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_layout)]
+    layout: Option<String>,
+    // Course folders inside a folder per term, <term name or id>/<course code>, see course_folder_path()
+    #[arg(long, conflicts_with = "layout")]
+    group_by_term: bool,
+    // File names, eg "{updated_date} {display_name}", see NAME_PLACEHOLDERS. Unset, attachments are saved as id_name
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_name_template)]
+    name_template: Option<String>,
+    // Names of discussion, announcement, assignment and module folders. Unset, assignments use their title and the rest id-title
+    #[arg(long, value_enum, value_name = "NAMING")]
+    content_folder_naming: Option<ContentFolderNaming>,
+    // Extra copies of the destination, filled by local copy after each run
+    #[arg(long = "mirror", value_name = "FOLDER")]
+    mirrors: Vec<PathBuf>,
     #[arg(short = 'n', long)]
     download_newer: bool,
-    #[arg(short = 't', long, value_name = "ID", num_args(1..))]
-    term_ids: Option<Vec<u32>>,
+    // Term IDs, case-insensitive parts of term names like "2024 Semester 1", or current
+    #[arg(short = 't', long, value_name = "ID_OR_NAME", num_args(1..))]
+    term_ids: Option<Vec<String>>,
+    // Same as -t current, the term that started last, see current_terms()
+    #[arg(long)]
+    current_term: bool,
+    // Course IDs or course URLs like https://canvas.example.edu/courses/41234
+    #[arg(long, value_name = "ID_OR_URL", num_args(1..))]
+    course_ids: Option<Vec<String>>,
+    #[arg(long)]
+    all_courses: bool,
+    // Parts of course codes or names, eg CS3230, selecting every course that matches one of them, see course_matches()
+    #[arg(value_name = "COURSE")]
+    course_filters: Vec<String>,
+    // Which enrollments --all-courses lists, completed ones drop out of the favourites once a term is over
+    #[arg(long, value_enum, default_value_t = EnrollmentState::Active, requires = "all_courses")]
+    enrollment_state: EnrollmentState,
+    #[arg(long, value_name = "ID", num_args(1..))]
+    exclude_course_ids: Vec<u32>,
+    #[arg(long, value_name = "GLOB", num_args(1..))]
+    exclude_course_codes: Vec<String>,
+    // Folders in course files to skip with everything inside, by name or path like "Week 1/Recordings"
+    #[arg(long = "exclude-folder", value_name = "GLOB", num_args(1..))]
+    exclude_folders: Vec<String>,
+    // Also print skip reasons, folder creation and the URLs of retried requests
+    #[arg(short = 'v', long, conflicts_with = "quiet")]
+    verbose: bool,
+    // Only print errors and a one-line summary, without progress bars, eg for cron
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    // Treat a missing or empty course selection as an error instead of listing courses, implied when stdout is not a terminal
+    #[arg(long)]
+    non_interactive: bool,
+    #[arg(long, value_enum, default_value_t = DestPolicy::Merge)]
+    dest_policy: DestPolicy,
+    #[arg(long, value_enum, default_value_t = LocalChangePolicy::Keep)]
+    on_local_change: LocalChangePolicy,
+    #[arg(long)]
+    no_size_probe: bool,
+    // Canvas API requests at a time, while crawling and probing sizes
+    #[arg(long, alias = "concurrency", value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=64))]
+    api_concurrency: u32,
+    // Files downloaded at a time, each transfer is bandwidth heavy
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=64))]
+    download_concurrency: u32,
+    // Retries of a throttled, failing or timed out Canvas request or download, 0 to fail fast
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(0..=20))]
+    retries: u32,
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout: u64,
+    // A download receiving nothing for this long is aborted and retried
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout: u64,
+    // canvas-downloader/<version> when omitted
+    #[arg(long, value_name = "STRING")]
+    user_agent: Option<String>,
+    // Sent with every request to the Canvas host, eg --header "X-Requested-By: my-archiver"
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = |value: &str| parse_header(value).map(|_| value.to_string()))]
+    #[serde(serialize_with = "redact_headers")]
+    headers: Vec<String>,
+    // In-progress downloads, <destination>/.canvasdl/tmp when omitted. May be on another filesystem, see move_file()
+    #[arg(long, value_name = "FOLDER")]
+    tmp_dir: Option<PathBuf>,
+    // Download files over 64 MiB in this many parts at once, from servers that support it
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+    segments: u32,
+    // Delete partial downloads on failure instead of continuing them on the next attempt
+    #[arg(long)]
+    no_resume: bool,
+    // Download course by course instead of round-robin across courses
+    #[arg(long)]
+    no_interleave: bool,
+    // Order of the download queue instead of round-robin across courses, see order_downloads()
+    #[arg(long, value_enum, conflicts_with = "no_interleave")]
+    order: Option<DownloadOrder>,
+    // Bytes downloaded but not yet written to disk, across all downloads
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024 * 1024, value_parser = clap::value_parser!(u32).range(64 * 1024..))]
+    max_buffered_bytes: u32,
+    // Progress bars of single files shown at once, --download-concurrency when omitted. Downloads beyond it only count
+    // towards the overall bar
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=64))]
+    max_bars: Option<u32>,
+    // Files of the same course downloaded at a time, on top of --download-concurrency
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=64))]
+    per_course_downloads: Option<u32>,
+    // Buffer of each file being written, fewer writes for many small chunks
+    #[arg(long, value_name = "BYTES", default_value_t = 256 * 1024, value_parser = clap::value_parser!(u32).range(4 * 1024..=64 * 1024 * 1024))]
+    write_buffer: u32,
+    #[arg(long)]
+    support_bundle: bool,
+    // Write storage_report.txt and .json with the disk usage of each synced course
+    #[arg(long)]
+    storage_report: bool,
+    // Write digest-<date>.html into the destination with new announcements, files and changed due dates
+    #[arg(long)]
+    digest: bool,
+    // Whether a run without changes writes a "no changes" digest
+    #[arg(long, value_enum, default_value_t = EmptyDigest::Skip, requires = "digest")]
+    empty_digest: EmptyDigest,
+    // Do not write changes-<timestamp>.md with the new and updated files, topics and failures of each course
+    #[arg(long)]
+    no_report: bool,
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ReportFormat::Markdown)]
+    report_format: ReportFormat,
+    // Run with the path of the digest as last argument, eg --notify-command "xdg-open"
+    #[arg(long, value_name = "COMMAND", requires = "digest")]
+    notify_command: Option<String>,
+    // What to sync, everything when omitted
+    #[arg(long, value_name = "LIST", value_enum, value_delimiter = ',', num_args(1..))]
+    content: Option<Vec<ContentType>>,
+    #[arg(long)]
+    skip_videos: bool,
+    // Skip files larger than this, eg 200M or 1.5G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    // Download at most this much per run, eg 2G. The rest is deferred to the next run, see apply_download_budget()
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_total_bytes: Option<u64>,
+    // Only sync content updated after this date, YYYY-MM-DD (local midnight) or RFC 3339
+    #[arg(long, value_name = "DATE", value_parser = |value: &str| parse_since(value).map(|_| value.to_string()))]
+    since: Option<String>,
+    // Only sync content updated before the end of this date, YYYY-MM-DD (whole day) or RFC 3339
+    #[arg(long, value_name = "DATE", value_parser = |value: &str| parse_until(value).map(|_| value.to_string()))]
+    until: Option<String>,
+    // Crawl and list what would be downloaded without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    // Print the destination tree the crawl found, then stop unless --yes is given
+    #[arg(long)]
+    print_plan_tree: bool,
+    // Continue without asking: with the download after --print-plan-tree, or when the disk looks too full for it
+    #[arg(long)]
+    yes: bool,
+    // Mark archived discussions, announcements and must-view module items as read on Canvas
+    #[arg(long)]
+    mark_read: bool,
+    // Also sync unpublished modules and assignments, only visible to course staff
+    #[arg(long)]
+    include_unpublished: bool,
+    // Before an update replaces a file, move it to "<name>.<date>.<ext>". Optionally the number of old versions kept
+    // per file, the oldest are removed first
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "0")]
+    keep_versions: Option<u32>,
+    // Remove the old copy of a file renamed and updated on Canvas, once the new version is downloaded under the new
+    // name. Copies edited locally are kept
+    #[arg(long)]
+    prune_renamed: bool,
+    // Rename the folder of a course whose course code changed, instead of syncing into the folder named after the old one
+    #[arg(long)]
+    follow_renames: bool,
+    // Sync even when the lock says another run is syncing into the destination
+    #[arg(long)]
+    force_lock: bool,
+    // Download only what failed last time, listed in .canvasdl/failed.json, without crawling the courses
+    #[arg(long, conflicts_with_all = ["dry_run", "print_plan_tree", "list_files"])]
+    retry_failed: bool,
+    // Download what an interrupted run left in .canvasdl/queue.json without crawling the courses again, a full sync
+    // when nothing is left
+    #[arg(long = "resume", conflicts_with_all = ["dry_run", "print_plan_tree", "list_files", "retry_failed"])]
+    resume_queue: bool,
+    // Exit with 0 even when downloads or requests failed, they are still listed and written to
+    // <destination>/failures.json
+    #[arg(long)]
+    ignore_errors: bool,
+    // Record a checksum of each download, and check it before downloading a file again whose modified time looks
+    // older than Canvas but whose size did not change, eg on exFAT drives
+    #[arg(long)]
+    verify_content: bool,
+    // Also try files Canvas reports as locked, eg locked only by module prerequisites
+    #[arg(long)]
+    include_locked: bool,
+    // Sync the submissions of every student, not just your own, needs grading rights
+    #[arg(long)]
+    all_submissions: bool,
+    // Same as the list files command, eg --list-files=csv
+    #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "table")]
+    list_files: Option<ListFormat>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+// Without a subcommand, download is run
+#[derive(Clone, PartialEq, Eq, clap::Subcommand)]
+enum Command {
+    // Sync the selected courses into the destination folder
+    Download,
+    // Print terms or courses in --all-courses or favourites scope, or the files a download of the selection would
+    // fetch, for scripts
+    List {
+        #[arg(value_enum, default_value_t = ListTarget::Courses)]
+        target: ListTarget,
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        format: ListFormat,
+    },
+    // Same as list courses
+    ListCourses {
+        #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+        format: ListFormat,
+    },
+    // Remove leftovers of interrupted downloads from the destination, needs no credentials
+    Clean {
+        // Also remove empty folders
+        #[arg(long)]
+        empty_folders: bool,
+    },
+    // Probe what the credentials can access and print a capability report, eg canvas-downloader -c cred.json doctor
+    Doctor,
+    // Move the sync state of the destination to another machine, needs no credentials
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, clap::Subcommand)]
+enum StateAction {
+    // Write the state, manifest, cached listings and failed, deferred and queued downloads into one JSON file
+    Export { file: PathBuf },
+    // Take over an export, into a copy of the destination it was exported from
+    Import {
+        file: PathBuf,
+        // Replace the sync state the destination has already
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ListTarget {
+    Terms,
+    Courses,
+    Files,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ListFormat {
+    Table,
+    Json,
+    Csv,
+    Tsv,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DestPolicy {
+    // Sync into whatever already exists at the destination
+    Merge,
+    // Refuse to sync into a destination recorded for another Canvas instance or user
+    FailIfMismatched,
+    // Require an empty or nonexistent destination
+    Fresh,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum EnrollmentState {
+    Active,
+    Completed,
+    All,
+}
+
+// "12345_Welcome", "Welcome (12345)" or "Welcome", see content_folder()
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ContentFolderNaming {
+    IdTitle,
+    TitleId,
+    Title,
+}
+
+// A kind of course content, each synced by its own processor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ContentType {
+    Files,
+    Videos,
+    Assignments,
+    Discussions,
+    Announcements,
+    Modules,
+    Users,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ReportFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum EmptyDigest {
+    Skip,
+    // A one-line "no changes" page
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum DownloadOrder {
+    // Quick wins first, files of unknown size last
+    Smallest,
+    Largest,
+    // Most recently updated on Canvas first
+    Newest,
+    // Course by course, in the order the courses were crawled
+    Course,
+}
+
+// What -n does when a file updated on Canvas was also modified locally
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum LocalChangePolicy {
+    Keep,
+    Overwrite,
+    // Save the Canvas copy alongside as "name (remote update).ext"
+    Version,
+    // Prompt when interactive, otherwise keep
+    Ask,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// Per course options, from the CLI flags or the [role.<role>] rules of the config file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CourseRules {
+    pub include_unpublished: bool,
+    pub all_submissions: bool,
+    // Enrollment concluded, denied content is expected, see report_api_error()
+    pub concluded: bool,
+    // Course dates, see announcements_url()
+    pub start_at: Option<DateTime<FixedOffset>>,
+    pub end_at: Option<DateTime<FixedOffset>>,
+}
+
+// How a run ended, the exit code for scripts, see exit_outcome() and the README.
+// clap exits with 2 on invalid flags too
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Success = 0,
+    Fatal = 1,
+    InvalidConfig = 2,
+    // No course selected or the selection matches nothing, e.g. a cron job that lost its -t
+    NoSelection = 3,
+    // Files left out on purpose, e.g. by --max-file-size or a kept local change
+    SuccessWithSkips = 4,
+    PartialFailure = 5,
+    Unauthorized = 6,
+    // Connection failed or Canvas answered 5xx, e.g. during maintenance
+    Unreachable = 7,
+    Interrupted = 8,
+}
+
+// Marks errors in flags, the config file or the credential file, see error_outcome()
+#[derive(Debug)]
+struct InvalidConfig;
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid configuration")
+    }
 }
 
 macro_rules! fork {
     // Motivation: recursive async functions are unsupported. We avoid this by using a non-async
-    // function `f` to tokio::spawn our recursive function. Conveniently, we can wrap our barrier logic in this function
-    ($f:expr, $arg:expr, $T:ty, $options:expr) => {{
-        fn g(arg: $T, options: Arc<ProcessOptions>) {
-            options.n_active_requests.fetch_add(1, Ordering::AcqRel);
-            tokio::spawn(async move {
-                let _sem = options.sem_requests.acquire().await.unwrap_or_else(|e| {
-                    panic!("Please report on GitHub. Unexpected closed sem, err={e}")
-                });
-                let res = $f(arg, options.clone()).await;
-                let new_val = options.n_active_requests.fetch_sub(1, Ordering::AcqRel) - 1;
+    // function `f` to tokio::spawn our recursive function. Conveniently, we can wrap our barrier logic in this function.
+    // Tasks wait for a permit of sem_api, unless another semaphore of ProcessOptions is named, or no_permit when `f`
+    // takes its permits itself, see atomic_download_file()
+    (@spawn $f:expr, $arg:expr, $T:ty, $options:expr, $o:ident => $acquire:expr) => {{
+        fn g(arg: $T, $o: Arc<ProcessOptions>) {
+            $o.n_active_requests.fetch_add(1, Ordering::AcqRel);
+            // Failures are grouped by the course of the task that forked this one
+            tokio::spawn(errors::COURSE.scope(errors::current_course(), async move {
+                let _sem = $acquire;
+                let res = $f(arg, $o.clone()).await;
+                let new_val = $o.n_active_requests.fetch_sub(1, Ordering::AcqRel) - 1;
                 if new_val == 0 {
-                    options.notify_main.notify_one();
+                    $o.notify_main.notify_one();
                 }
                 if let Err(e) = res {
-                    eprintln!("{e:?}");
+                    $o.errors.report(&errors::task_content(stringify!($f)), &e, &$o.progress_bars);
                 }
-            });
+            }));
         }
         g($arg, $options);
     }};
+    ($f:expr, $arg:expr, $T:ty, $options:expr) => {
+        fork!($f, $arg, $T, $options, sem_api)
+    };
+    ($f:expr, $arg:expr, $T:ty, $options:expr, no_permit) => {
+        fork!(@spawn $f, $arg, $T, $options, options => ())
+    };
+    ($f:expr, $arg:expr, $T:ty, $options:expr, $sem:ident) => {
+        fork!(@spawn $f, $arg, $T, $options, options => options.$sem.acquire().await.unwrap_or_else(|e| {
+            panic!("Please report on GitHub. Unexpected closed sem, err={e}")
+        }))
+    };
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = CommandLineOptions::parse();
+async fn main() {
+    // Every way a run ends goes through here
+    let cancel = CancellationToken::new();
+    let outcome = tokio::select! {
+        result = run(cancel.clone()) => result.unwrap_or_else(|e| {
+            eprintln!("Error: {e:?}");
+            error_outcome(&e)
+        }),
+        Ok(()) = wait_for_interrupts(&cancel) => {
+            eprintln!("Interrupted");
+            Outcome::Interrupted
+        }
+    };
+    std::process::exit(outcome as i32);
+}
+
+// The first Ctrl-C lets run() wind down, see ProcessOptions.cancel. Returns on the second one, which quits right away
+async fn wait_for_interrupts(cancel: &CancellationToken) -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    cancel.cancel();
+    eprintln!("Stopping, press Ctrl-C again to quit immediately");
+    tokio::signal::ctrl_c().await
+}
+
+// Why a run failed early
+fn error_outcome(error: &anyhow::Error) -> Outcome {
+    // Found anywhere in the chain of contexts
+    if error.downcast_ref::<InvalidConfig>().is_some() {
+        return Outcome::InvalidConfig;
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<canvas::ApiError>() {
+            match e {
+                canvas::ApiError::Unauthorized => return Outcome::Unauthorized,
+                canvas::ApiError::Status(status) if status.starts_with('5') => return Outcome::Unreachable,
+                _ => {}
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_connect() || e.is_timeout() || e.status().is_some_and(|status| status.is_server_error()) {
+                return Outcome::Unreachable;
+            }
+            if matches!(e.status(), Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)) {
+                return Outcome::Unauthorized;
+            }
+        }
+    }
+    Outcome::Fatal
+}
+
+async fn run(cancel: CancellationToken) -> Result<Outcome> {
+    let loaded = config::load().context(InvalidConfig)?;
+    if loaded.args.print_config {
+        return config::print(&loaded).map(|_| Outcome::Success);
+    }
+    let role_rules = loaded.role_rules;
+    let args = loaded.args;
+
+    // Only looks at the destination
+    if let Some(Command::Clean { empty_folders }) = args.command {
+        let tmp_dir = args
+            .tmp_dir
+            .clone()
+            .unwrap_or_else(|| state::state_dir(&args.destination_folder).join(state::TMP_DIR));
+        return clean::run(&args.destination_folder, &tmp_dir, empty_folders).map(|_| Outcome::Success);
+    }
+    match &args.command {
+        Some(Command::State { action: StateAction::Export { file } }) => {
+            return state_bundle::export(&args.destination_folder, file).map(|_| Outcome::Success);
+        }
+        Some(Command::State { action: StateAction::Import { file, force } }) => {
+            return state_bundle::import(&args.destination_folder, file, *force).map(|_| Outcome::Success);
+        }
+        _ => {}
+    }
 
     // Load credentials
-    let file = std::fs::File::open(&args.credential_file)
-        .with_context(|| "Could not open credential file")?;
-    let cred: canvas::Credentials =
-        serde_json::from_reader(file).with_context(|| "Credential file is not valid json")?;
+    let cred: canvas::Credentials = match (&args.credential_file, loaded.credentials) {
+        (Some(credential_file), _) => {
+            let file = std::fs::File::open(credential_file)
+                .with_context(|| "Could not open credential file")
+                .context(InvalidConfig)?;
+            serde_json::from_reader(file)
+                .with_context(|| "Credential file is not valid json")
+                .context(InvalidConfig)?
+        }
+        (None, Some(credentials)) => credentials,
+        (None, None) => {
+            return Err(anyhow!(
+                "Please provide a credential file via -c, or canvas_url and canvas_token in the config file"
+            )
+            .context(InvalidConfig))
+        }
+    };
+
+    if args.dest_policy == DestPolicy::Fresh && !is_empty_or_missing(&args.destination_folder)? {
+        return Err(anyhow!(
+            "Destination {} is not empty. --dest-policy fresh requires an empty or nonexistent folder, \
+             pick another destination or pass --dest-policy merge to sync into it anyway",
+            args.destination_folder.to_string_lossy()
+        )
+        .context(InvalidConfig));
+    }
 
     // Create sub-folder if not exists
-    if !args.destination_folder.exists() {
+    let command = run_command(&args);
+    let read_only = is_read_only(&args, &command);
+    if !read_only && !args.destination_folder.exists() {
         std::fs::create_dir(&args.destination_folder)
             .unwrap_or_else(|e| panic!("Failed to create destination directory, err={e}"));
     }
+    // Held until run() returns or is dropped on Ctrl-C
+    let _lock = if read_only {
+        None
+    } else {
+        Some(lock::acquire(&args.destination_folder, args.force_lock)?)
+    };
 
-    // Prepare GET request options
-    let client = reqwest::ClientBuilder::new()
-        .tcp_keepalive(Some(Duration::from_secs(10)))
-        .http2_keep_alive_interval(Some(Duration::from_secs(2)))
-        .build()
-        .with_context(|| "Failed to create HTTP client")?;
-    let user_link = format!("{}/api/v1/users/self", cred.canvas_url);
-    let user = client
-        .get(&user_link)
-        .bearer_auth(&cred.canvas_token)
-        .send()
-        .await?
-        .json::<canvas::User>()
-        .await
-        .with_context(|| "Failed to get user info")?;
-    let courses_link = format!("{}/api/v1/users/self/favorites/courses", cred.canvas_url);
-    let options = Arc::new(ProcessOptions {
-        canvas_token: cred.canvas_token.clone(),
-        canvas_url: cred.canvas_url.clone(),
-        client: client.clone(),
-        user: user.clone(),
-        // Process
-        files_to_download: tokio::sync::Mutex::new(Vec::new()),
-        download_newer: args.download_newer,
-        // Download
-        progress_bars: MultiProgress::new(),
-        progress_style: {
-            let style_template = if termsize::get().map_or(false, |size| size.cols < 100) {
-                "[{wide_bar:.cyan/blue}] {total_bytes} - {msg}"
-            } else {
-                "[{bar:20.cyan/blue}] {bytes}/{total_bytes} - {bytes_per_sec} - {msg}"
-            };
-            ProgressStyle::default_bar()
-                .template(style_template)
-                .unwrap_or_else(|e| panic!("Please report this issue on GitHub: error with progress bar style={style_template}, err={e}"))
-                .progress_chars("=>-")
-        },
-        // Synchronization
-        n_active_requests: AtomicUsize::new(0),
-        sem_requests: tokio::sync::Semaphore::new(8), // WARN magic constant.
-        notify_main: tokio::sync::Notify::new(),
-        // TODO handle canvas rate limiting errors, maybe scale up if possible
-    });
+    let clients = http_clients(&args)?;
+    // doctor checks the token, so it always asks Canvas
+    let cache_key = cache::key(&cred.canvas_url, &cred.canvas_token);
+    let cached = (!args.no_cache && command != Command::Doctor)
+        .then(|| cache::Cache::load(&args.destination_folder, &cache_key))
+        .flatten();
+    let cache_fresh = cached.is_some();
+    let mut cache = if let Some(cached) = cached {
+        cached
+    } else {
+        let configured_api_base = canvas::api_base(&cred.canvas_url, cred.api_prefix.as_deref());
+        let user_link = format!("{configured_api_base}/users/self");
+        let user_resp = clients
+            .client
+            .get(&user_link)
+            .headers(clients.canvas_headers.clone())
+            .bearer_auth(&cred.canvas_token)
+            .send()
+            .await?;
+        // Without a configured prefix, follow wherever the instance redirected the first request
+        let api_base = match &cred.api_prefix {
+            Some(_) => configured_api_base,
+            None => canvas::detect_api_base(user_resp.url()).unwrap_or(configured_api_base),
+        };
+        let user_status = user_resp.status();
+        if matches!(user_status, reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) {
+            return Err(anyhow!(canvas::ApiError::Unauthorized)
+                .context("Canvas rejected the token. Check canvasToken in the credential file"));
+        }
+        if user_status.is_server_error() {
+            return Err(anyhow!(canvas::ApiError::Status(user_status.to_string()))
+                .context("Canvas is unavailable, it may be down for maintenance"));
+        }
+        let user = user_resp
+            .json::<canvas::User>()
+            .await
+            .with_context(|| format!("Failed to get user info, Canvas answered {user_status}. Check canvasToken in the credential file"))?;
+        cache::Cache::new(cache_key, api_base, user)
+    };
+    let api_base = cache.api_base.clone();
+    let user = cache.user.clone();
+
+    // Check and record which instance and user this destination belongs to
+    let previous_state = State::load(&args.destination_folder)?;
+    if args.dest_policy == DestPolicy::FailIfMismatched {
+        if let Some(previous) = &previous_state {
+            check_state_matches(previous, &cred.canvas_url, &user)?;
+        }
+    }
+    // Everything would be new, the digest starts with the second run
+    let first_sync = previous_state.is_none();
+    let mut state = previous_state.unwrap_or_default();
+    state.canvas_url = Some(cred.canvas_url.clone());
+    state.user_id = Some(user.id);
+    state.user_name = Some(user.name.clone());
+    if !read_only {
+        state.save(&args.destination_folder)?;
+    }
+    let courses_links = if args.all_courses {
+        let enrollment_states: &[&str] = match args.enrollment_state {
+            EnrollmentState::Active => &["active"],
+            EnrollmentState::Completed => &["completed"],
+            EnrollmentState::All => &["active", "completed"],
+        };
+        enrollment_states
+            .iter()
+            .map(|state| format!("{api_base}/courses?enrollment_state={state}&include[]=term"))
+            .collect()
+    } else {
+        vec![format!("{api_base}/users/self/favorites/courses?include[]=term")]
+    };
+    let options = Arc::new(process_options(&args, &cred, clients, api_base, user.clone(), state, cancel)?);
+
+    if command == Command::Doctor {
+        return doctor::run(&options).await.map(|_| Outcome::Success);
+    }
+    if args.retry_failed {
+        return retry_failed(&args, options).await;
+    }
+    if args.resume_queue {
+        let queue = state::load_queue(&args.destination_folder);
+        if !queue.is_empty() {
+            return resume_queue(&args, options, queue).await;
+        }
+        options.info("No interrupted downloads to resume, syncing everything");
+    }
+
+    // The change report compares against what was downloaded before this run
+    let manifest_before = options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .clone();
 
     // Get courses
-    let courses: Vec<canvas::Course> = get_pages(courses_link.clone(), &options)
-        .await?
-        .into_iter()
-        .map(|resp| resp.json::<Vec<serde_json::Value>>()) // resp --> Result<Vec<json>>
-        .collect::<stream::FuturesUnordered<_>>() // (in any order)
-        .flat_map_unordered(None, |json_res| {
-            let jsons = json_res.unwrap_or_else(|e| panic!("Failed to parse courses, err={e}")); // Result<Vec<json>> --> Vec<json>
-            stream::iter(jsons.into_iter()) // Vec<json> --> json
-        })
-        .filter(|json| ready(json.get("enrollments").is_some())) // (enrolled?)
-        .map(serde_json::from_value) // json --> Result<course>
-        .try_collect()
-        .await
-        .with_context(|| "Error when getting course json")?; // Result<course> --> course
+    let mut courses = Vec::new();
+    let mut courses_cached = false;
+    for courses_link in courses_links {
+        let listed = match cache.courses.get(&courses_link) {
+            Some(courses) if cache_fresh => {
+                courses_cached = true;
+                courses.clone()
+            }
+            _ => {
+                let courses = get_courses(courses_link.clone(), &options).await?;
+                cache.courses.insert(courses_link, courses.clone());
+                if !read_only {
+                    if let Err(e) = cache.save(&args.destination_folder) {
+                        eprintln!("Failed to cache the course list, err={e:?}");
+                    }
+                }
+                courses
+            }
+        };
+        // --enrollment-state all lists a course twice when some of your enrollments in it are concluded
+        for course in listed {
+            if !courses.iter().any(|known: &canvas::Course| known.id == course.id) {
+                courses.push(course);
+            }
+        }
+    }
+    // Selection errors may be due to favourites changed since
+    let cache_hint = if courses_cached {
+        format!(
+            "\nThe course list was cached less than {} minutes ago, pass --no-cache if your favourites or enrollments changed",
+            cache::TTL_MINUTES
+        )
+    } else {
+        String::new()
+    };
 
-    // Filter courses by term IDs
-    let Some(term_ids) = args.term_ids else {
-        println!("Please provide the Term ID(s) to download via -t");
-        print_all_courses_by_term(&courses);
-        return Ok(());
+    match command {
+        Command::List { target: ListTarget::Courses, format } => {
+            match format {
+                ListFormat::Table => print_courses_table(&courses),
+                ListFormat::Json => println!("{}", serde_json::to_string_pretty(&courses)?),
+                ListFormat::Csv | ListFormat::Tsv => {
+                    let rows: Vec<[String; 6]> = courses
+                        .iter()
+                        .map(|course| {
+                            [
+                                course.id.to_string(),
+                                course.course_code.clone(),
+                                course.name.clone(),
+                                course.enrollment_term_id.to_string(),
+                                course.term_name().to_string(),
+                                course.role().unwrap_or_default().to_string(),
+                            ]
+                        })
+                        .collect();
+                    print_records(format, &["id", "course_code", "name", "term_id", "term", "role"], &rows);
+                }
+            }
+            return Ok(Outcome::Success);
+        }
+        Command::List { target: ListTarget::Terms, format } => {
+            match format {
+                ListFormat::Table => print_all_courses_by_term(&courses),
+                ListFormat::Json => println!("{}", serde_json::to_string_pretty(&terms_json(&courses))?),
+                ListFormat::Csv | ListFormat::Tsv => {
+                    let rows: Vec<[String; 3]> = group_courses_by_term(&courses)
+                        .into_iter()
+                        .map(|(term_id, courses)| {
+                            let course_ids: Vec<String> = courses.iter().map(|course| course.id.to_string()).collect();
+                            [
+                                term_id.to_string(),
+                                courses.first().map_or("", |course| course.term_name()).to_string(),
+                                course_ids.join(" "),
+                            ]
+                        })
+                        .collect();
+                    print_records(format, &["id", "name", "course_ids"], &rows);
+                }
+            }
+            return Ok(Outcome::Success);
+        }
+        _ => {}
+    }
+
+    if courses.is_empty() && !args.all_courses {
+        println!("No favourite courses found. Use --all-courses to include courses that are not starred in Canvas{cache_hint}");
+        return Ok(Outcome::Success);
+    }
+
+    // Filter courses by term IDs and course IDs
+    // Unattended runs (cron, CI) must not look successful when nothing is selected
+    let interactive = {
+        use std::io::IsTerminal;
+        !args.non_interactive && std::io::stdout().is_terminal()
+    };
+    let mut picked_course_ids = None;
+    let term_refs: Option<Vec<String>> = match (&args.term_ids, args.current_term) {
+        (term_refs, false) => term_refs.clone(),
+        (term_refs, true) => Some(term_refs.iter().flatten().cloned().chain(["current".to_string()]).collect()),
+    };
+    if term_refs.as_ref().is_none_or(Vec::is_empty) && args.course_ids.is_none() && args.course_filters.is_empty() {
+        use std::io::IsTerminal;
+        if !interactive {
+            eprintln!("No courses selected, pass Term ID(s) via -t or Course ID(s) via --course-ids");
+            return Ok(Outcome::NoSelection);
+        }
+        if !std::io::stdin().is_terminal() {
+            println!("Please provide the Term ID(s) to download via -t");
+            print_all_courses_by_term(&courses);
+            return Ok(Outcome::Success);
+        }
+        // Interactive, picked courses behave exactly like --course-ids
+        let picked = pick_courses(&courses);
+        if picked.is_empty() {
+            println!("No course selected");
+            return Ok(Outcome::Success);
+        }
+        picked_course_ids = Some(picked);
+    }
+    let course_ids = match picked_course_ids {
+        Some(picked) => Some(picked),
+        None => args
+            .course_ids
+            .as_ref()
+            .map(|course_refs| {
+                course_refs
+                    .iter()
+                    .map(|course_ref| parse_course_ref(course_ref, &cred.canvas_url))
+                    .collect::<Result<Vec<u32>>>()
+            })
+            .transpose()
+            .context(InvalidConfig)?,
+    };
+    if let Some(course_ids) = &course_ids {
+        // Courses missing from the listing (e.g. not favourited) are fetched directly
+        let mut unavailable = Vec::new();
+        for course_id in course_ids {
+            if courses.iter().any(|course| course.id == *course_id) {
+                continue;
+            }
+            match get_course(*course_id, &options).await {
+                Ok(course) => courses.push(course),
+                Err(e) => unavailable.push(format!("{course_id}: {e}")),
+            }
+        }
+        if !unavailable.is_empty() {
+            println!("Could not access the following Course ID(s):");
+            for reason in unavailable {
+                println!("  * {reason}");
+            }
+            println!("Please try the following ID(s) instead");
+            print_all_courses_with_ids(&courses);
+            return Ok(if interactive { Outcome::Success } else { Outcome::NoSelection });
+        }
+    }
+    // Courses matching a positional filter are selected like --course-ids, across terms unless -t narrows them down
+    let course_ids = if args.course_filters.is_empty() {
+        course_ids
+    } else {
+        let matched: Vec<&canvas::Course> = courses
+            .iter()
+            .filter(|course| course_matches(course, &args.course_filters))
+            .collect();
+        if matched.is_empty() {
+            println!("No course code or name contains {}{cache_hint}", args.course_filters.join(" or "));
+            print_all_courses_by_term(&courses);
+            return Ok(if interactive { Outcome::Success } else { Outcome::NoSelection });
+        }
+        options.info(format!("Courses matching {}:", args.course_filters.join(" or ")));
+        for course in &matched {
+            options.info(format!("  * {} - {} ({})", course.course_code, course.name, course.term_name()));
+        }
+        Some(course_ids.into_iter().flatten().chain(matched.iter().map(|course| course.id)).collect())
     };
+    let wants_current_term = term_refs
+        .iter()
+        .flatten()
+        .any(|term_ref| term_ref.eq_ignore_ascii_case("current"));
+    if wants_current_term {
+        let current = current_terms(&courses);
+        if current.is_empty() {
+            println!("No courses to tell the current term from{cache_hint}");
+            print_all_courses_by_term(&courses);
+            return Ok(Outcome::NoSelection);
+        }
+        if current.len() > 1 {
+            options.info("Several terms started last, all of them are selected");
+        }
+        for (term_id, term_name) in &current {
+            options.info(format!("Current term: {term_id} - {term_name}"));
+            for course in courses.iter().filter(|course| course.enrollment_term_id == *term_id) {
+                options.info(format!("  * {} - {}", course.course_code, course.name));
+            }
+        }
+    }
+    let term_ids = term_refs
+        .as_ref()
+        .map(|term_refs| resolve_term_refs(term_refs, &courses))
+        .transpose()
+        .map_err(|e| anyhow!("{e}{cache_hint}").context(InvalidConfig))?;
     let courses_matching_term_ids: Vec<&canvas::Course> = courses
         .iter()
-        .filter(|course_json| term_ids.contains(&course_json.enrollment_term_id))
+        .filter(|course_json| {
+            term_ids
+                .as_ref()
+                .is_none_or(|term_ids| term_ids.contains(&course_json.enrollment_term_id))
+        })
+        .filter(|course_json| {
+            course_ids
+                .as_ref()
+                .is_none_or(|course_ids| course_ids.contains(&course_json.id))
+        })
         .collect();
     if courses_matching_term_ids.is_empty() {
-        println!("Could not find any course matching Term ID(s) {term_ids:?}");
+        match (&term_ids, &course_ids) {
+            (Some(term_ids), Some(course_ids)) => println!(
+                "Could not find any course matching both Term ID(s) {term_ids:?} and Course ID(s) {course_ids:?}"
+            ),
+            (Some(term_ids), None) => println!("Could not find any course matching Term ID(s) {term_ids:?}"),
+            _ => println!("Could not find any course matching the selection"),
+        }
+        if courses_cached {
+            println!("{}", cache_hint.trim_start());
+        }
+        if !interactive {
+            eprintln!("Nothing to sync, check the Term ID(s) and Course ID(s) in your configuration");
+            return Ok(Outcome::NoSelection);
+        }
         println!("Please try the following ID(s) instead");
-        print_all_courses_by_term(&courses);
-        return Ok(());
+        print_all_courses_with_ids(&courses);
+        return Ok(Outcome::Success);
     }
 
-    println!("Courses found:");
+    options.info("Courses found:");
+    let mut synced_courses = Vec::new();
+    let mut role_activated = Vec::new();
     for course in courses_matching_term_ids {
-        println!("  * {} - {}", course.course_code, course.name);
+        if let Some(reason) = exclusion_reason(course, &args) {
+            options.info(format!("  - {} - {} (excluded: {reason})", course.course_code, course.name));
+            continue;
+        }
+        let (rules, by_role) = course_rules_for(course, &args, &role_rules);
+        let mut notes: Vec<String> = course.role().map(str::to_string).into_iter().collect();
+        if rules.concluded {
+            notes.push("concluded".to_string());
+        }
+        if !by_role.is_empty() {
+            notes.push(format!("{} by role rule", by_role.join(", ")));
+        }
+        let notes = if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) };
+        options.info(format!("  * {} - {}{notes}", course.course_code, course.name));
+        if !by_role.is_empty() {
+            role_activated.push((course.course_code.clone(), by_role));
+        }
 
         // Prep path and mkdir -p
-        let course_folder_path = args
-            .destination_folder
-            .join(course.course_code.replace('/', "_"));
-        create_folder_if_not_exist(&course_folder_path)?;
-        // Prep URL for course's root folder
-        let course_folders_link = format!(
-            "{}/api/v1/courses/{}/folders/by_path/",
-            cred.canvas_url, course.id
-        );
-        
-        /*
-        let folder_path = course_folder_path.join("files");
-        fork!(
-            process_folders,
-            (course_folders_link, folder_path),
-            (String, PathBuf),
-            options.clone()
-        );
-         */
-        
-        let course_api_link = format!(
-            "{}/api/v1/courses/{}/",
-            cred.canvas_url, course.id
-        );
-        fork!(
-            process_data,
-            (course_api_link, course_folder_path.clone()),
-            (String, PathBuf),
-            options.clone()
-        );
+        let course_folder_path = tracked_course_folder(course, &args, &options)?;
+        options.create_folder(&course_folder_path)?;
+        options
+            .course_folders
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned course folders lock, err={e}"))
+            .push(course_folder_path.clone());
+        options
+            .course_rules
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned course rules lock, err={e}"))
+            .insert(course_folder_path.clone(), rules);
+        options
+            .course_ids
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned course ids lock, err={e}"))
+            .insert(course_folder_path.clone(), course.id);
+        // Listed in the summary also when nothing is found in it
+        options
+            .course_summaries
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned summaries lock, err={e}"))
+            .entry(course_label(&course_folder_path, &options))
+            .or_default();
+        synced_courses.push((course, course_folder_path.clone()));
+        errors::COURSE.sync_scope(Some(course_label(&course_folder_path, &options)), || -> Result<()> {
+            // Prep URL for course's root folder
+            let course_folders_link = options.api_url(&format!("courses/{}/folders/by_path/", course.id));
+            if options.content.contains(&ContentType::Files) {
+                let folder_path = course_folder_path.join("files");
+                fork!(
+                    process_folders,
+                    (course_folders_link, folder_path),
+                    (String, PathBuf),
+                    options.clone()
+                );
+            }
 
-        let video_folder_path = course_folder_path.join("videos");
-        create_folder_if_not_exist(&video_folder_path)?;
-        fork!(
-            process_videos,
-            (cred.canvas_url.clone(), course.id, video_folder_path),
-            (String, u32, PathBuf),
-            options.clone()
-        );
+            let course_api_link = options.api_url(&format!("courses/{}/", course.id));
+            fork!(
+                process_data,
+                (course_api_link, course_folder_path.clone()),
+                (String, PathBuf),
+                options.clone()
+            );
+
+            if options.content.contains(&ContentType::Videos) {
+                // Created by process_video_folder() once there is something to put in it
+                let video_folder_path = course_folder_path.join("videos");
+                fork!(
+                    process_videos,
+                    (cred.canvas_url.clone(), course.id, video_folder_path),
+                    (String, u32, PathBuf),
+                    options.clone()
+                );
+            }
+            Ok(())
+        })?;
     }
 
     // Invariants
@@ -215,14 +1039,157 @@ async fn main() -> Result<()> {
     //    3. --> n_active_requests == 0 only after all tasks done
     //    4. --> main() progresses only after all files have been queried
     // 2. No starvation: forks are done acyclically, all tasks +1 and -1 exactly once
-    // 3. Bounded concurrency: acquire or block on semaphore before request
+    // 3. Bounded concurrency: acquire or block on semaphore before request, sem_api while crawling, sem_downloads after
     // 4. No busy wait: Last task will see that there are 0 active requests and notify main
     options.notify_main.notified().await;
     assert_eq!(options.n_active_requests.load(Ordering::Acquire), 0);
-    println!();
+    if let Some(http_cache) = &options.http_cache {
+        let (revalidated, hits) = http_cache.counts();
+        options.detail(format!("{hits} of {revalidated} cached Canvas listing(s) were unchanged and served from the cache"));
+        match http_cache.evict() {
+            Ok(0) => {}
+            Ok(removed) => options.detail(format!("Removed {removed} old listing(s) from the cache")),
+            Err(e) => eprintln!("Failed to clean up the cache of Canvas listings, err={e:?}"),
+        }
+    }
+    options.info("");
+    if options.cancel.is_cancelled() {
+        options.errors.print_summary();
+        eprintln!("Interrupted while looking for files, nothing was downloaded");
+        return Ok(Outcome::Interrupted);
+    }
 
-    let files_to_download = options.files_to_download.lock().await;
-    println!(
+    let mut files_to_download = options.files_to_download.lock().await;
+
+    let planned = std::mem::take(
+        &mut *options
+            .planned
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned plan lock, err={e}")),
+    );
+    if let Command::List { target: ListTarget::Files, format } = command {
+        options.errors.print_summary();
+        print_files(&planned, format, &options)?;
+        return Ok(Outcome::Success);
+    }
+    if args.print_plan_tree {
+        options.errors.print_summary();
+        let planned: Vec<(PathBuf, PlanStatus)> =
+            planned.into_iter().map(|(canvas_file, status)| (canvas_file.filepath, status)).collect();
+        plan_tree::print(&args.destination_folder, &planned);
+        if read_only {
+            if !args.yes {
+                println!("Nothing was downloaded, pass --yes together with --print-plan-tree to continue");
+            }
+            return Ok(Outcome::Success);
+        }
+    }
+    if args.dry_run {
+        options.errors.print_summary();
+        print_dry_run(&files_to_download, &options);
+        return Ok(Outcome::Success);
+    }
+
+    // Files updated on Canvas that were also modified locally
+    let conflicts = std::mem::take(&mut *options
+        .local_conflicts
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned conflicts lock, err={e}")));
+    let mut local_change_outcomes = Vec::new();
+    let mut n_updates_kept_out = 0;
+    for mut canvas_file in conflicts {
+        let decision = match args.on_local_change {
+            LocalChangePolicy::Ask => ask_local_change(&canvas_file.filepath),
+            policy => policy,
+        };
+        let outcome = match decision {
+            LocalChangePolicy::Overwrite => {
+                files_to_download.push(canvas_file.clone());
+                "overwritten with the Canvas version".to_string()
+            }
+            LocalChangePolicy::Version => {
+                let local_path = canvas_file.filepath.clone();
+                canvas_file.filepath = remote_update_path(&local_path);
+                let outcome = format!(
+                    "local copy kept, Canvas version saved as {}",
+                    canvas_file.filepath.to_string_lossy()
+                );
+                // Skip when this exact Canvas version was already saved alongside in a previous run
+                let already_saved = canvas_file
+                    .filepath
+                    .strip_prefix(&options.destination_folder)
+                    .ok()
+                    .and_then(|relative_path| {
+                        options
+                            .state
+                            .lock()
+                            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+                            .files
+                            .get(relative_path.to_string_lossy().as_ref())
+                            .cloned()
+                    })
+                    .is_some_and(|recorded| recorded == canvas_file.updated_at);
+                if !already_saved {
+                    files_to_download.push(canvas_file.clone());
+                }
+                canvas_file.filepath = local_path;
+                outcome
+            }
+            LocalChangePolicy::Keep | LocalChangePolicy::Ask => {
+                n_updates_kept_out += 1;
+                "local copy kept, Canvas update not downloaded".to_string()
+            }
+        };
+        local_change_outcomes.push((canvas_file.filepath, outcome));
+    }
+
+    // The same Canvas file found in several places is downloaded once and copied to the others afterwards
+    let local_copies = dedup_downloads(&mut files_to_download, options.case_insensitive);
+    for (file, source) in &local_copies {
+        options.detail(format!(
+            "{} is queued more than once, copying it from {}",
+            file.filepath.to_string_lossy(),
+            source.to_string_lossy()
+        ));
+    }
+    if !args.no_size_probe {
+        probe_unknown_sizes(&mut files_to_download, &options).await;
+        files_to_download.retain(|f| !exceeds_max_file_size(&options, f, f.size));
+    }
+    let (deferred, n_unknown_size) = match args.max_total_bytes {
+        Some(max_total_bytes) => apply_download_budget(&mut files_to_download, max_total_bytes, &options),
+        None => (Vec::new(), 0),
+    };
+    if !read_only {
+        state::save_deferred(&args.destination_folder, &deferred)?;
+    }
+    if let Some(order) = args.order {
+        order_downloads(&mut files_to_download, order, |canvas_file| course_folder_of(&canvas_file.filepath, &options));
+    } else if !args.no_interleave {
+        *files_to_download = interleave_by_course(std::mem::take(&mut *files_to_download), &options);
+    }
+
+    // Better to stop now than to fill the disk halfway through
+    if !read_only {
+        match clean::remove_stale_tmp_files(&options.tmp_dir, options.resume) {
+            Ok((0, _)) => {}
+            Ok((files_removed, bytes_removed)) => options.info(format!(
+                "Removed {files_removed} leftover download(s) of earlier runs, {}",
+                indicatif::HumanBytes(bytes_removed)
+            )),
+            Err(e) => eprintln!("Failed to remove leftover downloads, err={e:?}"),
+        }
+        check_free_space(&files_to_download, &args, &options)?;
+        // The crawl is not repeated with --resume when the downloads are interrupted
+        let queue = state::queued_files(&args.destination_folder, &files_to_download);
+        state::save_queue(&args.destination_folder, &queue)?;
+        *options
+            .queue
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned queue lock, err={e}")) = queue;
+    }
+
+    options.info(format!(
         "Downloading {} file{}",
         files_to_download.len(),
         if files_to_download.len() == 1 {
@@ -230,1487 +1197,7242 @@ async fn main() -> Result<()> {
         } else {
             "s"
         }
+    ));
+
+    download_all(&files_to_download, &options).await;
+    let found_sizes = std::mem::take(
+        &mut *options
+            .found_sizes
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned found sizes lock, err={e}")),
     );
-
-    // Download files
-    options.n_active_requests.fetch_add(1, Ordering::AcqRel); // prevent notifying until all spawned
-    for canvas_file in files_to_download.iter() {
-        fork!(
-            atomic_download_file,
-            canvas_file.clone(),
-            File,
-            options.clone()
-        );
-    }
-
-    // Wait for downloads
-    let new_val = options.n_active_requests.fetch_sub(1, Ordering::AcqRel) - 1;
-    if new_val == 0 {
-        // notify if all finished immediately
-        options.notify_main.notify_one();
+    for canvas_file in files_to_download.iter_mut().filter(|canvas_file| canvas_file.size == 0) {
+        canvas_file.size = found_sizes.get(&canvas_file.filepath).copied().unwrap_or(0);
     }
-    options.notify_main.notified().await;
     // Sanity check: running tasks trying to acquire sem will panic
-    options.sem_requests.close();
+    options.sem_api.close();
+    options.sem_downloads.close();
     assert_eq!(options.n_active_requests.load(Ordering::Acquire), 0);
+    copy_duplicates(&local_copies, &options);
 
-    for canvas_file in files_to_download.iter() {
-        println!(
-            "Downloaded {} to {}",
+    let skipped_for_size = std::mem::take(
+        &mut *options
+            .skipped_for_size
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned skipped lock, err={e}")),
+    );
+    let refused_locked = std::mem::take(
+        &mut *options
+            .refused_locked
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned refused lock, err={e}")),
+    );
+    let cancelled = std::mem::take(
+        &mut *options
+            .cancelled
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned cancelled lock, err={e}")),
+    );
+    let failed_downloads = take_failed_downloads(&options);
+    let mut n_downloaded = 0;
+    for canvas_file in files_to_download.iter() {
+        if skipped_for_size
+            .iter()
+            .chain(refused_locked.iter())
+            .chain(cancelled.iter())
+            .chain(failed_downloads.iter())
+            .any(|f| f.filepath == canvas_file.filepath)
+        {
+            continue;
+        }
+        n_downloaded += 1;
+        options.info(format!(
+            "Downloaded {} to {} ({})",
             canvas_file.display_name,
-            canvas_file.filepath.to_string_lossy()
+            canvas_file.filepath.to_string_lossy(),
+            indicatif::HumanBytes(canvas_file.size)
+        ));
+    }
+    options.info(format!(
+        "Used up to {} concurrent API requests and {} concurrent downloads",
+        args.api_concurrency, args.download_concurrency
+    ));
+    print_course_summaries(&options, &refused_locked);
+    if !skipped_for_size.is_empty() {
+        options.info("Skipped for exceeding --max-file-size:");
+        for canvas_file in skipped_for_size.iter() {
+            options.info(format!(
+                "  * {} ({} bytes)",
+                canvas_file.filepath.to_string_lossy(),
+                canvas_file.size
+            ));
+        }
+    }
+    let n_skipped_locked = options.n_skipped_locked.load(Ordering::Relaxed);
+    if n_skipped_locked > 0 {
+        options.info(format!(
+            "{n_skipped_locked} file(s) still locked on Canvas, downloaded once unlocked. Pass --include-locked to try \
+             them anyway"
+        ));
+    }
+    if !refused_locked.is_empty() {
+        options.info("Locked by instructor, Canvas refused the download:");
+        for canvas_file in refused_locked.iter() {
+            options.info(format!("  * {}", canvas_file.filepath.to_string_lossy()));
+        }
+    }
+    if !deferred.is_empty() {
+        options.info(format!(
+            "Deferred {} file(s), {}, to stay within --max-total-bytes. They are downloaded first on the next run",
+            deferred.len(),
+            indicatif::HumanBytes(deferred.iter().map(|f| f.size).sum())
+        ));
+    }
+    if n_unknown_size > 0 {
+        options.info(format!(
+            "{n_unknown_size} downloaded file(s) had no known size and were not counted towards --max-total-bytes"
+        ));
+    }
+    if !local_change_outcomes.is_empty() {
+        options.info("Locally modified files:");
+        for (filepath, outcome) in local_change_outcomes {
+            options.info(format!("  * {}: {outcome}", filepath.to_string_lossy()));
+        }
+    }
+    if !role_activated.is_empty() {
+        options.info("Turned on by [role] rules of the config file:");
+        for (course_code, by_role) in &role_activated {
+            options.info(format!("  * {course_code}: {}", by_role.join(", ")));
+        }
+    }
+    if args.quiet {
+        println!(
+            "Synced {} course(s): {n_downloaded} file(s) downloaded, {} skipped for size, {} failure(s)",
+            synced_courses.len(),
+            skipped_for_size.len(),
+            options.errors.count()
         );
     }
 
-    Ok(())
-}
+    options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .save(&options.destination_folder)?;
+    let run_metrics = options.metrics.snapshot();
+    {
+        let mut manifest = options
+            .manifest
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"));
+        manifest.record_run(run_metrics.clone());
+        manifest.save(&options.destination_folder)?;
+    }
+    let previously_failed = state::load_failed(&args.destination_folder);
+    state::save_failed(
+        &args.destination_folder,
+        &state::failed_files(&args.destination_folder, &previously_failed, &failed_downloads),
+    )?;
+    if !options.cancel.is_cancelled() && !read_only {
+        state::save_queue(&args.destination_folder, &[])?;
+    }
 
-async fn atomic_download_file(file: File, options: Arc<ProcessOptions>) -> Result<()> {
-    // Create tmp file from hash
-    let mut tmp_path = file.filepath.clone();
-    tmp_path.pop();
-    let mut h = DefaultHasher::new();
-    file.display_name.hash(&mut h);
-    tmp_path.push(&h.finish().to_string().add(".tmp"));
+    // Only removed when empty, leftovers of failed downloads stay for inspection
+    let _ = std::fs::remove_dir(&options.tmp_dir);
 
-    // Aborted download?
-    if let Err(e) = download_file((&tmp_path, &file), options.clone()).await {
-        if let Err(e) = std::fs::remove_file(&tmp_path) {
-            eprintln!(
-                "Failed to remove temporary file {tmp_path:?} for {}, err={e:?}",
-                file.display_name
-            );
+    let metrics_path = state::state_dir(&args.destination_folder).join("metrics.json");
+    write_artifact(&metrics_path, serde_json::to_string_pretty(&run_metrics)?.as_bytes())?;
+    write_failure_logs(&args.destination_folder, &options)?;
+
+    // What finished is in the state file, the rest is picked up by the next run. Digest, indexes and mirrors wait for a
+    // complete sync
+    if options.cancel.is_cancelled() {
+        eprintln!(
+            "Interrupted: {n_downloaded} of {} file(s) downloaded, {} cancelled",
+            files_to_download.len(),
+            cancelled.len()
+        );
+        for canvas_file in cancelled.iter() {
+            options.detail(format!("  * cancelled {}", canvas_file.filepath.to_string_lossy()));
+        }
+        return Ok(Outcome::Interrupted);
+    }
+    if !args.no_report && !first_sync {
+        if let Err(e) = write_report(&args, &options, &manifest_before) {
+            eprintln!("Failed to write the change report, err={e:?}");
+        }
+    }
+    if args.digest {
+        if first_sync {
+            options.info("No digest for the first sync of a destination");
+        } else {
+            let changes = options
+                .changes
+                .lock()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned changes lock, err={e}"));
+            match digest::write(&args.destination_folder, &changes, args.empty_digest == EmptyDigest::Write) {
+                Ok(Some(digest_path)) => {
+                    options.info(format!("Digest written to {}", digest_path.to_string_lossy()));
+                    if let Some(command) = &args.notify_command {
+                        if let Err(e) = digest::notify(command, &digest_path) {
+                            eprintln!("{e:?}");
+                        }
+                    }
+                }
+                Ok(None) => options.detail("No changes, no digest written"),
+                Err(e) => eprintln!("Failed to write digest, err={e:?}"),
+            }
+        }
+    }
+    if args.support_bundle {
+        match write_support_bundle(&args, &cred, &options, &run_metrics) {
+            Ok(bundle_path) => options.info(format!("Support bundle written to {}", bundle_path.to_string_lossy())),
+            Err(e) => eprintln!("Failed to write the support bundle, err={e:?}"),
         }
-        return Err(e);
     }
 
-    // Update file time
-    let updated_at = DateTime::parse_from_rfc3339(&file.updated_at)?;
-    let updated_time = filetime::FileTime::from_unix_time(
-        updated_at.timestamp(),
-        updated_at.timestamp_subsec_nanos(),
-    );
-    if let Err(e) = filetime::set_file_mtime(&tmp_path, updated_time) {
-        eprintln!(
-            "Failed to set modified time of {} with updated_at of {}, err={e:?}",
-            file.display_name, file.updated_at
-        )
+    let synced_at = Local::now().to_rfc3339();
+    for (course, course_folder_path) in &synced_courses {
+        // The README tells when a course was last synced completely
+        if options.errors.course_failed(&course_label(course_folder_path, &options)) {
+            options.detail(format!("Not updating the README of {}, parts of it failed", course.course_code));
+        } else if let Err(e) = write_course_readme(course, course_folder_path, &args, &cred.canvas_url, &synced_at) {
+            eprintln!("Failed to write README for {}, err={e:?}", course.course_code);
+        }
+        if let Err(e) = course_index::write(course, course_folder_path, &options).await {
+            eprintln!("Failed to write index for {}, err={e:?}", course.course_code);
+        }
     }
 
-    // Atomically rename file, doesn't change mtime
-    std::fs::rename(&tmp_path, &file.filepath)?;
-    Ok(())
+    // Touches the metadata of every file, so only on request
+    if args.storage_report {
+        let report_path = storage_report::write(&args.destination_folder, &synced_courses)?;
+        options.info(format!("Storage report written to {}", report_path.to_string_lossy()));
+    }
+
+    // Mirrors are derived from the primary destination, a failing one never fails the run
+    if !args.mirrors.is_empty() {
+        let course_folders: Vec<PathBuf> = synced_courses.iter().map(|(_, path)| path.clone()).collect();
+        replicate_mirrors(&args.mirrors, &course_folders, &options);
+        options
+            .state
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+            .save(&args.destination_folder)?;
+    }
+
+    Ok(if options.errors.count() > 0 && !args.ignore_errors {
+        Outcome::PartialFailure
+    } else if !skipped_for_size.is_empty() || !refused_locked.is_empty() || !deferred.is_empty() || n_updates_kept_out > 0 {
+        Outcome::SuccessWithSkips
+    } else {
+        Outcome::Success
+    })
 }
 
-async fn download_file(
-    (tmp_path, canvas_file): (&PathBuf, &File),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    // Get file
-    let mut resp = options
-        .client
-        .get(&canvas_file.url)
-        .bearer_auth(&options.canvas_token)
-        .send()
-        .await
-        .with_context(|| format!("Something went wrong when reaching {}", canvas_file.url))?;
-    if !resp.status().is_success() {
-        return Err(Error::msg(format!(
-            "Failed to download {}, got {resp:?}",
-            canvas_file.display_name
-        )));
+// Downloads the files, one task each, and waits for all of them
+// Which command a run performs, the list options are shorthands for `list`
+fn run_command(args: &CommandLineOptions) -> Command {
+    match args.command.clone() {
+        Some(Command::ListCourses { format }) => Command::List { target: ListTarget::Courses, format },
+        Some(command) => command,
+        None => match args.list_files {
+            Some(format) => Command::List { target: ListTarget::Files, format },
+            None => Command::Download,
+        },
     }
+}
 
-    // Create + Open file
-    let mut file = std::fs::File::create(tmp_path)
-        .with_context(|| format!("Unable to create tmp file for {:?}", canvas_file.filepath))?;
+fn is_read_only(args: &CommandLineOptions, command: &Command) -> bool {
+    args.dry_run || *command != Command::Download || (args.print_plan_tree && !args.yes)
+}
 
-    // Progress bar
-    let download_size = resp
-        .headers() // Gives us the HeaderMap
-        .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
-        .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
-        .and_then(|ct_len| ct_len.parse().ok()) // Parses the Option as u64
-        .unwrap_or(0); // Fallback to 0
-    let progress_bar = options.progress_bars.add(ProgressBar::new(download_size));
-    progress_bar.set_message(canvas_file.display_name.to_string());
-    progress_bar.set_style(options.progress_style.clone());
+// Everything the crawl and downloads share, for the user and instance run() signed in to
+fn process_options(
+    args: &CommandLineOptions,
+    cred: &canvas::Credentials,
+    clients: HttpClients,
+    api_base: String,
+    user: canvas::User,
+    state: State,
+    cancel: CancellationToken,
+) -> Result<ProcessOptions> {
+    let command = run_command(args);
+    // stdout is the listing, for piping into other tools
+    let listing_files = matches!(command, Command::List { target: ListTarget::Files, .. });
+    let read_only = is_read_only(args, &command);
+    Ok(ProcessOptions {
+        canvas_token: cred.canvas_token.clone(),
+        canvas_url: cred.canvas_url.clone(),
+        api_base,
+        client: clients.client,
+        storage_client: clients.storage_client,
+        video_client: clients.video_client,
+        video_hosts: std::sync::Mutex::new(HashSet::new()),
+        user,
+        destination_folder: args.destination_folder.clone(),
+        tmp_dir: args
+            .tmp_dir
+            .clone()
+            .unwrap_or_else(|| state::state_dir(&args.destination_folder).join(state::TMP_DIR)),
+        resume: !args.no_resume,
+        segments: args.segments,
+        state: std::sync::Mutex::new(state),
+        manifest: std::sync::Mutex::new(manifest::Manifest::load(&args.destination_folder)),
+        http_cache: (!args.no_cache)
+            .then(|| http_cache::HttpCache::new(&args.destination_folder, cache::key(&cred.canvas_url, &cred.canvas_token), read_only)),
+        prune_renamed: args.prune_renamed,
+        keep_versions: args.keep_versions,
+        // Process
+        files_to_download: tokio::sync::Mutex::new(Vec::new()),
+        download_newer: args.download_newer,
+        exclude_folders: args.exclude_folders.clone(),
+        course_folders: std::sync::Mutex::new(Vec::new()),
+        course_rules: std::sync::Mutex::new(HashMap::new()),
+        course_ids: std::sync::Mutex::new(HashMap::new()),
+        flat: args.flat,
+        content_folder_naming: args.content_folder_naming,
+        name_template: args.name_template.clone(),
+        case_insensitive: destination_case_insensitive(&args.destination_folder, read_only),
+        claimed_paths: std::sync::Mutex::new(HashMap::new()),
+        verbosity: if args.quiet || listing_files {
+            Verbosity::Quiet
+        } else if args.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        },
+        max_file_size: args.max_file_size,
+        since: args.since.as_deref().map(parse_since).transpose().map_err(|e| anyhow!(e))?,
+        until: args.until.as_deref().map(parse_until).transpose().map_err(|e| anyhow!(e))?,
+        skipped_for_size: std::sync::Mutex::new(Vec::new()),
+        include_locked: args.include_locked,
+        verify_content: args.verify_content,
+        n_skipped_locked: AtomicUsize::new(0),
+        refused_locked: std::sync::Mutex::new(Vec::new()),
+        dry_run: read_only,
+        record_plan: args.print_plan_tree || listing_files,
+        planned: std::sync::Mutex::new(Vec::new()),
+        mark_read: args.mark_read && !read_only,
+        local_conflicts: std::sync::Mutex::new(Vec::new()),
+        changes: std::sync::Mutex::new(digest::Changes::default()),
+        new_topics: std::sync::Mutex::new(Vec::new()),
+        course_summaries: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+        content: {
+            let mut content: HashSet<ContentType> = match &args.content {
+                Some(content) => content.iter().copied().collect(),
+                None => <ContentType as clap::ValueEnum>::value_variants().iter().copied().collect(),
+            };
+            if args.skip_videos {
+                content.remove(&ContentType::Videos);
+            }
+            content
+        },
+        // Download
+        max_buffered_bytes: args.max_buffered_bytes,
+        write_buffer: args.write_buffer as usize,
+        sem_buffered_bytes: tokio::sync::Semaphore::new(args.max_buffered_bytes as usize),
+        // Redirected output gets no bars at all instead of one line per redraw
+        progress_bars: if console::Term::stderr().is_term() && !args.quiet {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        },
+        progress_style: std::sync::RwLock::new(progress_style_for_width(terminal_width())),
+        active_progress_bars: std::sync::Mutex::new(Vec::new()),
+        overall: OverallProgress::default(),
+        max_bars: args.max_bars.unwrap_or(args.download_concurrency) as usize,
+        user_agent: clients.user_agent,
+        canvas_headers: clients.canvas_headers,
+        retries: args.retries,
+        request_timeout: Duration::from_secs(args.request_timeout),
+        stall_timeout: Duration::from_secs(args.stall_timeout),
+        // Synchronization
+        n_active_requests: AtomicUsize::new(0),
+        sem_api: tokio::sync::Semaphore::new(args.api_concurrency as usize),
+        sem_downloads: tokio::sync::Semaphore::new(args.download_concurrency as usize),
+        per_course_downloads: args.per_course_downloads,
+        course_sems: std::sync::Mutex::new(HashMap::new()),
+        notify_main: tokio::sync::Notify::new(),
+        cancel,
+        cancelled: std::sync::Mutex::new(Vec::new()),
+        failed_downloads: std::sync::Mutex::new(Vec::new()),
+        queue: std::sync::Mutex::new(Vec::new()),
+        found_sizes: std::sync::Mutex::new(HashMap::new()),
+        metrics: Metrics::default(),
+        errors: errors::ErrorReporter::default(),
+        // TODO handle canvas rate limiting errors, maybe scale up if possible
+    })
+}
 
-    // Download
-    while let Some(chunk) = resp.chunk().await? {
-        progress_bar.inc(chunk.len() as u64);
-        let mut cursor = std::io::Cursor::new(chunk);
-        std::io::copy(&mut cursor, &mut file)
-            .with_context(|| format!("Could not write to file {:?}", canvas_file.filepath))?;
+// Copies the synced course folders to each mirror. How each went is recorded in the state, for the caller to save
+fn replicate_mirrors(mirrors: &[PathBuf], course_folders: &[PathBuf], options: &ProcessOptions) {
+    let mut state = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+    for mirror_path in mirrors {
+        let previous = state.mirrors.get(&mirror_path.to_string_lossy().to_string());
+        // Content folders renamed since this mirror was last replicated, see content_folder()
+        let renamed_folders: Vec<(PathBuf, PathBuf)> = previous
+            .map(|previous| {
+                previous
+                    .content_folders
+                    .iter()
+                    .filter_map(|(key, previous_path)| {
+                        let current_path = state.content_folders.get(key)?;
+                        (current_path != previous_path).then(|| (PathBuf::from(previous_path), PathBuf::from(current_path)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let status = match mirror::replicate(&options.destination_folder, mirror_path, course_folders, &renamed_folders) {
+            Ok(files_copied) => {
+                options.info(format!("Mirrored {files_copied} changed file(s) to {}", mirror_path.to_string_lossy()));
+                state::MirrorStatus {
+                    synced_at: Some(Local::now().to_rfc3339()),
+                    files_copied,
+                    error: None,
+                    content_folders: state.content_folders.clone(),
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not mirror to {}, err={e:?}", mirror_path.to_string_lossy());
+                state::MirrorStatus {
+                    synced_at: previous.and_then(|previous| previous.synced_at.clone()),
+                    files_copied: 0,
+                    error: Some(format!("{e:#}")),
+                    content_folders: previous.map(|previous| previous.content_folders.clone()).unwrap_or_default(),
+                }
+            }
+        };
+        state.mirrors.insert(mirror_path.to_string_lossy().to_string(), status);
     }
+}
 
-    progress_bar.finish();
-    Ok(())
+async fn download_all(files: &[File], options: &Arc<ProcessOptions>) {
+    let resize_watcher = tokio::spawn(watch_terminal_width(options.clone()));
+    if files.len() > 1 {
+        options.overall.start(&options.progress_bars, files);
+    }
+    options.n_active_requests.fetch_add(1, Ordering::AcqRel); // prevent notifying until all spawned
+    for canvas_file in files.iter() {
+        let course = course_label(&course_folder_of(&canvas_file.filepath, options), options);
+        errors::COURSE.sync_scope(Some(course), || {
+            fork!(
+                atomic_download_file,
+                canvas_file.clone(),
+                File,
+                options.clone(),
+                no_permit
+            );
+        });
+    }
+
+    // Wait for downloads
+    let new_val = options.n_active_requests.fetch_sub(1, Ordering::AcqRel) - 1;
+    if new_val == 0 {
+        // notify if all finished immediately
+        options.notify_main.notify_one();
+    }
+    options.notify_main.notified().await;
+    resize_watcher.abort();
+    options.overall.finish();
+    let _ = options.progress_bars.clear();
+    options.errors.print_summary();
+    options.errors.print_failed_downloads();
 }
 
-fn print_all_courses_by_term(courses: &[canvas::Course]) {
-    let mut grouped_courses: HashMap<u32, Vec<&str>> = HashMap::new();
+fn take_failed_downloads(options: &ProcessOptions) -> Vec<File> {
+    std::mem::take(
+        &mut *options
+            .failed_downloads
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned failed lock, err={e}")),
+    )
+}
 
-    for course in courses.iter() {
-        let course_id: u32 = course.enrollment_term_id;
-        grouped_courses
-            .entry(course_id)
-            .or_insert_with(Vec::new)
-            .push(&course.course_code);
+// errors.log and failures.json of this run
+fn write_failure_logs(destination: &Path, options: &ProcessOptions) -> Result<()> {
+    // Every failure of this run, including the repeats only counted on screen
+    let errors_log_path = state::state_dir(destination).join(errors::ERRORS_LOG);
+    match options.errors.log() {
+        Some(log) => {
+            write_artifact(&errors_log_path, log.as_bytes())?;
+            eprintln!("All failures are listed in {}", errors_log_path.to_string_lossy());
+        }
+        None => {
+            let _ = std::fs::remove_file(&errors_log_path);
+        }
     }
-    println!("{: <10}| {:?}", "Term IDs", "Courses");
-    for (key, value) in &grouped_courses {
-        println!("{: <10}| {:?}", key, value);
+    // Next to the course folders, for scripts checking on a cron job
+    let failures_path = destination.join(errors::FAILURES_JSON);
+    write_artifact(&failures_path, options.errors.failures_json()?.as_bytes())
+}
+
+// Files listed by an earlier run, through filter_files() again to drop what is on disk by now. `path` is
+// destination-relative
+fn refilter_files<'a>(
+    options: &ProcessOptions,
+    listed: impl Iterator<Item = (&'a str, File, Option<u32>)>,
+) -> Vec<File> {
+    // filter_files() names files after the folder they were found in
+    let mut folders: Vec<(PathBuf, Vec<File>)> = Vec::new();
+    for (path, mut canvas_file, name_prefix) in listed {
+        let path = options.destination_folder.join(path);
+        let folder = path.parent().map_or_else(|| options.destination_folder.clone(), Path::to_path_buf);
+        canvas_file.name_prefix = name_prefix;
+        match folders.iter_mut().find(|(known_folder, _)| *known_folder == folder) {
+            Some((_, files)) => files.push(canvas_file),
+            None => folders.push((folder, vec![canvas_file])),
+        }
     }
+    folders
+        .into_iter()
+        .flat_map(|(folder, files)| filter_files(options, &folder, files))
+        .collect()
 }
 
-fn create_folder_if_not_exist(folder_path: &PathBuf) -> Result<()> {
-    if !folder_path.exists() {
-        std::fs::create_dir(&folder_path).with_context(|| {
-            format!(
-                "Failed to create directory: {}",
-                folder_path.to_string_lossy()
-            )
-        })?;
+async fn resume_queue(
+    args: &CommandLineOptions,
+    options: Arc<ProcessOptions>,
+    queue: Vec<state::QueuedFile>,
+) -> Result<Outcome> {
+    let files = refilter_files(
+        &options,
+        queue.iter().map(|queued| (queued.path.as_str(), queued.file.clone(), queued.name_prefix)),
+    );
+    options.info(format!(
+        "Resuming {} of {} queued download(s), the rest are on disk by now",
+        files.len(),
+        queue.len()
+    ));
+    // Downloads done since are removed from the queue, see dequeue()
+    let queue = state::queued_files(&args.destination_folder, &files);
+    state::save_queue(&args.destination_folder, &queue)?;
+    *options
+        .queue
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned queue lock, err={e}")) = queue;
+
+    if let Err(e) = clean::remove_stale_tmp_files(&options.tmp_dir, options.resume) {
+        eprintln!("Failed to remove leftover downloads, err={e:?}");
+    }
+    check_free_space(&files, args, &options)?;
+    download_all(&files, &options).await;
+
+    let failed_downloads = take_failed_downloads(&options);
+    let n_cancelled = options
+        .cancelled
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned cancelled lock, err={e}"))
+        .len();
+    let previously_failed = state::load_failed(&args.destination_folder);
+    state::save_failed(
+        &args.destination_folder,
+        &state::failed_files(&args.destination_folder, &previously_failed, &failed_downloads),
+    )?;
+    if !options.cancel.is_cancelled() {
+        state::save_queue(&args.destination_folder, &[])?;
     }
+    options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .save(&options.destination_folder)?;
+    options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .save(&options.destination_folder)?;
+    let _ = std::fs::remove_dir(&options.tmp_dir);
+    write_failure_logs(&args.destination_folder, &options)?;
+
+    options.info(format!(
+        "{} download(s) finished, {} failed, {n_cancelled} left for the next --resume",
+        files.len().saturating_sub(failed_downloads.len() + n_cancelled),
+        failed_downloads.len()
+    ));
+    Ok(if options.cancel.is_cancelled() {
+        Outcome::Interrupted
+    } else if options.errors.count() > 0 && !args.ignore_errors {
+        Outcome::PartialFailure
+    } else {
+        Outcome::Success
+    })
+}
+
+// --retry-failed: downloads the files of failed.json again instead of crawling. filter_files() drops what an earlier
+// run or Canvas resolved meanwhile, eg a file downloaded since
+async fn retry_failed(args: &CommandLineOptions, options: Arc<ProcessOptions>) -> Result<Outcome> {
+    let previously_failed = state::load_failed(&args.destination_folder);
+    if previously_failed.is_empty() {
+        options.info("No failed downloads to retry");
+        return Ok(Outcome::Success);
+    }
+
+    let files = refilter_files(
+        &options,
+        previously_failed.iter().map(|failed| (failed.path.as_str(), failed.file.clone(), failed.name_prefix)),
+    );
+    options.info(format!(
+        "Retrying {} of {} failed download(s), the rest were resolved since",
+        files.len(),
+        previously_failed.len()
+    ));
+
+    if let Err(e) = clean::remove_stale_tmp_files(&options.tmp_dir, options.resume) {
+        eprintln!("Failed to remove leftover downloads, err={e:?}");
+    }
+    download_all(&files, &options).await;
+
+    // Cancelled retries stay on the list as they were
+    let failed_downloads = take_failed_downloads(&options);
+    let cancelled = std::mem::take(
+        &mut *options
+            .cancelled
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned cancelled lock, err={e}")),
+    );
+    let mut still_failed = state::failed_files(&args.destination_folder, &previously_failed, &failed_downloads);
+    still_failed.extend(state::failed_files(&args.destination_folder, &previously_failed, &cancelled).into_iter().map(
+        |mut entry| {
+            entry.attempts -= 1;
+            entry
+        },
+    ));
+    state::save_failed(&args.destination_folder, &still_failed)?;
+    options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .save(&options.destination_folder)?;
+    options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .save(&options.destination_folder)?;
+    let _ = std::fs::remove_dir(&options.tmp_dir);
+    write_failure_logs(&args.destination_folder, &options)?;
+
+    options.info(format!(
+        "{} download(s) succeeded, {} failed again",
+        files.len().saturating_sub(failed_downloads.len() + cancelled.len()),
+        failed_downloads.len()
+    ));
+    Ok(if options.cancel.is_cancelled() {
+        Outcome::Interrupted
+    } else if options.errors.count() > 0 && !args.ignore_errors {
+        Outcome::PartialFailure
+    } else {
+        Outcome::Success
+    })
+}
+
+// changes-<timestamp>.md (or .json) in the destination, unless nothing changed
+fn write_report(
+    args: &CommandLineOptions,
+    options: &ProcessOptions,
+    manifest_before: &manifest::Manifest,
+) -> Result<()> {
+    let topics = options
+        .new_topics
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned topics lock, err={e}"));
+    let manifest_after = options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"));
+    let mut report = report::Report::build(manifest_before, &manifest_after, &topics, &options.errors.failures(), |path| {
+        course_label(&course_folder_of(&options.destination_folder.join(path), options), options)
+    });
+    if report.is_empty() {
+        options.detail("No changes, no change report written");
+        return Ok(());
+    }
+    let (extension, body) = match args.report_format {
+        ReportFormat::Markdown => ("md", report.markdown()),
+        ReportFormat::Json => {
+            report.summaries = options
+                .course_summaries
+                .lock()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned summaries lock, err={e}"))
+                .clone();
+            ("json", serde_json::to_string_pretty(&report)?)
+        }
+    };
+    let report_path = args
+        .destination_folder
+        .join(format!("changes-{}.{extension}", Local::now().format("%Y-%m-%d-%H%M%S")));
+    write_artifact(&report_path, body.as_bytes())?;
+    options.info(format!("Changes written to {}", report_path.to_string_lossy()));
     Ok(())
 }
 
-// async recursion needs boxing
-async fn process_folders(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
+// Everything a maintainer needs to look into a report, zipped to attach to an issue. The token and the query strings of
+// signed URLs are left out
+fn write_support_bundle(
+    args: &CommandLineOptions,
+    cred: &canvas::Credentials,
+    options: &ProcessOptions,
+    run_metrics: &metrics::MetricsSnapshot,
+) -> Result<PathBuf> {
+    let canvas_host = Url::parse(&cred.canvas_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let bundle = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "canvas_host": canvas_host,
+        "options": args,
+        "metrics": run_metrics,
+    });
+    let manifest = options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .clone();
+    let mut entries = vec![
+        ("bundle.json", serde_json::to_string_pretty(&bundle)?),
+        ("metrics.json", serde_json::to_string_pretty(run_metrics)?),
+        (manifest::MANIFEST_FILE, redact_log(&serde_json::to_string_pretty(&manifest)?, &cred.canvas_token)),
+    ];
+    if let Some(log) = options.errors.log() {
+        entries.push((errors::ERRORS_LOG, redact_log(&log, &cred.canvas_token)));
+    }
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, content) in entries {
+        zip.start_file(name, zip::write::FileOptions::default())?;
+        zip.write_all(content.as_bytes())?;
+    }
+    let bytes = zip.finish()?.into_inner();
+    let bundle_path = state::state_dir(&args.destination_folder)
+        .join(format!("support-bundle-{}.zip", Local::now().format("%Y%m%d-%H%M%S")));
+    write_artifact(&bundle_path, &bytes)?;
+    Ok(bundle_path)
+}
+
+// Signed download URLs carry their credentials in the query string
+fn redact_log(log: &str, token: &str) -> String {
+    let query = Regex::new(r#"(https?://[^\s"?]+)\?[^\s"]*"#).expect("url query regex is valid");
+    let log = if token.is_empty() { log.to_string() } else { log.replace(token, "<redacted>") };
+    query.replace_all(&log, "$1?<redacted>").into_owned()
+}
+
+const COURSE_README: &str = "README.txt";
+const COURSE_README_HEADER: &str = "This folder was generated by canvas-downloader";
+
+// Provenance note so an archive still explains itself years later
+fn write_course_readme(
+    course: &canvas::Course,
+    course_folder_path: &Path,
+    args: &CommandLineOptions,
+    canvas_url: &str,
+    synced_at: &str,
 ) -> Result<()> {
-    let pages = get_pages(url, &options).await?;
+    let readme_path = course_folder_path.join(COURSE_README);
+    // Never clobber a README.txt someone else wrote
+    if let Ok(existing) = std::fs::read_to_string(&readme_path) {
+        if !existing.starts_with(COURSE_README_HEADER) {
+            return Ok(());
+        }
+    }
 
-    // For each page
-    for pg in pages {
-        let uri = pg.url().to_string();
-        let folders_result = pg.json::<canvas::FolderResult>().await;
+    let mut readme = format!("{COURSE_README_HEADER} {}.\n\n", env!("CARGO_PKG_VERSION"));
+    readme.push_str(&format!("Canvas instance:      {canvas_url}\n"));
+    readme.push_str(&format!("Course:               {} - {} (id {})\n", course.course_code, course.name, course.id));
+    readme.push_str(&format!("Course URL:           {canvas_url}/courses/{}\n", course.id));
+    readme.push_str(&format!("Last successful sync: {synced_at}\n\n"));
+
+    // Serialized field by field so that new options show up here automatically
+    readme.push_str("Options in effect:\n");
+    if let Value::Object(fields) = serde_json::to_value(args)? {
+        for (key, value) in fields {
+            readme.push_str(&format!("  --{} = {value}\n", key.replace('_', "-")));
+        }
+    }
+    readme.push_str(&format!(
+        "\nSync state for the whole destination is kept in {}.\n\
+         To refresh this folder, run canvas-downloader again with the options above.\n",
+        state::state_dir(&args.destination_folder).join(state::STATE_FILE).to_string_lossy()
+    ));
 
-        match folders_result {
-            // Got folders
-            Ok(canvas::FolderResult::Ok(folders)) => {
-                for folder in folders {
-                    // println!("  * {} - {}", folder.id, folder.name);
-                    let sanitized_folder_name = sanitize_foldername(folder.name);
-                    // if the folder has no parent, it is the root folder of a course
-                    // so we avoid the extra directory nesting by not appending the root folder name
-                    let folder_path = if folder.parent_folder_id.is_some() {
-                        path.join(sanitized_folder_name)
-                    } else {
-                        path.clone()
-                    };
-                    if !folder_path.exists() {
-                        if let Err(e) = std::fs::create_dir(&folder_path) {
-                            eprintln!(
-                                "Failed to create directory: {}, err={e}",
-                                folder_path.to_string_lossy()
-                            );
-                            continue;
-                        };
-                    }
+    write_artifact(&readme_path, readme.as_bytes())
+}
 
-                    fork!(
-                        process_files,
-                        (folder.files_url, folder_path.clone()),
-                        (String, PathBuf),
-                        options.clone()
-                    );
-                    fork!(
-                        process_folders,
-                        (folder.folders_url, folder_path),
-                        (String, PathBuf),
-                        options.clone()
-                    );
+// Drops queued files with the same target path, keeping the newest, and takes the Canvas files queued under several
+// paths (course files, module items, links in descriptions) out of the queue but for the first.
+// Returns the files taken out with the path of the one downloaded, see copy_duplicates()
+fn dedup_downloads(files: &mut Vec<File>, case_insensitive: bool) -> Vec<(File, PathBuf)> {
+    let updated_at = |file: &File| DateTime::parse_from_rfc3339(&file.updated_at).ok();
+    // Same key as claim_path()
+    let path_key = |filepath: &Path| -> PathBuf {
+        if case_insensitive {
+            PathBuf::from(filepath.to_string_lossy().to_lowercase())
+        } else {
+            filepath.to_path_buf()
+        }
+    };
+    let mut by_path: HashMap<PathBuf, usize> = HashMap::new();
+    let mut unique: Vec<File> = Vec::with_capacity(files.len());
+    for file in files.drain(..) {
+        match by_path.get(&path_key(&file.filepath)) {
+            Some(&i) => {
+                if updated_at(&file) > updated_at(&unique[i]) {
+                    unique[i] = file;
                 }
             }
-
-            // Got status code
-            Ok(canvas::FolderResult::Err { status }) => {
-                let course_has_no_folders = status == "unauthorized";
-                if !course_has_no_folders {
-                    eprintln!(
-                        "Failed to access folders at link:{uri}, path:{path:?}, status:{status}",
-                    );
-                }
+            None => {
+                by_path.insert(path_key(&file.filepath), unique.len());
+                unique.push(file);
             }
+        }
+    }
 
-            // Parse error
-            Err(e) => {
-                eprintln!("Error when getting folders at link:{uri}, path:{path:?}\n{e:?}",);
+    let mut downloaded_as: HashMap<(u32, String), PathBuf> = HashMap::new();
+    let mut copies = Vec::new();
+    for file in unique {
+        if file.id == 0 {
+            files.push(file);
+            continue;
+        }
+        match downloaded_as.get(&(file.id, file.url.clone())) {
+            Some(source) => copies.push((file, source.clone())),
+            None => {
+                downloaded_as.insert((file.id, file.url.clone()), file.filepath.clone());
+                files.push(file);
             }
         }
     }
+    copies
+}
 
-    Ok(())
+// Copies of files downloaded by this run, see dedup_downloads(). A failed download is reported already and leaves
+// nothing to copy
+fn copy_duplicates(copies: &[(File, PathBuf)], options: &ProcessOptions) {
+    for (file, source) in copies {
+        let downloaded = {
+            let state = options
+                .state
+                .lock()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+            source
+                .strip_prefix(&options.destination_folder)
+                .ok()
+                .and_then(|relative_path| state.files.get(&relative_path.to_string_lossy().to_string()))
+                .is_some_and(|updated_at| *updated_at == file.updated_at)
+        };
+        if !downloaded {
+            continue;
+        }
+        if let Err(e) = copy_file(source, &file.filepath) {
+            eprintln!(
+                "Failed to copy {} to {}, err={e:?}",
+                source.to_string_lossy(),
+                file.filepath.to_string_lossy()
+            );
+            continue;
+        }
+        if let Ok(relative_path) = file.filepath.strip_prefix(&options.destination_folder) {
+            options
+                .state
+                .lock()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+                .files
+                .insert(relative_path.to_string_lossy().to_string(), file.updated_at.clone());
+        }
+    }
 }
 
-async fn process_videos(
-    (url, id, path):
-    (String, u32, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let session = get_canvas_api(format!("{}/login/session_token?return_to={}/courses/{}/external_tools/128", url, url, id), &options).await?;
-    let session_result = session.json::<canvas::Session>().await?;
+// Fails when the downloads need more than the free space of the destination minus disk::SPACE_MARGIN, unless --yes
+// is given or the user goes ahead when asked
+fn check_free_space(files: &[File], args: &CommandLineOptions, options: &ProcessOptions) -> Result<()> {
+    let (required, n_unknown_size) = disk::required_space(files);
+    if n_unknown_size > 0 {
+        options.info(format!("{n_unknown_size} file(s) to download have no known size, the free space check counts them as 0"));
+    }
+    // The destination may not exist yet, its closest existing parent is on the same filesystem
+    let Some(available) = args.destination_folder.ancestors().find(|path| path.exists()).and_then(disk::available_space) else {
+        options.detail("Could not tell the free space of the destination, not checking it");
+        return Ok(());
+    };
+    if required.saturating_add(disk::SPACE_MARGIN) <= available {
+        return Ok(());
+    }
+    let message = format!(
+        "The downloads need {} but only {} are free on the destination, keeping {} spare",
+        indicatif::HumanBytes(required),
+        indicatif::HumanBytes(available),
+        indicatif::HumanBytes(disk::SPACE_MARGIN)
+    );
+    if args.yes {
+        eprintln!("{message}, continuing because of --yes");
+        return Ok(());
+    }
+    use std::io::IsTerminal;
+    if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        print!("{message}. Continue anyway? [y/N] ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("{message}. Free up space, limit the run with --max-total-bytes, or pass --yes to download anyway"))
+}
 
-    // Need a new client for each session for the cookie store
-    let client = reqwest::ClientBuilder::new()
-        .cookie_store(true)
-        .build()?;
-    let videos = client
-        .get(session_result.session_url)
-        .send()
-        .await?;
+fn ask_local_change(filepath: &Path) -> LocalChangePolicy {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() {
+        return LocalChangePolicy::Keep;
+    }
+    loop {
+        print!(
+            "{} was modified locally and updated on Canvas. [k]eep local, [o]verwrite, or [v]ersion? ",
+            filepath.to_string_lossy()
+        );
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return LocalChangePolicy::Keep;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "k" | "keep" | "" => return LocalChangePolicy::Keep,
+            "o" | "overwrite" => return LocalChangePolicy::Overwrite,
+            "v" | "version" => return LocalChangePolicy::Version,
+            _ => continue,
+        }
+    }
+}
 
-    // Parse the form that contains the parameters needed to request
-    let video_html = videos.text().await?;
-    let (action, params) = {
-        let panopto_document = Document::from_read(video_html.as_bytes())?;
-        let panopto_form = panopto_document
-            .find(Name("form"))
-            .filter(|n| n.attr("data-tool-id") == Some("mediaweb.ap.panopto.com"))
-            .next()
-            .ok_or(anyhow!("Could not find panopto form"))?;
-        let action = panopto_form
-            .attr("action")
-            .ok_or(anyhow!("Could not find panopto form action"))?
-            .to_string();
-        let params = panopto_form
-            .find(Name("input"))
-            .filter_map(|n| n.attr("name").map(|name| (name.to_string(), n.attr("value").unwrap_or("").to_string())))
-            .collect::<Vec<(_, _)>>();
-        (action, params)
+// Moves the copy an update is about to replace to "<name>.<its updated date>.<ext>" next to it, removing the oldest
+// of these beyond `keep` (0 keeps all). Returns where it was moved to
+fn keep_version(options: &ProcessOptions, file: &File, keep: u32) -> Result<PathBuf> {
+    let relative = |path: &Path| path.strip_prefix(&options.destination_folder).unwrap_or(path).to_string_lossy().to_string();
+    let relative_path = relative(&file.filepath);
+    // Canvas version of the copy when known, else when it was last modified
+    let updated_at = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .files
+        .get(&relative_path)
+        .and_then(|updated_at| DateTime::parse_from_rfc3339(updated_at).ok())
+        .map(|updated_at| updated_at.with_timezone(&Local))
+        .or_else(|| Some(std::fs::metadata(&file.filepath).ok()?.modified().ok()?.into()))
+        .unwrap_or_else(Local::now);
+
+    let stem = file.filepath.file_stem().unwrap_or_default().to_string_lossy();
+    let version_path = |date: String| {
+        file.filepath.with_file_name(match file.filepath.extension() {
+            Some(ext) => format!("{stem}.{date}.{}", ext.to_string_lossy()),
+            None => format!("{stem}.{date}"),
+        })
     };
-    // set origin and referral headers
-    let panopto_response = client
-        .post(action)
-        .header("Origin", &url)
-        .header("Referer", format!("{}/", url))
-        .form(&params)
-        .send()
-        .await?;
+    // Updated twice on the same day, or kept twice with the same time, eg when the copy was modified locally. rename()
+    // would replace an earlier version
+    let mut kept_path = version_path(updated_at.format("%Y-%m-%d").to_string());
+    let mut n = 1;
+    while kept_path.exists() {
+        let time = updated_at.format("%Y-%m-%d_%H%M%S");
+        kept_path = version_path(if n == 1 { time.to_string() } else { format!("{time}_{n}") });
+        n += 1;
+    }
+    std::fs::rename(&file.filepath, &kept_path)
+        .with_context(|| format!("Failed to keep the previous version of {:?} as {kept_path:?}", file.filepath))?;
+    options.detail(format!("Kept the previous version as {}", kept_path.to_string_lossy()));
+
+    let version = manifest::KeptVersion { path: relative(&kept_path), updated_at: updated_at.to_rfc3339() };
+    let keep = (keep > 0).then_some(keep as usize);
+    let over = options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .add_version(file.id, version, keep);
+    for version in over {
+        let version_path = options.destination_folder.join(&version.path);
+        match std::fs::remove_file(&version_path) {
+            Ok(()) => options.detail(format!("Removed the old version {}", version_path.to_string_lossy())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to remove the old version {version_path:?}, err={e:?}"),
+        }
+    }
+    Ok(kept_path)
+}
 
-    // parse location header as url
-    let panopto_location = Url::parse(panopto_response
-        .headers()
-        .get(header::LOCATION)
-        .ok_or(anyhow!("No location header"))?
-        .to_str()?)?;
-    // get folderID from query string
-    let panopto_folder_id = panopto_location
-        .query_pairs()
-        .find(|(key, _)| key == "folderID")
-        .map(|(_, value)| value)
-        .ok_or(anyhow!("Could not get Panopto Folder ID"))?
-        .to_string();
-    let panopto_host = panopto_location
-        .host_str()
-        .ok_or(anyhow!("Could not get Panopto Host"))?
-        .to_string();
-    process_video_folder((panopto_host, panopto_folder_id, client.clone(), path), options).await?;
+// "notes.pdf" --> "notes (remote update).pdf"
+fn remote_update_path(filepath: &Path) -> PathBuf {
+    let stem = filepath.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match filepath.extension() {
+        Some(ext) => format!("{stem} (remote update).{}", ext.to_string_lossy()),
+        None => format!("{stem} (remote update)"),
+    };
+    filepath.with_file_name(name)
+}
+
+async fn atomic_download_file(file: File, options: Arc<ProcessOptions>) -> Result<()> {
+    // The course slot comes first, so a download waiting for one does not hold a slot files of other courses could use
+    let _course_permit = course_permit(&file, &options).await;
+    let _permit = options
+        .sem_downloads
+        .acquire()
+        .await
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Unexpected closed sem, err={e}"));
+    // Reported here instead of by fork!(), which does not know the file
+    let result = download_into_place(&file, &options).await;
+    options.overall.file_done();
+    if let Err(e) = result {
+        options
+            .failed_downloads
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned failed lock, err={e}"))
+            .push(file.clone());
+        options.errors.report_download(&file.url, file.filepath.clone(), &e, &options.progress_bars);
+    }
     Ok(())
 }
 
-async fn process_video_folder(
-    (host, id, client, path):
-    (String, String, reqwest::Client, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    // POST json folderID: to https://mediaweb.ap.panopto.com/Panopto/Services/Data.svc/GetFolderInfo
-    let folderinfo_result = client
-        .post(format!("https://{}/Panopto/Services/Data.svc/GetFolderInfo", host))
-        .json(&json!({
-            "folderID": id,
-        }))
-        .send()
-        .await?;
-    // write into videos.json
-    let folderinfo = folderinfo_result.text().await?;
-    let mut file = std::fs::File::create(path.join("folder.json"))?;
-    file.write_all(folderinfo.as_bytes())?;
+// With --per-course-downloads, one of the slots of the file's course
+async fn course_permit(file: &File, options: &ProcessOptions) -> Option<tokio::sync::OwnedSemaphorePermit> {
+    let (limit, course_id) = (options.per_course_downloads?, file.course_id?);
+    let sem = options
+        .course_sems
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned course sems lock, err={e}"))
+        .entry(course_id)
+        .or_insert_with(|| Arc::new(tokio::sync::Semaphore::new(limit as usize)))
+        .clone();
+    Some(
+        sem.acquire_owned()
+            .await
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Unexpected closed sem, err={e}")),
+    )
+}
 
-    // write into sessions.json
-    let mut sessions_file = std::fs::File::create(path.join("sessions.json"))?;
+async fn download_into_place(file: &File, options: &Arc<ProcessOptions>) -> Result<()> {
+    if options.cancel.is_cancelled() {
+        cancel_download(file.clone(), options);
+        return Ok(());
+    }
+    std::fs::create_dir_all(&options.tmp_dir)
+        .with_context(|| format!("Failed to create tmp directory {:?}", options.tmp_dir))?;
+    let tmp_path = options.tmp_dir.join(tmp_file_name(file, options.resume));
+
+    // Aborted download? Unless --no-resume, the tmp file is kept for the next attempt to continue from
+    match retry_download(&tmp_path, file, options).await {
+        Ok(true) => {}
+        // Over --max-file-size or locked, nothing was written
+        Ok(false) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            dequeue(file, options);
+            return Ok(());
+        }
+        // Stopped by Ctrl-C, a resumable partial download stays for the next run
+        Err(e) if e.is::<errors::Cancelled>() => {
+            if !options.resume {
+                let _ = std::fs::remove_file(&tmp_path);
+            }
+            cancel_download(file.clone(), options);
+            return Ok(());
+        }
+        Err(e) if options.resume => return Err(e),
+        Err(e) => {
+            if let Err(e) = std::fs::remove_file(&tmp_path) {
+                eprintln!(
+                    "Failed to remove temporary file {tmp_path:?} for {}, err={e:?}",
+                    file.display_name
+                );
+            }
+            return Err(e);
+        }
+    }
 
-    for i in 0.. {
-        let sessions_result = client
-            .post(format!("https://{}/Panopto/Services/Data.svc/GetSessions", host))
-            .json(&json!({
-                "queryParameters":
-                {
-                    "query":null,
-                    "sortColumn":1,
-                    "sortAscending":false,
-                    "maxResults":100,
-                    "page":i,
-                    "startDate":null,
-                    "endDate":null,
-                    "folderID":id,
-                    "bookmarked":false,
-                    "getFolderData":true,
-                    "isSharedWithMe":false,
-                    "isSubscriptionsPage":false,
-                    "includeArchived":true,
-                    "includeArchivedStateCount":true,
-                    "sessionListOnlyArchived":false,
-                    "includePlaylists":true
-                }
-            }))
-            .send()
-            .await?;
+    // Update file time
+    let updated_at = DateTime::parse_from_rfc3339(&file.updated_at)?;
+    let updated_time = filetime::FileTime::from_unix_time(
+        updated_at.timestamp(),
+        updated_at.timestamp_subsec_nanos(),
+    );
+    let (mtime_path, moved_path, filepath) = (tmp_path.clone(), tmp_path.clone(), file.filepath.clone());
+    let mtime_set = tokio::task::spawn_blocking(move || filetime::set_file_mtime(&mtime_path, updated_time)).await?;
+    if let Err(e) = mtime_set {
+        eprintln!(
+            "Failed to set modified time of {} with updated_at of {}, err={e:?}",
+            file.display_name, file.updated_at
+        )
+    }
 
-        let sessions_text = sessions_result.text().await?;
-        sessions_file.write_all(sessions_text.as_bytes())?;
-        
-        let folder_sessions = serde_json::from_str::<Value>(&sessions_text)?;
-        let folder_sessions_results = folder_sessions
-            .get("d")
-            .ok_or(anyhow!("Could not get Panopto Folder Sessions"))?;
-    
-        let sessions = serde_json::from_value::<canvas::PanoptoSessionInfo>(folder_sessions_results.clone())?;
-        
-        // End of page results
-        if sessions.Results.len() == 0 {
-            break;
+    let content_hash = if options.verify_content {
+        let hashed_path = tmp_path.clone();
+        Some(tokio::task::spawn_blocking(move || content_hash(&hashed_path)).await??)
+    } else {
+        None
+    };
+
+    // Real size of a file queued without one, for the overall bar and the summary
+    if file.size == 0 {
+        let size = tokio::fs::metadata(&tmp_path).await.map_or(0, |metadata| metadata.len());
+        options.overall.size_found(size);
+        options
+            .found_sizes
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned found sizes lock, err={e}"))
+            .insert(file.filepath.clone(), size);
+    }
+
+    // Atomically rename file, doesn't change mtime. A copy across filesystems may take a while
+    let updated = file.filepath.exists();
+    let kept_version = match options.keep_versions {
+        Some(keep) if updated => Some(keep_version(options, file, keep)?),
+        _ => None,
+    };
+    if let Err(e) = tokio::task::spawn_blocking(move || move_file(&moved_path, &filepath)).await? {
+        // Back in place, the download failed
+        if let Some(kept_version) = kept_version {
+            let _ = std::fs::rename(kept_version, &file.filepath);
         }
-        for result in sessions.Results {
-            fork!(
-                process_session,
-                (host.clone(), result, client.clone(), path.clone()),
-                (String, canvas::PanoptoResult, reqwest::Client, PathBuf),
-                options.clone()
-            )
+        return Err(e);
+    }
+
+    // After the move, a copy across filesystems is created anew
+    if let Some(created_at) = &file.created_at {
+        match DateTime::parse_from_rfc3339(created_at) {
+            Ok(created_at) => {
+                let created_path = file.filepath.clone();
+                let created_set =
+                    tokio::task::spawn_blocking(move || disk::set_created(&created_path, created_at.into())).await?;
+                if let Err(e) = created_set {
+                    eprintln!(
+                        "Failed to set creation time of {} with created_at of {created_at}, err={e:?}",
+                        file.display_name
+                    )
+                }
+            }
+            Err(e) => eprintln!(
+                "Failed to parse created_at time for {}, {created_at}, err={e:?}",
+                file.display_name
+            ),
         }
-        // Subfolders are the same, so process only the first request
-        if i == 0 {
-            for subfolder in sessions.Subfolders {
-                let subfolder_path = path.join(sanitize_foldername(subfolder.Name));
-                create_folder_if_not_exist(&subfolder_path)?;
-                fork!(
-                    process_video_folder,
-                    (host.clone(), subfolder.ID, client.clone(), subfolder_path),
-                    (String, String, reqwest::Client, PathBuf),
-                    options.clone()
-                );
+    }
+    options
+        .changes
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned changes lock, err={e}"))
+        .files
+        .push(digest::ChangedFile {
+            course_folder: course_folder_of(&file.filepath, options),
+            path: file.filepath.clone(),
+            updated,
+        });
+
+    // Remember the Canvas timestamp to tell local edits apart from Canvas updates later
+    let mut renamed_from = None;
+    if let Ok(relative_path) = file.filepath.strip_prefix(&options.destination_folder) {
+        let relative_path = relative_path.to_string_lossy().to_string();
+        let mut state = options
+            .state
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+        let downloaded_before = state.files.insert(relative_path.clone(), file.updated_at.clone()).is_some();
+        count_for_course(options, file, |summary| {
+            if downloaded_before {
+                summary.updated += 1;
+            } else {
+                summary.new += 1;
+            }
+        });
+        let size = std::fs::metadata(&file.filepath).map_or(file.size, |metadata| metadata.len());
+        let mut manifest = options
+            .manifest
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"));
+        renamed_from = manifest.lookup(file.id).map(|entry| entry.path.clone()).filter(|path| *path != relative_path);
+        manifest.record(file.id, relative_path.clone(), file.updated_at.clone(), size);
+        match content_hash {
+            Some(hash) => {
+                state.content.insert(relative_path, state::ContentRecord { size, hash });
+            }
+            None => {
+                state.content.remove(&relative_path);
             }
         }
     }
+
+    // Renamed and updated on Canvas, the copy under the old name is of an earlier version
+    if let Some(previous_path) = renamed_from.filter(|_| options.prune_renamed) {
+        let previous_file = options.destination_folder.join(&previous_path);
+        if previous_file.is_file() && !locally_modified(options, &previous_file) {
+            match std::fs::remove_file(&previous_file) {
+                Ok(()) => {
+                    options.info(format!("Removed {}, renamed on Canvas", previous_file.to_string_lossy()));
+                    let mut state = options
+                        .state
+                        .lock()
+                        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+                    state.files.remove(&previous_path);
+                    state.content.remove(&previous_path);
+                }
+                Err(e) => eprintln!("Failed to remove {previous_file:?}, renamed on Canvas, err={e:?}"),
+            }
+        }
+    }
+    dequeue(file, options);
     Ok(())
 }
 
-async fn process_session(
-    (host, result, client, path):
-    (String, canvas::PanoptoResult, reqwest::Client, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    // POST deliveryID: to https://mediaweb.ap.panopto.com/Panopto/Pages/Viewer/DeliveryInfo.aspx
-    let resp = client
-        .post(format!("https://{}/Panopto/Pages/Viewer/DeliveryInfo.aspx", host))
-        .form(&[
-            ("deliveryId",result.DeliveryID.as_str()),
-            ("invocationId",""),
-            ("isLiveNotes","false"),
-            ("refreshAuthCookie","true"),
-            ("isActiveBroadcast","false"),
-            ("isEditing","false"),
-            ("isKollectiveAgentInstalled","false"),
-            ("isEmbed","false"),
-            ("responseType","json"),
-        ])
-        .send()
-        .await?;
+// Done with a queued download, for --resume
+fn dequeue(file: &File, options: &ProcessOptions) {
+    let mut queue = options
+        .queue
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned queue lock, err={e}"));
+    let queued_path = |queued: &state::QueuedFile| options.destination_folder.join(&queued.path);
+    let Some(index) = queue.iter().position(|queued| queued_path(queued) == file.filepath) else {
+        return;
+    };
+    queue.remove(index);
+    if let Err(e) = state::save_queue(&options.destination_folder, &queue) {
+        eprintln!("Failed to update the download queue, err={e:?}");
+    }
+}
 
-    let delivery_info = resp.json::<canvas::PanoptoDeliveryInfo>().await?;
-    
-    let viewer_file_id = delivery_info.ViewerFileId;
-    let panopto_url = Url::parse(&result.IosVideoUrl)?;
-    let panopto_cdn_host = panopto_url.host_str().unwrap_or("s-cloudfront.cdn.ap.panopto.com");
-    let panopto_master_m3u8 = format!("https://{}/sessions/{}/{}-{}.hls/master.m3u8", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id);
-    let m3u8_resp = client
-        .get(panopto_master_m3u8)
-        .send()
-        .await?;
-    let m3u8_text = m3u8_resp.text().await?;
-    let m3u8_parser = m3u8_rs::parse_playlist_res(m3u8_text.as_bytes());
-    match m3u8_parser {
-        Ok(Playlist::MasterPlaylist(pl)) => {
-            // get the highest bandwidth
-            let download_variant = pl.variants
-                .iter()
-                .max_by_key(|v| v.bandwidth)
-                .unwrap();
+fn cancel_download(file: File, options: &ProcessOptions) {
+    options
+        .cancelled
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned cancelled lock, err={e}"))
+        .push(file);
+}
 
-            let panopto_index_m3u8 = format!("https://{}/sessions/{}/{}-{}.hls/{}", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id, download_variant.uri);
-            
-            let index_m3u8_resp = client
-                .get(panopto_index_m3u8)
-                .send()
-                .await?;
-            let index_m3u8_text = index_m3u8_resp.text().await?;
-            let index_m3u8_parser = m3u8_rs::parse_playlist_res(index_m3u8_text.as_bytes());
-            match index_m3u8_parser {
-                Ok(Playlist::MasterPlaylist(_index_pl)) => {},
-                Ok(Playlist::MediaPlaylist(index_pl)) => {
-                    let uri_id = download_variant.uri.split("/").next().ok_or(anyhow!("Could not get URI ID"))?;
-                    let file_uri = index_pl.segments[0].uri.clone();
-                    let file_uri_ext = Path::new(&file_uri).extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
-                    let panopto_mp4_file = format!("https://{}/sessions/{}/{}-{}.hls/{}/{}", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id, uri_id, file_uri);
-                    let download_file_name = if file_uri_ext == "" {
-                        format!("{}", result.SessionName)
-                    } else {
-                        format!("{}.{}", result.SessionName, file_uri_ext)
-                    };
+// 64-bit FNV-1a of the file as hex, stable across versions unlike DefaultHasher. Tells a download apart from a
+// changed file, it is no protection against tampering
+fn content_hash(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("Could not open {path:?}"))?;
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buffer).with_context(|| format!("Could not read {path:?}"))?;
+        if n == 0 {
+            break;
+        }
+        for byte in &buffer[..n] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(format!("{hash:016x}"))
+}
+
+// With --verify-content, a file that looks updated by its modified time is kept when Canvas reports the size of the
+// recorded download and the local copy still has its checksum. Modified times are off on exFAT and some network
+// mounts, which round them or fail to set them
+fn unchanged_content(options: &ProcessOptions, canvas_file: &File) -> bool {
+    let Ok(relative_path) = canvas_file.filepath.strip_prefix(&options.destination_folder) else {
+        return false;
+    };
+    let recorded = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .content
+        .get(relative_path.to_string_lossy().as_ref())
+        .cloned();
+    let Some(recorded) = recorded else {
+        return false;
+    };
+    let local_size = std::fs::metadata(&canvas_file.filepath).ok().map(|metadata| metadata.len());
+    if recorded.size != canvas_file.size || local_size != Some(canvas_file.size) {
+        return false;
+    }
+    // Hashing a large file blocks, this only runs for the few files whose modified time is off
+    tokio::task::block_in_place(|| content_hash(&canvas_file.filepath)).is_ok_and(|hash| hash == recorded.hash)
+}
+
+// Rename, falling back to copy + rename when the tmp folder is on another filesystem
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    move_file_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+// `rename` is only swapped out by the tests, to fail like across filesystems
+fn move_file_with(from: &Path, to: &Path, rename: impl Fn(&Path, &Path) -> std::io::Result<()>) -> Result<()> {
+    let Err(rename_err) = rename(from, to) else {
+        return Ok(());
+    };
+    // Only a --tmp-dir on another filesystem is worth copying, other errors would fail the copy too
+    if rename_err.kind() != std::io::ErrorKind::CrossesDevices {
+        return Err(Error::new(rename_err).context(format!("Failed to move {from:?} to {to:?}")));
+    }
+    if let Err(e) = copy_file(from, to) {
+        return Err(Error::new(rename_err)
+            .context(format!("Failed to move {from:?} to {to:?}, copy fallback failed with err={e}")));
+    }
+    std::fs::remove_file(from).with_context(|| format!("Failed to remove {from:?} after copying it to {to:?}"))
+}
+
+// Copy keeping the mtime, next to the destination first so the final rename stays atomic. The partial copy is named
+// like the artifacts of write_artifact(), so `clean` removes it when the run crashes halfway
+fn copy_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let file_name = to.file_name().unwrap_or_default().to_string_lossy();
+    let partial = to.with_file_name(format!(".{file_name}.{}.part", std::process::id()));
+    let copied = std::fs::copy(from, &partial).and_then(|_| {
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(from)?);
+        filetime::set_file_mtime(&partial, mtime)?;
+        std::fs::rename(&partial, to)
+    });
+    if copied.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    copied
+}
+
+// Keeps the files deferred by the previous run, then the newest, until max_total_bytes is used up. The first file is
+// always kept, so one larger than max_total_bytes on its own is not deferred forever.
+// Returns the deferred files and how many kept files have no known size, which count as 0
+fn apply_download_budget(files: &mut Vec<File>, max_total_bytes: u64, options: &ProcessOptions) -> (Vec<File>, usize) {
+    let previously_deferred = state::load_deferred(&options.destination_folder);
+    let relative_path = |file: &File| -> String {
+        file.filepath
+            .strip_prefix(&options.destination_folder)
+            .unwrap_or(&file.filepath)
+            .to_string_lossy()
+            .to_string()
+    };
+    // Canvas timestamps are UTC, so they sort as strings
+    files.sort_by(|a, b| {
+        let a_deferred = previously_deferred.contains(&relative_path(a));
+        let b_deferred = previously_deferred.contains(&relative_path(b));
+        b_deferred.cmp(&a_deferred).then_with(|| b.updated_at.cmp(&a.updated_at))
+    });
+
+    let mut total_bytes = 0;
+    let mut n_unknown_size = 0;
+    let mut deferred = Vec::new();
+    for file in std::mem::take(files) {
+        if total_bytes + file.size > max_total_bytes {
+            if !files.is_empty() {
+                deferred.push(file);
+                continue;
+            }
+            options.info(format!(
+                "Downloading {} ({}) although it exceeds --max-total-bytes on its own",
+                file.filepath.to_string_lossy(),
+                indicatif::HumanBytes(file.size)
+            ));
+        }
+        total_bytes += file.size;
+        if file.size == 0 {
+            n_unknown_size += 1;
+        }
+        files.push(file);
+    }
+    (deferred, n_unknown_size)
+}
+
+// Stable, so files that compare equal stay in the order they were found
+fn order_downloads(files: &mut [File], order: DownloadOrder, course_of: impl Fn(&File) -> PathBuf) {
+    match order {
+        DownloadOrder::Smallest => files.sort_by_key(|f| (f.size == 0, f.size)),
+        DownloadOrder::Largest => files.sort_by_key(|f| std::cmp::Reverse(f.size)),
+        // Canvas timestamps are UTC, so they sort as strings
+        DownloadOrder::Newest => files.sort_by(|a, b| b.updated_at.cmp(&a.updated_at)),
+        DownloadOrder::Course => {
+            let mut first_seen: HashMap<PathBuf, usize> = HashMap::new();
+            for f in files.iter() {
+                let n_courses = first_seen.len();
+                first_seen.entry(course_of(f)).or_insert(n_courses);
+            }
+            files.sort_by_cached_key(|f| first_seen.get(&course_of(f)).copied().unwrap_or(usize::MAX));
+        }
+    }
+}
+
+// Round-robin across courses in the order they were crawled, so a run stopped early leaves every course partly synced.
+// Downloads are launched in this order, the request semaphore hands out permits first come first served
+fn interleave_by_course(files: Vec<File>, options: &ProcessOptions) -> Vec<File> {
+    let n_files = files.len();
+    let mut queues: Vec<(PathBuf, std::collections::VecDeque<File>)> = Vec::new();
+    for file in files {
+        let course_folder = course_folder_of(&file.filepath, options);
+        match queues.iter_mut().find(|(folder, _)| *folder == course_folder) {
+            Some((_, queue)) => queue.push_back(file),
+            None => queues.push((course_folder, std::collections::VecDeque::from([file]))),
+        }
+    }
+    let mut interleaved = Vec::with_capacity(n_files);
+    while !queues.is_empty() {
+        for (_, queue) in queues.iter_mut() {
+            interleaved.extend(queue.pop_front());
+        }
+        queues.retain(|(_, queue)| !queue.is_empty());
+    }
+    interleaved
+}
+
+// Fill in File.size from Content-Length for entries discovered without one (Panopto, HTML links)
+async fn probe_unknown_sizes(files: &mut [File], options: &ProcessOptions) {
+    // Hosts that do not answer HEAD properly, e.g. signed storage URLs only valid for GET
+    let no_head_hosts = std::sync::Mutex::new(HashSet::new());
+    let no_head_hosts = &no_head_hosts;
+    let unknown = files
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.size == 0)
+        .map(|(i, f)| (i, f.url.clone()))
+        .collect::<Vec<_>>();
+
+    let sizes = stream::iter(unknown)
+        .map(|(i, url)| async move {
+            let host = Url::parse(&url).ok()?.host_str()?.to_string();
+            if no_head_hosts.lock().ok()?.contains(&host) {
+                return None;
+            }
+            // Bounded by the same semaphore as the crawl
+            let _permit = options.sem_api.acquire().await.ok()?;
+            options.metrics.record_request("size_probe");
+            let resp = tokio::time::timeout(
+                options.request_timeout,
+                send_file_request(options, reqwest::Method::HEAD, &url, 0),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            match resp.status() {
+                reqwest::StatusCode::FORBIDDEN
+                | reqwest::StatusCode::METHOD_NOT_ALLOWED
+                | reqwest::StatusCode::NOT_IMPLEMENTED => {
+                    no_head_hosts.lock().ok()?.insert(host);
+                    return None;
+                }
+                status if !status.is_success() => return None,
+                _ => {}
+            }
+            let size = resp
+                .headers()
+                .get(header::CONTENT_LENGTH)?
+                .to_str()
+                .ok()?
+                .parse::<u64>()
+                .ok()?;
+            Some((i, size))
+        })
+        .buffer_unordered(64)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (i, size) in sizes.into_iter().flatten() {
+        files[i].size = size;
+    }
+}
+
+// Fresh signed URL of a Canvas file, None when the file object can not be fetched again
+async fn refreshed_url(canvas_file: &File, options: &ProcessOptions) -> Option<String> {
+    options.metrics.record_request("url_refresh");
+    let url = options.api_url(&format!("files/{}", canvas_file.id));
+    let resp = get_canvas_api(url, options).await.ok()?;
+    let status = resp.status();
+    let body = resp.text().await.ok()?;
+    let refreshed = canvas::parse_api_body::<File>(status, &body, None).ok()?;
+    (!refreshed.url.is_empty()).then_some(refreshed.url)
+}
+
+// All tmp files share one folder, so the full destination path is hashed, with the ID and version of the file so
+// that two files or two versions never share one. Resumable names stay the same across runs, see download_file().
+// Otherwise the process ID keeps a run from picking up what a crashed one left behind
+fn tmp_file_name(file: &File, resumable: bool) -> String {
+    let mut h = DefaultHasher::new();
+    file.filepath.hash(&mut h);
+    file.id.hash(&mut h);
+    file.updated_at.hash(&mut h);
+    // Hidden, and recognizable for clean::remove_stale_tmp_files()
+    if resumable {
+        format!(".canvasdl-{}.tmp", h.finish())
+    } else {
+        format!(".canvasdl-{}.{}.tmp", h.finish(), std::process::id())
+    }
+}
+
+// Up to --retries more attempts after network errors and 5xx answers, one progress bar for all of them
+async fn retry_download(tmp_path: &PathBuf, canvas_file: &File, options: &Arc<ProcessOptions>) -> Result<bool> {
+    let mut progress_bar = None;
+    let mut retry = 0;
+    loop {
+        let result = download_file((tmp_path, canvas_file, &mut progress_bar), options.clone()).await;
+        let cause = match &result {
+            Err(e) if retry < options.retries => download_retry_cause(e),
+            _ => None,
+        };
+        let Some(cause) = cause else {
+            // Done with this file, the overall bar counts it from here
+            if let Some(progress_bar) = &progress_bar {
+                if result.is_err() {
+                    options.overall.move_bytes(progress_bar.position(), 0);
+                }
+                progress_bar.finish_and_clear();
+            }
+            return result;
+        };
+        let wait_time = backoff(options, retry);
+        options.metrics.record_retry(cause, wait_time);
+        options.detail(format!(
+            "Got {cause:?} downloading {}, waiting {wait_time:?} before retrying, retry {retry}",
+            canvas_file.display_name
+        ));
+        if let Some(progress_bar) = &progress_bar {
+            let stalled = result.as_ref().is_err_and(|e| e.chain().any(|cause| cause.is::<Stalled>()));
+            let status = if stalled { "stalled, retrying" } else { "retrying" };
+            progress_bar.set_message(format!("{} ({status})", canvas_file.display_name));
+        }
+        tokio::select! {
+            () = tokio::time::sleep(wait_time) => {}
+            () = options.cancel.cancelled() => {
+                if let Some(progress_bar) = &progress_bar {
+                    options.overall.move_bytes(progress_bar.position(), 0);
+                    progress_bar.finish_and_clear();
+                }
+                return Err(errors::Cancelled.into());
+            }
+        }
+        retry += 1;
+    }
+}
+
+// Next part of a download body, or errors::Cancelled as soon as Ctrl-C is pressed. A connection that goes silent
+// without closing fails with Stalled, instead of holding its download slot forever
+async fn next_chunk(resp: &mut Response, options: &ProcessOptions) -> Result<Option<impl std::ops::Deref<Target = [u8]>>> {
+    tokio::select! {
+        chunk = resp.chunk() => Ok(chunk?),
+        () = tokio::time::sleep(options.stall_timeout) => Err(Stalled(options.stall_timeout).into()),
+        () = options.cancel.cancelled() => Err(errors::Cancelled.into()),
+    }
+}
+
+// 401, 403 and 404 are final, so are errors writing to disk. A truncated body is retried like a dropped connection
+fn download_retry_cause(error: &Error) -> Option<RetryCause> {
+    for cause in error.chain() {
+        if let Some(DownloadStatus(status)) = cause.downcast_ref::<DownloadStatus>() {
+            return status.is_server_error().then_some(RetryCause::ServerError);
+        }
+        if cause.downcast_ref::<SizeMismatch>().is_some() {
+            return Some(RetryCause::NetworkError);
+        }
+        if cause.downcast_ref::<Stalled>().is_some() {
+            return Some(RetryCause::Timeout);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() {
+                Some(RetryCause::Timeout)
+            } else if e.is_connect() || e.is_request() || e.is_body() || e.is_decode() {
+                Some(RetryCause::NetworkError)
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+// Unsuccessful answer to a download, see download_retry_cause()
+#[derive(Debug)]
+struct DownloadStatus(reqwest::StatusCode);
+
+impl std::fmt::Display for DownloadStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "got {}", self.0)
+    }
+}
+
+impl std::error::Error for DownloadStatus {}
+
+// Exponential with full jitter, but never longer than a request may take, so many retries do not wait for hours
+fn backoff(options: &ProcessOptions, retry: u32) -> Duration {
+    let backoff_cap_ms = 1000_u64
+        .saturating_mul(2_u64.saturating_pow(retry))
+        .min(options.request_timeout.as_millis() as u64);
+    Duration::from_millis(rand::thread_rng().gen_range(0..backoff_cap_ms.max(1)))
+}
+
+// Ok(false) when skipped because Content-Length exceeds --max-file-size
+async fn download_file(
+    (tmp_path, canvas_file, progress_bar): (&PathBuf, &File, &mut Option<ProgressBar>),
+    options: Arc<ProcessOptions>,
+) -> Result<bool> {
+    // Canvas may leave out the URL of a locked file, --include-locked asks for it
+    let url = if canvas_file.url.is_empty() && canvas_file.locked_for_user && canvas_file.id != 0 {
+        refreshed_url(canvas_file, &options).await.unwrap_or_default()
+    } else {
+        canvas_file.url.clone()
+    };
+    if url.is_empty() && canvas_file.locked_for_user {
+        refuse_locked(canvas_file, &options);
+        return Ok(false);
+    }
+
+    // What an earlier attempt left in the tmp file, see atomic_download_file(). A tmp file older than the Canvas
+    // update holds the start of the previous version
+    let mut resume_from = if options.resume {
+        let updated_at = DateTime::parse_from_rfc3339(&canvas_file.updated_at).ok();
+        std::fs::metadata(tmp_path).ok().map_or(0, |metadata| {
+            let written_after_update = match (metadata.modified(), updated_at) {
+                (Ok(modified), Some(updated_at)) => modified >= std::time::SystemTime::from(updated_at),
+                _ => false,
+            };
+            if written_after_update { metadata.len() } else { 0 }
+        })
+    } else {
+        0
+    };
+
+    // Get file
+    options.metrics.record_request("download");
+    let mut resp = send_file_request(&options, reqwest::Method::GET, &url, resume_from)
+        .await
+        .with_context(|| format!("Something went wrong when reaching {url}"))?;
+    // Signed URLs of Canvas files expire, those gathered early in a long crawl may be stale by now
+    let denied = |status: reqwest::StatusCode| {
+        matches!(status, reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)
+    };
+    if denied(resp.status()) && canvas_file.id != 0 {
+        if let Some(url) = refreshed_url(canvas_file, &options).await {
+            options.detail(format!("Download URL of {} expired, retrying with a refreshed one", canvas_file.display_name));
+            options.metrics.record_request("download");
+            resp = send_file_request(&options, reqwest::Method::GET, &url, resume_from)
+                .await
+                .with_context(|| format!("Something went wrong when reaching {url}"))?;
+            if denied(resp.status()) && !canvas_file.locked_for_user {
+                return Err(Error::msg(format!(
+                    "Failed to download {}, access denied also with a refreshed URL, got {resp:?}",
+                    canvas_file.display_name
+                )));
+            }
+        }
+    }
+    if denied(resp.status()) {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        if canvas_file.locked_for_user || locked_body(&body) {
+            refuse_locked(canvas_file, &options);
+            return Ok(false);
+        }
+        return Err(Error::msg(format!(
+            "Failed to download {}, got {status}: {}",
+            canvas_file.display_name,
+            body.chars().take(200).collect::<String>()
+        )));
+    }
+    // 200 ignores the range and 416 means the tmp file is not a prefix of the file anymore, both start over.
+    // So does a 206 for another range than asked for
+    let resumed = resume_from > 0
+        && resp.status() == reqwest::StatusCode::PARTIAL_CONTENT
+        && content_range_start(&resp) == Some(resume_from);
+    if resume_from > 0 && !resumed {
+        options.detail(format!("Could not resume {}, downloading it again", canvas_file.display_name));
+        resume_from = 0;
+        if resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE || resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            options.metrics.record_request("download");
+            resp = send_file_request(&options, reqwest::Method::GET, resp.url().as_str(), 0)
+                .await
+                .with_context(|| format!("Something went wrong when reaching {url}"))?;
+        }
+    }
+    if !resp.status().is_success() {
+        return Err(Error::new(DownloadStatus(resp.status()))
+            .context(format!("Failed to download {}, got {resp:?}", canvas_file.display_name)));
+    }
+
+    // The rest of a resumed file is counted with what is there
+    let expected_size = resp
+        .headers() // Gives us the HeaderMap
+        .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
+        .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
+        .and_then(|ct_len| ct_len.parse::<u64>().ok()) // Parses the Option as u64
+        .map(|ct_len| ct_len + resume_from);
+    let download_size = expected_size.unwrap_or(0); // Fallback to 0
+    // Panopto and linked files only reveal their size now
+    if exceeds_max_file_size(&options, canvas_file, download_size) {
+        return Ok(false);
+    }
+
+    // Big files from servers that take ranges are fetched in several parts at once, with the permits of downloads
+    // that are not running, see download_segments()
+    let accepts_ranges = resp
+        .headers()
+        .get(header::ACCEPT_RANGES)
+        .is_some_and(|accept_ranges| accept_ranges.as_bytes() == b"bytes");
+    if options.segments > 1 && !resumed && accepts_ranges && download_size > SEGMENT_THRESHOLD {
+        let mut extra_permits = Vec::new();
+        // One is always left for the next file in line
+        while extra_permits.len() + 1 < options.segments as usize && options.sem_downloads.available_permits() > 1 {
+            match options.sem_downloads.try_acquire() {
+                Ok(permit) => extra_permits.push(permit),
+                Err(_) => break,
+            }
+        }
+        if !extra_permits.is_empty() {
+            // Ranges go to where the file actually is, eg the CDN Canvas redirected to
+            let segment_url = resp.url().clone();
+            drop(resp);
+            let progress_bar = progress_bar.get_or_insert_with(|| add_progress_bar(&options, download_size));
+            progress_bar.set_length(download_size);
+            progress_bar.set_message(canvas_file.display_name.to_string());
+            options.overall.move_bytes(progress_bar.position(), 0);
+            progress_bar.set_position(0);
+            progress_bar.reset_eta();
+            let n_segments = extra_permits.len() as u64 + 1;
+            download_segments((tmp_path, canvas_file, &segment_url, download_size, n_segments, progress_bar), &options)
+                .await?;
+            return Ok(true);
+        }
+    }
+
+    let maybe_error_page = !resumed && maybe_error_page(&resp, canvas_file, expected_size);
+
+    // Create + Open file, appending to the part downloaded before. Through tokio, so slow storage does not block
+    // the worker threads other tasks run on
+    let file = if resumed {
+        options.detail(format!("Resuming {} at {}", canvas_file.display_name, indicatif::HumanBytes(resume_from)));
+        tokio::fs::OpenOptions::new().append(true).open(tmp_path).await
+    } else {
+        tokio::fs::File::create(tmp_path).await
+    }
+    .with_context(|| format!("Unable to create tmp file for {:?}", canvas_file.filepath))?;
+    let mut file = BudgetedWriter::new(file, options.write_buffer, &options.sem_buffered_bytes, options.max_buffered_bytes);
+
+    // Progress bar, a retry reuses the one of the attempt before
+    let progress_bar = progress_bar.get_or_insert_with(|| add_progress_bar(&options, download_size));
+    set_progress_length(&options, progress_bar, download_size);
+    progress_bar.set_message(canvas_file.display_name.to_string());
+    options.overall.move_bytes(progress_bar.position(), resume_from);
+    progress_bar.set_position(resume_from);
+    progress_bar.reset_eta();
+
+    // Download
+    let mut written = resume_from;
+    let mut not_shown = 0;
+    while let Some(chunk) = next_chunk(&mut resp, &options).await? {
+        if maybe_error_page && written == 0 {
+            if let Some(kind) = error_page_kind(&chunk) {
+                // Nothing of the file to resume from
+                drop(file);
+                let _ = std::fs::remove_file(tmp_path);
+                return Err(Error::msg(format!(
+                    "Failed to download {}, got {kind} instead of the file. The token may have expired or the file \
+                     needs a permission you do not have: {}",
+                    canvas_file.display_name,
+                    String::from_utf8_lossy(&chunk[..chunk.len().min(200)])
+                )));
+            }
+        }
+        let chunk_len = chunk.len() as u64;
+        file.write(&chunk)
+            .await
+            .with_context(|| format!("Could not write to file {:?}", canvas_file.filepath))?;
+        // Counted once written, not when received
+        written += chunk_len;
+        not_shown = show_progress(&options, progress_bar, not_shown + chunk_len);
+    }
+    file.flush()
+        .await
+        .with_context(|| format!("Could not write to file {:?}", canvas_file.filepath))?;
+    progress_bar.inc(not_shown);
+    options.overall.add_bytes(not_shown);
+
+    // A connection closed early can end the body without an error. Canvas' size only stands in when the server
+    // sent no Content-Length, some Canvas sizes are off for files converted after upload
+    let expected_size = expected_size.or((canvas_file.size > 0).then_some(canvas_file.size));
+    if let Some(expected_size) = expected_size.filter(|expected_size| written != *expected_size) {
+        // Longer than announced is not the start of the file to resume from
+        if written > expected_size {
+            drop(file);
+            let _ = std::fs::remove_file(tmp_path);
+        }
+        return Err(Error::new(SizeMismatch { expected: expected_size, written })
+            .context(format!("Failed to download {}", canvas_file.display_name)));
+    }
+
+    Ok(true)
+}
+
+// Bytes written before the progress bar is moved, small chunks each redrawing it cost more than writing them
+const PROGRESS_STEP: u64 = 64 * 1024;
+
+// Moves the progress bar and the overall one once PROGRESS_STEP bytes were written, returns the bytes not shown yet
+fn show_progress(options: &ProcessOptions, progress_bar: &ProgressBar, not_shown: u64) -> u64 {
+    if not_shown < PROGRESS_STEP {
+        return not_shown;
+    }
+    progress_bar.inc(not_shown);
+    options.overall.add_bytes(not_shown);
+    0
+}
+
+// Canvas answers some failures with 200 and a login page or a JSON error. Suspect when the Content-Type is HTML or
+// JSON but the file is not, and the body is small, see error_page_kind()
+fn maybe_error_page(resp: &Response, canvas_file: &File, expected_size: Option<u64>) -> bool {
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if !content_type.starts_with("text/html") && !content_type.starts_with("application/json") {
+        return false;
+    }
+    let extension = Path::new(&canvas_file.display_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if matches!(extension.as_deref(), Some("html" | "htm" | "xhtml" | "json")) {
+        return false;
+    }
+    expected_size.is_none_or(|expected_size| expected_size < 64 * 1024)
+}
+
+// The first bytes of a suspect body, see maybe_error_page()
+fn error_page_kind(start: &[u8]) -> Option<&'static str> {
+    let start = String::from_utf8_lossy(&start[..start.len().min(1024)]).to_lowercase();
+    let start = start.trim_start_matches('\u{feff}').trim_start();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("an HTML page")
+    } else if start.starts_with("{\"errors\"") || start.starts_with("{\"error\"") {
+        Some("a JSON error")
+    } else {
+        None
+    }
+}
+
+// Smaller files are not worth the extra requests
+const SEGMENT_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// Splits the file into `n_segments` ranges downloaded at once into a preallocated tmp file
+async fn download_segments(
+    (tmp_path, canvas_file, url, size, n_segments, progress_bar): (&Path, &File, &Url, u64, u64, &ProgressBar),
+    options: &ProcessOptions,
+) -> Result<()> {
+    let file = tokio::fs::File::create(tmp_path)
+        .await
+        .with_context(|| format!("Unable to create tmp file for {:?}", canvas_file.filepath))?;
+    file.set_len(size)
+        .await
+        .with_context(|| format!("Unable to create tmp file for {:?}", canvas_file.filepath))?;
+    drop(file);
+    let segment_len = size.div_ceil(n_segments);
+    let segments = (0..n_segments)
+        .map(|i| (i * segment_len, ((i + 1) * segment_len).min(size)))
+        .filter(|(start, end)| start < end);
+    let results = join_all(segments.map(|(start, end)| {
+        download_segment((tmp_path, canvas_file, url, start, end, progress_bar), options)
+    }))
+    .await;
+    if let Some(e) = results.into_iter().find_map(Result::err) {
+        // Holes are zeros, so the length of a preallocated file says nothing about what is there to resume
+        let _ = std::fs::remove_file(tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Bytes start..end of the file, written at the same offset into the tmp file
+async fn download_segment(
+    (tmp_path, canvas_file, url, start, end, progress_bar): (&Path, &File, &Url, u64, u64, &ProgressBar),
+    options: &ProcessOptions,
+) -> Result<()> {
+    options.metrics.record_request("download");
+    // The token is for Canvas, not for the CDN the file may have been redirected to
+    let (client, send_token) = client_for(options, url);
+    let mut request = client.get(url.clone()).header(header::RANGE, format!("bytes={start}-{}", end - 1));
+    if send_token {
+        request = request.headers(options.canvas_headers.clone()).bearer_auth(&options.canvas_token);
+    }
+    let mut resp = request
+        .send()
+        .await
+        .with_context(|| format!("Something went wrong when reaching {url}"))?;
+    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT || content_range_start(&resp) != Some(start) {
+        return Err(Error::new(DownloadStatus(resp.status()))
+            .context(format!("Failed to download bytes {start}-{end} of {}", canvas_file.display_name)));
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(tmp_path)
+        .await
+        .with_context(|| format!("Unable to open tmp file for {:?}", canvas_file.filepath))?;
+    file.seek(std::io::SeekFrom::Start(start))
+        .await
+        .with_context(|| format!("Unable to open tmp file for {:?}", canvas_file.filepath))?;
+    let mut file = BudgetedWriter::new(file, options.write_buffer, &options.sem_buffered_bytes, options.max_buffered_bytes);
+    let mut written = 0;
+    let mut not_shown = 0;
+    while let Some(chunk) = next_chunk(&mut resp, options).await? {
+        file.write(&chunk)
+            .await
+            .with_context(|| format!("Could not write to file {:?}", canvas_file.filepath))?;
+        written += chunk.len() as u64;
+        not_shown = show_progress(options, progress_bar, not_shown + chunk.len() as u64);
+    }
+    file.flush()
+        .await
+        .with_context(|| format!("Could not write to file {:?}", canvas_file.filepath))?;
+    progress_bar.inc(not_shown);
+    options.overall.add_bytes(not_shown);
+    if written != end - start {
+        return Err(Error::new(SizeMismatch { expected: end - start, written })
+            .context(format!("Failed to download bytes {start}-{end} of {}", canvas_file.display_name)));
+    }
+    Ok(())
+}
+
+// Writes a download through a buffer of --write-buffer bytes. Received bytes keep their share of --max-buffered-bytes
+// until they are flushed to the file, so slow storage stops the downloads from reading on
+struct BudgetedWriter<'a, W> {
+    file: tokio::io::BufWriter<W>,
+    capacity: usize,
+    budget: &'a tokio::sync::Semaphore,
+    max_buffered_bytes: u32,
+    held: Option<tokio::sync::SemaphorePermit<'a>>,
+    held_bytes: usize,
+}
+
+impl<'a, W: tokio::io::AsyncWrite + Unpin> BudgetedWriter<'a, W> {
+    fn new(file: W, capacity: usize, budget: &'a tokio::sync::Semaphore, max_buffered_bytes: u32) -> Self {
+        BudgetedWriter {
+            file: tokio::io::BufWriter::with_capacity(capacity, file),
+            capacity,
+            budget,
+            max_buffered_bytes,
+            held: None,
+            held_bytes: 0,
+        }
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        let chunk_bytes = u32::try_from(chunk.len()).unwrap_or(u32::MAX).min(self.max_buffered_bytes);
+        let permit = match self.budget.try_acquire_many(chunk_bytes) {
+            Ok(permit) => permit,
+            // Waiting with budget held could leave every download waiting for the others
+            Err(_) => {
+                self.flush().await?;
+                self.budget
+                    .acquire_many(chunk_bytes)
+                    .await
+                    .unwrap_or_else(|e| panic!("Please report on GitHub. Unexpected closed sem, err={e}"))
+            }
+        };
+        self.file.write_all(chunk).await?;
+        self.held_bytes += chunk.len();
+        match &mut self.held {
+            Some(held) => held.merge(permit),
+            None => self.held = Some(permit),
+        }
+        if self.held_bytes >= self.capacity {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Releases the budget of everything written so far
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.held = None;
+        self.held_bytes = 0;
+        Ok(())
+    }
+}
+
+// Bytes received differ from the announced size, see download_file()
+#[derive(Debug)]
+struct SizeMismatch {
+    expected: u64,
+    written: u64,
+}
+
+impl std::fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {} bytes, got {}", self.expected, self.written)
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+// No data received for --stall-timeout, see next_chunk()
+#[derive(Debug)]
+struct Stalled(Duration);
+
+impl std::fmt::Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stalled, nothing received for {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+// Redirects are followed here rather than by reqwest, so the token only ever goes to the Canvas host. Canvas answers
+// a file download with a redirect to file storage, whose signed URL must not get it, see client_for()
+async fn send_file_request(options: &ProcessOptions, method: reqwest::Method, url: &str, resume_from: u64) -> Result<Response> {
+    let mut url = Url::parse(url).with_context(|| format!("Invalid download URL {url:?}"))?;
+    for _ in 0..MAX_REDIRECTS {
+        let (client, send_token) = client_for(options, &url);
+        let mut request = client.request(method.clone(), url.clone());
+        if send_token {
+            request = request.headers(options.canvas_headers.clone()).bearer_auth(&options.canvas_token);
+        }
+        if resume_from > 0 {
+            request = request.header(header::RANGE, format!("bytes={resume_from}-"));
+        }
+        let resp = request.send().await?;
+        let location = resp.headers().get(header::LOCATION).and_then(|location| location.to_str().ok());
+        match location {
+            Some(location) if resp.status().is_redirection() => {
+                url = resp.url().join(location).with_context(|| format!("Invalid redirect to {location:?}"))?;
+            }
+            _ => return Ok(resp),
+        }
+    }
+    Err(anyhow!("Too many redirects, last to {url}"))
+}
+
+// As many as reqwest follows by default
+const MAX_REDIRECTS: usize = 10;
+
+// "bytes 1000-4999/5000" --> 1000
+fn content_range_start(resp: &Response) -> Option<u64> {
+    let content_range = resp.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    content_range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+// Canvas answers "This file is currently locked" or an error with lock_info for files behind a lock date or prerequisite
+fn locked_body(body: &str) -> bool {
+    let body = body.to_lowercase();
+    body.contains("locked") || body.contains("lock_info")
+}
+
+// Not a failure of the run, listed separately at its end
+fn refuse_locked(canvas_file: &File, options: &ProcessOptions) {
+    options.detail(format!("Failed to download {}: locked by instructor", canvas_file.display_name));
+    options
+        .refused_locked
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned refused lock, err={e}"))
+        .push(canvas_file.clone());
+}
+
+// Bars are drawn to stderr, so that is the terminal to measure. None when it is not a terminal
+fn terminal_width() -> Option<u16> {
+    console::Term::stderr().size_checked().map(|(_rows, cols)| cols)
+}
+
+// The wide style overflows and wraps every redraw when the width is unknown, so that gets the narrow one
+fn progress_style_for_width(cols: Option<u16>) -> ProgressStyle {
+    let style_template = if cols.is_none_or(|cols| cols < 100) {
+        "[{wide_bar:.cyan/blue}] {total_bytes} - {msg}"
+    } else {
+        "[{bar:20.cyan/blue}] {bytes}/{total_bytes} - {bytes_per_sec} - {msg}"
+    };
+    ProgressStyle::default_bar()
+        .template(style_template)
+        .unwrap_or_else(|e| panic!("Please report this issue on GitHub: error with progress bar style={style_template}, err={e}"))
+        .progress_chars("=>-")
+}
+
+// Added when a download starts and cleared by retry_download() when it ends. Beyond --max-bars the bar is hidden
+fn add_progress_bar(options: &ProcessOptions, len: u64) -> ProgressBar {
+    let mut active_progress_bars = options
+        .active_progress_bars
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned progress bars lock, err={e}"));
+    active_progress_bars.retain(|progress_bar| !progress_bar.is_finished());
+    if active_progress_bars.len() >= options.max_bars {
+        let progress_bar = ProgressBar::hidden();
+        progress_bar.set_length(len);
+        return progress_bar;
+    }
+    let progress_bar = options.progress_bars.add(ProgressBar::new(len));
+    set_progress_length(options, &progress_bar, len);
+    active_progress_bars.push(progress_bar.clone());
+    progress_bar
+}
+
+// Without a known size, eg Panopto videos and linked files served without Content-Length, a spinner counts the bytes
+// instead of a bar that can not fill up
+fn set_progress_length(options: &ProcessOptions, progress_bar: &ProgressBar, len: u64) {
+    progress_bar.set_length(len);
+    if len > 0 {
+        progress_bar.set_style(
+            options
+                .progress_style
+                .read()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned progress style lock, err={e}"))
+                .clone(),
+        );
+        return;
+    }
+    let style_template = "{spinner:.cyan} {bytes} - {bytes_per_sec} - {msg}";
+    progress_bar.set_style(
+        ProgressStyle::default_spinner()
+            .template(style_template)
+            .unwrap_or_else(|e| panic!("Please report this issue on GitHub: error with progress bar style={style_template}, err={e}")),
+    );
+}
+
+// Polled rather than SIGWINCH so that it works the same on every platform
+async fn watch_terminal_width(options: Arc<ProcessOptions>) {
+    let mut cols = terminal_width();
+    loop {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let new_cols = terminal_width();
+        if new_cols == cols {
+            continue;
+        }
+        cols = new_cols;
+        let style = progress_style_for_width(cols);
+        *options
+            .progress_style
+            .write()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned progress style lock, err={e}")) = style.clone();
+        let mut active_progress_bars = options
+            .active_progress_bars
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned progress bars lock, err={e}"));
+        active_progress_bars.retain(|progress_bar| !progress_bar.is_finished());
+        // Spinners of files of unknown size fit any width
+        for progress_bar in active_progress_bars.iter().filter(|progress_bar| progress_bar.length() != Some(0)) {
+            progress_bar.set_style(style.clone());
+        }
+    }
+}
+
+// Pinned above the bars of single files: files handled of all queued, and bytes downloaded of the sizes Canvas
+// reports. Without any known size only the downloaded bytes are shown
+pub struct OverallProgress {
+    bar: ProgressBar,
+    bytes_done: AtomicU64,
+    total_bytes: AtomicU64,
+    n_unknown_size: AtomicUsize,
+}
+
+impl Default for OverallProgress {
+    fn default() -> Self {
+        OverallProgress {
+            // Drawn once start() adds it to the MultiProgress
+            bar: ProgressBar::hidden(),
+            bytes_done: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            n_unknown_size: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl OverallProgress {
+    fn start(&self, progress_bars: &MultiProgress, files: &[File]) {
+        self.bar.set_length(files.len() as u64);
+        self.bar.set_position(0);
+        self.bytes_done.store(0, Ordering::Relaxed);
+        self.total_bytes.store(files.iter().map(|f| f.size).sum(), Ordering::Relaxed);
+        self.n_unknown_size.store(files.iter().filter(|f| f.size == 0).count(), Ordering::Relaxed);
+        self.bar.set_style(
+            ProgressStyle::with_template("[{bar:20.green/white}] {pos}/{len} files, {msg}")
+                .unwrap_or_else(|e| panic!("Please report this issue on GitHub: error with overall progress style, err={e}"))
+                .progress_chars("=>-"),
+        );
+        self.show_bytes();
+        // First, the bars of single files are added below it
+        progress_bars.insert(0, self.bar.clone());
+    }
+
+    fn add_bytes(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        self.show_bytes();
+    }
+
+    // A download resumed, started over or given up, its bar moved from `from` to `to`
+    fn move_bytes(&self, from: u64, to: u64) {
+        let _ = self.bytes_done.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |done| {
+            Some((done + to).saturating_sub(from))
+        });
+        self.show_bytes();
+    }
+
+    fn file_done(&self) {
+        self.bar.inc(1);
+    }
+
+    // A file of unknown size was downloaded, its bytes join the total
+    fn size_found(&self, size: u64) {
+        self.total_bytes.fetch_add(size, Ordering::Relaxed);
+        let _ = self
+            .n_unknown_size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(1)));
+        self.show_bytes();
+    }
+
+    fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+
+    fn show_bytes(&self) {
+        let bytes_done = indicatif::HumanBytes(self.bytes_done.load(Ordering::Relaxed));
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed);
+        let n_unknown_size = self.n_unknown_size.load(Ordering::Relaxed);
+        let message = if total_bytes == 0 {
+            format!("{bytes_done}")
+        } else if n_unknown_size > 0 {
+            format!("{bytes_done}/{} + {n_unknown_size} file(s) of unknown size", indicatif::HumanBytes(total_bytes))
+        } else {
+            format!("{bytes_done}/{}", indicatif::HumanBytes(total_bytes))
+        };
+        self.bar.set_message(message);
+    }
+}
+
+fn print_dry_run(files_to_download: &[File], options: &ProcessOptions) {
+    let mut total_bytes = 0;
+    for canvas_file in files_to_download {
+        // filter_files only lets existing files through when Canvas has a newer version
+        let status = if canvas_file.filepath.exists() { "update" } else { "new" };
+        let size = if canvas_file.size == 0 {
+            "unknown".to_string()
+        } else {
+            indicatif::HumanBytes(canvas_file.size).to_string()
+        };
+        total_bytes += canvas_file.size;
+        println!("{status:<6} {size:>10}  {}", canvas_file.filepath.to_string_lossy());
+    }
+    let n_conflicts = options
+        .local_conflicts
+        .lock()
+        .map_or(0, |conflicts| conflicts.len());
+    if n_conflicts > 0 {
+        println!("{n_conflicts} locally modified file(s) would be handled per --on-local-change");
+    }
+    println!(
+        "Dry run: {} file(s), {} in total, nothing was written",
+        files_to_download.len(),
+        indicatif::HumanBytes(total_bytes)
+    );
+}
+
+// What `list files` prints: every file the crawl found in crawl order, also those a download would skip, see plan()
+fn print_files(planned: &[(File, PlanStatus)], format: ListFormat, options: &ProcessOptions) -> Result<()> {
+    let header = ["course", "path", "display_name", "size", "updated_at", "url", "id", "status", "versions"];
+    // Earlier versions kept by --keep-versions
+    let versions = |canvas_file: &File| -> Vec<manifest::KeptVersion> {
+        manifest_entry(options, canvas_file).map(|entry| entry.versions).unwrap_or_default()
+    };
+    let rows: Vec<[String; 9]> = planned
+        .iter()
+        .map(|(canvas_file, status)| {
+            let relative_path = |path: &Path| {
+                path.strip_prefix(&options.destination_folder).unwrap_or(path).to_string_lossy().to_string()
+            };
+            let versions: Vec<String> = versions(canvas_file).into_iter().map(|version| version.path).collect();
+            [
+                relative_path(&course_folder_of(&canvas_file.filepath, options)),
+                relative_path(&canvas_file.filepath),
+                canvas_file.display_name.clone(),
+                canvas_file.size.to_string(),
+                canvas_file.updated_at.clone(),
+                canvas_file.url.clone(),
+                canvas_file.id.to_string(),
+                status.label().to_string(),
+                versions.join(";"),
+            ]
+        })
+        .collect();
+    match format {
+        ListFormat::Table => {
+            println!("{: <10}| {: <10}| {: <26}| {: <17}| Path", "File ID", "Size", "Updated", "Status");
+            for row in &rows {
+                let n_versions = row[8].split(';').filter(|path| !path.is_empty()).count();
+                let versions = if n_versions > 0 { format!(" (+{n_versions} old version(s))") } else { String::new() };
+                println!("{: <10}| {: <10}| {: <26}| {: <17}| {}{versions}", row[6], row[3], row[4], row[7], row[1]);
+            }
+        }
+        ListFormat::Json => {
+            let files: Vec<Value> = planned
+                .iter()
+                .zip(&rows)
+                .map(|((canvas_file, _), row)| {
+                    json!({
+                        "course": row[0],
+                        "path": row[1],
+                        "display_name": canvas_file.display_name,
+                        "size": canvas_file.size,
+                        "updated_at": canvas_file.updated_at,
+                        "url": canvas_file.url,
+                        "id": canvas_file.id,
+                        "status": row[7],
+                        "versions": versions(canvas_file),
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&files)?);
+        }
+        ListFormat::Csv | ListFormat::Tsv => print_records(format, &header, &rows),
+    }
+    Ok(())
+}
+
+// CSV quotes fields as needed, TSV has no quoting so tabs and line breaks become spaces
+fn print_records<const N: usize>(format: ListFormat, header: &[&str; N], rows: &[[String; N]]) {
+    let field = |value: &str| -> String {
+        match format {
+            ListFormat::Tsv => value.replace(['\t', '\n', '\r'], " "),
+            _ if value.contains([',', '"', '\n', '\r']) => format!("\"{}\"", value.replace('"', "\"\"")),
+            _ => value.to_string(),
+        }
+    };
+    let separator = if format == ListFormat::Tsv { "\t" } else { "," };
+    println!("{}", header.iter().map(|name| field(name)).collect::<Vec<_>>().join(separator));
+    for row in rows {
+        println!("{}", row.iter().map(|value| field(value)).collect::<Vec<_>>().join(separator));
+    }
+}
+
+// [{"id": 247, "name": "2024 semester 1", "course_ids": [...]}, ...]
+fn terms_json(courses: &[canvas::Course]) -> Value {
+    group_courses_by_term(courses)
+        .into_iter()
+        .map(|(term_id, courses)| {
+            json!({
+                "id": term_id,
+                "name": courses.first().map_or("", |course| course.term_name()),
+                "course_ids": courses.iter().map(|course| course.id).collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+// Sorted by term ID so that listings, and picker numbers, are stable between runs
+fn group_courses_by_term(courses: &[canvas::Course]) -> Vec<(u32, Vec<&canvas::Course>)> {
+    let mut grouped_courses: HashMap<u32, Vec<&canvas::Course>> = HashMap::new();
+
+    for course in courses.iter() {
+        let course_id: u32 = course.enrollment_term_id;
+        grouped_courses
+            .entry(course_id)
+            .or_default()
+            .push(course);
+    }
+    let mut grouped_courses: Vec<_> = grouped_courses.into_iter().collect();
+    grouped_courses.sort_by_key(|(term_id, _)| *term_id);
+    grouped_courses
+}
+
+fn print_all_courses_by_term(courses: &[canvas::Course]) {
+    println!("{: <10}| {: <20}| {:?}", "Term IDs", "Term", "Courses");
+    for (key, value) in group_courses_by_term(courses) {
+        let course_codes: Vec<&str> = value.iter().map(|course| course.course_code.as_str()).collect();
+        let term_name = value.first().map_or("", |course| course.term_name());
+        println!("{: <10}| {: <20}| {:?}", key, term_name, course_codes);
+    }
+}
+
+// Term that started last among the listed courses, or the highest term ID when Canvas has no start dates.
+// Several when tied
+fn current_terms(courses: &[canvas::Course]) -> Vec<(u32, &str)> {
+    let mut terms: Vec<(u32, &str, Option<DateTime<FixedOffset>>)> = courses
+        .iter()
+        .map(|course| {
+            let start_at = course
+                .term
+                .as_ref()
+                .and_then(|term| term.start_at.as_deref())
+                .and_then(|start_at| DateTime::parse_from_rfc3339(start_at).ok());
+            (course.enrollment_term_id, course.term_name(), start_at)
+        })
+        .collect();
+    terms.sort_by_key(|(id, _, _)| *id);
+    terms.dedup_by_key(|(id, _, _)| *id);
+    let latest_start = terms.iter().filter_map(|(_, _, start_at)| *start_at).max();
+    let latest_id = terms.iter().map(|(id, _, _)| *id).max();
+    terms
+        .into_iter()
+        .filter(|(id, _, start_at)| match latest_start {
+            Some(latest_start) => *start_at == Some(latest_start),
+            None => Some(*id) == latest_id,
+        })
+        .map(|(id, name, _)| (id, name))
+        .collect()
+}
+
+// "247" or "2024 semester 1" --> 247, names match case-insensitively against the terms of the listed courses
+fn resolve_term_refs(term_refs: &[String], courses: &[canvas::Course]) -> Result<Vec<u32>> {
+    let mut terms: Vec<(u32, &str)> = courses
+        .iter()
+        .map(|course| (course.enrollment_term_id, course.term_name()))
+        .collect();
+    terms.sort();
+    terms.dedup();
+    let list_terms = |terms: &[(u32, &str)]| -> String {
+        terms
+            .iter()
+            .map(|(id, name)| format!("\n  * {id} - {name}"))
+            .collect()
+    };
+
+    let mut term_ids = Vec::new();
+    for term_ref in term_refs {
+        if let Ok(id) = term_ref.parse::<u32>() {
+            term_ids.push(id);
+            continue;
+        }
+        if term_ref.eq_ignore_ascii_case("current") {
+            term_ids.extend(current_terms(courses).into_iter().map(|(id, _)| id));
+            continue;
+        }
+        let needle = term_ref.to_lowercase();
+        let exact: Vec<(u32, &str)> = terms
+            .iter()
+            .filter(|(_, name)| name.to_lowercase() == needle)
+            .copied()
+            .collect();
+        let candidates: Vec<(u32, &str)> = if exact.is_empty() {
+            terms
+                .iter()
+                .filter(|(_, name)| name.to_lowercase().contains(&needle))
+                .copied()
+                .collect()
+        } else {
+            exact
+        };
+        match candidates.as_slice() {
+            [(id, _)] => term_ids.push(*id),
+            [] => {
+                return Err(anyhow!(
+                    "No term matches \"{term_ref}\", available terms are:{}",
+                    list_terms(&terms)
+                ))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "\"{term_ref}\" matches several terms, pass one of their IDs instead:{}",
+                    list_terms(&candidates)
+                ))
+            }
+        }
+    }
+    Ok(term_ids)
+}
+
+// Toggle courses by number until an empty line, returns the selected course IDs
+fn pick_courses(courses: &[canvas::Course]) -> Vec<u32> {
+    let grouped_courses = group_courses_by_term(courses);
+    let numbered: Vec<&canvas::Course> = grouped_courses
+        .iter()
+        .flat_map(|(_, courses)| courses.iter().copied())
+        .collect();
+    let mut selected = vec![false; numbered.len()];
+    loop {
+        let mut number = 0;
+        for (term_id, term_courses) in &grouped_courses {
+            let term_name = term_courses.first().map_or("", |course| course.term_name());
+            println!("Term {term_id} {term_name}");
+            for course in term_courses {
+                println!(
+                    "  [{}] {:>3}. {} - {}",
+                    if selected[number] { "x" } else { " " },
+                    number + 1,
+                    course.course_code,
+                    course.name
+                );
+                number += 1;
+            }
+        }
+        print!("Toggle courses by number or range (eg 1 3 5-7), 'a' for all, enter to start: ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return Vec::new();
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            break;
+        }
+        if answer.eq_ignore_ascii_case("a") {
+            let all = selected.iter().all(|selected| *selected);
+            selected.iter_mut().for_each(|selected| *selected = !all);
+            continue;
+        }
+        for token in answer.split(|c: char| c == ',' || c.is_whitespace()).filter(|token| !token.is_empty()) {
+            let range = match token.split_once('-') {
+                Some((start, end)) => start.parse::<usize>().ok().zip(end.parse::<usize>().ok()),
+                None => token.parse::<usize>().ok().map(|n| (n, n)),
+            };
+            match range {
+                Some((start, end)) if start >= 1 && start <= end && end <= numbered.len() => {
+                    (start - 1..end).for_each(|i| selected[i] = !selected[i]);
+                }
+                _ => println!("Ignoring {token}, expected a number between 1 and {}", numbered.len()),
+            }
+        }
+    }
+    numbered
+        .iter()
+        .zip(selected)
+        .filter(|(_, selected)| *selected)
+        .map(|(course, _)| course.id)
+        .collect()
+}
+
+// "41234" or "https://canvas.example.edu/courses/41234/modules" --> 41234
+fn parse_course_ref(course_ref: &str, canvas_url: &str) -> Result<u32> {
+    if let Ok(id) = course_ref.parse::<u32>() {
+        return Ok(id);
+    }
+    let url = Url::parse(course_ref)
+        .with_context(|| format!("{course_ref} is neither a course ID nor a course URL"))?;
+    let canvas_host = Url::parse(canvas_url)
+        .ok()
+        .and_then(|canvas_url| canvas_url.host_str().map(str::to_string));
+    if url.host_str().map(str::to_string) != canvas_host {
+        return Err(anyhow!(
+            "{course_ref} belongs to a different Canvas instance than {canvas_url} from the credential file"
+        ));
+    }
+    let re = Regex::new(r"^/courses/([0-9]+)").expect("course url regex is valid");
+    re.captures(url.path())
+        .and_then(|captures| captures.get(1))
+        .and_then(|id| id.as_str().parse().ok())
+        .ok_or_else(|| anyhow!("{course_ref} is not a course URL, expected .../courses/<id>"))
+}
+
+async fn get_courses(courses_link: String, options: &ProcessOptions) -> Result<Vec<canvas::Course>> {
+    get_pages(courses_link, options)
+        .await?
+        .into_iter()
+        .map(|resp| resp.json::<Vec<serde_json::Value>>()) // resp --> Result<Vec<json>>
+        .collect::<stream::FuturesUnordered<_>>() // (in any order)
+        .flat_map_unordered(None, |json_res| {
+            let jsons = json_res.unwrap_or_else(|e| panic!("Failed to parse courses, err={e}")); // Result<Vec<json>> --> Vec<json>
+            stream::iter(jsons) // Vec<json> --> json
+        })
+        .filter(|json| ready(json.get("enrollments").is_some())) // (enrolled?)
+        .map(serde_json::from_value) // json --> Result<course>
+        .try_collect()
+        .await
+        .with_context(|| "Error when getting course json") // Result<course> --> course
+}
+
+async fn get_course(course_id: u32, options: &ProcessOptions) -> Result<canvas::Course> {
+    let course_url = options.api_url(&format!("courses/{course_id}?include[]=term"));
+    let resp = get_canvas_api(course_url, options).await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    match canvas::parse_api_body::<canvas::Course>(status, &body, None) {
+        Ok(course) => Ok(course),
+        Err(canvas::ApiError::Unauthorized) | Err(canvas::ApiError::NotFound) => {
+            Err(anyhow!("not enrolled in this course, or no access to it"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn exclusion_reason(course: &canvas::Course, args: &CommandLineOptions) -> Option<String> {
+    if args.exclude_course_ids.contains(&course.id) {
+        return Some(format!("course id {}", course.id));
+    }
+    args.exclude_course_codes
+        .iter()
+        .find(|pattern| glob_match(pattern, &course.course_code))
+        .map(|pattern| format!("course code matches {pattern}"))
+}
+
+// Case-insensitive wildcard match supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` swallow one more character
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn print_courses_table(courses: &[canvas::Course]) {
+    let mut sorted_courses: Vec<&canvas::Course> = courses.iter().collect();
+    sorted_courses.sort_by_key(|course| (course.enrollment_term_id, course.id));
+    println!(
+        "{: <10}| {: <12}| {: <40}| {: <10}| {: <10}| Term",
+        "Course ID", "Course Code", "Name", "Term ID", "Role"
+    );
+    for course in sorted_courses {
+        println!(
+            "{: <10}| {: <12}| {: <40}| {: <10}| {: <10}| {}",
+            course.id,
+            course.course_code,
+            course.name,
+            course.enrollment_term_id,
+            course.role().unwrap_or("-"),
+            course.term_name()
+        );
+    }
+}
+
+fn print_all_courses_with_ids(courses: &[canvas::Course]) {
+    let mut sorted_courses: Vec<&canvas::Course> = courses.iter().collect();
+    sorted_courses.sort_by_key(|course| (course.enrollment_term_id, course.id));
+    println!("{: <10}| {: <10}| {: <10}| Course", "Term IDs", "Course ID", "Role");
+    for course in sorted_courses {
+        println!(
+            "{: <10}| {: <10}| {: <10}| {} - {}",
+            course.enrollment_term_id,
+            course.id,
+            course.role().unwrap_or("-"),
+            course.course_code,
+            course.name
+        );
+    }
+}
+
+fn is_empty_or_missing(folder_path: &Path) -> Result<bool> {
+    if !folder_path.exists() {
+        return Ok(true);
+    }
+    let mut entries = std::fs::read_dir(folder_path)
+        .with_context(|| format!("Failed to read directory: {}", folder_path.to_string_lossy()))?;
+    Ok(entries.next().is_none())
+}
+
+fn check_state_matches(previous: &State, canvas_url: &str, user: &canvas::User) -> Result<()> {
+    if let Some(previous_url) = &previous.canvas_url {
+        if previous_url.trim_end_matches('/') != canvas_url.trim_end_matches('/') {
+            return Err(anyhow!(
+                "Destination was synced from {previous_url} but the credentials are for {canvas_url}. \
+                 Use a different destination, or pass --dest-policy merge to mix them deliberately"
+            ));
+        }
+    }
+    if let Some(previous_id) = previous.user_id {
+        if previous_id != user.id {
+            return Err(anyhow!(
+                "Destination was synced by user {} (id {previous_id}) but the credentials are for {} (id {}). \
+                 Use a different destination, or pass --dest-policy merge to mix them deliberately",
+                previous.user_name.as_deref().unwrap_or("unknown"),
+                user.name,
+                user.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+// One place deciding how loud each class of API error is
+fn report_api_error(
+    options: &ProcessOptions,
+    what: &str,
+    uri: &str,
+    path: &Path,
+    error: &canvas::ApiError,
+    quiet_unauthorized: bool,
+) {
+    if quiet_unauthorized && matches!(error, canvas::ApiError::Unauthorized) {
+        return;
+    }
+    if matches!(error, canvas::ApiError::Unauthorized) && course_rules(path, options).concluded {
+        options.detail(format!("No access to {what} of a concluded course at {}", path.to_string_lossy()));
+        return;
+    }
+    options.errors.report_api(
+        what,
+        uri,
+        error,
+        format!("Failed to get {what} at link:{uri}, path:{path:?}: {error}"),
+        &options.progress_bars,
+    );
+}
+
+fn create_folder_if_not_exist(folder_path: &PathBuf) -> Result<()> {
+    if !folder_path.exists() {
+        // Nested --layout templates need the intermediate folders
+        std::fs::create_dir_all(folder_path).with_context(|| {
+            format!(
+                "Failed to create directory: {}",
+                folder_path.to_string_lossy()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+struct HttpClients {
+    client: reqwest::Client,
+    storage_client: reqwest::Client,
+    video_client: reqwest::Client,
+    user_agent: String,
+    canvas_headers: header::HeaderMap,
+}
+
+fn http_clients(args: &CommandLineOptions) -> Result<HttpClients> {
+    let user_agent = args
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| format!("canvas-downloader/{}", env!("CARGO_PKG_VERSION")));
+    let mut canvas_headers = header::HeaderMap::new();
+    for value in &args.headers {
+        let (name, value) = parse_header(value).map_err(|e| anyhow!(e))?;
+        canvas_headers.append(name, value);
+    }
+    let client = client_builder(&user_agent)
+        .tcp_keepalive(Some(Duration::from_secs(10)))
+        .http2_keep_alive_interval(Some(Duration::from_secs(2)))
+        .build()
+        .with_context(|| "Failed to create HTTP client")?;
+    // Pools of their own, see client_for()
+    let storage_client = download_client(&user_agent, args.download_concurrency)?;
+    let video_client = download_client(&user_agent, args.download_concurrency)?;
+    Ok(HttpClients { client, storage_client, video_client, user_agent, canvas_headers })
+}
+
+// Every client identifies itself the same way, so admins can allow the tool by User-Agent. --header values may be
+// credentials for the Canvas host, so they are only added to requests that also carry the token
+fn client_builder(user_agent: &str) -> reqwest::ClientBuilder {
+    reqwest::ClientBuilder::new().user_agent(user_agent)
+}
+
+// Downloads keep their connection busy for long, so they get pools of their own without the keep-alive pings of the
+// Canvas API client, see client_for()
+fn download_client(user_agent: &str, download_concurrency: u32) -> Result<reqwest::Client> {
+    client_builder(user_agent)
+        // See send_file_request()
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_max_idle_per_host(download_concurrency as usize)
+        .pool_idle_timeout(Duration::from_secs(30))
+        .build()
+        .with_context(|| "Failed to create HTTP client")
+}
+
+// Client for a download and whether it gets the token. Files on the Canvas host carry the token and are usually
+// redirected to file storage, whose signed URLs need none. Panopto CDNs get a pool of their own
+fn client_for<'a>(options: &'a ProcessOptions, url: &Url) -> (&'a reqwest::Client, bool) {
+    let Some(host) = url.host_str() else {
+        return (&options.storage_client, false);
+    };
+    let canvas_host = Url::parse(&options.canvas_url).ok().and_then(|canvas_url| canvas_url.host_str().map(str::to_string));
+    if canvas_host.as_deref() == Some(host) {
+        return (&options.storage_client, true);
+    }
+    let video_host = options
+        .video_hosts
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned video hosts lock, err={e}"))
+        .contains(host);
+    if video_host {
+        (&options.video_client, false)
+    } else {
+        (&options.storage_client, false)
+    }
+}
+
+// Values may be credentials, eg a session cookie, so course READMEs, support bundles and --print-config only get the
+// header names
+fn redact_headers<S: serde::Serializer>(headers: &[String], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let redacted = headers.iter().map(|value| match value.split_once(':') {
+        Some((name, _)) => format!("{}: <redacted>", name.trim()),
+        None => "<redacted>".to_string(),
+    });
+    serializer.collect_seq(redacted)
+}
+
+// "X-Requested-By: my-archiver" --> (x-requested-by, my-archiver)
+fn parse_header(value: &str) -> Result<(header::HeaderName, header::HeaderValue), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("{value:?} is not a header, expected \"Name: value\""))?;
+    let name = header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("{:?} is not a valid header name, expected \"Name: value\"", name.trim()))?;
+    let header_value = header::HeaderValue::from_str(header_value.trim())
+        .map_err(|_| format!("The value of header {name} contains characters not allowed in headers"))?;
+    Ok((name, header_value))
+}
+
+// Probed by creating a file and looking it up in another case, as a FAT or exFAT drive is case-insensitive on any OS.
+// Read-only runs must not write, they look up an existing entry in another case instead, so that --dry-run and
+// --print-plan-tree show the same paths as the real run
+fn destination_case_insensitive(destination: &Path, read_only: bool) -> bool {
+    let platform_default = cfg!(any(target_os = "macos", target_os = "windows"));
+    if !destination.is_dir() {
+        return platform_default;
+    }
+    if read_only {
+        let swap_case = |name: &str| -> String {
+            name.chars()
+                .map(|c| if c.is_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+                .collect()
+        };
+        let existing = std::fs::read_dir(destination)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .find(|name| name.chars().any(|c| c.is_ascii_alphabetic()));
+        return existing.map_or(platform_default, |name| destination.join(swap_case(&name)).exists());
+    }
+    let probe = state::state_dir(destination).join(format!(".CaseProbe.{}", std::process::id()));
+    if std::fs::create_dir_all(state::state_dir(destination)).is_err() || std::fs::File::create(&probe).is_err() {
+        return platform_default;
+    }
+    let case_insensitive = probe
+        .with_file_name(format!(".caseprobe.{}", std::process::id()))
+        .exists();
+    let _ = std::fs::remove_file(&probe);
+    case_insensitive
+}
+
+// Write next to the destination and rename over it, so an interrupted run never leaves a truncated artifact
+fn write_artifact(path: &Path, bytes: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Artifact path {path:?} has no file name"))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.part",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let result = std::fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result.with_context(|| format!("Could not write to file {path:?}"))
+}
+
+// async recursion needs boxing
+async fn process_folders(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let pages = get_pages(url, &options).await?;
+
+    // For each page
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+
+        match canvas::parse_api_body::<Vec<canvas::Folder>>(status, &page_body, None) {
+            // Got folders
+            Ok(folders) => {
+                for folder in folders {
+                    // println!("  * {} - {}", folder.id, folder.name);
+                    let sanitized_folder_name = sanitize_foldername(folder.name);
+                    if folder.parent_folder_id.is_some() {
+                        // "course files/Week 1/Lecture Recordings" --> "Week 1/Lecture Recordings"
+                        let relative_path = folder
+                            .full_name
+                            .split_once('/')
+                            .map_or(folder.full_name.as_str(), |(_, relative_path)| relative_path);
+                        let excluded_by = options.exclude_folders.iter().find(|pattern| {
+                            glob_match(pattern, &sanitized_folder_name) || glob_match(pattern, relative_path)
+                        });
+                        if let Some(pattern) = excluded_by {
+                            options.detail(format!("Skipping folder {relative_path}, matches --exclude-folder {pattern}"));
+                            continue;
+                        }
+                    }
+                    // if the folder has no parent, it is the root folder of a course
+                    // so we avoid the extra directory nesting by not appending the root folder name
+                    // With --flat every folder shares the course folder, see filter_files()
+                    let folder_path = if folder.parent_folder_id.is_some() && !options.flat {
+                        path.join(sanitized_folder_name)
+                    } else {
+                        path.clone()
+                    };
+                    if !options.dry_run && !options.flat && !folder_path.exists() {
+                        options.detail(format!("Creating folder {}", folder_path.to_string_lossy()));
+                        if let Err(e) = std::fs::create_dir(&folder_path) {
+                            eprintln!(
+                                "Failed to create directory: {}, err={e}",
+                                folder_path.to_string_lossy()
+                            );
+                            continue;
+                        };
+                    }
+
+                    fork!(
+                        process_files,
+                        (folder.files_url, folder_path.clone()),
+                        (String, PathBuf),
+                        options.clone()
+                    );
+                    fork!(
+                        process_folders,
+                        (folder.folders_url, folder_path),
+                        (String, PathBuf),
+                        options.clone()
+                    );
+                }
+            }
+
+            // Unauthorized means the course has no folders
+            Err(e) => report_api_error(&options, "folders", &uri, &path, &e, true),
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_videos(
+    (url, id, path):
+    (String, u32, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let Some((client, panopto_host, panopto_folder_id)) = launch_panopto(&url, id, &options).await? else {
+        options.detail(format!("No Panopto tool in course {id}, no videos to sync"));
+        return Ok(());
+    };
+    process_video_folder((panopto_host, panopto_folder_id, client.clone(), path), options).await?;
+    Ok(())
+}
+
+// LTI launch into the course's Panopto tool, returns the cookie-holding client, Panopto host and folder ID.
+// None when the course has no Panopto tool
+async fn launch_panopto(url: &str, id: u32, options: &ProcessOptions) -> Result<Option<(reqwest::Client, String, String)>> {
+    let session = get_canvas_api(format!("{}/login/session_token?return_to={}/courses/{}/external_tools/128", url, url, id), options).await?;
+    let session_result = session.json::<canvas::Session>().await?;
+
+    // Need a new client for each session for the cookie store
+    let client = client_builder(&options.user_agent)
+        .cookie_store(true)
+        .build()?;
+    options.metrics.record_request("panopto");
+    let videos = client
+        .get(session_result.session_url)
+        .send()
+        .await?;
+
+    // Parse the form that contains the parameters needed to request
+    let video_html = videos.text().await?;
+    let (action, params) = {
+        let panopto_document = Document::from_read(video_html.as_bytes())?;
+        let Some(panopto_form) = panopto_document
+            .find(Name("form"))
+            .find(|n| n.attr("data-tool-id") == Some("mediaweb.ap.panopto.com"))
+        else {
+            return Ok(None);
+        };
+        let action = panopto_form
+            .attr("action")
+            .ok_or(anyhow!("Could not find panopto form action"))?
+            .to_string();
+        let params = panopto_form
+            .find(Name("input"))
+            .filter_map(|n| n.attr("name").map(|name| (name.to_string(), n.attr("value").unwrap_or("").to_string())))
+            .collect::<Vec<(_, _)>>();
+        (action, params)
+    };
+    // set origin and referral headers
+    options.metrics.record_request("panopto");
+    let panopto_response = client
+        .post(action)
+        .header("Origin", url)
+        .header("Referer", format!("{}/", url))
+        .form(&params)
+        .send()
+        .await?;
+
+    // parse location header as url
+    let panopto_location = Url::parse(panopto_response
+        .headers()
+        .get(header::LOCATION)
+        .ok_or(anyhow!("No location header"))?
+        .to_str()?)?;
+    // get folderID from query string
+    let panopto_folder_id = panopto_location
+        .query_pairs()
+        .find(|(key, _)| key == "folderID")
+        .map(|(_, value)| value)
+        .ok_or(anyhow!("Could not get Panopto Folder ID"))?
+        .to_string();
+    let panopto_host = panopto_location
+        .host_str()
+        .ok_or(anyhow!("Could not get Panopto Host"))?
+        .to_string();
+    Ok(Some((client, panopto_host, panopto_folder_id)))
+}
+
+async fn process_video_folder(
+    (host, id, client, path):
+    (String, String, reqwest::Client, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    // POST json folderID: to https://mediaweb.ap.panopto.com/Panopto/Services/Data.svc/GetFolderInfo
+    options.metrics.record_request("panopto");
+    let folderinfo_result = client
+        .post(format!("https://{}/Panopto/Services/Data.svc/GetFolderInfo", host))
+        .json(&json!({
+            "folderID": id,
+        }))
+        .send()
+        .await?;
+    let folderinfo = folderinfo_result.text().await?;
+
+    // write into sessions.json
+    let mut sessions_body = String::new();
+
+    for i in 0.. {
+        options.metrics.record_request("panopto");
+        let sessions_result = client
+            .post(format!("https://{}/Panopto/Services/Data.svc/GetSessions", host))
+            .json(&json!({
+                "queryParameters":
+                {
+                    "query":null,
+                    "sortColumn":1,
+                    "sortAscending":false,
+                    "maxResults":100,
+                    "page":i,
+                    "startDate":null,
+                    "endDate":null,
+                    "folderID":id,
+                    "bookmarked":false,
+                    "getFolderData":true,
+                    "isSharedWithMe":false,
+                    "isSubscriptionsPage":false,
+                    "includeArchived":true,
+                    "includeArchivedStateCount":true,
+                    "sessionListOnlyArchived":false,
+                    "includePlaylists":true
+                }
+            }))
+            .send()
+            .await?;
+
+        let sessions_text = sessions_result.text().await?;
+        sessions_body.push_str(&sessions_text);
+        
+        let folder_sessions = serde_json::from_str::<Value>(&sessions_text)?;
+        let folder_sessions_results = folder_sessions
+            .get("d")
+            .ok_or(anyhow!("Could not get Panopto Folder Sessions"))?;
+    
+        let sessions = serde_json::from_value::<canvas::PanoptoSessionInfo>(folder_sessions_results.clone())?;
+        
+        // End of page results
+        if sessions.Results.is_empty() {
+            break;
+        }
+        if i == 0 {
+            options.create_folder(&path)?;
+        }
+        for result in sessions.Results {
+            fork!(
+                process_session,
+                (host.clone(), result, client.clone(), path.clone()),
+                (String, canvas::PanoptoResult, reqwest::Client, PathBuf),
+                options.clone()
+            )
+        }
+        // Subfolders are the same, so process only the first request
+        if i == 0 {
+            for subfolder in sessions.Subfolders {
+                let subfolder_path = path.join(sanitize_foldername(subfolder.Name));
+                fork!(
+                    process_video_folder,
+                    (host.clone(), subfolder.ID, client.clone(), subfolder_path),
+                    (String, String, reqwest::Client, PathBuf),
+                    options.clone()
+                );
+            }
+        }
+    }
+    // No folder for a Panopto folder without recordings
+    if !path.exists() && !options.dry_run {
+        options.detail(format!("No recordings in {}", path.to_string_lossy()));
+        return Ok(());
+    }
+    options.write_artifact(&path.join("folder.json"), folderinfo.as_bytes()).await?;
+    options.write_artifact(&path.join("sessions.json"), sessions_body.as_bytes()).await?;
+    Ok(())
+}
+
+async fn process_session(
+    (host, result, client, path):
+    (String, canvas::PanoptoResult, reqwest::Client, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    // POST deliveryID: to https://mediaweb.ap.panopto.com/Panopto/Pages/Viewer/DeliveryInfo.aspx
+    options.metrics.record_request("panopto");
+    let resp = client
+        .post(format!("https://{}/Panopto/Pages/Viewer/DeliveryInfo.aspx", host))
+        .form(&[
+            ("deliveryId",result.DeliveryID.as_str()),
+            ("invocationId",""),
+            ("isLiveNotes","false"),
+            ("refreshAuthCookie","true"),
+            ("isActiveBroadcast","false"),
+            ("isEditing","false"),
+            ("isKollectiveAgentInstalled","false"),
+            ("isEmbed","false"),
+            ("responseType","json"),
+        ])
+        .send()
+        .await?;
+
+    let delivery_info = resp.json::<canvas::PanoptoDeliveryInfo>().await?;
+    
+    let viewer_file_id = delivery_info.ViewerFileId;
+    let panopto_url = Url::parse(&result.IosVideoUrl)?;
+    let panopto_cdn_host = panopto_url.host_str().unwrap_or("s-cloudfront.cdn.ap.panopto.com");
+    options
+        .video_hosts
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned video hosts lock, err={e}"))
+        .insert(panopto_cdn_host.to_string());
+    let panopto_master_m3u8 = format!("https://{}/sessions/{}/{}-{}.hls/master.m3u8", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id);
+    options.metrics.record_request("panopto");
+    let m3u8_resp = client
+        .get(panopto_master_m3u8)
+        .send()
+        .await?;
+    let m3u8_text = m3u8_resp.text().await?;
+    let m3u8_parser = m3u8_rs::parse_playlist_res(m3u8_text.as_bytes());
+    match m3u8_parser {
+        Ok(Playlist::MasterPlaylist(pl)) => {
+            // get the highest bandwidth
+            let download_variant = pl.variants
+                .iter()
+                .max_by_key(|v| v.bandwidth)
+                .ok_or_else(|| anyhow!("No variants in the playlist of {}", result.SessionName))?;
+
+            let panopto_index_m3u8 = format!("https://{}/sessions/{}/{}-{}.hls/{}", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id, download_variant.uri);
+            
+            options.metrics.record_request("panopto");
+            let index_m3u8_resp = client
+                .get(panopto_index_m3u8)
+                .send()
+                .await?;
+            let index_m3u8_text = index_m3u8_resp.text().await?;
+            let index_m3u8_parser = m3u8_rs::parse_playlist_res(index_m3u8_text.as_bytes());
+            match index_m3u8_parser {
+                Ok(Playlist::MasterPlaylist(_index_pl)) => {},
+                Ok(Playlist::MediaPlaylist(index_pl)) => {
+                    let uri_id = download_variant.uri.split("/").next().ok_or(anyhow!("Could not get URI ID"))?;
+                    let file_uri = index_pl.segments[0].uri.clone();
+                    let file_uri_ext = Path::new(&file_uri).extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
+                    let panopto_mp4_file = format!("https://{}/sessions/{}/{}-{}.hls/{}/{}", panopto_cdn_host, result.SessionID, result.DeliveryID, viewer_file_id, uri_id, file_uri);
+                    let download_file_name = if file_uri_ext.is_empty() {
+                        result.SessionName.to_string()
+                    } else {
+                        format!("{}.{}", result.SessionName, file_uri_ext)
+                    };
+
+                    let date_regex = Regex::new(r"/Date\((\d+)\)/").expect("date regex is valid");
+                    let date_match_rfc3339 = date_regex
+                        .captures(&result.StartTime)
+                        .and_then(|x| x.get(1))
+                        .map(|x| x.as_str())
+                        .ok_or(anyhow!("Parse error for StartTime"))
+                        .and_then(|x| x.parse::<i64>().map_err(|e| anyhow!("Conversion error for StartTime: {}", e)))
+                        .and_then(|x| Utc.timestamp_millis_opt(x).earliest().ok_or(anyhow!("Timestamp parse error for StartTime")))
+                        .map(|x| x.to_rfc3339())?;
+
+                    let file = canvas::File {
+                        display_name: download_file_name,
+                        folder_id: 0,
+                        id: 0,
+                        size: 0,
+                        url: panopto_mp4_file,
+                        locked_for_user: false,
+                        updated_at: date_match_rfc3339,
+                        filepath: path.clone(),
+                        source_context: None,
+                        name_prefix: None,
+                        created_at: None,
+                        course_id: None,
+                    };
+                    let mut lock = options.files_to_download.lock().await;
+                    let mut filtered_files = filter_files(&options, &path, [file].to_vec());
+                    lock.append(&mut filtered_files);
+                },
+                Err(e) => eprintln!("Error: {:?}", e),
+            }
+            
+        }
+        Ok(Playlist::MediaPlaylist(_pl)) => {},
+        Err(e) => eprintln!("Error: {:?}", e),
+    }
+
+    Ok(())
+}
+
+async fn process_data(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    if options.content.contains(&ContentType::Assignments) {
+        let assignments_path = path.join("assignments");
+        options.create_folder(&assignments_path)?;
+        fork!(
+            process_assignments,
+            (url.clone(), assignments_path),
+            (String, PathBuf),
+            options.clone()
+        );
+    }
+    if options.content.contains(&ContentType::Users) {
+        let users_path = path.join("users.json");
+        fork!(
+            process_users,
+            (url.clone(), users_path),
+            (String, PathBuf),
+            options.clone()
+        );
+    }
+    if options.content.contains(&ContentType::Discussions) {
+        let discussions_path = path.join("discussions");
+        options.create_folder(&discussions_path)?;
+        fork!(
+            process_discussions,
+            (url.clone(), false, discussions_path),
+            (String, bool, PathBuf),
+            options.clone()
+        );
+    }
+    if options.content.contains(&ContentType::Announcements) {
+        let announcements_path = path.join("announcements");
+        options.create_folder(&announcements_path)?;
+        fork!(
+            process_discussions,
+            (url.clone(), true, announcements_path),
+            (String, bool, PathBuf),
+            options.clone()
+        );
+    }
+
+    if options.content.contains(&ContentType::Modules) {
+        let modules_path = path.join("modules");
+        options.create_folder(&modules_path)?;
+        fork!(
+            process_modules,
+            (url.clone(), modules_path),
+            (String, PathBuf),
+            options.clone()
+        );
+    }
+
+    Ok(())
+}
+
+async fn process_page_body(
+    (url, title, path): (String, String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let page_resp = get_canvas_api(url.clone(), &options).await?;
+
+    let page_file_path = path.join(format!("{}.json", sanitize_filename::sanitize(title)));
+    let page_resp_text = page_resp.text().await?;
+
+    let page_body_result = serde_json::from_str::<canvas::PageBody>(&page_resp_text);
+    if let Ok(page_body) = &page_body_result {
+        if outside_date_range(&options, &page_body.updated_at) {
+            return Ok(());
+        }
+    }
+    options.write_artifact(&page_file_path, page_resp_text.as_bytes()).await?;
+    match page_body_result {
+        Result::Ok(page_body) => {
+            let page_html = format!(
+                "<html><head><title>{}</title></head><body>{}</body></html>",
+                page_body.title, page_body.body);
+            
+            let page_html_path = path.join(format!("{}.html", sanitize_filename::sanitize(page_body.url)));
+            options.write_artifact(&page_html_path, page_html.as_bytes()).await?;
+            
+            fork!(
+                process_html_links,
+                (page_html, path),
+                (String, PathBuf),
+                options.clone()
+            )
+        }
+        Result::Err(e) => {
+            eprintln!("Error when parsing page body at link:{url}, path:{page_file_path:?}\n{e:?}",);
+        }
+    }
+    Ok(())
+}
+
+async fn process_assignments(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let assignments_url = format!("{}assignments?include[]=submission&include[]=assignment_visibility&include[]=all_dates&include[]=overrides&include[]=observed_users&include[]=can_edit&include[]=score_statistics", url);
+    let pages = get_pages(assignments_url, &options).await?;
+    
+    let assignments_json = path.join("assignments.json");
+    let mut assignments_body = String::new();
+
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+
+        assignments_body.push_str(&page_body);
+
+        match canvas::parse_api_body::<Vec<canvas::Assignment>>(status, &page_body, Some(&assignments_json)) {
+            Ok(assignments) => {
+                let rules = course_rules(&path, &options);
+                let (include_unpublished, all_submissions) = (rules.include_unpublished, rules.all_submissions);
+                for assignment in assignments {
+                    if assignment.published == Some(false) && !include_unpublished {
+                        options.detail(format!("Skipping unpublished assignment {}", assignment.name));
+                        continue;
+                    }
+                    let assignment_path = content_folder(&options, &path, "assignment", assignment.id, &assignment.name)?;
+                    options.create_folder(&assignment_path)?;
+                    record_due_date(&options, &assignment, &assignment_path);
+                    let submissions_url = format!("{}assignments/{}/submissions/", url, assignment.id);
+                    if all_submissions {
+                        fork!(
+                            process_all_submissions,
+                            (submissions_url, assignment_path.clone()),
+                            (String, PathBuf),
+                            options.clone()
+                        );
+                    } else {
+                        fork!(
+                            process_submissions,
+                            (submissions_url, assignment_path.clone()),
+                            (String, PathBuf),
+                            options.clone()
+                        );
+                    }
+                    let peer_reviews_url = format!("{}assignments/{}/peer_reviews?include[]=submission_comments&include[]=user", url, assignment.id);
+                    fork!(
+                        process_peer_reviews,
+                        (peer_reviews_url, url.clone(), assignment.id, assignment_path.clone()),
+                        (String, String, u32, PathBuf),
+                        options.clone()
+                    );
+                    fork!(
+                        process_html_links,
+                        (assignment.description, assignment_path),
+                        (String, PathBuf),
+                        options.clone()
+                    );
+                }
+            }
+            Err(e) => report_api_error(&options, "assignments", &uri, &path, &e, false),
+        }
+    }
+    options.write_artifact(&assignments_json, assignments_body.as_bytes()).await?;
+    Ok(())
+}
+
+// Due dates are compared with the previous run, assignments seen for the first time are not a change
+fn record_due_date(options: &ProcessOptions, assignment: &canvas::Assignment, assignment_path: &Path) {
+    let previous = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .due_dates
+        .insert(assignment.id.to_string(), assignment.due_at.clone());
+    if let Some(previous) = previous.filter(|previous| *previous != assignment.due_at) {
+        options
+            .changes
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned changes lock, err={e}"))
+            .due_dates
+            .push(digest::DueDateChange {
+                course_folder: course_folder_of(assignment_path, options),
+                folder: assignment_path.to_path_buf(),
+                name: assignment.name.clone(),
+                old: previous,
+                new: assignment.due_at.clone(),
+            });
+    }
+}
+
+async fn process_submissions(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let submissions_url = format!("{}{}", url, options.user.id);
+
+    let resp = get_canvas_api(submissions_url, &options).await?;
+    let submissions_body = resp.text().await?;
+    let submissions_json = path.join("submission.json");
+    options.write_artifact(&submissions_json, submissions_body.as_bytes()).await?;
+
+    let submissions_result = serde_json::from_str::<canvas::Submission>(&submissions_body);
+    match submissions_result {
+        Result::Ok(submissions) => {
+            let mut filtered_files = filter_files(&options, &path, submissions.attachments);
+            let mut lock = options.files_to_download.lock().await;
+            lock.append(&mut filtered_files);
+        }
+        Result::Err(e) => {
+            eprintln!("Error when getting submissions at link:{url}, path:{path:?}\n{e:?}",);
+        }
+    }
+    Ok(())
+}
+
+// Every student's submission, for course staff. Attachments go to a folder per student
+async fn process_all_submissions(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let pages = get_pages(format!("{url}?include[]=user"), &options).await?;
+
+    let submissions_json = path.join("submissions.json");
+    let mut bodies = Vec::new();
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+        match canvas::parse_api_body::<Vec<canvas::Submission>>(status, &page_body, Some(&submissions_json)) {
+            Ok(submissions) => {
+                for submission in submissions {
+                    if submission.attachments.is_empty() {
+                        continue;
+                    }
+                    let student_folder = match (&submission.user, submission.user_id) {
+                        (Some(user), _) => format!("{}_{}", user.id, sanitize_foldername(&user.name)),
+                        (None, Some(user_id)) => user_id.to_string(),
+                        (None, None) => submission.id.to_string(),
+                    };
+                    let student_path = path.join("submissions").join(student_folder);
+                    options.create_folder(&student_path)?;
+                    let mut filtered_files = filter_files(&options, &student_path, submission.attachments);
+                    let mut lock = options.files_to_download.lock().await;
+                    lock.append(&mut filtered_files);
+                }
+                bodies.push(page_body);
+            }
+            Err(e) => report_api_error(&options, "submissions", &uri, &path, &e, false),
+        }
+    }
+    options.write_artifact(&submissions_json, bodies.concat().as_bytes()).await?;
+    Ok(())
+}
+
+async fn process_peer_reviews(
+    (peer_reviews_url, url, assignment_id, path): (String, String, u32, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let pages = get_pages(peer_reviews_url, &options).await?;
+
+    let mut peer_reviews = Vec::new();
+    // As sent by Canvas, with the fields canvas::PeerReview leaves out
+    let mut raw_reviews = Vec::<serde_json::Value>::new();
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+        match canvas::parse_api_body::<Vec<canvas::PeerReview>>(status, &page_body, None) {
+            Ok(mut reviews) => {
+                peer_reviews.append(&mut reviews);
+                raw_reviews.append(&mut serde_json::from_str(&page_body)?);
+            }
+            // Courses without peer review visibility answer 403, nothing to archive
+            Err(e) => {
+                report_api_error(&options, "peer reviews", &uri, &path, &e, true);
+                return Ok(());
+            }
+        }
+    }
+    if peer_reviews.is_empty() {
+        return Ok(());
+    }
+
+    // One JSON array, also when Canvas paginated the reviews
+    let peer_reviews_json = path.join("peer_reviews.json");
+    options.write_artifact(&peer_reviews_json, serde_json::to_string_pretty(&raw_reviews)?.as_bytes()).await?;
+
+    let peer_review_path = path.join("peer_review");
+    options.create_folder(&peer_review_path)?;
+
+    // Reviews others wrote about my submission
+    let received = peer_reviews
+        .iter()
+        .filter(|review| review.user_id == options.user.id)
+        .collect::<Vec<_>>();
+    if !received.is_empty() {
+        let mut feedback = String::new();
+        for review in received {
+            let reviewer = review
+                .assessor
+                .as_ref()
+                .map(|assessor| assessor.display_name.clone())
+                .unwrap_or_else(|| format!("Reviewer {}", review.assessor_id));
+            feedback.push_str(&format!("== {} ({}) ==\n", reviewer, review.workflow_state));
+            for comment in review.submission_comments.iter().flatten() {
+                feedback.push_str(&format!(
+                    "[{}] {}:\n{}\n\n",
+                    comment.created_at, comment.author_name, comment.comment
+                ));
+            }
+            feedback.push('\n');
+        }
+        let feedback_path = peer_review_path.join("feedback.txt");
+        options.write_artifact(&feedback_path, feedback.as_bytes()).await?;
+    }
+
+    // Submissions I have been assigned to review
+    let to_review_path = peer_review_path.join("to_review");
+    for review in peer_reviews.iter().filter(|review| review.assessor_id == options.user.id) {
+        options.create_folder(&to_review_path)?;
+        let submission_url = format!("{}assignments/{}/submissions/{}", url, assignment_id, review.user_id);
+        let resp = get_canvas_api(submission_url.clone(), &options).await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        match canvas::parse_api_body::<canvas::Submission>(status, &body, None) {
+            Ok(submission) => {
+                let files = submission.attachments
+                    .into_iter()
+                    .map(|mut f| {
+                        f.name_prefix = Some(review.asset_id);
+                        f
+                    })
+                    .collect();
+                let mut filtered_files = filter_files(&options, &to_review_path, files);
+                let mut lock = options.files_to_download.lock().await;
+                lock.append(&mut filtered_files);
+            }
+            Err(e) => report_api_error(&options, "submission to review", &submission_url, &to_review_path, &e, false),
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_users (
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let users_url = format!("{}users?include_inactive=true&include[]=avatar_url&include[]=enrollments&include[]=email&include[]=observed_users&include[]=can_be_removed&include[]=custom_links", url);
+    let pages = get_pages(users_url, &options).await?;
+    
+    let mut users_body = String::new();
+    for pg in pages {
+        let page_body = pg.text().await?;
+        users_body.push_str(&page_body);
+    }
+    options.write_artifact(&path, users_body.as_bytes()).await?;
+
+    Ok(())
+}
+
+// The announcements listing only covers the last few weeks unless given a range: the whole course,
+// from its start (or the epoch when unset) until now or its end, narrowed by --since/--until
+fn announcements_url(course_url: &str, path: &Path, options: &ProcessOptions) -> String {
+    let rules = course_rules(path, options);
+    let start = options
+        .since
+        .or(rules.start_at)
+        .map_or_else(|| DateTime::<Utc>::from(std::time::UNIX_EPOCH), |start| start.with_timezone(&Utc));
+    let end = options.until.map_or_else(
+        || {
+            let tomorrow = Utc::now() + chrono::Duration::days(1);
+            rules.end_at.map_or(tomorrow, |end_at| end_at.with_timezone(&Utc).max(tomorrow))
+        },
+        |until| until.with_timezone(&Utc),
+    );
+    // ".../courses/123/" --> "123"
+    let course_id = course_url.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    options.api_url(&format!(
+        "announcements?context_codes[]=course_{course_id}&start_date={}&end_date={}",
+        start.format("%Y-%m-%dT%H:%M:%SZ"),
+        end.format("%Y-%m-%dT%H:%M:%SZ")
+    ))
+}
+
+async fn process_discussions(
+    (url, announcement, path): (String, bool, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let discussion_url = if announcement {
+        announcements_url(&url, &path, &options)
+    } else {
+        format!("{}discussion_topics", url)
+    };
+    let pages = get_pages(discussion_url, &options).await?;
+
+    let discussion_path = path.join("discussions.json");
+    let mut discussion_body = String::new();
+
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+
+        discussion_body.push_str(&page_body);
+
+        match canvas::parse_api_body::<Vec<canvas::Discussion>>(status, &page_body, Some(&discussion_path)) {
+            Ok(discussions) => {
+                for discussion in discussions {
+                    let last_activity = discussion.last_reply_at.as_ref().or(discussion.posted_at.as_ref());
+                    if last_activity.is_some_and(|last_activity| outside_date_range(&options, last_activity)) {
+                        continue;
+                    }
+                    // download attachments
+                    let discussion_folder_path = content_folder(&options, &path, "discussion", discussion.id, &discussion.title)?;
+                    if !discussion_folder_path.exists() {
+                        options
+                            .new_topics
+                            .lock()
+                            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned topics lock, err={e}"))
+                            .push(report::NewTopic {
+                                course: course_label(&course_folder_of(&path, &options), &options),
+                                announcement,
+                                title: discussion.title.clone(),
+                                posted_at: discussion.posted_at.clone(),
+                            });
+                    }
+                    if announcement && !discussion_folder_path.exists() {
+                        options
+                            .changes
+                            .lock()
+                            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned changes lock, err={e}"))
+                            .announcements
+                            .push(digest::NewAnnouncement {
+                                course_folder: course_folder_of(&path, &options),
+                                folder: discussion_folder_path.clone(),
+                                title: discussion.title.clone(),
+                                posted_at: discussion.posted_at.clone(),
+                                message: discussion.message.clone(),
+                            });
+                    }
+                    options.create_folder(&discussion_folder_path)?;
+
+                    let files = discussion.attachments
+                        .into_iter()
+                        .map(|mut f| {
+                            f.name_prefix = Some(f.id);
+                            f
+                        })
+                        .collect();
+                    {
+                        let mut filtered_files = filter_files(&options, &discussion_folder_path, files);
+                        let mut lock = options.files_to_download.lock().await;
+                        lock.append(&mut filtered_files);
+                    }
+                    
+                    fork!(
+                        process_html_links,
+                        (discussion.message, discussion_folder_path.clone()),
+                        (String, PathBuf),
+                        options.clone()
+                    );
+                    let view_url = format!("{}discussion_topics/{}/view", url, discussion.id);
+                    let unread = discussion.read_state.as_deref() == Some("unread") || discussion.unread_count > 0;
+                    let mark_read = (options.mark_read && unread).then(|| {
+                        (
+                            format!("{}discussion_topics/{}/read_all", url, discussion.id),
+                            json!({ "read_state": discussion.read_state, "unread_count": discussion.unread_count }),
+                        )
+                    });
+                    fork!(
+                        process_discussion_view,
+                        (view_url, discussion_folder_path, mark_read),
+                        (String, PathBuf, Option<(String, Value)>),
+                        options.clone()
+                    )
+                }
+            }
+            Err(e) => report_api_error(&options, "discussions", &uri, &path, &e, false),
+        }
+    }
+    options.write_artifact(&discussion_path, discussion_body.as_bytes()).await?;
+    Ok(())
+}
+
+
+async fn process_modules(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let module_url = format!("{}modules", url);
+    let pages = get_pages(module_url, &options).await?;
+
+    let module_path = path.join("modules.json");
+    let mut module_body = String::new();
+
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+
+        module_body.push_str(&page_body);
+
+        match canvas::parse_api_body::<Vec<canvas::ModuleSection>>(status, &page_body, Some(&module_path)) {
+            Ok(module_sections) => {
+                let include_unpublished = course_rules(&path, &options).include_unpublished;
+                for module_section in module_sections {
+                    if module_section.published == Some(false) && !include_unpublished {
+                        options.detail(format!("Skipping unpublished module {}", module_section.name));
+                        continue;
+                    }
+                    // download attachments
+                    let module_section_folder_path = content_folder(&options, &path, "module", module_section.id, &module_section.name)?;
+                    options.create_folder(&module_section_folder_path)?;
+
+                    fork!(
+                        process_module_items,
+                        (module_section.items_url, module_section_folder_path.clone()),
+                        (String, PathBuf),
+                        options.clone()
+                    );
+                }
+            }
+            Err(e) => report_api_error(&options, "modules", &uri, &path, &e, false),
+        }
+    }
+    options.write_artifact(&module_path, module_body.as_bytes()).await?;
+    Ok(())
+}
+
+
+async fn process_module_items(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let page = get_canvas_api(url, &options).await?;
+
+    let item_path = path.join("items.json");
+    let uri = page.url().to_string();
+    let status = page.status();
+    let page_body = page.text().await?;
+
+    options.write_artifact(&item_path, page_body.as_bytes()).await?;
+   
+    
+    match canvas::parse_api_body::<Vec<canvas::ModuleItem>>(status, &page_body, Some(&item_path)) {
+        Ok(module_items) => {
+            let raw_items = serde_json::from_str::<Vec<Value>>(&page_body).unwrap_or_default();
+            let mut external_tools = Vec::new();
+            let include_unpublished = course_rules(&path, &options).include_unpublished;
+            for item in module_items {
+                if item.published == Some(false) && !include_unpublished {
+                    options.detail(format!("Skipping unpublished module item {}", item.title));
+                    continue;
+                }
+                let item_folder_path = content_folder(&options, &path, "module_item", item.id, &item.title)?;
+                options.create_folder(&item_folder_path)?;
+
+                let must_view = item
+                    .completion_requirement
+                    .as_ref()
+                    .is_some_and(|requirement| requirement.requirement_type == "must_view" && !requirement.completed);
+                if options.mark_read && must_view {
+                    if let Ok(mut mark_read_url) = Url::parse(&uri) {
+                        mark_read_url.set_query(None);
+                        let mark_read_url = format!("{}/{}/mark_read", mark_read_url.as_str().trim_end_matches('/'), item.id);
+                        let prior_state = raw_items
+                            .iter()
+                            .find(|raw| raw.get("id").and_then(Value::as_u64) == Some(item.id.into()))
+                            .and_then(|raw| raw.get("completion_requirement"))
+                            .cloned()
+                            .unwrap_or(Value::Null);
+                        fork!(
+                            mark_as_read,
+                            (mark_read_url, reqwest::Method::POST, json!({ "completion_requirement": prior_state }), item_folder_path.clone()),
+                            (String, reqwest::Method, Value, PathBuf),
+                            options.clone()
+                        );
+                    }
+                }
+
+                //This is not a great solution, but it works for now
+                if item.Type == "ExternalTool" {
+                    let raw_item = raw_items
+                        .iter()
+                        .find(|raw| raw.get("id").and_then(Value::as_u64) == Some(item.id.into()))
+                        .cloned()
+                        .unwrap_or(Value::Null);
+                    match process_external_tool(&uri, &item, raw_item, &item_folder_path, &options).await {
+                        Ok(entry) => external_tools.push(entry),
+                        Err(e) => eprintln!("Failed to record external tool {} at path:{item_folder_path:?}\n{e:?}", item.title),
+                    }
+                } else if item.Type == "Page" {
+                    let Some(item_url) = item.url else {
+                        eprintln!("Module item {} has no page url, path:{item_folder_path:?}", item.title);
+                        continue;
+                    };
+                    fork!(
+                        process_page_body,
+                        (item_url, item.title, item_folder_path),
+                        (String, String, PathBuf),
+                        options.clone()
+                    );
+                } else if item.Type == "File" {
+                    let Some(item_url) = item.url.clone() else {
+                        eprintln!("Module item {} has no file url, path:{item_folder_path:?}", item.title);
+                        continue;
+                    };
+                    let (item_context, cross_context) = module_item_file_context(&uri, &item_url);
+                    let file_url = match &item_context {
+                        Some((_, file_id)) if cross_context => {
+                            options.api_url(&format!("files/{file_id}"))
+                        }
+                        _ => item_url,
+                    };
+                    let pg = get_canvas_api(file_url, &options).await?;
+                    let files_result = pg.json::<canvas::File>().await;
+
+
+                    match files_result {
+                        // Got files
+                        Ok(mut file) => {
+                            file.source_context = item_context.map(|(context, _)| context.to_string());
+                            let filtered_files = filter_files(&options, &item_folder_path, vec![file]);
+                            let mut lock = options.files_to_download.lock().await;
+                            queue_module_files(&mut lock, filtered_files, cross_context);
+                        }
+                     
+                        // Parse error
+                        Err(e) => {
+                            eprintln!("Error when getting files at link:{uri}, path:{path:?}\n{e:?}",);
+                        }
+                    };
+        
+
+                }
+            }
+            if !external_tools.is_empty() {
+                let index_path = path.join("external_tools.txt");
+                options.write_artifact(&index_path, (external_tools.join("\n") + "\n").as_bytes()).await?;
+            }
+        }
+        Err(e) => report_api_error(&options, "module items", &uri, &path, &e, false),
+    }
+    
+    Ok(())
+}
+
+
+// Hosts of LTI tools whose content another pipeline of ours archives
+const KNOWN_TOOL_PROVIDERS: &[(&str, &str)] = &[("panopto.com", "videos")];
+
+// Record an LTI module item whose content lives outside Canvas, returns its line for the module index
+async fn process_external_tool(
+    module_items_uri: &str,
+    item: &canvas::ModuleItem,
+    raw_item: Value,
+    path: &Path,
+    options: &ProcessOptions,
+) -> Result<String> {
+    let launch_url = item
+        .html_url
+        .clone()
+        .or_else(|| item.external_url.clone())
+        .unwrap_or_default();
+
+    // Tool metadata is often hidden from students, the module item alone is still worth keeping
+    let tool = match (file_context(module_items_uri), item.content_id) {
+        (Some((FileContext::Course(course_id), _)), Some(tool_id)) => {
+            let tool_url = options.api_url(&format!("courses/{course_id}/external_tools/{tool_id}"));
+            match get_canvas_api(tool_url, options).await {
+                Ok(resp) if resp.status().is_success() => resp.json::<Value>().await.unwrap_or(Value::Null),
+                _ => Value::Null,
+            }
+        }
+        _ => Value::Null,
+    };
+    let tool_host = item
+        .external_url
+        .as_deref()
+        .and_then(|url| Url::parse(url).ok())
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let tool_name = tool
+        .get("name")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| tool_host.clone());
+
+    let metadata_path = path.join("external_tool.json");
+    let metadata = json!({ "module_item": raw_item, "tool": tool });
+    options.write_artifact(&metadata_path, serde_json::to_string_pretty(&metadata)?.as_bytes()).await?;
+
+    if let Some((_, folder)) = KNOWN_TOOL_PROVIDERS
+        .iter()
+        .find(|(provider_host, _)| tool_host.ends_with(provider_host))
+    {
+        return Ok(format!("{} [{tool_name}] - archived in {folder}/", item.title));
+    }
+
+    // Internet shortcut, opens the launch URL on every major OS
+    let shortcut_path = path.join(format!("{}.url", sanitize_filename::sanitize(&item.title)));
+    options.write_artifact(&shortcut_path, format!("[InternetShortcut]\r\nURL={launch_url}\r\n").as_bytes()).await?;
+    Ok(format!("{} [{tool_name}] - {launch_url}", item.title))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FileContext {
+    Course(u32),
+    User(u32),
+    Global,
+}
+
+impl std::fmt::Display for FileContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileContext::Course(id) => write!(f, "course_{id}"),
+            FileContext::User(id) => write!(f, "user_{id}"),
+            FileContext::Global => write!(f, "global"),
+        }
+    }
+}
+
+// Context owning the file of a module item, and whether it is another one than the module's. Cross-listed courses may
+// point at files owned by another course or a user
+fn module_item_file_context(module_items_uri: &str, item_url: &str) -> (Option<(FileContext, u32)>, bool) {
+    let module_context = file_context(module_items_uri).map(|(context, _)| context);
+    let item_context = file_context(item_url);
+    let cross_context = match (&module_context, &item_context) {
+        (Some(module_context), Some((item_context, _))) => module_context != item_context,
+        _ => false,
+    };
+    (item_context, cross_context)
+}
+
+// Files from another context are deduplicated by their global id, several module items may point at the same one
+fn queue_module_files(queue: &mut Vec<File>, mut files: Vec<File>, cross_context: bool) {
+    if cross_context {
+        files.retain(|f| !queue.iter().any(|queued| queued.id == f.id));
+    }
+    queue.append(&mut files);
+}
+
+// Parse the owning context, and the file id when present, out of a Canvas API url
+fn file_context(url: &str) -> Option<(FileContext, u32)> {
+    let re = Regex::new(r"/api/v1/(?:(courses|users)/([0-9]+)/)?(?:[a-z_]+/[0-9]+/)*(?:files/([0-9]+))?")
+        .expect("file context regex is valid");
+    let path = Url::parse(url).ok()?.path().to_string();
+    let captures = re.captures(&path)?;
+    let context = match (captures.get(1).map(|m| m.as_str()), captures.get(2)) {
+        (Some("courses"), Some(id)) => FileContext::Course(id.as_str().parse().ok()?),
+        (Some("users"), Some(id)) => FileContext::User(id.as_str().parse().ok()?),
+        _ => FileContext::Global,
+    };
+    let file_id = captures
+        .get(3)
+        .and_then(|id| id.as_str().parse().ok())
+        .unwrap_or(0);
+    Some((context, file_id))
+}
+
+// The only write to Canvas, opt-in with --mark-read. Failures are reported but never affect the archive
+async fn mark_as_read(
+    (url, method, prior_state, path): (String, reqwest::Method, Value, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    // Keep the state from before the first mark, later runs would only see "read"
+    let state_path = path.join("read_state.json");
+    if !state_path.exists() {
+        let prior_state = json!({ "before_mark_read": prior_state, "marked_read_at": Local::now().to_rfc3339() });
+        if let Err(e) = options.write_artifact(&state_path, serde_json::to_string_pretty(&prior_state)?.as_bytes()).await {
+            eprintln!("Not marking {url} as read, could not record its read state: {e:?}");
+            return Ok(());
+        }
+    }
+    match send_canvas_api(method, url.clone(), &options).await {
+        Ok(resp) if resp.status().is_success() => {}
+        Ok(resp) => eprintln!("Failed to mark {url} as read, got {}", resp.status()),
+        Err(e) => eprintln!("Failed to mark {url} as read: {e:?}"),
+    }
+    Ok(())
+}
+
+async fn process_discussion_view(
+    (url, path, mark_read): (String, PathBuf, Option<(String, Value)>),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    let resp = get_canvas_api(url.clone(), &options).await?;
+    let discussion_view_body = resp.text().await?;
+    
+    let discussion_view_json = path.join("discussion.json");
+    options.write_artifact(&discussion_view_json, discussion_view_body.as_bytes()).await?;
+
+    // Only once the view is archived
+    if let Some((read_all_url, prior_state)) = mark_read {
+        fork!(
+            mark_as_read,
+            (read_all_url, reqwest::Method::PUT, prior_state, path.clone()),
+            (String, reqwest::Method, Value, PathBuf),
+            options.clone()
+        );
+    }
+
+    let discussion_view_result = serde_json::from_str::<canvas::DiscussionView>(&discussion_view_body);
+    let mut attachments_all = Vec::new();
+    match discussion_view_result {
+        Result::Ok(discussion_view) => {
+            for view in discussion_view.view {
+                if let Some(message) = view.message {
+                    fork!(
+                        process_html_links,
+                        (message, path.clone()),
+                        (String, PathBuf),
+                        options.clone()
+                    )
+                }
+                if let Some(mut attachments) = view.attachments {
+                    attachments_all.append(&mut attachments);
+                }
+                if let Some(attachment) = view.attachment {
+                    attachments_all.push(attachment);
+                }
+            }
+        }
+        Result::Err(e) => {
+            eprintln!("Error when getting submissions at link:{url}, path:{path:?}\n{e:?}",);
+        }
+    }
+
+    let files = attachments_all
+        .into_iter()
+        .map(|mut f| {
+            f.name_prefix = Some(f.id);
+            f
+        })
+        .collect();
+    let mut filtered_files = filter_files(&options, &path, files);
+    let mut lock = options.files_to_download.lock().await;
+    lock.append(&mut filtered_files);
+
+    Ok(())
+}
+
+async fn process_files((url, path): (String, PathBuf), options: Arc<ProcessOptions>) -> Result<()> {
+    let pages = get_pages(url, &options).await?;
+
+    // For each page
+    for pg in pages {
+        let uri = pg.url().to_string();
+        let status = pg.status();
+        let page_body = pg.text().await?;
+
+        match canvas::parse_api_body::<Vec<File>>(status, &page_body, None) {
+            // Got files
+            Ok(files) => {
+                let mut filtered_files = filter_files(&options, &path, files);
+                let mut lock = options.files_to_download.lock().await;
+                lock.append(&mut filtered_files);
+            }
+
+            // Unauthorized means the course has no files
+            Err(e) => report_api_error(&options, "files", &uri, &path, &e, true),
+        };
+    }
+
+    Ok(())
+}
+
+fn manifest_entry(options: &ProcessOptions, canvas_file: &File) -> Option<manifest::ManifestEntry> {
+    options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .lookup(canvas_file.id)
+        .cloned()
+}
+
+// Where the file was downloaded to before when it is still there under another name, eg after it was renamed on
+// Canvas, and whether that copy is of the same version
+fn previous_copy(options: &ProcessOptions, canvas_file: &File) -> Option<(PathBuf, bool)> {
+    let entry = manifest_entry(options, canvas_file)?;
+    let previous_path = options.destination_folder.join(&entry.path);
+    (previous_path != canvas_file.filepath && previous_path.is_file())
+        .then_some((previous_path, entry.updated_at == canvas_file.updated_at))
+}
+
+// Moves the copy of a file renamed on Canvas to its new name, along with what the state and manifest know about it
+fn rename_previous_copy(options: &ProcessOptions, canvas_file: &File, previous_path: &Path) -> Result<()> {
+    if let Some(parent) = canvas_file.filepath.parent() {
+        options.create_folder(&parent.to_path_buf())?;
+    }
+    std::fs::rename(previous_path, &canvas_file.filepath)
+        .with_context(|| format!("Failed to rename {previous_path:?} to {:?}", canvas_file.filepath))?;
+    let relative = |path: &Path| path.strip_prefix(&options.destination_folder).unwrap_or(path).to_string_lossy().to_string();
+    let (previous_path, new_path) = (relative(previous_path), relative(&canvas_file.filepath));
+    let mut state = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+    if let Some(updated_at) = state.files.remove(&previous_path) {
+        state.files.insert(new_path.clone(), updated_at);
+    }
+    if let Some(content) = state.content.remove(&previous_path) {
+        state.content.insert(new_path.clone(), content);
+    }
+    let mut manifest = options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"));
+    if let Some(entry) = manifest.lookup(canvas_file.id).cloned() {
+        manifest.record(canvas_file.id, new_path, entry.updated_at, entry.size);
+    }
+    Ok(())
+}
+
+// FAT keeps modified times to 2 seconds
+const MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+// Whether Canvas has a newer version than the local copy. Compared with the Canvas updated_at of the version downloaded
+// when recorded, as the modified time is rounded to 2 seconds by FAT and truncated by some NFS servers
+fn newer_on_canvas(
+    canvas_updated_at: &str,
+    downloaded_updated_at: Option<&str>,
+    modified: impl FnOnce() -> Result<std::time::SystemTime>,
+) -> Result<bool> {
+    let new_updated_at = DateTime::parse_from_rfc3339(canvas_updated_at)?;
+    match downloaded_updated_at.and_then(|updated_at| DateTime::parse_from_rfc3339(updated_at).ok()) {
+        Some(downloaded_updated_at) => Ok(downloaded_updated_at < new_updated_at),
+        // Downloaded before either was recorded, within the coarsest rounding of modified times
+        None => {
+            let new_modified = std::time::SystemTime::from(new_updated_at);
+            Ok(new_modified.duration_since(modified()?).is_ok_and(|newer_by| newer_by > MTIME_GRANULARITY))
+        }
+    }
+}
+
+fn filter_files(options: &ProcessOptions, path: &Path, files: Vec<File>) -> Vec<File> {
+    fn updated(options: &ProcessOptions, canvas_file: &File) -> bool {
+        let filepath = &canvas_file.filepath;
+        (|| -> Result<bool> {
+            let relative_path = filepath.strip_prefix(&options.destination_folder).unwrap_or(filepath);
+            let downloaded_updated_at = manifest_entry(options, canvas_file)
+                .filter(|entry| options.destination_folder.join(&entry.path) == *filepath)
+                .map(|entry| entry.updated_at)
+                .or_else(|| {
+                    options
+                        .state
+                        .lock()
+                        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+                        .files
+                        .get(relative_path.to_string_lossy().as_ref())
+                        .cloned()
+                });
+            let updated = newer_on_canvas(&canvas_file.updated_at, downloaded_updated_at.as_deref(), || {
+                Ok(std::fs::metadata(filepath)?.modified()?)
+            })?;
+            if updated && options.verify_content && unchanged_content(options, canvas_file) {
+                options.detail(format!("Skipping {filepath:?}: modified time is off, content is unchanged"));
+                return Ok(false);
+            }
+            if updated && !options.download_newer {
+                options.info(format!("Found update for {filepath:?}. Use -n to download updated files."));
+            }
+            Ok(updated)
+        })()
+        .unwrap_or(false)
+    }
+
+    // only download files that do not exist or are updated
+    files
+        .into_iter()
+        .filter_map(|mut f| {
+            let sanitized_filename = file_name(options.name_template.as_deref(), &f, path);
+            f.filepath = if options.flat {
+                course_folder_of(path, options).join(sanitized_filename)
+            } else {
+                path.join(sanitized_filename)
+            };
+            f.filepath = claim_path(options, &f)?;
+            f.course_id = course_id_of(path, options);
+            Some(f)
+        })
+        .filter(|f| {
+            let mut manifest = options
+                .manifest
+                .lock()
+                .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"));
+            if !f.locked_for_user {
+                if manifest.was_locked(f.id) && !f.filepath.exists() {
+                    options.info(format!("Previously locked, now available: {}", f.filepath.to_string_lossy()));
+                }
+                return true;
+            }
+            // Downloaded once unlocked, see Report::build()
+            if let Ok(relative_path) = f.filepath.strip_prefix(&options.destination_folder) {
+                manifest.record_locked(f.id, relative_path.to_string_lossy().to_string());
+            }
+            if options.include_locked {
+                return true;
+            }
+            options.detail(format!("Skipping {}: locked on Canvas", f.filepath.to_string_lossy()));
+            options.n_skipped_locked.fetch_add(1, Ordering::Relaxed);
+            plan(options, f, PlanStatus::Locked);
+            false
+        })
+        .filter(|f| {
+            let too_large = exceeds_max_file_size(options, f, f.size);
+            if too_large {
+                plan(options, f, PlanStatus::Skipped);
+            }
+            !too_large
+        })
+        .filter(|f| {
+            if DateTime::parse_from_rfc3339(&f.updated_at).is_ok() {
+                return true;
+            }
+            eprintln!(
+                "Failed to parse updated_at time for {}, {}",
+                f.display_name, f.updated_at
+            );
+            plan(options, f, PlanStatus::Skipped);
+            false
+        })
+        // With -n, an update must also be newer than the local copy below
+        .filter(|f| {
+            let outside = outside_date_range(options, &f.updated_at);
+            if outside {
+                options.detail(format!("Skipping {}: updated outside --since/--until", f.filepath.to_string_lossy()));
+                plan(options, f, PlanStatus::Skipped);
+            }
+            !outside
+        })
+        .filter(|f| {
+            if !f.filepath.exists() {
+                // Renamed on Canvas, an update is downloaded under the new name, see --prune-renamed
+                if let Some((previous_path, true)) = previous_copy(options, f) {
+                    let renamed = options.dry_run
+                        || match rename_previous_copy(options, f, &previous_path) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                eprintln!("{e:?}, downloading it again instead");
+                                false
+                            }
+                        };
+                    if renamed {
+                        options.info(format!(
+                            "Renamed {} to {}, as on Canvas",
+                            previous_path.to_string_lossy(),
+                            f.filepath.to_string_lossy()
+                        ));
+                        plan(options, f, PlanStatus::Renamed);
+                        return false;
+                    }
+                }
+                plan(options, f, PlanStatus::New);
+                return true;
+            }
+            if !(updated(options, f) && options.download_newer) {
+                options.detail(format!("Skipping {}: already downloaded", f.filepath.to_string_lossy()));
+                plan(options, f, PlanStatus::Exists);
+                return false;
+            }
+            if locally_modified(options, &f.filepath) {
+                // Resolved in main() once the crawl is done, according to --on-local-change
+                options
+                    .local_conflicts
+                    .lock()
+                    .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned conflicts lock, err={e}"))
+                    .push(f.clone());
+                plan(options, f, PlanStatus::ModifiedLocally);
+                return false;
+            }
+            plan(options, f, PlanStatus::Update);
+            true
+        })
+        .collect()
+}
+
+// Name a file is saved under in `folder`. Without --name-template, attachments get their prefix as before
+fn file_name(name_template: Option<&str>, file: &File, folder: &Path) -> String {
+    let legacy_name = match file.name_prefix {
+        Some(prefix) => format!("{prefix}_{}", file.display_name),
+        None => file.display_name.clone(),
+    };
+    let Some(template) = name_template else {
+        return sanitize_filename::sanitize(legacy_name);
+    };
+    let folder_name = folder.file_name().unwrap_or_default().to_string_lossy();
+    let updated_date = DateTime::parse_from_rfc3339(&file.updated_at)
+        .map(|updated_at| updated_at.with_timezone(&Local).format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    let expanded = expand_name_template(template, |name| match name {
+        "id" => file.id.to_string(),
+        "display_name" => file.display_name.clone(),
+        "updated_date" => updated_date.clone(),
+        "folder" => folder_name.to_string(),
+        _ => String::new(),
+    });
+    // Sanitized after expansion, a display name may bring its own slashes
+    let sanitized = sanitize_filename::sanitize(expanded);
+    if sanitized.trim_matches(NAME_SEPARATORS).is_empty() {
+        return sanitize_filename::sanitize(legacy_name);
+    }
+    sanitized
+}
+
+const NAME_SEPARATORS: &[char] = &[' ', '_', '-', '.'];
+
+// "{id}_{folder}_{display_name}" with an empty folder --> "12_slides.pdf": an empty placeholder takes the separators after
+// it along (before it, at the end), then repeated separators are collapsed and trimmed off both ends
+fn expand_name_template(template: &str, value: impl Fn(&str) -> String) -> String {
+    let placeholder = Regex::new(r"\{([^{}]*)\}").expect("name template placeholder regex is valid");
+    let mut expanded = String::new();
+    let mut drop_separators = false;
+    let mut last_end = 0;
+    for captures in placeholder.captures_iter(template) {
+        let Some(whole) = captures.get(0) else {
+            continue;
+        };
+        let literal = &template[last_end..whole.start()];
+        push_literal(&mut expanded, literal, drop_separators);
+        let value = value(&captures[1]);
+        drop_separators = value.is_empty();
+        // Nothing after it but separators, so the ones before go instead
+        if drop_separators && template[whole.end()..].trim_matches(NAME_SEPARATORS).is_empty() {
+            expanded.truncate(expanded.trim_end_matches(NAME_SEPARATORS).len());
+        }
+        expanded.push_str(&value);
+        last_end = whole.end();
+    }
+    push_literal(&mut expanded, &template[last_end..], drop_separators);
+
+    let mut collapsed = String::with_capacity(expanded.len());
+    for c in expanded.chars() {
+        if NAME_SEPARATORS.contains(&c) && collapsed.ends_with(c) {
+            continue;
+        }
+        collapsed.push(c);
+    }
+    collapsed.trim_matches(NAME_SEPARATORS).to_string()
+}
+
+fn push_literal(expanded: &mut String, literal: &str, drop_separators: bool) {
+    if drop_separators {
+        expanded.push_str(literal.trim_start_matches(NAME_SEPARATORS));
+    } else {
+        expanded.push_str(literal);
+    }
+}
+
+// Counts towards the per-course summary, the plan itself is only kept for --print-plan-tree
+fn plan(options: &ProcessOptions, file: &File, status: PlanStatus) {
+    match status {
+        PlanStatus::Exists | PlanStatus::Renamed => {
+            count_for_course(options, file, |summary| summary.up_to_date += 1);
+        }
+        PlanStatus::Locked => count_for_course(options, file, |summary| summary.locked += 1),
+        // Counted once downloaded, see download_into_place()
+        _ => {}
+    }
+    if !options.record_plan {
+        return;
+    }
+    options
+        .planned
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned plan lock, err={e}"))
+        .push((file.clone(), status));
+}
+
+// Folder of a discussion, announcement, assignment, module or module item inside `parent`, named by --content-folder-naming.
+// The folder is recorded in the state by Canvas ID, so a changed naming or a title edited on Canvas renames the folder
+// of an earlier run instead of starting a second one
+fn content_folder(options: &ProcessOptions, parent: &Path, kind: &str, id: u32, title: &str) -> Result<PathBuf> {
+    let key = format!("{kind}/{id}");
+    let title = sanitize_foldername(title);
+    let folder_name = |naming: ContentFolderNaming| match naming {
+        ContentFolderNaming::IdTitle => format!("{id}_{title}"),
+        ContentFolderNaming::TitleId => format!("{title} ({id})"),
+        ContentFolderNaming::Title => title.clone(),
+    };
+    // What earlier versions named these folders
+    let legacy_naming = if kind == "assignment" { ContentFolderNaming::Title } else { ContentFolderNaming::IdTitle };
+    let naming = options.content_folder_naming.unwrap_or(legacy_naming);
+
+    // Lowercasing stands in for Unicode case folding, like in claim_path()
+    let claim_key = |folder_path: &Path| -> PathBuf {
+        if options.case_insensitive {
+            PathBuf::from(folder_path.to_string_lossy().to_lowercase())
+        } else {
+            folder_path.to_path_buf()
+        }
+    };
+    let mut claimed_paths = options
+        .claimed_paths
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned claimed paths lock, err={e}"));
+    let mut state = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+
+    // Same title as another object of this run or of an earlier one, the ID tells them apart
+    let mut folder_path = parent.join(folder_name(naming));
+    let taken = claimed_paths
+        .get(&claim_key(&folder_path))
+        .is_some_and(|claimed_by| claimed_by != &key)
+        || state
+            .content_folders
+            .iter()
+            .any(|(other_key, other_path)| {
+                other_key != &key && claim_key(&options.destination_folder.join(other_path)) == claim_key(&folder_path)
+            });
+    if taken {
+        folder_path = parent.join(folder_name(ContentFolderNaming::TitleId));
+    }
+    claimed_paths.insert(claim_key(&folder_path), key.clone());
+
+    // Only the name is taken from the state, the parent may have been renamed just before
+    let previous_path = match state.content_folders.get(&key) {
+        Some(previous_path) => Path::new(previous_path).file_name().map(|name| parent.join(name)),
+        None => Some(parent.join(folder_name(legacy_naming))),
+    };
+    if options.dry_run {
+        return Ok(folder_path);
+    }
+    if let Some(previous_path) = previous_path {
+        if previous_path != folder_path && previous_path.is_dir() && !folder_path.exists() {
+            std::fs::rename(&previous_path, &folder_path)
+                .with_context(|| format!("Failed to rename {previous_path:?} to {folder_path:?}"))?;
+            options.detail(format!(
+                "Renamed {} to {}",
+                previous_path.to_string_lossy(),
+                folder_path.to_string_lossy()
+            ));
+        }
+    }
+    let relative_path = folder_path
+        .strip_prefix(&options.destination_folder)
+        .unwrap_or(&folder_path)
+        .to_string_lossy()
+        .to_string();
+    state.content_folders.insert(key, relative_path);
+    Ok(folder_path)
+}
+
+// Files may end up with the same target path: from different folders with --flat, or differing only in case on a
+// case-insensitive destination (report.PDF and report.pdf). The first one seen keeps the name, later ones get their
+// Canvas file ID appended, eg "notes_123.pdf", which is kept in the state file so later runs map it the same way.
+// None when the same file was already queued under this path
+fn claim_path(options: &ProcessOptions, file: &File) -> Option<PathBuf> {
+    // Files found outside Canvas files (Panopto, links) have no ID
+    let identity = if file.id != 0 {
+        file.id.to_string()
+    } else {
+        let mut h = DefaultHasher::new();
+        file.url.hash(&mut h);
+        format!("{:08x}", h.finish() as u32)
+    };
+    // Lowercasing stands in for Unicode case folding
+    let claim_key = |filepath: &Path| -> PathBuf {
+        if options.case_insensitive {
+            PathBuf::from(filepath.to_string_lossy().to_lowercase())
+        } else {
+            filepath.to_path_buf()
+        }
+    };
+    let relative_path = |filepath: &Path| -> String {
+        filepath
+            .strip_prefix(&options.destination_folder)
+            .unwrap_or(filepath)
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut claimed_paths = options
+        .claimed_paths
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned claimed paths lock, err={e}"));
+    let mut state = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+
+    let stem = file.filepath.file_stem().unwrap_or_default().to_string_lossy();
+    let with_identity = match file.filepath.extension() {
+        Some(ext) => format!("{stem}_{identity}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_{identity}"),
+    };
+    let with_identity = file.filepath.with_file_name(with_identity);
+    // Renamed by an earlier run, keep that name even if the other file is not seen first this time
+    let previously_renamed = state
+        .renamed
+        .get(&relative_path(&with_identity))
+        .is_some_and(|renamed| renamed.identity == identity);
+    let candidates = if previously_renamed {
+        vec![with_identity.clone()]
+    } else {
+        vec![file.filepath.clone(), with_identity.clone()]
+    };
+    for filepath in candidates {
+        match claimed_paths.get(&claim_key(&filepath)) {
+            Some(owner) if *owner == identity => return None,
+            Some(_) => continue,
+            None => {
+                claimed_paths.insert(claim_key(&filepath), identity.clone());
+                if filepath == with_identity {
+                    state.renamed.insert(
+                        relative_path(&filepath),
+                        state::RenamedFile { identity, remote_name: file.display_name.clone() },
+                    );
+                }
+                return Some(filepath);
+            }
+        }
+    }
+    // Only when a file is literally named like another one plus its ID
+    None
+}
+
+// Records the file for the end of run summary when it is over --max-file-size. A size of 0 is unknown
+fn exceeds_max_file_size(options: &ProcessOptions, file: &File, size: u64) -> bool {
+    let Some(max_file_size) = options.max_file_size else {
+        return false;
+    };
+    if size <= max_file_size {
+        return false;
+    }
+    options.info(format!("{}: skipped: exceeds max size", file.filepath.to_string_lossy()));
+    options
+        .skipped_for_size
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned skipped lock, err={e}"))
+        .push(File {
+            size,
+            ..file.clone()
+        });
+    true
+}
+
+// Timestamps that fail to parse are never outside --since/--until
+fn outside_date_range(options: &ProcessOptions, timestamp: &str) -> bool {
+    if options.since.is_none() && options.until.is_none() {
+        return false;
+    }
+    DateTime::parse_from_rfc3339(timestamp).is_ok_and(|timestamp| {
+        options.since.is_some_and(|since| timestamp < since) || options.until.is_some_and(|until| timestamp > until)
+    })
+}
+
+// "2024-01-31" is midnight in the local timezone, anything else must be RFC 3339
+fn parse_since(value: &str) -> Result<DateTime<FixedOffset>, String> {
+    let value = value.trim();
+    if let Ok(since) = DateTime::parse_from_rfc3339(value) {
+        return Ok(since);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+        .map(|since| since.with_timezone(&since.offset().fix()))
+        .ok_or_else(|| format!("{value:?} is not a date, expected YYYY-MM-DD or RFC 3339 like 2024-01-31T09:00:00+01:00"))
+}
+
+// Like --since, but "2024-01-31" includes that whole day
+fn parse_until(value: &str) -> Result<DateTime<FixedOffset>, String> {
+    let until = parse_since(value)?;
+    if DateTime::parse_from_rfc3339(value.trim()).is_ok() {
+        return Ok(until);
+    }
+    Ok(until + chrono::Duration::days(1))
+}
+
+// "200M" --> 209715200, suffixes are powers of 1024 and an optional trailing B or iB is accepted
+fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let upper = trimmed.to_ascii_uppercase();
+    let number = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (number, multiplier) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("{trimmed:?} is not a size, expected eg 500K, 200M or 1.5G"))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("{trimmed:?} is not a size, expected eg 500K, 200M or 1.5G"));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+// A file is locally modified when its mtime is newer than the Canvas updated_at we set it to
+fn locally_modified(options: &ProcessOptions, filepath: &Path) -> bool {
+    let Ok(relative_path) = filepath.strip_prefix(&options.destination_folder) else {
+        return false;
+    };
+    let recorded = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"))
+        .files
+        .get(relative_path.to_string_lossy().as_ref())
+        .cloned();
+    (|| -> Result<bool> {
+        let Some(recorded) = recorded else {
+            return Ok(false);
+        };
+        let recorded = std::time::SystemTime::from(DateTime::parse_from_rfc3339(&recorded)?);
+        let local_modified = std::fs::metadata(filepath)?.modified()?;
+        // Allow for filesystems with coarse mtime granularity
+        Ok(local_modified > recorded + Duration::from_secs(2))
+    })()
+    .unwrap_or(false)
+}
+
+async fn process_html_links(
+    (html, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    // If file link is part of course files
+    let mut file_links = Vec::new();
+    for link in Document::from(html.as_str()).find(Name("a")) {
+        if let Some(file_link) = file_api_link(&link, &options) {
+            if !file_links.contains(&file_link) {
+                file_links.push(file_link);
+            }
+        }
+    }
+
+    let mut link_files = join_all(
+        file_links
+            .into_iter()
+            .map(|x| process_file_id((x, path.clone()), options.clone())),
+    )
+    .await
+    .into_iter()
+    .filter_map(|x| x.ok())
+    .collect::<Vec<File>>();
+
+    // If image is from canvas it is likely the file url gives permission denied, so download from the CDN
+    let image_links = Document::from(html.as_str())
+        .find(Name("img"))
+        .filter_map(|n| n.attr("src"))
+        .filter(|x| x.starts_with(&options.canvas_url))
+        .filter(|x| !x.contains("equation_images"))
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>();
+
+    link_files.append(
+        join_all(
+            image_links
+                .into_iter()
+                .map(|x| prepare_link_for_download((x, path.clone()), options.clone())),
+        )
+        .await
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .collect::<Vec<File>>()
+        .as_mut(),
+    );
+
+    // Canvas Studio clips go next to the Panopto videos of the course
+    if options.content.contains(&ContentType::Videos) {
+        let videos_path = course_folder_of(&path, &options).join("videos");
+        for embed_url in studio::embed_urls(&html) {
+            fork!(
+                studio::process_embed,
+                (embed_url, path.clone(), videos_path.clone()),
+                (String, PathBuf, PathBuf),
+                options.clone()
+            );
+        }
+    }
+
+    let mut filtered_files = filter_files(&options, &path, link_files);
+    let mut lock = options.files_to_download.lock().await;
+    lock.append(&mut filtered_files);
+
+    Ok(())
+}
+
+// Files API link of an <a> pointing at a Canvas file. The rich content editor writes these in several shapes:
+// absolute or relative /courses/1/files/2 hrefs, instructure_file_link anchors with data-api-endpoint or data-id,
+// and /media_attachments_iframe/2 players
+fn file_api_link(link: &select::node::Node, options: &ProcessOptions) -> Option<String> {
+    let canvas_url = Url::parse(&options.canvas_url).ok()?;
+    // Relative hrefs are relative to the Canvas instance, links to other hosts are not Canvas files
+    let resolve = |href: &str| -> Option<Url> {
+        let url = canvas_url.join(href).ok()?;
+        (url.host_str() == canvas_url.host_str()).then_some(url)
+    };
+
+    if let Some(api_endpoint) = link.attr("data-api-endpoint").and_then(resolve) {
+        let api_file = Regex::new(r"/api/v1/(?:courses/[0-9]+/)?files/[0-9]+").expect("api file regex is valid");
+        if let Some(m) = api_file.find(api_endpoint.path()) {
+            return Some(options.api_url(m.as_str().trim_start_matches("/api/v1")));
+        }
+    }
+    let is_file_link = link
+        .attr("class")
+        .is_some_and(|class| class.split_whitespace().any(|class| class == "instructure_file_link"));
+    if is_file_link {
+        if let Some(id) = link.attr("data-id").filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())) {
+            return Some(options.api_url(&format!("files/{id}")));
+        }
+    }
+
+    let url = resolve(link.attr("href")?)?;
+    let course_file = Regex::new(r"/courses/[0-9]+/files/[0-9]+").expect("course file regex is valid");
+    if let Some(m) = course_file.find(url.path()) {
+        return Some(options.api_url(m.as_str()));
+    }
+    let media_attachment = Regex::new(r"/media_attachments_iframe/([0-9]+)").expect("media attachment regex is valid");
+    media_attachment
+        .captures(url.path())
+        .map(|captures| options.api_url(&format!("files/{}", &captures[1])))
+}
+
+// Counts towards the summary line of the course the file was queued for, see File::course_id
+fn count_for_course(options: &ProcessOptions, file: &File, count: impl FnOnce(&mut report::CourseSummary)) {
+    let course_folder = file.course_id.and_then(|course_id| {
+        options.course_ids.lock().ok().and_then(|course_ids| {
+            course_ids.iter().find(|(_, id)| **id == course_id).map(|(course_folder, _)| course_folder.clone())
+        })
+    });
+    // Retried and resumed downloads are queued without crawling the courses
+    let course_folder = course_folder.unwrap_or_else(|| course_folder_of(&file.filepath, options));
+    let course = course_label(&course_folder, options);
+    count(
+        options
+            .course_summaries
+            .lock()
+            .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned summaries lock, err={e}"))
+            .entry(course)
+            .or_default(),
+    );
+}
+
+// One line per course, once the downloads are done. Failures are attributed by the course they were reported in
+fn print_course_summaries(options: &ProcessOptions, refused_locked: &[File]) {
+    for canvas_file in refused_locked {
+        count_for_course(options, canvas_file, |summary| summary.locked += 1);
+    }
+    let mut summaries = options
+        .course_summaries
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned summaries lock, err={e}"));
+    for course in options.errors.failures().into_iter().filter_map(|failure| failure.course) {
+        summaries.entry(course).or_default().failed += 1;
+    }
+    if summaries.is_empty() {
+        return;
+    }
+    options.info("Per course:");
+    for (course, summary) in summaries.iter() {
+        options.info(format!("  * {}", summary.line(course)));
+    }
+}
+
+// "<destination>/Spring 2024/CS101" --> "Spring 2024/CS101", how failures name the course
+fn course_label(course_folder: &Path, options: &ProcessOptions) -> String {
+    course_folder
+        .strip_prefix(&options.destination_folder)
+        .unwrap_or(course_folder)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Rules of the course `path` belongs to, see course_rules_for()
+fn course_rules(path: &Path, options: &ProcessOptions) -> CourseRules {
+    let course_folder = course_folder_of(path, options);
+    options
+        .course_rules
+        .lock()
+        .ok()
+        .and_then(|course_rules| course_rules.get(&course_folder).copied())
+        .unwrap_or_default()
+}
+
+// Explicit flags apply to every course, [role.<role>] rules fill in the rest.
+// Also returns the options turned on by a role rule, for the summary
+fn course_rules_for(
+    course: &canvas::Course,
+    args: &CommandLineOptions,
+    role_rules: &config::RoleRules,
+) -> (CourseRules, Vec<&'static str>) {
+    let mut rules = CourseRules {
+        include_unpublished: args.include_unpublished,
+        all_submissions: args.all_submissions,
+        concluded: course.concluded(),
+        start_at: course.start_at.as_deref().and_then(|start_at| DateTime::parse_from_rfc3339(start_at).ok()),
+        end_at: course.end_at.as_deref().and_then(|end_at| DateTime::parse_from_rfc3339(end_at).ok()),
+    };
+    let mut by_role = Vec::new();
+    let Some(role_options) = course.role().and_then(|role| role_rules.get(role)) else {
+        return (rules, by_role);
+    };
+    if !args.include_unpublished && role_options.get("include_unpublished") == Some(&true) {
+        rules.include_unpublished = true;
+        by_role.push("--include-unpublished");
+    }
+    if !args.all_submissions && role_options.get("all_submissions") == Some(&true) {
+        rules.all_submissions = true;
+        by_role.push("--all-submissions");
+    }
+    (rules, by_role)
+}
+
+// Canvas id of the course `path` belongs to, None outside of course folders
+fn course_id_of(path: &Path, options: &ProcessOptions) -> Option<u32> {
+    let course_folder = course_folder_of(path, options);
+    options.course_ids.lock().ok().and_then(|course_ids| course_ids.get(&course_folder).copied())
+}
+
+// ".../<course folder>/announcements/12_Week 1" --> ".../<course folder>"
+fn course_folder_of(path: &Path, options: &ProcessOptions) -> PathBuf {
+    // Course folders may be nested with --layout
+    let course_folder = options
+        .course_folders
+        .lock()
+        .ok()
+        .and_then(|course_folders| course_folders.iter().find(|course_folder| path.starts_with(course_folder)).cloned());
+    if let Some(course_folder) = course_folder {
+        return course_folder;
+    }
+    path.strip_prefix(&options.destination_folder)
+        .ok()
+        .and_then(|relative_path| relative_path.components().next())
+        .map_or_else(|| path.to_path_buf(), |course_folder| options.destination_folder.join(course_folder))
+}
+
+async fn process_file_id(
+    (url, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<File> {
+    let url = url.trim_end_matches("/download");
+
+    let file_resp = get_canvas_api(url.to_string(), &options).await?;
+    let file_result = file_resp.json::<canvas::File>().await;
+    match file_result {
+        Result::Ok(mut file) => {
+            let file_path = path.join(&file.display_name);
+            file.filepath = file_path;
+            Ok(file)
+        }
+        Err(e) => {
+            eprintln!("Error when getting file info at link:{url}, path:{path:?}\n{e:?}",);
+            Err(Into::into(e))
+        }
+    }
+}
+async fn prepare_link_for_download(
+    (link, path): (String, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<File> {
+
+    options.metrics.record_request("link");
+    let resp = options
+        .client
+        .head(&link)
+        .bearer_auth(&options.canvas_token)
+        .timeout(options.request_timeout)
+        .send()
+        .await?;
+    let headers = resp.headers();
+    // get filename out of Content-Disposition header
+    let filename = headers
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| {
+            let re = Regex::new(r#"filename="(.*)""#).expect("filename regex is valid");
+            re.captures(x)
+        })
+        .and_then(|x| x.get(1))
+        .map(|x| x.as_str())
+        .unwrap_or_else(|| {
+            let re = Regex::new(r"/([^/]+)$").expect("last segment regex is valid");
+            re.captures(&link)
+                .and_then(|x| x.get(1))
+                .map(|x| x.as_str())
+                .unwrap_or("unknown")
+        });
+    // last-modified header to TZ string
+    let updated_at = headers
+        .get(header::LAST_MODIFIED)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| {
+            let dt = DateTime::parse_from_rfc2822(x).ok()?;
+            Some(dt.with_timezone(&Local).to_rfc3339())
+        })
+        .unwrap_or_else(|| Local::now().to_rfc3339());
+    
+    let file = File {
+        id: 0,
+        folder_id: 0,
+        display_name: filename.to_string(),
+        size: 0,
+        url: link.clone(),
+        updated_at,
+        locked_for_user: false,
+        filepath: path.join(filename),
+        source_context: None,
+        name_prefix: None,
+        created_at: None,
+        course_id: None,
+    };
+    Ok(file)
+}
+
+// Reverse proxies may advertise their internal hostname, keep talking to the one we authenticated against
+fn rewrite_to_api_host(link: String, options: &ProcessOptions) -> String {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    let (Ok(mut next), Ok(base)) = (Url::parse(&link), Url::parse(&options.api_base)) else {
+        return link;
+    };
+    if next.host_str() == base.host_str() && next.scheme() == base.scheme() && next.port() == base.port() {
+        return link;
+    }
+    WARNED.call_once(|| {
+        eprintln!(
+            "Warning: Canvas pointed to the next page at {}, fetching it from {} instead",
+            next.host_str().unwrap_or_default(),
+            base.host_str().unwrap_or_default()
+        )
+    });
+    if next.set_scheme(base.scheme()).is_err()
+        || next.set_host(base.host_str()).is_err()
+        || next.set_port(base.port()).is_err()
+    {
+        return link;
+    }
+    next.to_string()
+}
+
+async fn get_pages(link: String, options: &ProcessOptions) -> Result<Vec<Response>> {
+    fn parse_next_page(resp: &Response) -> Option<String> {
+        // Parse LINK header
+        let links = resp.headers().get(header::LINK)?.to_str().ok()?; // ok to not have LINK header
+        let rels = parse_link_header::parse_with_rel(links).unwrap_or_else(|e| {
+            panic!(
+                "Error parsing header for next page, uri={}, err={e:?}",
+                resp.url()
+            )
+        });
+
+        // Is last page?
+        let nex = rels.get("next")?; // ok to not have "next"
+        let cur = rels
+            .get("current")
+            .unwrap_or_else(|| panic!("Could not find current page for {}", resp.url()));
+        let last = rels
+            .get("last")?;
+        if cur == last {
+            return None;
+        };
+
+        // Next page
+        Some(nex.raw_uri.clone())
+    }
+
+    let mut link = Some(link);
+    let mut resps = Vec::new();
+
+    while let Some(uri) = link {
+        // GET request
+        let resp = get_canvas_api(uri, options).await?;
+
+        // Get next page before returning for json
+        link = parse_next_page(&resp).map(|next| rewrite_to_api_host(next, options));
+        resps.push(resp);
+    }
+    Ok(resps)
+}
 
-                    let date_regex = Regex::new(r"/Date\((\d+)\)/").unwrap();
-                    let date_match_rfc3339 = date_regex
-                        .captures(&result.StartTime)
-                        .and_then(|x| x.get(1))
-                        .map(|x| x.as_str())
-                        .ok_or(anyhow!("Parse error for StartTime"))
-                        .and_then(|x| x.parse::<i64>().map_err(|e| anyhow!("Conversion error for StartTime: {}", e)))
-                        .and_then(|x| Utc.timestamp_millis_opt(x).earliest().ok_or(anyhow!("Timestamp parse error for StartTime")))
-                        .map(|x| x.to_rfc3339())?;
+const LAYOUT_PLACEHOLDERS: [&str; 5] = ["term_id", "term_name", "course_code", "course_name", "course_id"];
 
-                    let file = canvas::File {
-                        display_name: download_file_name,
-                        folder_id: 0,
-                        id: 0,
-                        size: 0,
-                        url: panopto_mp4_file,
-                        locked_for_user: false,
-                        updated_at: date_match_rfc3339,
-                        filepath: path.clone(),
-                    };
-                    let mut lock = options.files_to_download.lock().await;
-                    let mut filtered_files = filter_files(&options, &path, [file].to_vec());
-                    lock.append(&mut filtered_files);
-                },
-                Err(e) => println!("Error: {:?}", e),
-            }
-            
-        }
-        Ok(Playlist::MediaPlaylist(_pl)) => {},
-        Err(e) => println!("Error: {:?}", e),
+const NAME_PLACEHOLDERS: [&str; 4] = ["id", "display_name", "updated_date", "folder"];
+
+// Case-insensitive substring of the course code or name, any filter may match
+fn course_matches(course: &canvas::Course, filters: &[String]) -> bool {
+    let course_code = course.course_code.to_lowercase();
+    let name = course.name.to_lowercase();
+    filters.iter().any(|filter| {
+        let filter = filter.trim().to_lowercase();
+        !filter.is_empty() && (course_code.contains(&filter) || name.contains(&filter))
+    })
+}
+
+// Checked when parsing the command line so a typo fails before anything is crawled
+fn parse_layout(value: &str) -> Result<String, String> {
+    check_placeholders(value, &LAYOUT_PLACEHOLDERS)?;
+    if value.split('/').all(|component| component.trim().is_empty()) {
+        return Err("layout must not be empty".to_string());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_name_template(value: &str) -> Result<String, String> {
+    check_placeholders(value, &NAME_PLACEHOLDERS)?;
+    if value.trim().is_empty() {
+        return Err("name template must not be empty".to_string());
     }
+    Ok(value.to_string())
+}
 
+fn check_placeholders(value: &str, placeholders: &[&str]) -> Result<(), String> {
+    let placeholder = Regex::new(r"\{([^{}]*)\}").expect("placeholder regex is valid");
+    for captures in placeholder.captures_iter(value) {
+        let name = &captures[1];
+        if !placeholders.contains(&name) {
+            return Err(format!(
+                "unknown placeholder {{{name}}}, expected one of {}",
+                placeholders.iter().map(|name| format!("{{{name}}}")).collect::<Vec<String>>().join(", ")
+            ));
+        }
+    }
+    let rest = placeholder.replace_all(value, "");
+    if rest.contains('{') || rest.contains('}') {
+        return Err(format!("unbalanced braces in {value:?}"));
+    }
     Ok(())
 }
 
-async fn process_data(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let assignments_path = path.join("assignments");
-    create_folder_if_not_exist(&assignments_path)?;
-    fork!(
-        process_assignments,
-        (url.clone(), assignments_path),
-        (String, PathBuf),
-        options.clone()
-    );
-    let users_path = path.join("users.json");
-    fork!(
-        process_users,
-        (url.clone(), users_path),
-        (String, PathBuf),
-        options.clone()
-    );
-    let discussions_path = path.join("discussions");
-    create_folder_if_not_exist(&discussions_path)?;
-    fork!(
-        process_discussions,
-        (url.clone(), false, discussions_path),
-        (String, bool, PathBuf),
-        options.clone()
-    );
-    let announcements_path = path.join("announcements");
-    create_folder_if_not_exist(&announcements_path)?;
-    fork!(
-        process_discussions,
-        (url.clone(), true, announcements_path),
-        (String, bool, PathBuf),
-        options.clone()
-    );
+// The folder the course was synced into before, when only its course code changed since. With --follow-renames that
+// folder is renamed to the new course code instead. A changed --layout or --group-by-term applies as given
+fn tracked_course_folder(course: &canvas::Course, args: &CommandLineOptions, options: &ProcessOptions) -> Result<PathBuf> {
+    let folder_path = course_folder_path(course, args);
+    let relative = |path: &Path| path.strip_prefix(&options.destination_folder).unwrap_or(path).to_string_lossy().to_string();
+    let mut state = options
+        .state
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned state lock, err={e}"));
+    let recorded_path = state
+        .course_folders
+        .get(&course.id.to_string())
+        .filter(|recorded| recorded.course_code != course.course_code)
+        .map(|recorded| options.destination_folder.join(&recorded.path))
+        // Synced before, but gone since
+        .filter(|recorded_path| *recorded_path != folder_path && recorded_path.is_dir());
+    let record = |path: &Path| state::CourseFolder { path: relative(path), course_code: course.course_code.clone() };
+    let Some(recorded_path) = recorded_path else {
+        // Destinations of earlier versions have no folders recorded, the current one is taken as is
+        if !options.dry_run {
+            state.course_folders.insert(course.id.to_string(), record(&folder_path));
+        }
+        return Ok(folder_path);
+    };
 
-    
-    /*
-    I do not need this
-
-    let pages_path = path.join("pages");
-    create_folder_if_not_exist(&pages_path)?;
-    fork!(
-        process_pages,
-        (url.clone(), pages_path),
-        (String, PathBuf),
-        options.clone()
-    );
-     */
-
-    let modules_path = path.join("modules");
-    create_folder_if_not_exist(&modules_path)?;
-    fork!(
-        process_modules,
-        (url.clone(), modules_path),
-        (String, PathBuf),
-        options.clone()
+    if !args.follow_renames || folder_path.exists() || options.dry_run {
+        eprintln!(
+            "Warning: {} ({}) is now named {}, still syncing into {}. Pass --follow-renames to rename the folder",
+            relative(&recorded_path),
+            course.id,
+            course.course_code,
+            recorded_path.to_string_lossy()
+        );
+        return Ok(recorded_path);
+    }
+    std::fs::rename(&recorded_path, &folder_path)
+        .with_context(|| format!("Failed to rename {recorded_path:?} to {folder_path:?}"))?;
+    eprintln!(
+        "Renamed {} to {}, the course code changed to {}",
+        recorded_path.to_string_lossy(),
+        folder_path.to_string_lossy(),
+        course.course_code
     );
+    let (from, to) = (relative(&recorded_path), relative(&folder_path));
+    state.move_folder(&from, &to);
+    options
+        .manifest
+        .lock()
+        .unwrap_or_else(|e| panic!("Please report on GitHub. Poisoned manifest lock, err={e}"))
+        .move_folder(&from, &to);
+    state.course_folders.insert(course.id.to_string(), record(&folder_path));
+    Ok(folder_path)
+}
 
-    Ok(())
+// Where a course is synced to, in this order:
+// 1. --layout, as given
+// 2. --group-by-term: <term>/<course code>, unless only the <course code> folder of runs without it exists.
+//    That folder is kept, so -n does not download the whole course again
+// 3. <course code>
+fn course_folder_path(course: &canvas::Course, args: &CommandLineOptions) -> PathBuf {
+    if let Some(layout) = &args.layout {
+        return args.destination_folder.join(expand_layout(layout, course));
+    }
+    let ungrouped = args.destination_folder.join(course.course_code.replace('/', "_"));
+    if !args.group_by_term {
+        return ungrouped;
+    }
+    // Listings include the term, courses without one are grouped by their term ID
+    let term = match sanitize_foldername(course.term_name()) {
+        term if term.is_empty() => course.enrollment_term_id.to_string(),
+        term => term,
+    };
+    let grouped = args.destination_folder.join(term).join(course.course_code.replace('/', "_"));
+    match (grouped.is_dir(), ungrouped.is_dir()) {
+        (true, true) => {
+            eprintln!(
+                "Warning: {} exists both in {} and {}, syncing into the first. Remove or merge the other to silence this",
+                course.course_code,
+                grouped.to_string_lossy(),
+                ungrouped.to_string_lossy()
+            );
+            grouped
+        }
+        (false, true) => ungrouped,
+        _ => grouped,
+    }
 }
 
-async fn process_pages(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let pages_url = format!("{}pages", url);
-    let pages = get_pages(pages_url, &options).await?;
-    
-    let pages_path = path.join("pages.json");
-    let mut pages_file = std::fs::File::create(pages_path.clone())
-        .with_context(|| format!("Unable to create file for {:?}", pages_path))?;
+// "{term_name}/{course_code}" --> "Spring 2024/CS101", placeholders already validated by parse_layout()
+fn expand_layout(layout: &str, course: &canvas::Course) -> PathBuf {
+    let placeholder = Regex::new(r"\{([^{}]*)\}").expect("layout placeholder regex is valid");
+    layout
+        .split('/')
+        .filter(|component| !component.trim().is_empty())
+        .map(|component| {
+            let expanded = placeholder.replace_all(component, |captures: &regex::Captures| {
+                match &captures[1] {
+                    "term_id" => course.enrollment_term_id.to_string(),
+                    "term_name" => course.term_name().to_string(),
+                    "course_code" => course.course_code.clone(),
+                    "course_name" => course.name.clone(),
+                    "course_id" => course.id.to_string(),
+                    _ => String::new(),
+                }
+            });
+            let sanitized = sanitize_foldername(expanded);
+            // eg {term_name} of a course without a term
+            if sanitized.is_empty() {
+                "_".to_string()
+            } else {
+                sanitized
+            }
+        })
+        .collect()
+}
 
-    for pg in pages {
-        let uri = pg.url().to_string();
-        let page_body = pg.text().await?;
+fn sanitize_foldername<S: AsRef<str>>(name: S) -> String {
+    let name = name.as_ref();
+    let rex = Regex::new(r#"[/\?<.">\\:\*\|":]"#).expect("folder name regex is valid");
 
-        pages_file
-            .write_all(page_body.as_bytes())
-            .with_context(|| format!("Could not write to file {:?}", pages_path))?;
+    let name_modified = rex.replace_all(name, "");
 
-        let page_result = serde_json::from_str::<canvas::PageResult>(&page_body);
+    String::from(name_modified.trim())
+}
 
-        match page_result {
-            Ok(canvas::PageResult::Ok(pages)) => {
-                for page in pages {
-                    let page_url = format!("{}pages/{}", url, page.url);
-                    let page_file_path = path.join(sanitize_foldername(page.url.clone()));
-                    create_folder_if_not_exist(&page_file_path)?;
-                    fork!(
-                        process_page_body,
-                        (page_url, page.url, page_file_path),
-                        (String, String, PathBuf),
-                        options.clone()
-                    )
-                }
-            }
+async fn get_canvas_api(url: String, options: &ProcessOptions) -> Result<Response> {
+    send_canvas_api(reqwest::Method::GET, url, options).await
+}
 
-            Ok(canvas::PageResult::Err { status }) => {
-                eprintln!("No pages found for url {} status: {}", uri, status);
+async fn send_canvas_api(method: reqwest::Method, url: String, options: &ProcessOptions) -> Result<Response> {
+    let mut query_pairs : Vec<(String, String)> = Vec::new();
+    // insert into query_pairs from url.query_pairs();
+    for (key, value) in Url::parse(&url)?.query_pairs() {
+        query_pairs.push((key.to_string(), value.to_string()));
+    }
+    let category = metrics::api_category(&url);
+    let http_cache = options.http_cache.as_ref().filter(|_| method == reqwest::Method::GET);
+    let cached = http_cache.and_then(|http_cache| http_cache.lookup(&url));
+    for retry in 0..=options.retries {
+        // Every crawl goes through here, so a cancelled run stops forking new tasks
+        if options.cancel.is_cancelled() {
+            return Err(errors::Cancelled.into());
+        }
+        options.metrics.record_request(&category);
+        let mut request = options
+            .client
+            .request(method.clone(), &url)
+            .query(&query_pairs)
+            .headers(options.canvas_headers.clone())
+            .bearer_auth(&options.canvas_token)
+            .timeout(options.request_timeout);
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
             }
-
-            Err(e) => {
-                eprintln!("No pages found for url {} error: {}", uri, e);
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
             }
+        }
+        let resp = request.send().await;
+
+        let cause = match resp {
+            Ok(resp) => {
+                options.metrics.observe_rate_limit(resp.headers());
+                if let (Some(http_cache), Some(cached)) = (http_cache, &cached) {
+                    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+                        http_cache.hit(&url);
+                        return cached_response(cached);
+                    }
+                }
+                if let Some(http_cache) = http_cache.filter(|_| resp.status() == reqwest::StatusCode::OK) {
+                    return cache_response(http_cache, &url, resp).await;
+                }
+                let cause = if resp.status() == reqwest::StatusCode::FORBIDDEN {
+                    RetryCause::Throttled
+                } else if resp.status().is_server_error() {
+                    RetryCause::ServerError
+                } else {
+                    return Ok(resp)
+                };
+                if retry == options.retries {
+                    return Ok(resp)
+                }
+                cause
+            },
+            Err(e) if e.is_timeout() && retry < options.retries => RetryCause::Timeout,
+            Err(e) => {options.detail(format!("Canvas request error uri: {} {}", url, e)); return Err(e.into())},
         };
+
+        let wait_time = backoff(options, retry);
+        options.metrics.record_retry(cause, wait_time);
+        if options.verbosity >= Verbosity::Verbose {
+            println!("Got {:?} for {}, waiting {:?} before retrying, retry {}", cause, url, wait_time, retry);
+        } else {
+            options.info(format!("Got {:?}, waiting {:?} before retrying, retry {}", cause, wait_time, retry));
+        }
+        tokio::select! {
+            () = tokio::time::sleep(wait_time) => {}
+            () = options.cancel.cancelled() => return Err(errors::Cancelled.into()),
+        }
+        
     }
+    Err(Error::msg("canvas request failed"))
+}
 
-    Ok(())
+// The listing Canvas said is unchanged, as if it had been sent again
+fn cached_response(cached: &http_cache::Entry) -> Result<Response> {
+    let mut builder = reqwest::ResponseBuilderExt::url(http::Response::builder(), Url::parse(&cached.url)?);
+    if let Some(link) = &cached.link {
+        builder = builder.header(header::LINK, link);
+    }
+    if let Some(content_type) = &cached.content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+    Ok(Response::from(builder.body(cached.body.clone())?))
 }
 
-async fn process_page_body(
-    (url, title, path): (String, String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let page_resp = get_canvas_api(url.clone(), &options).await?;
+// Keeps a JSON listing Canvas can tell is unchanged next time, the body is read here and handed on as if it came
+// straight from Canvas
+async fn cache_response(http_cache: &http_cache::HttpCache, url: &str, resp: Response) -> Result<Response> {
+    let header_value =
+        |name: header::HeaderName| resp.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let (etag, last_modified) = (header_value(header::ETAG), header_value(header::LAST_MODIFIED));
+    let content_type = header_value(header::CONTENT_TYPE);
+    let is_json = content_type.as_deref().is_some_and(|content_type| content_type.contains("json"));
+    if (etag.is_none() && last_modified.is_none()) || !is_json {
+        return Ok(resp);
+    }
+    let entry = http_cache::Entry {
+        url: url.to_string(),
+        etag,
+        last_modified,
+        link: header_value(header::LINK),
+        content_type,
+        body: resp.text().await?,
+    };
+    if let Err(e) = http_cache.store(&entry) {
+        eprintln!("Failed to cache the response of {url}, err={e:?}");
+    }
+    cached_response(&entry)
+}
 
-    let page_file_path = path.join(format!("{}.json", sanitize_filename::sanitize(title)));
-    let mut page_file = std::fs::File::create(page_file_path.clone())
-        .with_context(|| format!("Unable to create file for {:?}", page_file_path))?;
+mod canvas {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::AtomicUsize;
 
-    let page_resp_text = page_resp.text().await?;
-    page_file
-        .write_all(page_resp_text.as_bytes())
-        .with_context(|| format!("Could not write to file {:?}", page_file_path))?;
+    use reqwest::StatusCode;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::Mutex;
 
-    let page_body_result = serde_json::from_str::<canvas::PageBody>(&page_resp_text);
-    match page_body_result {
-        Result::Ok(page_body) => {
-            let page_html = format!(
-                "<html><head><title>{}</title></head><body>{}</body></html>",
-                page_body.title, page_body.body);
-            
-            let page_html_path = path.join(format!("{}.html", sanitize_filename::sanitize(page_body.url)));
-            let mut page_html_file = std::fs::File::create(page_html_path.clone())
-                .with_context(|| format!("Unable to create file for {:?}", page_html_path))?;
+    // Why a Canvas API response could not be used
+    #[derive(Debug)]
+    pub enum ApiError {
+        Unauthorized,
+        NotFound,
+        RateLimited,
+        // Any other error reported by Canvas, e.g. an unexpected HTTP status
+        Status(String),
+        // The body matched neither the error nor the expected shape
+        ParseFailed { body_path: Option<PathBuf>, error: String },
+    }
 
-            page_html_file
-                .write_all(page_html.as_bytes())
-                .with_context(|| format!("Could not write to file {:?}", page_html_path))?;
-            
-            fork!(
-                process_html_links,
-                (page_html, path),
-                (String, PathBuf),
-                options.clone()
-            )
-        }
-        Result::Err(e) => {
-            eprintln!("Error when parsing page body at link:{url}, path:{page_file_path:?}\n{e:?}",);
+    impl std::fmt::Display for ApiError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ApiError::Unauthorized => write!(f, "unauthorized"),
+                ApiError::NotFound => write!(f, "not found"),
+                ApiError::RateLimited => write!(f, "rate limited"),
+                ApiError::Status(status) => write!(f, "status {status}"),
+                ApiError::ParseFailed { body_path: Some(body_path), error } => {
+                    write!(f, "unexpected response (saved to {body_path:?}), err={error}")
+                }
+                ApiError::ParseFailed { body_path: None, error } => {
+                    write!(f, "unexpected response, err={error}")
+                }
+            }
         }
     }
-    Ok(())
-}
 
-async fn process_assignments(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let assignments_url = format!("{}assignments?include[]=submission&include[]=assignment_visibility&include[]=all_dates&include[]=overrides&include[]=observed_users&include[]=can_edit&include[]=score_statistics", url);
-    let pages = get_pages(assignments_url, &options).await?;
-    
-    let assignments_json = path.join("assignments.json");
-    let mut assignments_file = std::fs::File::create(assignments_json.clone())
-        .with_context(|| format!("Unable to create file for {:?}", assignments_json))?;
+    impl std::error::Error for ApiError {}
 
-    for pg in pages {
-        let uri = pg.url().to_string();
-        let page_body = pg.text().await?;
-
-        assignments_file
-            .write_all(page_body.as_bytes())
-            .with_context(|| format!("Unable to write to file for {:?}", assignments_json))?;
+    pub type ApiResult<T> = Result<T, ApiError>;
 
-        let assignment_result = serde_json::from_str::<canvas::AssignmentResult>(&page_body);
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        status: Option<String>,
+        errors: Option<serde_json::Value>,
+    }
 
-        match assignment_result {
-            Ok(canvas::AssignmentResult::Ok(assignments)) => {
-                for assignment in assignments {
-                    let assignment_path = path.join(sanitize_foldername(assignment.name));
-                    create_folder_if_not_exist(&assignment_path)?;
-                    let submissions_url = format!("{}assignments/{}/submissions/", url, assignment.id);
-                    fork!(
-                        process_submissions,
-                        (submissions_url, assignment_path.clone()),
-                        (String, PathBuf),
-                        options.clone()
-                    );
-                    fork!(
-                        process_html_links,
-                        (assignment.description, assignment_path),
-                        (String, PathBuf),
-                        options.clone()
-                    );
-                }
+    impl ErrorBody {
+        fn classify(self) -> ApiError {
+            let status = self.status.unwrap_or_default();
+            let errors = self.errors.map(|e| e.to_string()).unwrap_or_default();
+            if status == "unauthorized" || errors.contains("unauthorized") {
+                ApiError::Unauthorized
+            } else if status == "not_found" || status == "not found" || errors.contains("does not exist") {
+                ApiError::NotFound
+            } else if errors.contains("Rate Limit Exceeded") {
+                ApiError::RateLimited
+            } else if !status.is_empty() {
+                ApiError::Status(status)
+            } else {
+                ApiError::Status(errors)
             }
-            Ok(canvas::AssignmentResult::Err { status }) => {
-                eprintln!(
-                    "Failed to access assignments at link:{uri}, path:{path:?}, status:{status}",
-                );
+        }
+    }
+
+    // Checks the HTTP status, then the error body shape, then the success shape
+    pub fn parse_api_body<T: DeserializeOwned>(
+        status: StatusCode,
+        body: &str,
+        body_path: Option<&Path>,
+    ) -> ApiResult<T> {
+        match status {
+            StatusCode::UNAUTHORIZED => return Err(ApiError::Unauthorized),
+            StatusCode::NOT_FOUND => return Err(ApiError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => return Err(ApiError::RateLimited),
+            StatusCode::FORBIDDEN if body.contains("Rate Limit Exceeded") => {
+                return Err(ApiError::RateLimited)
             }
-            Err(e) => {
-                eprintln!("Error when getting assignments at link:{uri}, path:{path:?}\n{e:?}",);
+            StatusCode::FORBIDDEN => return Err(ApiError::Unauthorized),
+            _ => {}
+        }
+        if let Ok(error_body) = serde_json::from_str::<ErrorBody>(body) {
+            if error_body.status.is_some() || error_body.errors.is_some() {
+                return Err(error_body.classify());
             }
         }
+        if !status.is_success() {
+            return Err(ApiError::Status(status.to_string()));
+        }
+        serde_json::from_str::<T>(body).map_err(|e| ApiError::ParseFailed {
+            body_path: body_path.map(Path::to_path_buf),
+            error: e.to_string(),
+        })
     }
-    Ok(())
-}
 
-async fn process_submissions(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let submissions_url = format!("{}{}", url, options.user.id);
+    #[derive(Clone, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Credentials {
+        pub canvas_url: String,
+        pub canvas_token: String,
+        // Path the API is served under on self-hosted instances, eg "/canvas" for https://lms.example.edu/canvas/api/v1
+        #[serde(default)]
+        pub api_prefix: Option<String>,
+    }
 
-    let resp = get_canvas_api(submissions_url, &options).await?;
-    let submissions_body = resp.text().await?;
-    let submissions_json = path.join("submission.json");
-    let mut submissions_file = std::fs::File::create(submissions_json.clone())
-        .with_context(|| format!("Unable to create file for {:?}", submissions_json))?;
+    pub fn api_base(canvas_url: &str, api_prefix: Option<&str>) -> String {
+        let prefix = api_prefix.unwrap_or_default().trim_matches('/');
+        let canvas_url = canvas_url.trim_end_matches('/');
+        if prefix.is_empty() {
+            format!("{canvas_url}/api/v1")
+        } else {
+            format!("{canvas_url}/{prefix}/api/v1")
+        }
+    }
 
-    submissions_file
-        .write_all(submissions_body.as_bytes())
-        .with_context(|| format!("Unable to write to file for {:?}", submissions_json))?;
+    // "https://lms.example.edu/canvas/api/v1/users/self" --> "https://lms.example.edu/canvas/api/v1"
+    pub fn detect_api_base(url: &reqwest::Url) -> Option<String> {
+        let path = url.path();
+        let end = path.find("/api/v1/")? + "/api/v1".len();
+        let mut base = url.clone();
+        base.set_path(&path[..end]);
+        base.set_query(None);
+        Some(base.to_string())
+    }
 
-    let submissions_result = serde_json::from_str::<canvas::Submission>(&submissions_body);
-    match submissions_result {
-        Result::Ok(submissions) => {
-            let mut filtered_files = filter_files(&options, &path, submissions.attachments);
-            let mut lock = options.files_to_download.lock().await;
-            lock.append(&mut filtered_files);
-        }
-        Result::Err(e) => {
-            eprintln!("Error when getting submissions at link:{url}, path:{path:?}\n{e:?}",);
-        }
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Course {
+        pub id: u32,
+        pub name: String,
+        pub course_code: String,
+        pub enrollment_term_id: u32,
+        // Only present when requested with include[]=term
+        #[serde(default)]
+        pub term: Option<Term>,
+        // Landing page on Canvas: feed, wiki, modules, assignments or syllabus
+        #[serde(default)]
+        pub default_view: Option<String>,
+        // Your enrollments in the course, a user may hold several
+        #[serde(default)]
+        pub enrollments: Vec<Enrollment>,
+        // Set by the teacher, often missing
+        #[serde(default)]
+        pub start_at: Option<String>,
+        #[serde(default)]
+        pub end_at: Option<String>,
     }
-    Ok(())
-}
 
-async fn process_users (
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let users_url = format!("{}users?include_inactive=true&include[]=avatar_url&include[]=enrollments&include[]=email&include[]=observed_users&include[]=can_be_removed&include[]=custom_links", url);
-    let pages = get_pages(users_url, &options).await?;
-    
-    let users_path = sanitize_filename::sanitize(path.to_string_lossy());
-    let mut users_file = std::fs::File::create(path.clone())
-        .with_context(|| format!("Unable to create file for {:?}", users_path))?;
+    impl Course {
+        pub fn term_name(&self) -> &str {
+            self.term.as_ref().map_or("", |term| term.name.as_str())
+        }
 
-    for pg in pages {
-        let page_body = pg.text().await?;
-        
-        users_file
-            .write_all(page_body.as_bytes())
-            .with_context(|| format!("Unable to write to file for {:?}", users_path))?;
+        // The most privileged of your enrollments: teacher, ta, designer, student or observer
+        pub fn role(&self) -> Option<&'static str> {
+            self.enrollments
+                .iter()
+                .filter_map(|enrollment| {
+                    // "teacher" in course listings, "TeacherEnrollment" elsewhere
+                    match enrollment.enrollment_type.trim_end_matches("Enrollment").to_lowercase().as_str() {
+                        "teacher" => Some((0, "teacher")),
+                        "ta" => Some((1, "ta")),
+                        "designer" => Some((2, "designer")),
+                        "student" => Some((3, "student")),
+                        "observer" => Some((4, "observer")),
+                        _ => None,
+                    }
+                })
+                .min()
+                .map(|(_, role)| role)
+        }
+
+        // All your enrollments ended, Canvas then denies parts of the course that were readable before
+        pub fn concluded(&self) -> bool {
+            !self.enrollments.is_empty()
+                && self
+                    .enrollments
+                    .iter()
+                    .all(|enrollment| enrollment.enrollment_state.as_deref() == Some("completed"))
+        }
     }
 
-    Ok(())
-}
-
-async fn process_discussions(
-    (url, announcement, path): (String, bool, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let discussion_url = format!("{}discussion_topics{}", url, if announcement { "?only_announcements=true" } else { "" });
-    let pages = get_pages(discussion_url, &options).await?;
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Enrollment {
+        #[serde(rename = "type")]
+        pub enrollment_type: String,
+        // Custom role name, or the type as "StudentEnrollment"
+        #[serde(default)]
+        pub role: Option<String>,
+        // active, completed, invited, ...
+        #[serde(default)]
+        pub enrollment_state: Option<String>,
+    }
 
-    let discussion_path = path.join("discussions.json");
-    let mut discussion_file = std::fs::File::create(discussion_path.clone())
-        .with_context(|| format!("Unable to create file for disc {:?}", discussion_path))?;
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Term {
+        pub id: u32,
+        pub name: String,
+        #[serde(default)]
+        pub start_at: Option<String>,
+    }
 
-    for pg in pages {
-        let uri = pg.url().to_string();
-        let page_body = pg.text().await?;
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct User {
+        pub id: u32,
+        pub name: String,
+    }
 
-        discussion_file
-            .write_all(page_body.as_bytes())
-            .with_context(|| format!("Unable to write to file for {:?}", discussion_path))?;
+    #[derive(Deserialize)]
+    pub struct Folder {
+        pub name: String,
+        pub folders_url: String,
+        pub files_url: String,
+        pub parent_folder_id: Option<u32>,
+        #[serde(default)]
+        pub full_name: String,
+    }
 
-        let discussion_result = serde_json::from_str::<canvas::DiscussionResult>(&page_body);
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct PageBody {
+        pub url: String,
+        pub title: String,
+        pub body: String,
+        pub updated_at: String,
+    }
 
-        match discussion_result {
-            Ok(canvas::DiscussionResult::Ok(discussions)) => {
-                for discussion in discussions {
-                    // download attachments
-                    let discussion_folder_path = path.join(format!("{}_{}", discussion.id, sanitize_foldername(discussion.title)));
-                    create_folder_if_not_exist(&discussion_folder_path)?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct ModuleSection {
+        pub id: u32,
+        pub items_url: String,
+        pub name: String,
+        // Only present for course staff
+        #[serde(default)]
+        pub published: Option<bool>,
+    }
 
-                    let files = discussion.attachments
-                        .into_iter()
-                        .map(|mut f| {
-                            f.display_name = format!("{}_{}", f.id, &f.display_name);
-                            f
-                        })
-                        .collect();
-                    {
-                        let mut filtered_files = filter_files(&options, &discussion_folder_path, files);
-                        let mut lock = options.files_to_download.lock().await;
-                        lock.append(&mut filtered_files);
-                    }
-                    
-                    fork!(
-                        process_html_links,
-                        (discussion.message, discussion_folder_path.clone()),
-                        (String, PathBuf),
-                        options.clone()
-                    );
-                    let view_url = format!("{}discussion_topics/{}/view", url, discussion.id);
-                    fork!(
-                        process_discussion_view,
-                        (view_url, discussion_folder_path),
-                        (String, PathBuf),
-                        options.clone()
-                    )
-                }
-            }
-            Ok(canvas::DiscussionResult::Err { status }) => {
-                eprintln!(
-                    "Failed to access discussions at link:{uri}, path:{path:?}, status:{status}",
-                );
-            }
-            Err(e) => {
-                eprintln!("Error when getting discussions at link:{uri}, path:{path:?}\n{e:?}",);
-            }
-        }
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    #[allow(non_snake_case)]
+    pub struct ModuleItem {
+        pub id: u32,
+        pub title: String,
+        pub Type: String,
+        #[serde(default)]
+        pub url: Option<String>,
+        #[serde(default, rename = "html_url")]
+        pub html_url: Option<String>,
+        #[serde(default, rename = "external_url")]
+        pub external_url: Option<String>,
+        #[serde(default, rename = "content_id")]
+        pub content_id: Option<u32>,
+        #[serde(default, rename = "completion_requirement")]
+        pub completion_requirement: Option<CompletionRequirement>,
+        #[serde(default)]
+        pub published: Option<bool>,
     }
-    Ok(())
-}
 
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct CompletionRequirement {
+        #[serde(rename = "type")]
+        pub requirement_type: String,
+        #[serde(default)]
+        pub completed: bool,
+    }
 
-async fn process_modules(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let module_url = format!("{}modules", url);
-    let pages = get_pages(module_url, &options).await?;
 
-    let module_path = path.join("modules.json");
-    let mut module_file = std::fs::File::create(module_path.clone())
-        .with_context(|| format!("Unable to create file for {:?}", module_path))?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Assignment {
+        pub id: u32,
+        pub name: String,
+        pub description: String,
+        #[serde(default)]
+        pub published: Option<bool>,
+        #[serde(default)]
+        pub due_at: Option<String>,
+    }
 
-    for pg in pages {
-        let uri = pg.url().to_string();
-        let page_body = pg.text().await?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Submission {
+        pub id: u32,
+        // Absent for submissions without uploads
+        #[serde(default)]
+        pub attachments: Vec<File>,
+        // Only present when requested with include[]=user
+        #[serde(default)]
+        pub user: Option<SubmissionUser>,
+        #[serde(default)]
+        pub user_id: Option<u32>,
+    }
 
-        module_file
-            .write_all(page_body.as_bytes())
-            .with_context(|| format!("Unable to write to file for {:?}", module_path))?;
-        
-        
-        let module_result = serde_json::from_str::<canvas::ModuleResult>(&page_body);
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct SubmissionUser {
+        pub id: u32,
+        pub name: String,
+    }
+    
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct PeerReview {
+        pub assessor_id: u32,
+        // Id of the submission being reviewed
+        pub asset_id: u32,
+        // Id of the user whose submission is being reviewed
+        pub user_id: u32,
+        pub workflow_state: String,
+        pub assessor: Option<PeerReviewUser>,
+        pub submission_comments: Option<Vec<SubmissionComment>>,
+    }
 
-        match module_result {
-            Ok(canvas::ModuleResult::Ok(module_sections)) => {
-                for module_section in module_sections {
-                    // download attachments
-                    let module_section_folder_path = path.join(format!("{}_{}", module_section.id, sanitize_foldername(module_section.name)));
-                    create_folder_if_not_exist(&module_section_folder_path)?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct PeerReviewUser {
+        pub display_name: String,
+    }
 
-                    fork!(
-                        process_module_items,
-                        (module_section.items_url, module_section_folder_path.clone()),
-                        (String, PathBuf),
-                        options.clone()
-                    );
-                }
-            }
-            Ok(canvas::ModuleResult::Err { status }) => {
-                eprintln!(
-                    "Failed to access modules at link:{uri}, path:{path:?}, status:{status}",
-                );
-            }
-            Err(e) => {
-                eprintln!("Error when getting modules at link:{uri}, path:{path:?}\n{e:?}",);
-            }
-        }
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct SubmissionComment {
+        pub author_name: String,
+        pub comment: String,
+        pub created_at: String,
     }
-    Ok(())
-}
 
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Discussion {
+        pub id: u32,
+        pub title: String,
+        pub message: String,
+        pub attachments: Vec<File>,
+        #[serde(default)]
+        pub posted_at: Option<String>,
+        #[serde(default)]
+        pub last_reply_at: Option<String>,
+        #[serde(default)]
+        pub read_state: Option<String>,
+        #[serde(default)]
+        pub unread_count: u32,
+    }
 
-async fn process_module_items(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let page = get_canvas_api(url, &options).await?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct DiscussionView {
+        pub view: Vec<Comments>,
+    }
 
-    let item_path = path.join("items.json");
-    let mut item_file = std::fs::File::create(item_path.clone())
-        .with_context(|| format!("Unable to create file for {:?}", item_path))?;
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Comments {
+        pub message: Option<String>,
+        pub attachment: Option<File>,
+        pub attachments: Option<Vec<File>>,
+    }
 
-    let uri = page.url().to_string();
-    let page_body = page.text().await?;
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct File {
+        pub id: u32,
+        pub folder_id: u32,
+        pub display_name: String,
+        pub size: u64,
+        pub url: String,
+        pub updated_at: String,
+        // Upload time, set as the creation time of the download where the platform has one, see disk::set_created()
+        #[serde(default)]
+        pub created_at: Option<String>,
+        pub locked_for_user: bool,
+        // Course the file is downloaded for, set when it is queued, see filter_files()
+        #[serde(default)]
+        pub course_id: Option<u32>,
+        #[serde(skip)]
+        pub filepath: std::path::PathBuf,
+        // Owning context when it differs from where the file was found, e.g. "course_123"
+        #[serde(skip)]
+        pub source_context: Option<String>,
+        // Attachments are saved as "<prefix>_<display_name>" unless --name-template says otherwise, see file_name()
+        #[serde(skip)]
+        pub name_prefix: Option<u32>,
+    }
 
-    item_file
-        .write_all(page_body.as_bytes())
-        .with_context(|| format!("Unable to write to file for {:?}", item_path))?;
-   
-    
-    let item_result = serde_json::from_str::<canvas::ModuleItemsResult>(&page_body);
+    #[derive(Clone, Debug, Deserialize)]
+    pub struct Session {
+        pub session_url: String,
+    }
 
-    match item_result {
-        Ok(canvas::ModuleItemsResult::Ok(module_items)) => {
-            for item in module_items {
-                let item_folder_path = path.join(format!("{}_{}", item.id, sanitize_foldername(item.title.clone())));
-                create_folder_if_not_exist(&item_folder_path)?;
+    #[derive(Clone, Debug, Deserialize)]
+    #[allow(non_snake_case)]
+    pub struct PanoptoSessionInfo {
+        pub Results: Vec<PanoptoResult>,
+        pub Subfolders: Vec<PanoptoSubfolder>,
+    }
 
-                //This is not a great solution, but it works for now
-                if item.Type == "Page" {
-                    fork!(
-                        process_page_body,
-                        (item.url.unwrap(), item.title, item_folder_path),
-                        (String, String, PathBuf),
-                        options.clone()
-                    );
-                } else if item.Type == "File" {
-                    let pg = get_canvas_api(item.url.clone().unwrap(), &options).await?;
-                    let files_result = pg.json::<canvas::File>().await;
+    #[derive(Clone, Debug, Deserialize)]
+    #[allow(non_snake_case)]
+    pub struct PanoptoResult {
+        pub DeliveryID: String,
+        pub SessionID: String,
+        pub SessionName: String,
+        pub StartTime: String,
+        pub IosVideoUrl: String,
+    }
 
+    #[derive(Clone, Debug, Deserialize)]
+    #[allow(non_snake_case)]
+    pub struct PanoptoSubfolder {
+        pub ID: String,
+        pub Name: String,
+    }
+    
+    #[derive(Clone, Debug, Deserialize)]
+    #[allow(non_snake_case)]
+    pub struct PanoptoDeliveryInfo {
+        pub ViewerFileId: String,
+    }
 
-                    match files_result {
-                        // Got files
-                        Ok(file) => {
-                            let mut filtered_files = filter_files(&options, &item_folder_path, vec![file]);
-                            let mut lock = options.files_to_download.lock().await;
-                            lock.append(&mut filtered_files);
-                        }
-                     
-                        // Parse error
-                        Err(e) => {
-                            eprintln!("Error when getting files at link:{uri}, path:{path:?}\n{e:?}",);
-                        }
-                    };
-        
+    pub struct ProcessOptions {
+        pub canvas_token: String,
+        pub canvas_url: String,
+        pub api_base: String, // eg https://canvas.example.edu/api/v1, see api_url()
+        pub client: reqwest::Client, // Canvas API, see client_for() for downloads
+        pub storage_client: reqwest::Client,
+        pub video_client: reqwest::Client,
+        pub video_hosts: std::sync::Mutex<std::collections::HashSet<String>>, // Panopto CDNs, see process_session()
+        pub user: User,
+        pub destination_folder: std::path::PathBuf,
+        pub tmp_dir: std::path::PathBuf, // in-progress downloads, created lazily
+        pub resume: bool, // continue partial downloads with a Range request, see download_file()
+        pub segments: u32, // parts a big file is downloaded in at once, see download_segments()
+        pub state: std::sync::Mutex<crate::state::State>,
+        pub manifest: std::sync::Mutex<crate::manifest::Manifest>, // see previous_copy()
+        pub http_cache: Option<crate::http_cache::HttpCache>, // None with --no-cache
+        pub prune_renamed: bool,
+        pub keep_versions: Option<u32>, // Some(0) keeps every version, see keep_version()
+        // Process
+        pub download_newer: bool,
+        pub exclude_folders: Vec<String>, // globs, see glob_match()
+        pub course_folders: std::sync::Mutex<Vec<std::path::PathBuf>>, // see course_folder_of()
+        pub course_rules: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, crate::CourseRules>>, // see course_rules()
+        pub course_ids: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, u32>>, // by course folder
+        pub flat: bool,
+        pub content_folder_naming: Option<crate::ContentFolderNaming>, // see content_folder()
+        pub name_template: Option<String>, // see file_name()
+        pub case_insensitive: bool, // destination filesystem, see destination_case_insensitive()
+        pub claimed_paths: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, String>>, // see claim_path()
+        pub verbosity: crate::Verbosity, // see info() and detail()
+        pub max_file_size: Option<u64>,
+        pub since: Option<chrono::DateTime<chrono::FixedOffset>>, // content updated before is skipped
+        pub until: Option<chrono::DateTime<chrono::FixedOffset>>, // content updated after is skipped
+        pub skipped_for_size: std::sync::Mutex<Vec<File>>, // over max_file_size, listed at the end of the run
+        pub include_locked: bool,
+        pub verify_content: bool, // see unchanged_content()
+        pub n_skipped_locked: AtomicUsize, // locked files left out without --include-locked
+        pub refused_locked: std::sync::Mutex<Vec<File>>, // locked files tried with --include-locked that Canvas refused
+        pub dry_run: bool, // crawl only, nothing is created on disk
+        pub record_plan: bool, // see plan()
+        pub planned: std::sync::Mutex<Vec<(File, crate::PlanStatus)>>, // every file the crawl found
+        pub mark_read: bool, // mark archived discussions and must-view module items read on Canvas
+        pub files_to_download: Mutex<Vec<File>>,
+        pub local_conflicts: std::sync::Mutex<Vec<File>>, // updated on Canvas and modified locally
+        pub changes: std::sync::Mutex<crate::digest::Changes>, // for the digest, see digest::write()
+        pub new_topics: std::sync::Mutex<Vec<crate::report::NewTopic>>, // for the change report, see write_report()
+        // by course label, see count_for_course()
+        pub course_summaries: std::sync::Mutex<std::collections::BTreeMap<String, crate::report::CourseSummary>>,
+        pub content: std::collections::HashSet<crate::ContentType>, // processors to fork
+        // Download
+        pub max_buffered_bytes: u32,
+        pub write_buffer: usize, // capacity of the BufWriter of each download
+        pub sem_buffered_bytes: tokio::sync::Semaphore, // Bytes received but not yet written, across downloads
+        pub progress_bars: indicatif::MultiProgress,
+        pub progress_style: std::sync::RwLock<indicatif::ProgressStyle>, // follows the terminal width
+        pub active_progress_bars: std::sync::Mutex<Vec<indicatif::ProgressBar>>,
+        pub overall: crate::OverallProgress, // files and bytes of the whole download, see download_all()
+        pub max_bars: usize, // visible bars of single files, see add_progress_bar()
+        pub user_agent: String,
+        pub canvas_headers: reqwest::header::HeaderMap, // --header, only sent to the Canvas host, see client_builder()
+        pub retries: u32, // after the first attempt, see send_canvas_api()
+        pub request_timeout: std::time::Duration, // Canvas requests and HEAD probes, not downloads
+        pub stall_timeout: std::time::Duration, // between two chunks of a download, see next_chunk()
+        // Synchronization
+        pub n_active_requests: AtomicUsize, // main() waits for this to be 0
+        pub sem_api: tokio::sync::Semaphore, // Limit #active Canvas API requests
+        pub sem_downloads: tokio::sync::Semaphore, // Limit #active downloads, so they can not starve the crawl
+        pub per_course_downloads: Option<u32>,
+        pub course_sems: std::sync::Mutex<std::collections::HashMap<u32, std::sync::Arc<tokio::sync::Semaphore>>>, // see course_permit()
+        pub notify_main: tokio::sync::Notify,
+        // Set by the first Ctrl-C: requests fail with errors::Cancelled and downloads stop between chunks
+        pub cancel: tokio_util::sync::CancellationToken,
+        pub cancelled: std::sync::Mutex<Vec<File>>, // downloads stopped or never started because of cancel
+        pub failed_downloads: std::sync::Mutex<Vec<File>>, // for failed.json, see retry_failed()
+        pub queue: std::sync::Mutex<Vec<crate::state::QueuedFile>>, // downloads not done yet, see dequeue()
+        pub found_sizes: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, u64>>, // files queued without a size
+        pub metrics: crate::metrics::Metrics,
+        pub errors: crate::errors::ErrorReporter, // failures, grouped so systemic ones are printed once
+    }
 
-                }
+    impl ProcessOptions {
+        // Progress notes, left out with --quiet. Errors are printed regardless, see ErrorReporter
+        pub fn info(&self, message: impl std::fmt::Display) {
+            if self.verbosity >= crate::Verbosity::Normal {
+                println!("{message}");
             }
         }
-        Ok(canvas::ModuleItemsResult::Err { status }) => {
-            eprintln!(
-                "Failed to access module items at link:{uri}, path:{path:?}, status:{status}",
-            );
+
+        // Only with --verbose
+        pub fn detail(&self, message: impl std::fmt::Display) {
+            if self.verbosity >= crate::Verbosity::Verbose {
+                println!("{message}");
+            }
         }
-        Err(e) => {
-            eprintln!("Error when getting module items at link:{uri}, path:{path:?}\n{e:?}",);
-            eprintln!("content was {page_body}",);
+
+        // Processors create folders and write artifacts through these so --dry-run leaves the disk untouched
+        pub fn create_folder(&self, folder_path: &std::path::PathBuf) -> anyhow::Result<()> {
+            if self.dry_run {
+                return Ok(());
+            }
+            if !folder_path.exists() {
+                self.detail(format!("Creating folder {}", folder_path.to_string_lossy()));
+            }
+            crate::create_folder_if_not_exist(folder_path)
+        }
+
+        // On a blocking thread, writing and syncing to slow storage would stall the tasks of a worker thread
+        pub async fn write_artifact(&self, path: &std::path::Path, bytes: &[u8]) -> anyhow::Result<()> {
+            if self.dry_run {
+                return Ok(());
+            }
+            let (path, bytes) = (path.to_path_buf(), bytes.to_vec());
+            tokio::task::spawn_blocking(move || crate::write_artifact(&path, &bytes)).await?
+        }
+
+        // api_url("courses/1/modules") --> "https://canvas.example.edu/api/v1/courses/1/modules"
+        pub fn api_url(&self, path: &str) -> String {
+            format!("{}/{}", self.api_base, path.trim_start_matches('/'))
         }
     }
-    
-    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{file_fixture, http_response, mock_server, process_options, Reply, TestDir};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn headers_only_reach_the_canvas_host() {
+        let destination = TestDir::new("canvas-headers");
+        let (storage_url, storage) = mock_server(1, |_| Reply::Close(http_response("200 OK", "", b"notes"))).await;
+        let redirect = format!("Location: {storage_url}/files/2/notes.pdf?verifier=abc\r\n");
+        let (canvas_url, canvas) = mock_server(2, move |request| {
+            if request.starts_with("get /api/v1/users/self ") {
+                Reply::Close(http_response("200 OK", "", b"{}"))
+            } else {
+                Reply::Close(http_response("302 Found", &redirect, b""))
+            }
+        })
+        .await;
+        // Another host name for the same mock server, the token and headers must not follow the redirect to storage
+        let canvas_url = canvas_url.replace("127.0.0.1", "localhost");
+        let options = process_options(
+            destination.path(),
+            &canvas_url,
+            &["--user-agent", "test-agent", "--header", "X-Requested-By: my-archiver"],
+        );
 
-async fn process_discussion_view(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
-    let resp = get_canvas_api(url.clone(), &options).await?;
-    let discussion_view_body = resp.text().await?;
-    
-    let discussion_view_json = path.join("discussion.json");
-    let mut discussion_view_file = std::fs::File::create(discussion_view_json.clone())
-        .with_context(|| format!("Unable to create file for v {:?}", discussion_view_json))?;
-
-    discussion_view_file
-        .write_all(discussion_view_body.as_bytes())
-        .with_context(|| format!("Unable to write to file for {:?}", discussion_view_json))?;
+        get_canvas_api(format!("{canvas_url}/api/v1/users/self"), &options).await.expect("api request");
+        let resp = send_file_request(&options, reqwest::Method::GET, &format!("{canvas_url}/files/2/download"), 0)
+            .await
+            .expect("download request");
+        assert_eq!(resp.text().await.expect("download body"), "notes");
 
-    let discussion_view_result = serde_json::from_str::<canvas::DiscussionView>(&discussion_view_body);
-    let mut attachments_all = Vec::new();
-    match discussion_view_result {
-        Result::Ok(discussion_view) => {
-            for view in discussion_view.view {
-                if let Some(message) = view.message {
-                    fork!(
-                        process_html_links,
-                        (message, path.clone()),
-                        (String, PathBuf),
-                        options.clone()
-                    )
-                }
-                if let Some(mut attachments) = view.attachments {
-                    attachments_all.append(&mut attachments);
-                }
-                if let Some(attachment) = view.attachment {
-                    attachments_all.push(attachment);
-                }
-            }
+        for request in canvas.await.expect("canvas server") {
+            assert!(request.contains("\r\nx-requested-by: my-archiver\r\n"), "{request}");
+            assert!(request.contains("\r\nauthorization: bearer test-token\r\n"), "{request}");
+            assert!(request.contains("\r\nuser-agent: test-agent\r\n"), "{request}");
         }
-        Result::Err(e) => {
-            eprintln!("Error when getting submissions at link:{url}, path:{path:?}\n{e:?}",);
+        let storage_requests = storage.await.expect("storage server");
+        assert!(storage_requests[0].starts_with("get /files/2/notes.pdf?verifier=abc "), "{storage_requests:?}");
+        assert!(!storage_requests[0].contains("authorization"), "{storage_requests:?}");
+        assert!(!storage_requests[0].contains("x-requested-by"), "{storage_requests:?}");
+        assert!(storage_requests[0].contains("\r\nuser-agent: test-agent\r\n"), "{storage_requests:?}");
+    }
+
+    // <destination>/CS101/notes.pdf of `size` bytes, downloaded from `url`
+    fn download_fixture(destination: &TestDir, url: String, size: usize) -> File {
+        let mut file = file_fixture(2, destination.join("CS101/notes.pdf"), "2024-01-10T09:00:00Z");
+        file.url = url;
+        file.size = size as u64;
+        std::fs::create_dir_all(destination.join("CS101")).expect("create course folder");
+        file
+    }
+
+    // Bytes that tell their offset apart, to catch a part written in the wrong place
+    fn file_body(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn truncated_body_never_reaches_the_destination() {
+        let destination = TestDir::new("truncated");
+        let body = file_body(4096);
+        let (url, server) = mock_server(1, move |_| {
+            // Announces the whole file, then drops the connection halfway
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 4096\r\nConnection: close\r\n\r\n".to_vec();
+            response.extend_from_slice(&body[..1000]);
+            Reply::Close(response)
+        })
+        .await;
+        let options = process_options(destination.path(), &url, &["--retries", "0"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download"), 4096);
+
+        atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        server.await.expect("mock server");
+        assert!(!file.filepath.exists());
+        assert_eq!(options.errors.count(), 1);
+        assert_eq!(options.failed_downloads.lock().expect("failed lock").len(), 1);
+        assert!(options.state.lock().expect("state lock").files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn error_pages_answered_with_200_are_refused() {
+        let pages = [
+            ("text/html; charset=utf-8", "<!DOCTYPE html>\n<html><title>Log In to Canvas</title></html>"),
+            ("application/json", r#"{"errors":[{"message":"user authorization required"}]}"#),
+        ];
+        for (content_type, page) in pages {
+            let destination = TestDir::new("error-page");
+            let headers = format!("Content-Type: {content_type}\r\n");
+            let (url, server) =
+                mock_server(1, move |_| Reply::Close(http_response("200 OK", &headers, page.as_bytes()))).await;
+            let options = process_options(destination.path(), &url, &["--retries", "0"]);
+            let file = download_fixture(&destination, format!("{url}/files/2/download"), 4096);
+
+            atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+            server.await.expect("mock server");
+            assert!(!file.filepath.exists(), "{content_type}");
+            assert!(!options.tmp_dir.join(tmp_file_name(&file, true)).exists(), "{content_type}");
+            let failures = options.errors.failures();
+            assert!(failures[0].error.iter().any(|error| error.contains("instead of the file")), "{failures:?}");
         }
     }
 
-    let files = attachments_all
-        .into_iter()
-        .map(|mut f| {
-            f.display_name = format!("{}_{}", f.id, &f.display_name);
-            f
+    #[tokio::test]
+    async fn stalled_download_times_out_and_frees_its_slot() {
+        let destination = TestDir::new("stalled");
+        let (url, server) = mock_server(1, |_| {
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 4096\r\n\r\n".to_vec();
+            response.extend_from_slice(&file_body(1000));
+            Reply::Stall(response)
         })
-        .collect();
-    let mut filtered_files = filter_files(&options, &path, files);
-    let mut lock = options.files_to_download.lock().await;
-    lock.append(&mut filtered_files);
+        .await;
+        let options = process_options(destination.path(), &url, &["--retries", "0", "--stall-timeout", "1"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download"), 4096);
+
+        let download = atomic_download_file(file.clone(), options.clone());
+        tokio::time::timeout(Duration::from_secs(10), download)
+            .await
+            .expect("stall timeout ends the download")
+            .expect("download reported");
+        server.await.expect("mock server");
+        assert!(!file.filepath.exists());
+        let failures = options.errors.failures();
+        assert!(failures[0].error.iter().any(|error| error.contains("stalled")), "{failures:?}");
+        // The slot and the buffered bytes go to the next download
+        assert_eq!(options.sem_downloads.available_permits(), 4);
+        assert_eq!(options.sem_buffered_bytes.available_permits(), options.max_buffered_bytes as usize);
+    }
 
-    Ok(())
-}
+    // What an interrupted earlier attempt left of `file`
+    fn partial_download(options: &ProcessOptions, file: &File, start: &[u8]) {
+        std::fs::create_dir_all(&options.tmp_dir).expect("create tmp dir");
+        std::fs::write(options.tmp_dir.join(tmp_file_name(file, true)), start).expect("write partial download");
+    }
 
-async fn process_files((url, path): (String, PathBuf), options: Arc<ProcessOptions>) -> Result<()> {
-    let pages = get_pages(url, &options).await?;
+    #[tokio::test]
+    async fn unknown_sizes_are_probed_and_zero_byte_files_downloaded() {
+        let destination = TestDir::new("unknown-sizes");
+        let unknown_length = |body: &str| -> Vec<u8> {
+            let chunk = if body.is_empty() { String::new() } else { format!("{:x}\r\n{body}\r\n", body.len()) };
+            format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{chunk}0\r\n\r\n")
+                .into_bytes()
+        };
+        let (url, server) = mock_server(5, move |request| {
+            let (method, path) = request.split_once(' ').expect("request line");
+            let response = match (method, path.split(' ').next().unwrap_or_default()) {
+                ("head", "/files/1/download") => http_response("200 OK", "", &[0; 4096]),
+                ("head", "/files/2/download") => http_response("200 OK", "", b""),
+                ("head", _) => unknown_length(""),
+                ("get", "/files/2/download") => http_response("200 OK", "", b""),
+                _ => unknown_length(&"x".repeat(100)),
+            };
+            Reply::Close(response)
+        })
+        .await;
+        let options = process_options(destination.path(), &url, &["--retries", "0"]);
+        let mut files: Vec<File> = (1..=3)
+            .map(|id| {
+                let mut file = download_fixture(&destination, format!("{url}/files/{id}/download"), 0);
+                file.id = id;
+                file.filepath = destination.join(format!("CS101/file {id}.pdf"));
+                file
+            })
+            .collect();
+
+        probe_unknown_sizes(&mut files, &options).await;
+        let sizes: Vec<u64> = files.iter().map(|file| file.size).collect();
+        // A HEAD without Content-Length leaves the size unknown, as does a file that is really empty
+        assert_eq!(sizes, [4096, 0, 0]);
+
+        for file in files.iter().skip(1) {
+            atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        }
+        server.await.expect("mock server");
+        assert_eq!(options.errors.count(), 0);
+        assert_eq!(std::fs::read(&files[1].filepath).expect("read empty file").len(), 0);
+        assert_eq!(std::fs::read(&files[2].filepath).expect("read download").len(), 100);
+        // Real sizes for the summary, see run()
+        let found_sizes = options.found_sizes.lock().expect("found sizes lock").clone();
+        assert_eq!(found_sizes.get(&files[1].filepath), Some(&0));
+        assert_eq!(found_sizes.get(&files[2].filepath), Some(&100));
+    }
 
-    // For each page
-    for pg in pages {
-        let uri = pg.url().to_string();
+    #[tokio::test]
+    async fn partial_download_is_resumed_with_a_range_request() {
+        let destination = TestDir::new("resume");
+        let body = file_body(4096);
+        let rest = body[1000..].to_vec();
+        let (url, server) = mock_server(1, move |_| {
+            let headers = "Content-Range: bytes 1000-4095/4096\r\n";
+            Reply::Close(http_response("206 Partial Content", headers, &rest))
+        })
+        .await;
+        let options = process_options(destination.path(), &url, &["--retries", "0"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download"), body.len());
+        partial_download(&options, &file, &body[..1000]);
+
+        atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        let requests = server.await.expect("mock server");
+        assert!(requests[0].contains("\r\nrange: bytes=1000-\r\n"), "{requests:?}");
+        assert_eq!(options.errors.count(), 0);
+        assert_eq!(std::fs::read(&file.filepath).expect("read download"), body);
+    }
 
-        let files_result = pg.json::<canvas::FileResult>().await;
+    #[tokio::test]
+    async fn range_ignored_by_the_server_downloads_the_whole_file() {
+        let destination = TestDir::new("resume-ignored");
+        let body = file_body(4096);
+        let whole = body.clone();
+        let (url, server) = mock_server(1, move |_| Reply::Close(http_response("200 OK", "", &whole))).await;
+        let options = process_options(destination.path(), &url, &["--retries", "0"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download"), body.len());
+        partial_download(&options, &file, &body[..1000]);
+
+        atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        let requests = server.await.expect("mock server");
+        assert!(requests[0].contains("\r\nrange: bytes=1000-\r\n"), "{requests:?}");
+        assert_eq!(options.errors.count(), 0);
+        // Not appended to what was there
+        assert_eq!(std::fs::read(&file.filepath).expect("read download"), body);
+    }
 
-        match files_result {
-            // Got files
-            Ok(canvas::FileResult::Ok(files)) => {
-                let mut filtered_files = filter_files(&options, &path, files);
-                let mut lock = options.files_to_download.lock().await;
-                lock.append(&mut filtered_files);
-            }
+    #[tokio::test]
+    async fn segments_are_reassembled_in_place() {
+        const SIZE: usize = 300_001;
+        let destination = TestDir::new("segments");
+        let body = file_body(SIZE);
+        let served = body.clone();
+        let (url, server) = mock_server(3, move |request| {
+            let range = request.split("\r\nrange: bytes=").nth(1).and_then(|range| range.split("\r\n").next());
+            let (start, end) = range.and_then(|range| range.split_once('-')).expect("range request");
+            let (start, end): (usize, usize) = (start.parse().expect("start"), end.parse().expect("end"));
+            let headers = format!("Content-Range: bytes {start}-{end}/{SIZE}\r\n");
+            Reply::Close(http_response("206 Partial Content", &headers, &served[start..=end]))
+        })
+        .await;
+        let options = process_options(destination.path(), &url, &[]);
+        let file = download_fixture(&destination, format!("{url}/files/2/notes.pdf"), SIZE);
+        let tmp_path = destination.join("notes.pdf.tmp");
+        let segment_url = Url::parse(&file.url).expect("valid url");
+
+        let progress_bar = ProgressBar::hidden();
+        download_segments((&tmp_path, &file, &segment_url, SIZE as u64, 3, &progress_bar), &options)
+            .await
+            .expect("download segments");
+        let mut ranges: Vec<String> = server
+            .await
+            .expect("mock server")
+            .iter()
+            .filter_map(|request| request.split("\r\nrange: ").nth(1)?.split("\r\n").next().map(str::to_string))
+            .collect();
+        ranges.sort();
+        assert_eq!(ranges, ["bytes=0-100000", "bytes=100001-200001", "bytes=200002-300000"]);
+        assert!(std::fs::read(&tmp_path).expect("read download") == body, "segments differ from the file");
+    }
 
-            // Got status code
-            Ok(canvas::FileResult::Err { status }) => {
-                let course_has_no_files = status == "unauthorized";
-                if !course_has_no_files {
-                    eprintln!(
-                        "Failed to access files at link:{uri}, path:{path:?}, status:{status}",
-                    );
-                }
+    #[tokio::test]
+    async fn expired_download_url_is_refreshed_once() {
+        let destination = TestDir::new("expired-url");
+        let body = file_body(4096);
+        let served = body.clone();
+        let (url, server) = mock_server(3, move |request| {
+            if request.starts_with("get /api/v1/files/2 ") {
+                let address = request.split("\r\nhost: ").nth(1).and_then(|host| host.split("\r\n").next());
+                let refreshed = json!({
+                    "id": 2,
+                    "folder_id": 3,
+                    "display_name": "notes.pdf",
+                    "size": 4096,
+                    "url": format!("http://{}/files/2/download?verifier=fresh", address.expect("host header")),
+                    "updated_at": "2024-01-10T09:00:00Z",
+                    "locked_for_user": false,
+                });
+                Reply::Close(http_response("200 OK", "", refreshed.to_string().as_bytes()))
+            } else if request.starts_with("get /files/2/download?verifier=fresh ") {
+                Reply::Close(http_response("200 OK", "", &served))
+            } else {
+                Reply::Close(http_response("403 Forbidden", "", b"Signature expired"))
             }
+        })
+        .await;
+        let options = process_options(destination.path(), &url, &["--retries", "0"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download?verifier=stale"), body.len());
+
+        atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        let requests = server.await.expect("mock server");
+        assert!(requests[0].starts_with("get /files/2/download?verifier=stale "), "{requests:?}");
+        assert!(requests[1].starts_with("get /api/v1/files/2 "), "{requests:?}");
+        assert!(requests[2].starts_with("get /files/2/download?verifier=fresh "), "{requests:?}");
+        assert_eq!(options.errors.count(), 0);
+        assert_eq!(std::fs::read(&file.filepath).expect("read download"), body);
+        let requests_by_category = options.metrics.snapshot().requests_by_category;
+        assert_eq!((requests_by_category["download"], requests_by_category["url_refresh"]), (2, 1));
+    }
 
-            // Parse error
-            Err(e) => {
-                eprintln!("Error when getting files at link:{uri}, path:{path:?}\n{e:?}",);
+    #[tokio::test]
+    async fn server_errors_are_retried_and_counted() {
+        const N_ATTEMPTS: usize = 3;
+        let destination = TestDir::new("retried");
+        let body = file_body(4096);
+        let served = body.clone();
+        let mut n_requests = 0;
+        let (url, server) = mock_server(N_ATTEMPTS, move |_| {
+            n_requests += 1;
+            if n_requests < N_ATTEMPTS {
+                Reply::Close(http_response("503 Service Unavailable", "", b""))
+            } else {
+                Reply::Close(http_response("200 OK", "", &served))
             }
-        };
+        })
+        .await;
+        // Backoff never waits longer than the request timeout
+        let options = process_options(destination.path(), &url, &["--retries", "5", "--request-timeout", "1"]);
+        let file = download_fixture(&destination, format!("{url}/files/2/download"), body.len());
+
+        atomic_download_file(file.clone(), options.clone()).await.expect("download reported");
+        assert_eq!(server.await.expect("mock server").len(), N_ATTEMPTS);
+        assert_eq!(options.errors.count(), 0);
+        assert_eq!(std::fs::read(&file.filepath).expect("read download"), body);
+        let metrics = options.metrics.snapshot();
+        assert_eq!(metrics.retries_server_error, N_ATTEMPTS as u64 - 1);
+        assert_eq!(metrics.requests_by_category["download"], N_ATTEMPTS as u64);
+        assert_eq!(metrics.retries_throttled + metrics.retries_timeout + metrics.retries_network_error, 0);
     }
 
-    Ok(())
-}
+    // Copy of `file` downloaded at the version `updated_at`, as the state records it
+    fn downloaded_copy(options: &ProcessOptions, file: &File, updated_at: &str) {
+        std::fs::create_dir_all(file.filepath.parent().expect("parent folder")).expect("create folder");
+        std::fs::write(&file.filepath, "downloaded").expect("write copy");
+        let mtime = DateTime::parse_from_rfc3339(updated_at).expect("valid time").timestamp();
+        filetime::set_file_mtime(&file.filepath, filetime::FileTime::from_unix_time(mtime, 0)).expect("set mtime");
+        let relative_path = file.filepath.strip_prefix(&options.destination_folder).expect("inside destination");
+        options
+            .state
+            .lock()
+            .expect("state lock")
+            .files
+            .insert(relative_path.to_string_lossy().to_string(), updated_at.to_string());
+    }
 
-fn filter_files(options: &ProcessOptions, path: &Path, files: Vec<File>) -> Vec<File> {
-    fn updated(filepath: &PathBuf, new_modified: &str) -> bool {
-        (|| -> Result<bool> {
-            let old_modified = std::fs::metadata(filepath)?.modified()?;
-            let new_modified =
-                std::time::SystemTime::from(DateTime::parse_from_rfc3339(new_modified)?);
-            let updated = old_modified < new_modified;
-            if updated {
-                println!("Found update for {filepath:?}. Use -n to download updated files.");
-            }
-            Ok(updated)
-        })()
-        .unwrap_or(false)
+    #[test]
+    fn resumed_queue_keeps_what_is_left_to_download() {
+        // Updates on Canvas wait for -n, also when resumed
+        let runs: [(&[&str], &[&str]); 2] =
+            [(&[], &["CS101/new.pdf"]), (&["-n"], &["CS101/new.pdf", "CS101/updated.pdf"])];
+        for (flags, expected) in runs {
+            let destination = TestDir::new("resume-queue");
+            let options = process_options(destination.path(), "https://canvas.example.edu", flags);
+            let files = [
+                file_fixture(1, destination.join("CS101/new.pdf"), "2024-02-01T09:00:00Z"),
+                file_fixture(2, destination.join("CS101/updated.pdf"), "2024-02-01T09:00:00Z"),
+                file_fixture(3, destination.join("CS101/current.pdf"), "2024-01-01T09:00:00Z"),
+            ];
+            downloaded_copy(&options, &files[1], "2024-01-01T09:00:00+00:00");
+            downloaded_copy(&options, &files[2], "2024-01-01T09:00:00+00:00");
+
+            state::save_queue(destination.path(), &state::queued_files(destination.path(), &files)).expect("save queue");
+            let queue = state::load_queue(destination.path());
+            let queued: Vec<(u32, &str)> = queue.iter().map(|queued| (queued.file.id, queued.path.as_str())).collect();
+            assert_eq!(queued, [(1, "CS101/new.pdf"), (2, "CS101/updated.pdf"), (3, "CS101/current.pdf")]);
+
+            let listed = queue.iter().map(|queued| (queued.path.as_str(), queued.file.clone(), queued.name_prefix));
+            let resumed: Vec<PathBuf> = refilter_files(&options, listed).into_iter().map(|file| file.filepath).collect();
+            let expected: Vec<PathBuf> = expected.iter().map(|path| destination.join(path)).collect();
+            assert_eq!(resumed, expected, "{flags:?}");
+
+            state::save_queue(destination.path(), &[]).expect("remove queue");
+            assert!(!state::state_dir(destination.path()).join(state::QUEUE_FILE).exists());
+            assert!(state::load_queue(destination.path()).is_empty());
+        }
     }
 
-    // only download files that do not exist or are updated
-    files
-        .into_iter()
-        .map(|mut f| {
-            let sanitized_filename = sanitize_filename::sanitize(&f.display_name);
-            f.filepath = path.join(sanitized_filename);
-            f
-        })
-        .filter(|f| !f.locked_for_user)
-        .filter(|f| {
-            if DateTime::parse_from_rfc3339(&f.updated_at).is_ok() {
-                return true;
+    #[test]
+    fn files_renamed_on_canvas_are_renamed_locally() {
+        let listed_at = "2024-01-10T09:00:00Z";
+        // (updated_at on Canvas, old copy still on disk) --> (downloaded again, old copy moved to the new name)
+        let cases = [
+            (listed_at, true, false, true),
+            ("2024-02-01T09:00:00Z", true, true, false),
+            (listed_at, false, true, false),
+        ];
+        for (updated_at, old_copy, downloaded, moved) in cases {
+            let destination = TestDir::new("renamed");
+            let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+            let old_file = file_fixture(7, destination.join("CS101/week 3 slides.pdf"), listed_at);
+            if old_copy {
+                downloaded_copy(&options, &old_file, listed_at);
             }
-            eprintln!(
-                "Failed to parse updated_at time for {}, {}",
-                f.display_name, f.updated_at
+            options.manifest.lock().expect("manifest lock").record(
+                7,
+                "CS101/week 3 slides.pdf".to_string(),
+                listed_at.to_string(),
+                1024,
             );
-            false
-        })
-        .filter(|f| {
-            !f.filepath.exists() || (updated(&f.filepath, &f.updated_at) && options.download_newer)
+
+            let renamed = file_fixture(7, "Week 03 - Slides.pdf", updated_at);
+            let queued = filter_files(&options, &destination.join("CS101"), vec![renamed]);
+            let new_path = destination.join("CS101/Week 03 - Slides.pdf");
+            let queued: Vec<&Path> = queued.iter().map(|file| file.filepath.as_path()).collect();
+            assert_eq!(queued, if downloaded { vec![new_path.as_path()] } else { vec![] }, "{updated_at} {old_copy}");
+            assert_eq!(new_path.exists(), moved);
+            assert_eq!(old_file.filepath.exists(), old_copy && !moved);
+            if moved {
+                assert_eq!(std::fs::read_to_string(&new_path).expect("read renamed copy"), "downloaded");
+                let state = options.state.lock().expect("state lock");
+                assert_eq!(state.files.get("CS101/Week 03 - Slides.pdf").map(String::as_str), Some(listed_at));
+                assert!(!state.files.contains_key("CS101/week 3 slides.pdf"));
+                let manifest = options.manifest.lock().expect("manifest lock");
+                assert_eq!(manifest.lookup(7).map(|entry| entry.path.as_str()), Some("CS101/Week 03 - Slides.pdf"));
+            }
+        }
+    }
+
+    #[test]
+    fn locked_files_are_skipped_counted_and_recorded() {
+        for (flags, queued) in [(&[][..], vec!["open.pdf"]), (&["--include-locked"][..], vec!["open.pdf", "exam.pdf"])] {
+            let destination = TestDir::new("locked");
+            let options = process_options(destination.path(), "https://canvas.example.edu", flags);
+            let mut exam = file_fixture(8, "exam.pdf", "2024-01-10T09:00:00Z");
+            exam.locked_for_user = true;
+            let files = vec![file_fixture(7, "open.pdf", "2024-01-10T09:00:00Z"), exam];
+
+            let filtered = filter_files(&options, &destination.join("CS101"), files);
+            let names: Vec<&str> = filtered.iter().map(|file| file.display_name.as_str()).collect();
+            assert_eq!(names, queued, "{flags:?}");
+            let n_skipped = if flags.is_empty() { 1 } else { 0 };
+            assert_eq!(options.n_skipped_locked.load(Ordering::Relaxed), n_skipped);
+            // Either way, so it is reported once it unlocks
+            let manifest = options.manifest.lock().expect("manifest lock");
+            assert!(manifest.was_locked(8));
+            assert!(!manifest.was_locked(7));
+        }
+    }
+
+    #[test]
+    fn claimed_paths_are_suffixed_once_and_kept() {
+        let destination = TestDir::new("claim-path");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let notes = |id| file_fixture(id, destination.join("CS101/notes.pdf"), "2024-01-10T09:00:00Z");
+        assert_eq!(claim_path(&options, &notes(1)), Some(destination.join("CS101/notes.pdf")));
+        // Another file for the same path, listed in another folder with --flat or as an attachment
+        assert_eq!(claim_path(&options, &notes(2)), Some(destination.join("CS101/notes_2.pdf")));
+        // The same file found again, eg linked from a module
+        assert_eq!(claim_path(&options, &notes(1)), None);
+        assert_eq!(claim_path(&options, &notes(2)), None);
+
+        // Next run, the renamed file keeps its name even when seen first
+        let next_run = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        next_run.state.lock().expect("state lock").renamed = options.state.lock().expect("state lock").renamed.clone();
+        assert_eq!(claim_path(&next_run, &notes(2)), Some(destination.join("CS101/notes_2.pdf")));
+        assert_eq!(claim_path(&next_run, &notes(1)), Some(destination.join("CS101/notes.pdf")));
+    }
+
+    #[tokio::test]
+    async fn flat_layout_keeps_files_of_the_same_name_apart() {
+        let destination = TestDir::new("flat");
+        let (url, server) = mock_server(2, |request| {
+            let body = if request.starts_with("get /files/1/") { "week 1 notes" } else { "week 2 notes" };
+            Reply::Close(http_response("200 OK", "", body.as_bytes()))
         })
-        .collect()
-}
+        .await;
+        let options = process_options(destination.path(), &url, &["--flat"]);
+        let mut queued = Vec::new();
+        for (id, folder) in [(1, "CS101/Week 1"), (2, "CS101/Week 2")] {
+            let mut notes = file_fixture(id, "notes.pdf", "2024-01-10T09:00:00Z");
+            notes.url = format!("{url}/files/{id}/download");
+            notes.size = 12;
+            queued.extend(filter_files(&options, &destination.join(folder), vec![notes]));
+        }
+        let queued_paths: Vec<&Path> = queued.iter().map(|file| file.filepath.as_path()).collect();
+        assert_eq!(queued_paths, [destination.join("CS101/notes.pdf"), destination.join("CS101/notes_2.pdf")]);
 
-async fn process_html_links(
-    (html, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<()> {
+        std::fs::create_dir_all(destination.join("CS101")).expect("create course folder");
+        for file in queued {
+            atomic_download_file(file, options.clone()).await.expect("download reported");
+        }
+        server.await.expect("mock server");
+        assert_eq!(options.errors.count(), 0);
+        assert_eq!(std::fs::read_to_string(destination.join("CS101/notes.pdf")).expect("read notes"), "week 1 notes");
+        assert_eq!(std::fs::read_to_string(destination.join("CS101/notes_2.pdf")).expect("read notes"), "week 2 notes");
+        assert!(!destination.join("CS101/Week 1").exists());
+    }
 
-    // If file link is part of course files
-    let re = Regex::new(r"/courses/[0-9]+/files/[0-9]+").unwrap();
-    let file_links = Document::from(html.as_str())
-        .find(Name("a"))
-        .filter_map(|n| n.attr("href"))
-        .filter(|x| x.starts_with(&options.canvas_url))
-        .map(|x| Url::parse(x))
-        .filter(|x| x.is_ok())
-        .map(|x| x.unwrap())
-        .filter(|x| re.is_match(x.path()))
-        .map(|x| format!("{}/api/v1{}", options.canvas_url, x.path()))
-        .collect::<Vec<String>>();
-    
-    let mut link_files = join_all(file_links.into_iter()
-        .map(|x| process_file_id((x, path.clone()), options.clone())))
-        .await
-        .into_iter()
-        .filter_map(|x| x.ok())
-        .collect::<Vec<File>>();
+    #[test]
+    fn extension_case_collisions_are_suffixed_on_case_insensitive_destinations() {
+        let destination = TestDir::new("extension-case");
+        let listed = || {
+            vec![
+                file_fixture(4, "Notes.PDF", "2024-01-10T09:00:00Z"),
+                file_fixture(9, "notes.pdf", "2024-01-12T09:00:00Z"),
+            ]
+        };
+        let queued_paths = |options: &ProcessOptions, files: Vec<File>| -> Vec<PathBuf> {
+            filter_files(options, &destination.join("CS101"), files).into_iter().map(|file| file.filepath).collect()
+        };
+        // As probed on a FAT drive, see destination_case_insensitive()
+        let case_insensitive_options = || {
+            let mut options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+            Arc::get_mut(&mut options).expect("unshared options").case_insensitive = true;
+            options
+        };
 
-    // If image is from canvas it is likely the file url gives permission denied, so download from the CDN
-    let image_links = Document::from(html.as_str())
-        .find(Name("img"))
-        .filter_map(|n| n.attr("src"))
-        .filter(|x| x.starts_with(&options.canvas_url))
-        .filter(|x| !x.contains("equation_images"))
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>();
-    
-    link_files.append(join_all(image_links.into_iter()
-        .map(|x| prepare_link_for_download((x, path.clone()), options.clone())))
-        .await
-        .into_iter()
-        .filter_map(|x| x.ok())
-        .collect::<Vec<File>>().as_mut());
+        let case_sensitive = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let expected = [destination.join("CS101/Notes.PDF"), destination.join("CS101/notes.pdf")];
+        assert_eq!(queued_paths(&case_sensitive, listed()), expected);
+
+        let options = case_insensitive_options();
+        let expected = [destination.join("CS101/Notes.PDF"), destination.join("CS101/notes_9.pdf")];
+        assert_eq!(queued_paths(&options, listed()), expected);
+        let renamed = options.state.lock().expect("state lock").renamed.clone();
+        let notes = renamed.get("CS101/notes_9.pdf").expect("rename recorded");
+        assert_eq!((notes.identity.as_str(), notes.remote_name.as_str()), ("9", "notes.pdf"));
+
+        // Listed the other way around next time, the mapping stays
+        let next_run = case_insensitive_options();
+        next_run.state.lock().expect("state lock").renamed = renamed;
+        let reversed = listed().into_iter().rev().collect();
+        let expected = [destination.join("CS101/notes_9.pdf"), destination.join("CS101/Notes.PDF")];
+        assert_eq!(queued_paths(&next_run, reversed), expected);
+    }
 
-    let mut filtered_files = filter_files(&options, &path, link_files);
-    let mut lock = options.files_to_download.lock().await;
-    lock.append(&mut filtered_files);
+    #[tokio::test]
+    async fn courses_without_a_video_tool_get_no_videos_folder() {
+        let destination = TestDir::new("no-videos");
+        let server_url = Arc::new(std::sync::Mutex::new(String::new()));
+        let (url, server) = mock_server(2, {
+            let server_url = server_url.clone();
+            move |request| {
+                if request.starts_with("get /login/session_token") {
+                    let server_url = server_url.lock().expect("server url");
+                    let session = json!({ "session_url": format!("{server_url}/launch"), "requires_terms_acceptance": false });
+                    return Reply::Close(http_response("200 OK", "", session.to_string().as_bytes()));
+                }
+                // Launches into Canvas without a Panopto form
+                Reply::Close(http_response("200 OK", "", b"<html><body><form action=\"/other\"></form></body></html>"))
+            }
+        })
+        .await;
+        *server_url.lock().expect("server url") = url.clone();
+        let options = process_options(destination.path(), &url, &[]);
+        let videos = destination.join("CS101/videos");
+
+        process_videos((url, 1, videos.clone()), options.clone()).await.expect("no video tool is no error");
+        let requests = server.await.expect("mock server");
+        assert!(requests[1].starts_with("get /launch"), "{requests:?}");
+        assert!(!videos.exists());
+        assert!(!destination.join("CS101").exists());
+        assert_eq!(options.errors.count(), 0);
+    }
 
-    Ok(())
-}
+    // A module of course 1 linking the same file of course 2 twice, as in a cross-listed course
+    #[test]
+    fn module_items_of_another_course_download_once() {
+        let module_items_uri = "https://canvas.example.edu/api/v1/courses/1/modules/7/items?per_page=100";
+        let item_url = "https://canvas.example.edu/api/v1/courses/2/files/55";
+        let (item_context, cross_context) = module_item_file_context(module_items_uri, item_url);
+        assert_eq!(item_context, Some((FileContext::Course(2), 55)));
+        assert!(cross_context);
+
+        let file = || -> File {
+            let mut file = file_fixture(55, "/courses/CS101/modules/1_Week 1/syllabus.pdf", "2024-01-31T09:00:00Z");
+            file.source_context = Some(FileContext::Course(2).to_string());
+            file
+        };
+        let mut queue = Vec::new();
+        queue_module_files(&mut queue, vec![file()], cross_context);
+        queue_module_files(&mut queue, vec![file()], cross_context);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].id, 55);
+        assert_eq!(queue[0].source_context.as_deref(), Some("course_2"));
+    }
 
-async fn process_file_id(
-    (url, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<File> {
-    let url = url.trim_end_matches("/download");
+    #[test]
+    fn module_items_of_the_same_course_are_not_cross_context() {
+        let module_items_uri = "https://canvas.example.edu/api/v1/courses/1/modules/7/items";
+        let item_url = "https://canvas.example.edu/api/v1/courses/1/files/55";
+        assert_eq!(module_item_file_context(module_items_uri, item_url), (Some((FileContext::Course(1), 55)), false));
+    }
 
-    let file_resp = get_canvas_api(url.to_string(), &options).await?;
-    let file_result = file_resp.json::<canvas::File>().await;
-    match file_result {
-        Result::Ok(mut file) => {
-            let file_path = path.join(&file.display_name);
-            file.filepath = file_path;
-            return Ok(file);
+    // Response bodies as sent by Canvas for each class of failure
+    #[test]
+    fn api_errors_are_classified() {
+        use canvas::{parse_api_body, ApiError};
+        let parse = |status: u16, body: &str| {
+            let status = reqwest::StatusCode::from_u16(status).expect("valid status");
+            parse_api_body::<Vec<serde_json::Value>>(status, body, None).expect_err("error response")
+        };
+        let unauthenticated = r#"{"status":"unauthenticated","errors":[{"message":"user authorization required"}]}"#;
+        assert!(matches!(parse(401, unauthenticated), ApiError::Unauthorized));
+        // Some endpoints answer 200 with the error body
+        let unauthorized = r#"{"status":"unauthorized","errors":[{"message":"user not authorized to perform that action"}]}"#;
+        assert!(matches!(parse(200, unauthorized), ApiError::Unauthorized));
+        assert!(matches!(parse(403, unauthorized), ApiError::Unauthorized));
+
+        let not_found = r#"{"errors":[{"message":"The specified resource does not exist."}]}"#;
+        assert!(matches!(parse(404, not_found), ApiError::NotFound));
+        assert!(matches!(parse(200, not_found), ApiError::NotFound));
+
+        assert!(matches!(parse(403, "403 Forbidden (Rate Limit Exceeded)\n"), ApiError::RateLimited));
+        assert!(matches!(parse(429, ""), ApiError::RateLimited));
+
+        match parse(500, "<html><body>Internal Server Error</body></html>") {
+            ApiError::Status(status) => assert_eq!(status, "500 Internal Server Error"),
+            e => panic!("{e:?}"),
         }
-        Err(e) => {
-            eprintln!("Error when getting file info at link:{url}, path:{path:?}\n{e:?}",);
-            return Err(Into::into(e));
+        match parse(200, r#"{"status":"locked","errors":[{"message":"This course has been locked"}]}"#) {
+            ApiError::Status(status) => assert_eq!(status, "locked"),
+            e => panic!("{e:?}"),
         }
     }
-}
-async fn prepare_link_for_download(
-    (link, path): (String, PathBuf),
-    options: Arc<ProcessOptions>,
-) -> Result<File> {
 
-    let resp = options
-        .client
-        .head(&link)
-        .bearer_auth(&options.canvas_token)
-        .timeout(Duration::from_secs(10))
-        .send()
-        .await?;
-    let headers = resp.headers();
-    // get filename out of Content-Disposition header
-    let filename = headers
-        .get(header::CONTENT_DISPOSITION)
-        .and_then(|x| x.to_str().ok())
-        .and_then(|x| {
-            let re = Regex::new(r#"filename="(.*)""#).unwrap();
-            re.captures(x)
-        })
-        .and_then(|x| x.get(1))
-        .map(|x| x.as_str())
-        .unwrap_or_else(|| {
-            let re = Regex::new(r"/([^/]+)$").unwrap();
-            re.captures(&link)
-                .and_then(|x| x.get(1))
-                .map(|x| x.as_str())
-                .unwrap_or("unknown")
-        });
-    // last-modified header to TZ string
-    let updated_at = headers
-        .get(header::LAST_MODIFIED)
-        .and_then(|x| x.to_str().ok())
-        .and_then(|x| {
-            let dt = DateTime::parse_from_rfc2822(x).ok()?;
-            Some(dt.with_timezone(&Local).to_rfc3339())
-        })
-        .unwrap_or_else(|| Local::now().to_rfc3339());
-    
-    let file = File {
-        id: 0,
-        folder_id: 0,
-        display_name: filename.to_string(),
-        size: 0,
-        url: link.clone(),
-        updated_at: updated_at,
-        locked_for_user: false,
-        filepath: path.join(filename),
-    };
-    Ok(file)
-}
+    #[test]
+    fn unexpected_api_bodies_keep_their_saved_path() {
+        use canvas::{parse_api_body, ApiError};
+        let body_path = Path::new("/tmp/canvasdl-body-1.json");
+        // A single folder where the listing is expected
+        let body = r#"{"id":3,"name":"course files","full_name":"course files"}"#;
+        match parse_api_body::<Vec<canvas::Folder>>(reqwest::StatusCode::OK, body, Some(body_path)) {
+            Err(ApiError::ParseFailed { body_path: Some(path), error }) => {
+                assert_eq!(path, body_path);
+                assert!(!error.is_empty());
+            }
+            result => panic!("{:?}", result.map(|folders| folders.len())),
+        }
+        let folders = parse_api_body::<Vec<serde_json::Value>>(reqwest::StatusCode::OK, "[]", Some(body_path))
+            .expect("empty listing");
+        assert!(folders.is_empty());
+    }
+
+    #[test]
+    fn name_template_placeholders_without_value_take_their_separators() {
+        let value = |name: &str| match name {
+            "id" => "12".to_string(),
+            "display_name" => "slides.pdf".to_string(),
+            _ => String::new(),
+        };
+        assert_eq!(expand_name_template("{id}_{display_name}", value), "12_slides.pdf");
+        // Missing in the middle, at the start and at the end
+        assert_eq!(expand_name_template("{id}_{folder}_{display_name}", value), "12_slides.pdf");
+        assert_eq!(expand_name_template("{updated_date} - {display_name}", value), "slides.pdf");
+        assert_eq!(expand_name_template("{display_name}_{folder}", value), "slides.pdf");
+    }
+
+    #[test]
+    fn name_template_separators_are_collapsed() {
+        let value = |name: &str| match name {
+            "id" => "12".to_string(),
+            "folder" => "_Week 1_".to_string(),
+            "display_name" => "slides.pdf".to_string(),
+            _ => String::new(),
+        };
+        assert_eq!(expand_name_template("{id}__{display_name}", value), "12_slides.pdf");
+        assert_eq!(expand_name_template("{folder}_{display_name}", value), "Week 1_slides.pdf");
+        assert_eq!(expand_name_template("--{id}  {display_name}..", value), "12 slides.pdf");
+    }
 
-async fn get_pages(link: String, options: &ProcessOptions) -> Result<Vec<Response>> {
-    fn parse_next_page(resp: &Response) -> Option<String> {
-        // Parse LINK header
-        let links = resp.headers().get(header::LINK)?.to_str().ok()?; // ok to not have LINK header
-        let rels = parse_link_header::parse_with_rel(links).unwrap_or_else(|e| {
-            panic!(
-                "Error parsing header for next page, uri={}, err={e:?}",
-                resp.url()
-            )
-        });
+    #[test]
+    fn empty_template_names_fall_back_to_the_display_name() {
+        let mut file = file_fixture(12, "/courses/CS101/files/slides.pdf", "not a timestamp");
+        let folder = Path::new("/courses/CS101/files");
+        assert_eq!(expand_name_template("{updated_date}", |_| String::new()), "");
+        assert_eq!(file_name(Some("{updated_date}"), &file, folder), "slides.pdf");
+        assert_eq!(file_name(Some("_{updated_date}_"), &file, folder), "slides.pdf");
+        file.name_prefix = Some(7);
+        assert_eq!(file_name(Some("{updated_date}"), &file, folder), "7_slides.pdf");
+        assert_eq!(file_name(None, &file, folder), "7_slides.pdf");
+        // Sanitized after expansion
+        file.display_name = "a/b.pdf".to_string();
+        assert_eq!(file_name(Some("{folder} {display_name}"), &file, folder), "files ab.pdf");
+    }
 
-        // Is last page?
-        let nex = rels.get("next")?; // ok to not have "next"
-        let cur = rels
-            .get("current")
-            .unwrap_or_else(|| panic!("Could not find current page for {}", resp.url()));
-        let last = rels
-            .get("last")?;
-        if cur == last {
-            return None;
+    #[test]
+    fn name_templates_with_unknown_placeholders_are_refused() {
+        assert!(parse_name_template("{id}_{display_name}").is_ok());
+        let e = parse_name_template("{id}_{name}").expect_err("unknown placeholder");
+        assert!(e.contains("{name}"), "{e}");
+        assert!(parse_name_template("  ").is_err());
+    }
+
+    fn course(course_code: &str, name: &str) -> canvas::Course {
+        serde_json::from_value(json!({
+            "id": 1,
+            "name": name,
+            "course_code": course_code,
+            "enrollment_term_id": 2,
+        }))
+        .expect("course fixture")
+    }
+
+    #[test]
+    fn nested_layouts_create_their_course_folders() {
+        let destination = TestDir::new("layout");
+        let layout = ["--layout", "{term_name}/{course_code} - {course_name}"];
+        let destination_arg = destination.path().to_string_lossy();
+        let argv = ["canvas-downloader", "-d", &destination_arg].into_iter().chain(layout);
+        let args = CommandLineOptions::parse_from(argv);
+        let options = process_options(destination.path(), "https://canvas.example.edu", &layout);
+
+        // Listed without a term
+        let mut intro = course("CS101", "Intro: Programming");
+        let folder = tracked_course_folder(&intro, &args, &options).expect("course folder");
+        assert_eq!(folder, destination.join("_/CS101 - Intro Programming"));
+        options.create_folder(&folder).expect("create course folder");
+        assert!(folder.is_dir());
+
+        intro.term = Some(canvas::Term { id: 2, name: "Spring 2024".to_string(), start_at: None });
+        assert_eq!(course_folder_path(&intro, &args), destination.join("Spring 2024/CS101 - Intro Programming"));
+
+        // Files below the course folder are attributed to it, not to the term folder
+        options.course_folders.lock().expect("course folders lock").push(folder.clone());
+        assert_eq!(course_folder_of(&folder.join("announcements/12_Week 1"), &options), folder);
+    }
+
+    #[test]
+    fn file_links_are_found_in_every_shape_the_editor_writes() {
+        let destination = TestDir::new("file-links");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let html = r#"
+            <p><a href="https://canvas.example.edu/courses/1/files/2?wrap=1">absolute</a></p>
+            <p><a href="/courses/1/files/3/download">relative</a></p>
+            <p><a class="instructure_file_link inline_disabled" href="/files/4"
+                data-api-endpoint="https://canvas.example.edu/api/v1/courses/1/files/4">api endpoint</a></p>
+            <p><a class="instructure_file_link" href="/preview" data-id="5">data id</a></p>
+            <p><a href="/media_attachments_iframe/6?type=video">video</a></p>
+            <p><a href="https://other.example.com/courses/1/files/7">other host</a></p>
+            <p><a href="https://canvas.example.edu/courses/1/pages/week-1">page</a></p>
+        "#;
+        let links: Vec<Option<String>> =
+            Document::from(html).find(Name("a")).map(|link| file_api_link(&link, &options)).collect();
+        let api = |path: &str| Some(format!("https://canvas.example.edu/api/v1/{path}"));
+        assert_eq!(
+            links,
+            [
+                api("courses/1/files/2"),
+                api("courses/1/files/3"),
+                api("courses/1/files/4"),
+                api("files/5"),
+                api("files/6"),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn announcements_are_listed_for_the_whole_course() {
+        let destination = TestDir::new("announcements");
+        let course_url = "https://canvas.example.edu/api/v1/courses/123/";
+        let path = destination.join("CS101");
+        let range = |url: &str| -> (String, String) {
+            let url = Url::parse(url).expect("announcements url");
+            let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(url.path(), "/api/v1/announcements");
+            assert_eq!(query.get("context_codes[]").map(String::as_str), Some("course_123"));
+            (query["start_date"].clone(), query["end_date"].clone())
         };
 
-        // Next page
-        Some(nex.raw_uri.clone())
+        // Course dates unknown: from the epoch until tomorrow, as the listing would stop at today
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let (start, end) = range(&announcements_url(course_url, &path, &options));
+        assert_eq!(start, "1970-01-01T00:00:00Z");
+        assert!(DateTime::parse_from_rfc3339(&end).expect("end date") > Utc::now());
+
+        let course_dates = CourseRules {
+            start_at: DateTime::parse_from_rfc3339("2024-01-08T00:00:00+01:00").ok(),
+            end_at: DateTime::parse_from_rfc3339("2099-06-30T00:00:00Z").ok(),
+            ..CourseRules::default()
+        };
+        options.course_rules.lock().expect("course rules lock").insert(path.clone(), course_dates);
+        let range_of_course = range(&announcements_url(course_url, &path, &options));
+        assert_eq!(range_of_course, ("2024-01-07T23:00:00Z".to_string(), "2099-06-30T00:00:00Z".to_string()));
+
+        // --since and --until narrow it down
+        let flags = ["--since", "2024-02-01T00:00:00Z", "--until", "2024-03-01T00:00:00Z"];
+        let options = process_options(destination.path(), "https://canvas.example.edu", &flags);
+        options.course_rules.lock().expect("course rules lock").insert(path.clone(), course_dates);
+        let narrowed = range(&announcements_url(course_url, &path, &options));
+        assert_eq!(narrowed, ("2024-02-01T00:00:00Z".to_string(), "2024-03-01T00:00:00Z".to_string()));
     }
 
-    let mut link = Some(link);
-    let mut resps = Vec::new();
+    #[test]
+    fn announcements_are_read_from_the_listing() {
+        let body = json!([{
+            "id": 42,
+            "title": "Exam moved",
+            "message": "<p>See <a href=\"/courses/123/files/9\">the schedule</a></p>",
+            "posted_at": "2024-02-05T10:00:00Z",
+            "last_reply_at": null,
+            "read_state": "unread",
+            "unread_count": 0,
+            "context_code": "course_123",
+            "is_announcement": true,
+            "attachments": [{
+                "id": 9,
+                "folder_id": 3,
+                "display_name": "schedule.pdf",
+                "size": 2048,
+                "url": "https://canvas.example.edu/files/9/download?verifier=abc",
+                "updated_at": "2024-02-05T09:00:00Z",
+                "locked_for_user": false,
+            }],
+        }])
+        .to_string();
+        let announcements =
+            canvas::parse_api_body::<Vec<canvas::Discussion>>(reqwest::StatusCode::OK, &body, None).expect("announcements");
+        let [announcement] = announcements.as_slice() else { panic!("one announcement, got {announcements:?}") };
+        assert_eq!((announcement.id, announcement.title.as_str()), (42, "Exam moved"));
+        assert!(announcement.message.contains("/courses/123/files/9"));
+        assert_eq!(announcement.posted_at.as_deref(), Some("2024-02-05T10:00:00Z"));
+        assert_eq!(announcement.last_reply_at, None);
+        assert_eq!((announcement.read_state.as_deref(), announcement.unread_count), (Some("unread"), 0));
+        let attachments: Vec<(u32, &str, u64)> = announcement
+            .attachments
+            .iter()
+            .map(|file| (file.id, file.display_name.as_str(), file.size))
+            .collect();
+        assert_eq!(attachments, [(9, "schedule.pdf", 2048)]);
+    }
 
-    while let Some(uri) = link {
-        // GET request
-        let resp = get_canvas_api(uri, options).await?;
+    #[test]
+    fn interrupted_artifact_writes_keep_the_previous_artifact() {
+        let destination = TestDir::new("artifact");
+        let report = destination.join("report.json");
+        write_artifact(&report, b"{\"run\": 1}").expect("write first report");
+
+        // Left behind by a run killed halfway through writing the next one
+        std::fs::write(destination.join(".report.json.1.part"), b"{\"ru").expect("write partial report");
+        assert_eq!(std::fs::read(&report).expect("read report"), b"{\"run\": 1}");
+
+        // Fails before the rename, here as the temporary file can not be created
+        let tmp_path = destination.join(format!(".report.json.{}.part", std::process::id()));
+        std::fs::create_dir(&tmp_path).expect("block temporary file");
+        write_artifact(&report, b"{\"run\": 2}").expect_err("temporary file is a folder");
+        assert_eq!(std::fs::read(&report).expect("read report"), b"{\"run\": 1}");
+
+        std::fs::remove_dir(&tmp_path).expect("unblock temporary file");
+        write_artifact(&report, b"{\"run\": 3}").expect("write next report");
+        assert_eq!(std::fs::read(&report).expect("read report"), b"{\"run\": 3}");
+        assert!(!tmp_path.exists());
+    }
 
-        // Get next page before returning for json
-        link = parse_next_page(&resp);
-        resps.push(resp);
+    #[test]
+    fn course_filters_match_code_or_name_ignoring_case() {
+        let algorithms = course("CS3230", "Design and Analysis of Algorithms");
+        let filters = |filters: &[&str]| filters.iter().map(ToString::to_string).collect::<Vec<String>>();
+        assert!(course_matches(&algorithms, &filters(&["CS3230"])));
+        assert!(course_matches(&algorithms, &filters(&["cs32"])));
+        assert!(course_matches(&algorithms, &filters(&["ALGORITHMS"])));
+        // Any filter may match
+        assert!(course_matches(&algorithms, &filters(&["MA1521", "analysis"])));
+        assert!(!course_matches(&algorithms, &filters(&["MA1521"])));
+        // Blank filters would match everything
+        assert!(!course_matches(&algorithms, &filters(&["", "  "])));
+        assert!(!course_matches(&algorithms, &filters(&[])));
     }
-    Ok(resps)
-}
 
-fn sanitize_foldername<S: AsRef<str>>(name: S) -> String {
-    let name = name.as_ref();
-    let rex = Regex::new(r#"[/\?<.">\\:\*\|":]"#).unwrap();
+    #[test]
+    fn trailing_arguments_are_course_filters() {
+        let args = CommandLineOptions::parse_from(["canvas-downloader", "-c", "creds.json", "CS3230", "ma1521"]);
+        assert_eq!(args.course_filters, ["CS3230", "ma1521"]);
+        let args = CommandLineOptions::parse_from(["canvas-downloader", "-c", "creds.json"]);
+        assert!(args.course_filters.is_empty());
+    }
 
-    let name_modified = rex.replace_all(&name, "");
+    // Files of CS101, MA1521 and CS101 again, as found by the crawl
+    fn download_queue() -> Vec<File> {
+        [
+            (1, "CS101", 300, "2024-01-10T09:00:00Z"),
+            (2, "MA1521", 0, "2024-03-01T09:00:00Z"),
+            (3, "CS101", 20, "2024-02-01T09:00:00Z"),
+            (4, "MA1521", 300, "2024-01-01T09:00:00Z"),
+        ]
+        .into_iter()
+        .map(|(id, course, size, updated_at)| {
+            let mut file = file_fixture(id, Path::new("/courses").join(course).join(format!("file {id}")), updated_at);
+            file.size = size;
+            file
+        })
+        .collect()
+    }
 
-    return String::from(name_modified.trim());
-}
+    fn ordered(order: DownloadOrder) -> Vec<u32> {
+        let mut files = download_queue();
+        let course_of = |f: &File| f.filepath.parent().expect("file in a course").to_path_buf();
+        order_downloads(&mut files, order, course_of);
+        files.iter().map(|f| f.id).collect()
+    }
 
-async fn get_canvas_api(url: String, options: &ProcessOptions) -> Result<Response> {
-    let mut query_pairs : Vec<(String, String)> = Vec::new();
-    // insert into query_pairs from url.query_pairs();
-    for (key, value) in Url::parse(&url)?.query_pairs() {
-        query_pairs.push((key.to_string(), value.to_string()));
+    #[test]
+    fn downloads_are_ordered_by_size() {
+        // Unknown sizes last, equal sizes in the order they were found
+        assert_eq!(ordered(DownloadOrder::Smallest), [3, 1, 4, 2]);
+        assert_eq!(ordered(DownloadOrder::Largest), [1, 4, 3, 2]);
     }
-    for retry in 0..3 {
-        let resp = options
-            .client
-            .get(&url)
-            .query(&query_pairs)
-            .bearer_auth(&options.canvas_token)
-            .timeout(Duration::from_secs(10))
-            .send()
-            .await;
 
-        match resp {
-            Ok(resp) => {
-                if resp.status() != reqwest::StatusCode::FORBIDDEN || retry == 2 {
-                    return Ok(resp)
-                }
-            },
-            Err(e) => {println!("Canvas request error uri: {} {}", url, e); return Err(e.into())},
-        }
+    #[test]
+    fn downloads_are_ordered_by_update() {
+        assert_eq!(ordered(DownloadOrder::Newest), [2, 3, 1, 4]);
+    }
 
-        let wait_time = Duration::from_millis(rand::thread_rng().gen_range(0..1000 * 2_u64.pow(retry)));
-        println!("Got 403 for {}, waiting {:?} before retrying, retry {}", url, wait_time, retry);
-        tokio::time::sleep(wait_time).await;
-        
+    #[test]
+    fn downloads_are_grouped_by_course() {
+        // Courses in the order they were crawled, files of a course in the order they were found
+        assert_eq!(ordered(DownloadOrder::Course), [1, 3, 2, 4]);
     }
-    Err(Error::msg("canvas request failed"))
-}
 
-mod canvas {
-    use std::sync::atomic::AtomicUsize;
+    fn budgeted(max_total_bytes: u64, options: &ProcessOptions) -> (Vec<u32>, Vec<u32>, usize) {
+        let mut files = download_queue();
+        let (deferred, n_unknown_size) = apply_download_budget(&mut files, max_total_bytes, options);
+        let ids = |files: &[File]| files.iter().map(|f| f.id).collect();
+        (ids(&files), ids(&deferred), n_unknown_size)
+    }
 
-    use serde::{Deserialize, Serialize};
-    use tokio::sync::Mutex;
+    #[test]
+    fn download_budget_counts_unknown_sizes_as_0() {
+        let destination = TestDir::new("budget");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        assert_eq!(disk::required_space(&download_queue()), (620, 1));
+        // Newest first, the file of unknown size takes none of the budget
+        assert_eq!(budgeted(320, &options), (vec![2, 3, 1], vec![4], 1));
+        assert_eq!(budgeted(10, &options), (vec![2], vec![3, 1, 4], 1));
+        assert_eq!(budgeted(1000, &options), (vec![2, 3, 1, 4], vec![], 1));
+    }
 
-    #[derive(Clone, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct Credentials {
-        pub canvas_url: String,
-        pub canvas_token: String,
+    #[test]
+    fn download_budget_always_keeps_the_first_file() {
+        let destination = TestDir::new("budget-first");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        // Deferred by the previous run, it goes first although larger than the budget on its own
+        let previously_deferred: Vec<File> = download_queue().into_iter().filter(|f| f.id == 4).collect();
+        state::save_deferred(destination.path(), &previously_deferred).expect("save deferred");
+        assert_eq!(budgeted(10, &options), (vec![4], vec![2, 3, 1], 0));
+        assert_eq!(budgeted(320, &options), (vec![4, 2, 3], vec![1], 1));
     }
 
-    #[derive(Deserialize)]
-    pub struct Course {
-        pub id: u32,
-        pub name: String,
-        pub course_code: String,
-        pub enrollment_term_id: u32,
+    #[test]
+    fn downloads_use_the_client_of_their_host() {
+        let destination = TestDir::new("client-for");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        options.video_hosts.lock().expect("video hosts lock").insert("cdn.panopto.example.com".to_string());
+        let client_for_url = |url: &str| client_for(&options, &Url::parse(url).expect("valid url"));
+
+        let (client, send_token) = client_for_url("https://canvas.example.edu/files/2/download?download_frd=1");
+        assert!(std::ptr::eq(client, &options.storage_client) && send_token);
+        let (client, send_token) = client_for_url("https://cdn.panopto.example.com/sessions/1/segment_0.ts");
+        assert!(std::ptr::eq(client, &options.video_client) && !send_token);
+        // Signed file storage URLs, and Canvas lookalikes
+        for url in ["https://files.instructure-uploads.example.com/1/notes.pdf", "https://canvas.example.edu.evil.test/x"] {
+            let (client, send_token) = client_for_url(url);
+            assert!(std::ptr::eq(client, &options.storage_client) && !send_token, "{url}");
+        }
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct User {
-        pub id: u32,
-        pub name: String,
+    // Found in the Files tree, as a module item and as a link in an assignment description
+    #[test]
+    fn file_queued_in_several_places_downloads_once() {
+        let mut files = vec![
+            file_fixture(55, "/courses/CS101/files/syllabus.pdf", "2024-01-31T09:00:00Z"),
+            file_fixture(55, "/courses/CS101/modules/1_Week 1/syllabus.pdf", "2024-01-31T09:00:00Z"),
+            file_fixture(55, "/courses/CS101/assignments/Essay/syllabus.pdf", "2024-01-31T09:00:00Z"),
+            file_fixture(56, "/courses/CS101/files/notes.pdf", "2024-01-31T09:00:00Z"),
+        ];
+        let copies = dedup_downloads(&mut files, false);
+        let queued: Vec<&Path> = files.iter().map(|f| f.filepath.as_path()).collect();
+        assert_eq!(queued, [Path::new("/courses/CS101/files/syllabus.pdf"), Path::new("/courses/CS101/files/notes.pdf")]);
+        assert_eq!(copies.len(), 2);
+        for (copy, source) in &copies {
+            assert_eq!(copy.id, 55);
+            assert_eq!(source, Path::new("/courses/CS101/files/syllabus.pdf"));
+        }
     }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum FolderResult {
-        Err { status: String },
-        Ok(Vec<Folder>),
+    #[test]
+    fn same_target_path_keeps_the_newest() {
+        let mut files = vec![
+            file_fixture(55, "/courses/CS101/files/syllabus.pdf", "2024-01-31T09:00:00Z"),
+            file_fixture(55, "/courses/CS101/files/syllabus.pdf", "2024-02-01T10:00:00Z"),
+            file_fixture(55, "/courses/CS101/files/syllabus.pdf", "2024-01-01T09:00:00Z"),
+            file_fixture(57, "/courses/CS101/files/Syllabus.pdf", "2024-01-01T09:00:00Z"),
+        ];
+        let copies = dedup_downloads(&mut files.clone(), false);
+        assert!(copies.is_empty());
+        dedup_downloads(&mut files, true);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].updated_at, "2024-02-01T10:00:00Z");
     }
 
-    #[derive(Deserialize)]
-    pub struct Folder {
-        pub id: u32,
-        pub name: String,
-        pub folders_url: String,
-        pub files_url: String,
-        pub for_submissions: bool,
-        pub can_upload: bool,
-        pub parent_folder_id: Option<u32>,
+    #[test]
+    fn tmp_names_differ_by_path_and_version() {
+        let first = file_fixture(12, "/courses/CS101/discussions/12_submission.pdf", "2024-01-31T09:00:00Z");
+        let second = file_fixture(13, "/courses/CS101/discussions/13_submission.pdf", "2024-01-31T09:00:00Z");
+        let updated = file_fixture(12, "/courses/CS101/discussions/12_submission.pdf", "2024-02-01T10:00:00Z");
+        assert_ne!(tmp_file_name(&first, true), tmp_file_name(&second, true));
+        assert_ne!(tmp_file_name(&first, true), tmp_file_name(&updated, true));
+        // Same across runs when resumable, else only picked up by this run
+        assert_eq!(tmp_file_name(&first, true), tmp_file_name(&first.clone(), true));
+        let own = tmp_file_name(&first, false);
+        assert!(own.ends_with(&format!(".{}.tmp", std::process::id())), "{own}");
     }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum FileResult {
-        Err { status: String },
-        Ok(Vec<File>),
+    // Two submission.pdf attachments written to the shared tmp folder at the same time, then moved into place
+    #[test]
+    fn same_named_files_download_side_by_side() {
+        let dest = TestDir::new("same-named");
+        let tmp_dir = dest.join(".canvasdl").join("tmp");
+        std::fs::create_dir_all(&tmp_dir).expect("create tmp dir");
+        let files = [12, 13].map(|id| {
+            file_fixture(id, dest.join(format!("{id}_submission.pdf")), "2024-01-31T09:00:00Z")
+        });
+        std::thread::scope(|scope| {
+            for file in &files {
+                let tmp_path = tmp_dir.join(tmp_file_name(file, false));
+                scope.spawn(move || {
+                    let mut tmp = std::fs::File::create(&tmp_path).expect("create tmp file");
+                    for _ in 0..64 {
+                        tmp.write_all(&[file.id as u8; 1024]).expect("write chunk");
+                        std::thread::yield_now();
+                    }
+                    drop(tmp);
+                    move_file(&tmp_path, &file.filepath).expect("move into place");
+                });
+            }
+        });
+        for file in &files {
+            let body = std::fs::read(&file.filepath).expect("downloaded file");
+            assert_eq!(body.len(), 64 * 1024);
+            assert!(body.iter().all(|byte| *byte == file.id as u8), "{:?} mixed up", file.filepath);
+        }
+        assert_eq!(std::fs::read_dir(&tmp_dir).expect("tmp dir").count(), 0);
     }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum PageResult {
-        Err { status: String },
-        Ok(Vec<Page>),
+    // rename() of a --tmp-dir on another filesystem
+    fn cross_device(_: &Path, _: &Path) -> std::io::Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::CrossesDevices))
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Page {
-        pub page_id: u32,
-        pub url: String,
-        pub title: String,
-        pub updated_at: String,
-        pub locked_for_user: bool,
+    #[test]
+    fn move_across_filesystems_falls_back_to_copy() {
+        let dest = TestDir::new("exdev");
+        let (tmp_path, filepath) = (dest.join("download.tmp"), dest.join("slides.pdf"));
+        std::fs::write(&tmp_path, b"slides").expect("write tmp file");
+        let mtime = filetime::FileTime::from_unix_time(1_706_691_600, 0);
+        filetime::set_file_mtime(&tmp_path, mtime).expect("set mtime");
+
+        move_file_with(&tmp_path, &filepath, cross_device).expect("copy fallback");
+        assert_eq!(std::fs::read(&filepath).expect("moved file"), b"slides");
+        let metadata = std::fs::metadata(&filepath).expect("moved file");
+        assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), mtime);
+        assert!(!tmp_path.exists());
+        // Nothing but the file itself, the partial copy was renamed into place
+        assert_eq!(std::fs::read_dir(dest.path()).expect("dest").count(), 1);
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct PageBody {
-        pub page_id: u32,
-        pub url: String,
-        pub title: String,
-        pub body: String,
-        pub updated_at: String,
-        pub locked_for_user: bool,
+    #[test]
+    fn failed_copy_fallback_keeps_the_tmp_file() {
+        let dest = TestDir::new("exdev-failed");
+        let tmp_path = dest.join("download.tmp");
+        std::fs::write(&tmp_path, b"slides").expect("write tmp file");
+        // The folder of the destination is gone, eg the network share dropped
+        let filepath = dest.join("unmounted").join("slides.pdf");
+        let e = move_file_with(&tmp_path, &filepath, cross_device).expect_err("copy fails");
+        assert!(e.to_string().contains("copy fallback failed"), "{e}");
+        assert!(tmp_path.exists());
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct ModuleSection {
-        pub id: u32,
-        pub items_url: String,
-        pub name: String,
+    #[test]
+    fn other_rename_errors_are_not_copied() {
+        let dest = TestDir::new("rename-denied");
+        let (tmp_path, filepath) = (dest.join("download.tmp"), dest.join("slides.pdf"));
+        std::fs::write(&tmp_path, b"slides").expect("write tmp file");
+        let denied = |_: &Path, _: &Path| Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        move_file_with(&tmp_path, &filepath, denied).expect_err("rename fails");
+        assert!(!filepath.exists());
+        assert!(tmp_path.exists());
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    #[serde(rename_all = "camelCase")]
+    // Storage taking its time, counting the bytes that reached it
+    struct SlowStorage {
+        disk: tokio::io::DuplexStream,
+        in_flight: Arc<std::sync::Mutex<(usize, usize)>>,
+    }
 
-    pub struct ModuleItem {
-        pub id: u32,
-        pub title: String,
-        pub Type: String,
-        #[serde(default)]
-        pub url: Option<String>,
+    impl tokio::io::AsyncWrite for SlowStorage {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let written = std::pin::Pin::new(&mut self.disk).poll_write(cx, buf);
+            if let std::task::Poll::Ready(Ok(n)) = written {
+                self.in_flight.lock().expect("in flight").0 -= n;
+            }
+            written
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.disk).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.disk).poll_shutdown(cx)
+        }
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn slow_storage_holds_the_buffered_bytes_budget() {
+        const BUDGET: usize = 32 * 1024;
+        const WRITE_BUFFER: usize = 16 * 1024;
+        const CHUNK: usize = 4 * 1024;
+        const N_DOWNLOADS: usize = 4;
+        const N_CHUNKS: usize = 32;
+        let budget = tokio::sync::Semaphore::new(BUDGET);
+        // Bytes received but not on disk yet, and the most there were at once
+        let in_flight = Arc::new(std::sync::Mutex::new((0, 0)));
+        let downloads = (0..N_DOWNLOADS).map(|_| {
+            let (disk, mut storage) = tokio::io::duplex(1024);
+            let slow_storage = SlowStorage { disk, in_flight: in_flight.clone() };
+            let stored = tokio::spawn(async move {
+                let mut stored = 0;
+                let mut buf = [0; 1024];
+                loop {
+                    tokio::time::sleep(Duration::from_micros(200)).await;
+                    match storage.read(&mut buf).await.expect("read storage") {
+                        0 => return stored,
+                        n => stored += n,
+                    }
+                }
+            });
+            let (budget, in_flight) = (&budget, in_flight.clone());
+            async move {
+                let mut writer = BudgetedWriter::new(slow_storage, WRITE_BUFFER, budget, BUDGET as u32);
+                for _ in 0..N_CHUNKS {
+                    {
+                        let mut in_flight = in_flight.lock().expect("in flight");
+                        in_flight.0 += CHUNK;
+                        in_flight.1 = in_flight.1.max(in_flight.0);
+                    }
+                    writer.write(&[7; CHUNK]).await.expect("write chunk");
+                }
+                writer.flush().await.expect("flush");
+                writer.file.shutdown().await.expect("close storage");
+                drop(writer);
+                stored.await.expect("storage")
+            }
+        });
+        let stored = join_all(downloads).await;
+        assert!(stored.iter().all(|stored| *stored == N_CHUNKS * CHUNK), "{stored:?}");
+        let (in_flight, max_in_flight) = *in_flight.lock().expect("in flight");
+        assert_eq!(in_flight, 0);
+        // Each download may have one chunk received and waiting for its budget on top
+        assert!(max_in_flight <= BUDGET + N_DOWNLOADS * CHUNK, "{max_in_flight} bytes in flight");
+        assert_eq!(budget.available_permits(), BUDGET);
+    }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum ModuleResult {
-        Err { status: String },
-        Ok(Vec<ModuleSection>),
+    // Storage counting the writes that reach it, each one a syscall on a real file
+    #[derive(Default)]
+    struct CountingStorage {
+        n_writes: usize,
+        bytes: usize,
     }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum ModuleItemsResult {
-        Err { status: String },
-        Ok(Vec<ModuleItem>),
+    impl tokio::io::AsyncWrite for CountingStorage {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.n_writes += 1;
+            self.bytes += buf.len();
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
     }
 
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum AssignmentResult {
-        Err { status: String },
-        Ok(Vec<Assignment>),
+    // Counts instead of timing, which is too noisy to assert on: small chunks as a server sends them reach storage and
+    // the progress bars once per --write-buffer and PROGRESS_STEP, not once each
+    #[tokio::test]
+    async fn small_chunks_are_written_and_shown_in_batches() {
+        const CHUNK: usize = 512;
+        const N_CHUNKS: usize = 4096;
+        let destination = TestDir::new("batched-writes");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let mut writer = BudgetedWriter::new(
+            CountingStorage::default(),
+            options.write_buffer,
+            &options.sem_buffered_bytes,
+            options.max_buffered_bytes,
+        );
+        let progress_bar = ProgressBar::hidden();
+        let (mut not_shown, mut n_shown) = (0, 0);
+        for _ in 0..N_CHUNKS {
+            writer.write(&[7; CHUNK]).await.expect("write chunk");
+            not_shown = show_progress(&options, &progress_bar, not_shown + CHUNK as u64);
+            if not_shown == 0 {
+                n_shown += 1;
+            }
+        }
+        writer.flush().await.expect("flush");
+        progress_bar.inc(not_shown);
+
+        let storage = writer.file.get_ref();
+        assert_eq!(storage.bytes, CHUNK * N_CHUNKS);
+        assert_eq!(storage.n_writes, CHUNK * N_CHUNKS / options.write_buffer);
+        assert_eq!(n_shown, CHUNK * N_CHUNKS / PROGRESS_STEP as usize);
+        assert_eq!(progress_bar.position(), (CHUNK * N_CHUNKS) as u64);
     }
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Assignment {
-        pub id: u32,
-        pub name: String,
-        pub description: String,
+
+    // Forks `fan_out` subtasks of its own until `depth` is 0, like folders listing their subfolders
+    async fn nested_task(
+        (depth, fan_out, n_done): (u32, u32, Arc<AtomicUsize>),
+        options: Arc<ProcessOptions>,
+    ) -> Result<()> {
+        tokio::task::yield_now().await;
+        if depth > 0 {
+            for _ in 0..fan_out {
+                fork!(nested_task, (depth - 1, fan_out, n_done.clone()), (u32, u32, Arc<AtomicUsize>), options.clone());
+            }
+        }
+        n_done.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Submission {
-        pub id: u32,
-        pub body: Option<String>,
-        pub attachments: Vec<File>,
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn forked_tasks_wake_main_once_all_are_done() {
+        const DEPTH: u32 = 6;
+        const FAN_OUT: u32 = 4;
+        let destination = TestDir::new("fork");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let n_done = Arc::new(AtomicUsize::new(0));
+        // As run() forks the courses
+        options.n_active_requests.fetch_add(1, Ordering::AcqRel);
+        for _ in 0..FAN_OUT {
+            fork!(nested_task, (DEPTH, FAN_OUT, n_done.clone()), (u32, u32, Arc<AtomicUsize>), options.clone());
+        }
+        if options.n_active_requests.fetch_sub(1, Ordering::AcqRel) == 1 {
+            options.notify_main.notify_one();
+        }
+        tokio::time::timeout(Duration::from_secs(60), options.notify_main.notified()).await.expect("main woken");
+
+        let n_tasks: u32 = (1..=DEPTH + 1).map(|level| FAN_OUT.pow(level)).sum();
+        assert_eq!(n_done.load(Ordering::Relaxed), n_tasks as usize);
+        assert_eq!(options.n_active_requests.load(Ordering::Acquire), 0);
+        // No other task reached 0 and left a wake-up behind
+        let woken_again = tokio::time::timeout(Duration::from_millis(200), options.notify_main.notified()).await;
+        assert!(woken_again.is_err());
+        assert_eq!(options.errors.count(), 0);
     }
-    
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum DiscussionResult {
-        Err { status: String },
-        Ok(Vec<Discussion>),
+
+    fn modified_at(timestamp: &str) -> std::time::SystemTime {
+        std::time::SystemTime::from(DateTime::parse_from_rfc3339(timestamp).expect("valid timestamp"))
     }
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Discussion {
-        pub id: u32,
-        pub title: String,
-        pub message: String,
-        pub attachments: Vec<File>,
+
+    #[test]
+    fn recorded_version_is_not_an_update_whatever_the_modified_time() {
+        // exFAT and NFS truncated the sub-second part when the modified time was set
+        let truncated = || Ok(modified_at("2024-01-31T09:00:00Z"));
+        let updated_at = "2024-01-31T09:00:00.750Z";
+        assert!(!newer_on_canvas(updated_at, Some(updated_at), truncated).expect("comparable"));
+        // FAT rounded it down to an even second
+        let rounded = || Ok(modified_at("2024-01-31T09:00:00Z"));
+        assert!(!newer_on_canvas("2024-01-31T09:00:01Z", Some("2024-01-31T09:00:01Z"), rounded).expect("comparable"));
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct DiscussionView {
-        pub unread_entries: Vec<u32>,
-        pub view: Vec<Comments>,
+    #[test]
+    fn older_recorded_version_is_an_update() {
+        // The modified time is not looked at
+        let modified = || -> Result<std::time::SystemTime> { Err(anyhow!("not asked for")) };
+        assert!(newer_on_canvas("2024-02-01T10:00:00Z", Some("2024-01-31T09:00:00Z"), modified).expect("comparable"));
+        assert!(!newer_on_canvas("2024-01-31T09:00:00Z", Some("2024-02-01T10:00:00Z"), modified).expect("comparable"));
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Comments {
-        pub id: u32,
-        pub message: Option<String>,
-        pub attachment: Option<File>,
-        pub attachments: Option<Vec<File>>,
+    #[test]
+    fn unrecorded_copy_falls_back_to_the_rounded_modified_time() {
+        let rounded = || Ok(modified_at("2024-01-31T09:00:00Z"));
+        assert!(!newer_on_canvas("2024-01-31T09:00:01.900Z", None, rounded).expect("comparable"));
+        let rounded = || Ok(modified_at("2024-01-31T09:00:00Z"));
+        assert!(newer_on_canvas("2024-01-31T10:00:00Z", None, rounded).expect("comparable"));
+        // Unparseable recorded timestamps, eg of a version kept before the manifest, count as not recorded
+        let rounded = || Ok(modified_at("2024-01-31T09:00:00Z"));
+        assert!(!newer_on_canvas("2024-01-31T09:00:01Z", Some(""), rounded).expect("comparable"));
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct File {
-        pub id: u32,
-        pub folder_id: u32,
-        pub display_name: String,
-        pub size: u64,
-        pub url: String,
-        pub updated_at: String,
-        pub locked_for_user: bool,
-        #[serde(skip)]
-        pub filepath: std::path::PathBuf,
+    #[test]
+    fn kept_versions_never_replace_each_other() {
+        let destination = TestDir::new("keep-versions");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &["--keep-versions", "2"]);
+        let file = file_fixture(2, destination.join("notes.pdf"), "2024-01-10T09:00:00Z");
+        // Every version has the same updated_at, so they all want the same name
+        options
+            .state
+            .lock()
+            .expect("state lock")
+            .files
+            .insert("notes.pdf".to_string(), "2024-01-10T09:00:00+00:00".to_string());
+
+        let mut kept = Vec::new();
+        for version in 1..=4 {
+            std::fs::write(&file.filepath, format!("version {version}")).expect("write copy");
+            kept.push(keep_version(&options, &file, 2).expect("keep version"));
+            assert!(!file.filepath.exists());
+            // Neither replaced nor removed yet
+            assert_eq!(std::fs::read_to_string(&kept[version - 1]).expect("read kept version"), format!("version {version}"));
+            if version >= 2 {
+                assert_ne!(kept[version - 1], kept[version - 2]);
+                assert!(kept[version - 2].exists());
+            }
+        }
+        // Only the newest two are left
+        let manifest = options.manifest.lock().expect("manifest lock");
+        let versions: Vec<PathBuf> = manifest
+            .lookup(2)
+            .expect("manifest entry")
+            .versions
+            .iter()
+            .map(|version| destination.join(&version.path))
+            .collect();
+        assert_eq!(versions, kept[2..]);
+        let on_disk = std::fs::read_dir(destination.path())
+            .expect("read destination")
+            .filter(|entry| entry.as_ref().is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with("notes.")))
+            .count();
+        assert_eq!(on_disk, 2);
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct Session {
-        pub session_url: String,
-        pub requires_terms_acceptance: bool,
+    #[test]
+    fn failing_mirror_does_not_fail_the_run() {
+        let destination = TestDir::new("mirror-run");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let notes = destination.join("CS101/notes.pdf");
+        std::fs::create_dir_all(destination.join("CS101")).expect("create course folder");
+        std::fs::write(&notes, "notes").expect("write notes");
+        let mirror = TestDir::new("mirror-run-mirror");
+        let unmounted = mirror.join("unmounted-drive");
+
+        replicate_mirrors(&[unmounted.clone(), mirror.path().to_path_buf()], &[destination.join("CS101")], &options);
+        let state = options.state.lock().expect("state lock");
+        let failed = &state.mirrors[&unmounted.to_string_lossy().to_string()];
+        assert!(failed.error.is_some() && failed.synced_at.is_none());
+        assert!(!unmounted.exists());
+        let mirrored = &state.mirrors[&mirror.path().to_string_lossy().to_string()];
+        assert_eq!((mirrored.files_copied, mirrored.error.as_deref()), (1, None));
+        assert!(mirror.join("CS101/notes.pdf").exists());
+        // The run's outcome only depends on the primary destination
+        assert_eq!(options.errors.count(), 0);
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    #[allow(non_snake_case)]
-    pub struct PanoptoSessionInfo {
-        pub TotalNumber: u32,
-        pub Results: Vec<PanoptoResult>,
-        pub Subfolders: Vec<PanoptoSubfolder>,
+    #[test]
+    fn api_base_is_detected_below_a_path_prefix() {
+        let detect = |url: &str| canvas::detect_api_base(&Url::parse(url).expect("valid url"));
+        assert_eq!(detect("https://h/canvas/api/v1/users/self").as_deref(), Some("https://h/canvas/api/v1"));
+        assert_eq!(detect("https://h/api/v1/users/self?per_page=100").as_deref(), Some("https://h/api/v1"));
+        assert_eq!(detect("https://h/login/canvas"), None);
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    #[allow(non_snake_case)]
-    pub struct PanoptoResult {
-        pub DeliveryID: String,
-        pub FolderID: String,
-        pub SessionID: String,
-        pub SessionName: String,
-        pub StartTime: String,
-        pub IosVideoUrl: String,
+    #[test]
+    fn next_pages_on_an_internal_host_are_fetched_from_the_configured_one() {
+        let destination = TestDir::new("api-host");
+        let options = process_options(destination.path(), "https://lms.example.edu/canvas", &[]);
+        assert_eq!(options.api_base, "https://lms.example.edu/canvas/api/v1");
+        let next = "http://canvas-internal:8080/canvas/api/v1/courses?page=2&per_page=100".to_string();
+        assert_eq!(
+            rewrite_to_api_host(next, &options),
+            "https://lms.example.edu/canvas/api/v1/courses?page=2&per_page=100"
+        );
+        let next = "https://lms.example.edu/canvas/api/v1/courses?page=3".to_string();
+        assert_eq!(rewrite_to_api_host(next.clone(), &options), next);
     }
 
-    #[derive(Clone, Debug, Deserialize)]
-    #[allow(non_snake_case)]
-    pub struct PanoptoSubfolder {
-        pub ID: String,
-        pub Name: String,
+    #[test]
+    fn failures_json_is_written_to_the_destination() {
+        let destination = TestDir::new("failure-logs");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        std::fs::create_dir_all(state::state_dir(destination.path())).expect("create state dir");
+        write_failure_logs(destination.path(), &options).expect("write failure logs");
+        let failures_path = destination.join(errors::FAILURES_JSON);
+        assert_eq!(std::fs::read_to_string(&failures_path).expect("read failures.json"), "[]");
+        assert!(!state::state_dir(destination.path()).join(errors::ERRORS_LOG).exists());
+
+        let url = "https://canvas.example.edu/files/2/download";
+        let error = anyhow!("403 Forbidden");
+        options.errors.report_download(url, destination.join("CS101/notes.pdf"), &error, &options.progress_bars);
+        write_failure_logs(destination.path(), &options).expect("write failure logs");
+        let failures: Value =
+            serde_json::from_str(&std::fs::read_to_string(&failures_path).expect("read failures.json")).expect("JSON");
+        assert_eq!(failures[0]["url"], url);
+        assert!(state::state_dir(destination.path()).join(errors::ERRORS_LOG).exists());
     }
-    
-    #[derive(Clone, Debug, Deserialize)]
-    #[allow(non_snake_case)]
-    pub struct PanoptoDeliveryInfo {
-        pub SessionId: String,
-        pub ViewerFileId: String,
+
+    #[test]
+    fn exit_codes_match_the_readme() {
+        let outcomes = [
+            (Outcome::Success, "Success"),
+            (Outcome::Fatal, "Any other error"),
+            (Outcome::InvalidConfig, "Invalid flags, config file or credential file"),
+            (Outcome::NoSelection, "No course selected"),
+            (Outcome::SuccessWithSkips, "Success, but some files were left out on purpose"),
+            (Outcome::PartialFailure, "Some downloads or requests failed"),
+            (Outcome::Unauthorized, "Canvas rejected the token"),
+            (Outcome::Unreachable, "Canvas could not be reached"),
+            (Outcome::Interrupted, "Interrupted with Ctrl-C"),
+        ];
+        // "| 4 | Success, but ... |" --> (4, "Success, but ...")
+        let table: Vec<(i32, &str)> = include_str!("../README.md")
+            .lines()
+            .filter_map(|line| {
+                let mut cells = line.strip_prefix("| ")?.splitn(2, " | ");
+                Some((cells.next()?.parse().ok()?, cells.next()?.trim_end_matches(" |")))
+            })
+            .collect();
+        assert_eq!(table.len(), outcomes.len(), "{table:?}");
+        for ((outcome, meaning), (code, readme_meaning)) in outcomes.into_iter().zip(table) {
+            assert_eq!(outcome as i32, code, "{outcome:?}");
+            assert!(readme_meaning.starts_with(meaning), "{outcome:?}: {readme_meaning}");
+        }
+
+        assert_eq!(error_outcome(&anyhow!("no such file").context(InvalidConfig)), Outcome::InvalidConfig);
+        assert_eq!(error_outcome(&anyhow!(canvas::ApiError::Unauthorized).context("users/self")), Outcome::Unauthorized);
+        let maintenance = anyhow!(canvas::ApiError::Status("503 Service Unavailable".to_string()));
+        assert_eq!(error_outcome(&maintenance), Outcome::Unreachable);
+        assert_eq!(error_outcome(&anyhow!("disk full")), Outcome::Fatal);
     }
 
-    pub struct ProcessOptions {
-        pub canvas_token: String,
-        pub canvas_url: String,
-        pub client: reqwest::Client,
-        pub user: User,
-        // Process
-        pub download_newer: bool,
-        pub files_to_download: Mutex<Vec<File>>,
-        // Download
-        pub progress_bars: indicatif::MultiProgress,
-        pub progress_style: indicatif::ProgressStyle,
-        // Synchronization
-        pub n_active_requests: AtomicUsize, // main() waits for this to be 0
-        pub sem_requests: tokio::sync::Semaphore, // Limit #active requests
-        pub notify_main: tokio::sync::Notify,
+    #[test]
+    fn header_values_are_not_serialized() {
+        let args = CommandLineOptions::parse_from(["canvas-downloader", "--header", "Cookie: session=secret"]);
+        let serialized = serde_json::to_string(&args).expect("serialize options");
+        assert!(serialized.contains("Cookie: <redacted>"), "{serialized}");
+        assert!(!serialized.contains("secret"), "{serialized}");
+    }
+
+    #[test]
+    fn support_bundle_log_leaves_out_the_token_and_signed_urls() {
+        let log = "== CS101 files, http 403\n\
+            https://files.example.com/2/notes.pdf?verifier=abc&sf_verifier=def: 403 Forbidden\n\
+            \"Authorization: Bearer 1234~token\"\n";
+        let redacted = redact_log(log, "1234~token");
+        assert!(redacted.contains("https://files.example.com/2/notes.pdf?<redacted> 403"), "{redacted}");
+        assert!(redacted.contains("Bearer <redacted>"), "{redacted}");
+        assert!(!redacted.contains("verifier") && !redacted.contains("token"), "{redacted}");
     }
 }
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::MetricsSnapshot;
+use crate::state::state_dir;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// Canvas file ID --> where and which version of it was downloaded. Unlike the paths of State::files, this follows a
+// file renamed on Canvas or in the destination
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    files: BTreeMap<u32, ManifestEntry>,
+    // Files left out for being locked on Canvas, until they are downloaded once unlocked
+    #[serde(default)]
+    locked: BTreeMap<u32, LockedFile>,
+    // Counters of the last complete or interrupted run, to tell a slow sync from a throttled one
+    #[serde(default)]
+    last_run: Option<RunMetadata>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    // Destination-relative
+    pub path: String,
+    // Canvas updated_at of the downloaded version
+    pub updated_at: String,
+    pub size: u64,
+    pub last_downloaded: String,
+    // Earlier versions moved aside by --keep-versions, oldest first
+    #[serde(default)]
+    pub versions: Vec<KeptVersion>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeptVersion {
+    // Destination-relative
+    pub path: String,
+    pub updated_at: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LockedFile {
+    // Destination-relative, where it will be downloaded to
+    pub path: String,
+    pub first_seen: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RunMetadata {
+    pub finished_at: String,
+    pub version: String,
+    pub metrics: MetricsSnapshot,
+}
+
+impl Manifest {
+    // Empty when missing, rebuilt by the next downloads when it can not be read
+    pub fn load(destination: &Path) -> Manifest {
+        let path = state_dir(destination).join(MANIFEST_FILE);
+        let Ok(file) = std::fs::File::open(&path) else {
+            return Manifest::default();
+        };
+        serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_else(|e| {
+            eprintln!("Ignoring unreadable manifest {path:?}, err={e}");
+            Manifest::default()
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &ManifestEntry)> {
+        self.files.iter().map(|(id, entry)| (*id, entry))
+    }
+
+    pub fn lookup(&self, id: u32) -> Option<&ManifestEntry> {
+        self.files.get(&id)
+    }
+
+    // Files without a Canvas ID, eg Panopto videos, are left out
+    pub fn record(&mut self, id: u32, path: String, updated_at: String, size: u64) {
+        if id == 0 {
+            return;
+        }
+        let last_downloaded = Local::now().to_rfc3339();
+        self.locked.remove(&id);
+        let versions = self.files.remove(&id).map(|entry| entry.versions).unwrap_or_default();
+        self.files.insert(id, ManifestEntry { path, updated_at, size, last_downloaded, versions });
+    }
+
+    pub fn record_locked(&mut self, id: u32, path: String) {
+        if id == 0 {
+            return;
+        }
+        let first_seen = self.locked.remove(&id).map_or_else(|| Local::now().to_rfc3339(), |locked| locked.first_seen);
+        self.locked.insert(id, LockedFile { path, first_seen });
+    }
+
+    pub fn was_locked(&self, id: u32) -> bool {
+        self.locked.contains_key(&id)
+    }
+
+    // Returns the versions beyond `keep`, oldest first, for the caller to remove
+    pub fn add_version(&mut self, id: u32, version: KeptVersion, keep: Option<usize>) -> Vec<KeptVersion> {
+        if id == 0 {
+            return Vec::new();
+        }
+        // Downloaded before the manifest existed, record() fills in the rest
+        let entry = self.files.entry(id).or_insert_with(|| ManifestEntry {
+            path: String::new(),
+            updated_at: String::new(),
+            size: 0,
+            last_downloaded: String::new(),
+            versions: Vec::new(),
+        });
+        entry.versions.push(version);
+        let n_over = keep.map_or(0, |keep| entry.versions.len().saturating_sub(keep));
+        entry.versions.drain(..n_over).collect()
+    }
+
+    pub fn record_run(&mut self, metrics: MetricsSnapshot) {
+        self.last_run = Some(RunMetadata {
+            finished_at: Local::now().to_rfc3339(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            metrics,
+        });
+    }
+
+    // After the folder `from` was renamed to `to`, both destination-relative
+    pub fn move_folder(&mut self, from: &str, to: &str) {
+        let entry_paths = self.files.values_mut().flat_map(|entry| {
+            std::iter::once(&mut entry.path).chain(entry.versions.iter_mut().map(|version| &mut version.path))
+        });
+        let locked_paths = self.locked.values_mut().map(|locked| &mut locked.path);
+        for path in entry_paths.chain(locked_paths) {
+            if let Some(moved) = crate::state::moved_path(path, from, to) {
+                *path = moved;
+            }
+        }
+    }
+
+    pub fn save(&self, destination: &Path) -> Result<()> {
+        std::fs::create_dir_all(state_dir(destination))?;
+        crate::write_artifact(&state_dir(destination).join(MANIFEST_FILE), serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(n: u32) -> KeptVersion {
+        KeptVersion { path: format!("notes.2024-01-{n:02}.pdf"), updated_at: format!("2024-01-{n:02}T09:00:00+00:00") }
+    }
+
+    #[test]
+    fn versions_beyond_the_limit_are_returned_oldest_first() {
+        let mut manifest = Manifest::default();
+        manifest.record(2, "notes.pdf".to_string(), "2024-01-01T09:00:00Z".to_string(), 1024);
+        for n in 1..=3 {
+            assert!(manifest.add_version(2, version(n), Some(3)).is_empty());
+        }
+        let over = manifest.add_version(2, version(4), Some(2));
+        assert_eq!(over.iter().map(|version| version.path.as_str()).collect::<Vec<_>>(), [
+            "notes.2024-01-01.pdf",
+            "notes.2024-01-02.pdf"
+        ]);
+        let kept = &manifest.lookup(2).expect("manifest entry").versions;
+        assert_eq!(kept.iter().map(|version| version.path.as_str()).collect::<Vec<_>>(), [
+            "notes.2024-01-03.pdf",
+            "notes.2024-01-04.pdf"
+        ]);
+        // Recording the new download keeps them
+        manifest.record(2, "notes.pdf".to_string(), "2024-01-05T09:00:00Z".to_string(), 2048);
+        assert_eq!(manifest.lookup(2).expect("manifest entry").versions.len(), 2);
+    }
+
+    #[test]
+    fn unlimited_versions_are_all_kept() {
+        let mut manifest = Manifest::default();
+        for n in 1..=5 {
+            assert!(manifest.add_version(2, version(n), None).is_empty());
+        }
+        assert_eq!(manifest.lookup(2).expect("manifest entry").versions.len(), 5);
+        // Files without a Canvas ID have no entry to keep versions in
+        assert!(manifest.add_version(0, version(1), Some(1)).is_empty());
+        assert!(manifest.lookup(0).is_none());
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+// Why a request was retried
+#[derive(Clone, Copy, Debug)]
+pub enum RetryCause {
+    Throttled, // 403 from Canvas' rate limiter
+    ServerError,
+    Timeout,
+    NetworkError, // connection reset or dropped during a download
+}
+
+// Request counters shared by every task, written out at the end of a run for supportability
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<String, u64>>,
+    retries_throttled: AtomicU64,
+    retries_server_error: AtomicU64,
+    retries_timeout: AtomicU64,
+    retries_network_error: AtomicU64,
+    backoff_ms: AtomicU64,
+    rate_limit_remaining_min: Mutex<Option<f64>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MetricsSnapshot {
+    pub requests_by_category: BTreeMap<String, u64>,
+    pub retries_throttled: u64,
+    pub retries_server_error: u64,
+    pub retries_timeout: u64,
+    pub retries_network_error: u64,
+    pub backoff_ms: u64,
+    // Lowest X-Rate-Limit-Remaining Canvas reported, None when the header is not exposed
+    pub rate_limit_remaining_min: Option<f64>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, category: &str) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry(category.to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn record_retry(&self, cause: RetryCause, wait: Duration) {
+        let counter = match cause {
+            RetryCause::Throttled => &self.retries_throttled,
+            RetryCause::ServerError => &self.retries_server_error,
+            RetryCause::Timeout => &self.retries_timeout,
+            RetryCause::NetworkError => &self.retries_network_error,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.backoff_ms
+            .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_rate_limit(&self, headers: &HeaderMap) {
+        let Some(remaining) = headers
+            .get("X-Rate-Limit-Remaining")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<f64>().ok())
+        else {
+            return;
+        };
+        if let Ok(mut min) = self.rate_limit_remaining_min.lock() {
+            if min.is_none_or(|min| remaining < min) {
+                *min = Some(remaining);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            requests_by_category: self
+                .requests
+                .lock()
+                .map(|requests| requests.clone())
+                .unwrap_or_default(),
+            retries_throttled: self.retries_throttled.load(Ordering::Relaxed),
+            retries_server_error: self.retries_server_error.load(Ordering::Relaxed),
+            retries_timeout: self.retries_timeout.load(Ordering::Relaxed),
+            retries_network_error: self.retries_network_error.load(Ordering::Relaxed),
+            backoff_ms: self.backoff_ms.load(Ordering::Relaxed),
+            rate_limit_remaining_min: self
+                .rate_limit_remaining_min
+                .lock()
+                .map(|min| *min)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// ".../api/v1/courses/1/discussion_topics/2/view" --> "discussion_topics"
+pub fn api_category(url: &str) -> String {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return "other".to_string();
+    };
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.collect())
+        .unwrap_or_default();
+    let Some(api) = segments.iter().position(|segment| *segment == "v1") else {
+        return segments.first().map_or("other", |segment| segment).to_string();
+    };
+    let rest = &segments[api + 1..];
+    match rest {
+        ["courses" | "users", _, category, ..] => category.to_string(),
+        [category, ..] => category.to_string(),
+        [] => "other".to_string(),
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+// Copies every file of the synced course folders that is missing or differs (size or mtime) in the mirror.
+// The mirror root has to exist already, so an unmounted network drive fails instead of filling the mount point.
+// Folders renamed in the primary since the last replication, destination-relative, are renamed in the mirror first
+pub fn replicate(
+    primary: &Path,
+    mirror: &Path,
+    course_folders: &[PathBuf],
+    renamed_folders: &[(PathBuf, PathBuf)],
+) -> Result<usize> {
+    if !mirror.is_dir() {
+        return Err(anyhow!("{} is not available", mirror.to_string_lossy()));
+    }
+    // Parents first, a renamed module moves its items along
+    let mut renamed_folders = renamed_folders.to_vec();
+    renamed_folders.sort_by_key(|(_, to)| to.components().count());
+    for (from, to) in renamed_folders {
+        let to = mirror.join(to);
+        let moved_with_parent = to.parent().zip(from.file_name()).map(|(parent, name)| parent.join(name));
+        let Some(from) = [moved_with_parent, Some(mirror.join(&from))]
+            .into_iter()
+            .flatten()
+            .find(|from| from.is_dir())
+        else {
+            continue;
+        };
+        if !to.exists() {
+            std::fs::rename(&from, &to).with_context(|| format!("Failed to rename {from:?} to {to:?}"))?;
+        }
+    }
+    let mut files_copied = 0;
+    for course_folder in course_folders {
+        let mut files = Vec::new();
+        if course_folder.exists() {
+            crate::storage_report::walk(course_folder, &mut files)?;
+        }
+        for (from, _) in files {
+            let Ok(relative_path) = from.strip_prefix(primary) else {
+                continue;
+            };
+            let to = mirror.join(relative_path);
+            if is_same(&from, &to) {
+                continue;
+            }
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent).with_context(|| format!("Failed to create directory {parent:?}"))?;
+            }
+            crate::copy_file(&from, &to).with_context(|| format!("Failed to copy {from:?} to {to:?}"))?;
+            files_copied += 1;
+        }
+    }
+    Ok(files_copied)
+}
+
+fn is_same(from: &Path, to: &Path) -> bool {
+    let (Ok(from), Ok(to)) = (std::fs::metadata(from), std::fs::metadata(to)) else {
+        return false;
+    };
+    // Network drives and FAT only keep mtimes to the second or two
+    let from_mtime = filetime::FileTime::from_last_modification_time(&from).unix_seconds();
+    let to_mtime = filetime::FileTime::from_last_modification_time(&to).unix_seconds();
+    from.len() == to.len() && (from_mtime - to_mtime).abs() <= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    // <primary>/CS101/Week 1/notes.pdf, modified a day ago
+    fn primary_with_course(name: &str) -> (TestDir, PathBuf) {
+        let primary = TestDir::new(name);
+        let notes = primary.join("CS101/Week 1/notes.pdf");
+        std::fs::create_dir_all(notes.parent().expect("notes folder")).expect("create course folder");
+        std::fs::write(&notes, "notes").expect("write notes");
+        let yesterday = filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() - 24 * 60 * 60, 0);
+        filetime::set_file_mtime(&notes, yesterday).expect("set mtime");
+        let course_folder = primary.join("CS101");
+        (primary, course_folder)
+    }
+
+    #[test]
+    fn missing_mirror_root_is_not_created() {
+        let (primary, course_folder) = primary_with_course("mirror-primary-unmounted");
+        let mirror = primary.join("unmounted-drive");
+        replicate(primary.path(), &mirror, &[course_folder], &[]).expect_err("mirror root missing");
+        assert!(!mirror.exists());
+        assert_eq!(std::fs::read_to_string(primary.join("CS101/Week 1/notes.pdf")).expect("read notes"), "notes");
+    }
+
+    #[test]
+    fn copies_keep_their_mtime_and_are_skipped_next_time() {
+        let (primary, course_folder) = primary_with_course("mirror-primary");
+        let mirror = TestDir::new("mirror");
+        let course_folders = [course_folder];
+        assert_eq!(replicate(primary.path(), mirror.path(), &course_folders, &[]).expect("first replication"), 1);
+
+        let mtime = |path: &Path| filetime::FileTime::from_last_modification_time(&std::fs::metadata(path).expect("metadata"));
+        let (from, to) = (primary.join("CS101/Week 1/notes.pdf"), mirror.join("CS101/Week 1/notes.pdf"));
+        assert_eq!(std::fs::read_to_string(&to).expect("read copy"), "notes");
+        assert_eq!(mtime(&to).unix_seconds(), mtime(&from).unix_seconds());
+        assert!(is_same(&from, &to));
+        assert_eq!(replicate(primary.path(), mirror.path(), &course_folders, &[]).expect("second replication"), 0);
+
+        std::fs::write(&from, "notes, corrected").expect("update notes");
+        assert_eq!(replicate(primary.path(), mirror.path(), &course_folders, &[]).expect("third replication"), 1);
+        assert_eq!(std::fs::read_to_string(&to).expect("read copy"), "notes, corrected");
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Deeper folders are summarized on one line, folders with more entries are cut off
+const MAX_DEPTH: usize = 6;
+const MAX_CHILDREN: usize = 25;
+
+// What a run would do with a file found by the crawl, see filter_files()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanStatus {
+    New,
+    Update,
+    Exists,
+    Renamed,
+    ModifiedLocally,
+    Locked,
+    Skipped,
+}
+
+impl PlanStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            PlanStatus::New => "new",
+            PlanStatus::Update => "update",
+            PlanStatus::Exists => "exists",
+            PlanStatus::Renamed => "renamed",
+            PlanStatus::ModifiedLocally => "modified locally",
+            PlanStatus::Locked => "locked",
+            PlanStatus::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Folder {
+    folders: BTreeMap<String, Folder>,
+    files: BTreeMap<String, PlanStatus>,
+}
+
+impl Folder {
+    fn n_files(&self) -> usize {
+        self.files.len() + self.folders.values().map(Folder::n_files).sum::<usize>()
+    }
+}
+
+// tree-style listing of the destination a run would produce, relative to it
+pub fn print(destination: &Path, planned: &[(PathBuf, PlanStatus)]) {
+    let mut root = Folder::default();
+    for (path, status) in planned {
+        let relative_path = path.strip_prefix(destination).unwrap_or(path);
+        let mut components: Vec<String> = relative_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let Some(file_name) = components.pop() else {
+            continue;
+        };
+        let folder = components
+            .into_iter()
+            .fold(&mut root, |folder, name| folder.folders.entry(name).or_default());
+        folder.files.insert(file_name, *status);
+    }
+
+    println!("{}", destination.to_string_lossy());
+    print_folder(&root, "", 1);
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, status) in planned {
+        *counts.entry(status.label()).or_default() += 1;
+    }
+    let counts: Vec<String> = counts.iter().map(|(label, n)| format!("{n} {label}")).collect();
+    println!("\n{} file(s): {}", planned.len(), counts.join(", "));
+}
+
+fn print_folder(folder: &Folder, prefix: &str, depth: usize) {
+    let entries: Vec<(&String, Option<&Folder>, Option<PlanStatus>)> = folder
+        .folders
+        .iter()
+        .map(|(name, subfolder)| (name, Some(subfolder), None))
+        .chain(folder.files.iter().map(|(name, status)| (name, None, Some(*status))))
+        .collect();
+    let n_entries = entries.len();
+    let shown = n_entries.min(MAX_CHILDREN);
+    for (i, (name, subfolder, status)) in entries.into_iter().take(shown).enumerate() {
+        let last = i + 1 == n_entries;
+        let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        match (subfolder, status) {
+            (Some(subfolder), _) if depth >= MAX_DEPTH => {
+                println!("{prefix}{branch}{name}/ ({} file(s) below)", subfolder.n_files());
+            }
+            (Some(subfolder), _) => {
+                println!("{prefix}{branch}{name}/");
+                print_folder(subfolder, &format!("{prefix}{indent}"), depth + 1);
+            }
+            (None, Some(status)) => println!("{prefix}{branch}{name} [{}]", status.label()),
+            (None, None) => {}
+        }
+    }
+    if n_entries > shown {
+        println!("{prefix}└── ... {} more", n_entries - shown);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::errors::Failure;
+use crate::manifest::Manifest;
+
+// What a sync changed per course, written to changes-<timestamp>.md (or .json) in the destination. Built from the
+// manifest before and after the run, so it only depends on what was recorded
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub generated_at: String,
+    // Course folder, destination-relative --> its changes
+    pub courses: BTreeMap<String, CourseReport>,
+    // Course folder, destination-relative --> what the run did there, see CourseSummary
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub summaries: BTreeMap<String, CourseSummary>,
+}
+
+// Files of a course by what the run did with them, printed at the end of the run
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CourseSummary {
+    pub new: usize,
+    pub updated: usize,
+    pub up_to_date: usize,
+    pub failed: usize,
+    pub locked: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CourseReport {
+    pub new_files: Vec<ReportFile>,
+    pub updated_files: Vec<ReportFile>,
+    // Left out by earlier runs for being locked on Canvas
+    pub unlocked_files: Vec<ReportFile>,
+    pub announcements: Vec<ReportTopic>,
+    pub discussions: Vec<ReportTopic>,
+    pub failures: Vec<ReportFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportFile {
+    // Destination-relative
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportTopic {
+    pub title: String,
+    pub posted_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportFailure {
+    pub content: String,
+    // Path of a failed download, else the URL of the failed request
+    pub target: Option<String>,
+    pub error: String,
+}
+
+// A discussion or announcement seen for the first time, by course
+pub struct NewTopic {
+    pub course: String,
+    pub announcement: bool,
+    pub title: String,
+    pub posted_at: Option<String>,
+}
+
+impl Report {
+    // `course_of` maps a destination-relative path to its course folder
+    pub fn build(
+        before: &Manifest,
+        after: &Manifest,
+        topics: &[NewTopic],
+        failures: &[Failure],
+        course_of: impl Fn(&str) -> String,
+    ) -> Report {
+        let mut report =
+            Report { generated_at: Local::now().to_rfc3339(), courses: BTreeMap::new(), summaries: BTreeMap::new() };
+        for (id, entry) in after.iter() {
+            let file = ReportFile { path: entry.path.clone(), size: entry.size };
+            let course = report.courses.entry(course_of(&entry.path)).or_default();
+            match before.lookup(id) {
+                None if before.was_locked(id) => course.unlocked_files.push(file),
+                None => course.new_files.push(file),
+                Some(previous) if previous.updated_at != entry.updated_at => course.updated_files.push(file),
+                Some(_) => {}
+            }
+        }
+        for topic in topics {
+            let course = report.courses.entry(topic.course.clone()).or_default();
+            let topic_report = ReportTopic { title: topic.title.clone(), posted_at: topic.posted_at.clone() };
+            if topic.announcement {
+                course.announcements.push(topic_report);
+            } else {
+                course.discussions.push(topic_report);
+            }
+        }
+        for failure in failures {
+            let target = failure
+                .path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .or_else(|| failure.url.clone());
+            report.courses.entry(failure.course.clone().unwrap_or_default()).or_default().failures.push(ReportFailure {
+                content: failure.content.clone(),
+                target,
+                error: failure.error.join(": "),
+            });
+        }
+        report.courses.retain(|_, course| !course.is_empty());
+        for course in report.courses.values_mut() {
+            course.new_files.sort_by(|a, b| a.path.cmp(&b.path));
+            course.updated_files.sort_by(|a, b| a.path.cmp(&b.path));
+            course.unlocked_files.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.courses.is_empty()
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut markdown = format!("# Canvas changes {}\n", Local::now().format("%Y-%m-%d %H:%M"));
+        for (course, changes) in &self.courses {
+            let course = if course.is_empty() { "Other" } else { course };
+            markdown.push_str(&format!("\n## {course}\n"));
+            let file_kinds = [
+                ("New files", &changes.new_files),
+                ("Updated files", &changes.updated_files),
+                ("Previously locked, now available", &changes.unlocked_files),
+            ];
+            for (heading, files) in file_kinds {
+                if files.is_empty() {
+                    continue;
+                }
+                markdown.push_str(&format!("\n### {heading}\n\n"));
+                for file in files {
+                    markdown.push_str(&format!("- `{}` ({})\n", file.path, indicatif::HumanBytes(file.size)));
+                }
+            }
+            let topic_kinds = [("New announcements", &changes.announcements), ("New discussions", &changes.discussions)];
+            for (heading, topics) in topic_kinds {
+                if topics.is_empty() {
+                    continue;
+                }
+                markdown.push_str(&format!("\n### {heading}\n\n"));
+                for topic in topics {
+                    match &topic.posted_at {
+                        Some(posted_at) => markdown.push_str(&format!("- {} ({posted_at})\n", topic.title)),
+                        None => markdown.push_str(&format!("- {}\n", topic.title)),
+                    }
+                }
+            }
+            if !changes.failures.is_empty() {
+                markdown.push_str("\n### Failed\n\n");
+                for failure in &changes.failures {
+                    let target = failure.target.as_ref().map(|target| format!(" `{target}`")).unwrap_or_default();
+                    markdown.push_str(&format!("- {}{target}: {}\n", failure.content, failure.error));
+                }
+            }
+        }
+        markdown
+    }
+}
+
+impl CourseSummary {
+    // "CS3230 — 4 new, 2 updated, 118 up to date, 1 failed, 3 locked"
+    pub fn line(&self, course: &str) -> String {
+        format!(
+            "{course} — {} new, {} updated, {} up to date, {} failed, {} locked",
+            self.new, self.updated, self.up_to_date, self.failed, self.locked
+        )
+    }
+}
+
+impl CourseReport {
+    fn is_empty(&self) -> bool {
+        self.new_files.is_empty()
+            && self.updated_files.is_empty()
+            && self.unlocked_files.is_empty()
+            && self.announcements.is_empty()
+            && self.discussions.is_empty()
+            && self.failures.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Phase;
+
+    // "CS101/files/a.pdf" --> "CS101"
+    fn course_of(path: &str) -> String {
+        path.split('/').next().unwrap_or_default().to_string()
+    }
+
+    fn snapshots() -> (Manifest, Manifest) {
+        let mut before = Manifest::default();
+        before.record(1, "CS101/files/syllabus.pdf".to_string(), "2024-01-10T09:00:00Z".to_string(), 100);
+        before.record(2, "CS101/files/week1.pdf".to_string(), "2024-01-10T09:00:00Z".to_string(), 200);
+        before.record_locked(3, "MA1521/files/exam.pdf".to_string());
+        let mut after = Manifest::default();
+        after.record(1, "CS101/files/syllabus.pdf".to_string(), "2024-01-10T09:00:00Z".to_string(), 100);
+        after.record(2, "CS101/files/week1.pdf".to_string(), "2024-02-01T09:00:00Z".to_string(), 250);
+        after.record(3, "MA1521/files/exam.pdf".to_string(), "2024-02-01T09:00:00Z".to_string(), 300);
+        after.record(5, "CS101/files/b.pdf".to_string(), "2024-02-01T09:00:00Z".to_string(), 10);
+        after.record(4, "CS101/files/a.pdf".to_string(), "2024-02-01T09:00:00Z".to_string(), 20);
+        (before, after)
+    }
+
+    fn paths(files: &[ReportFile]) -> Vec<&str> {
+        files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    #[test]
+    fn files_are_reported_by_what_changed() {
+        let (before, after) = snapshots();
+        let report = Report::build(&before, &after, &[], &[], course_of);
+        assert_eq!(report.courses.keys().collect::<Vec<_>>(), ["CS101", "MA1521"]);
+        let cs101 = &report.courses["CS101"];
+        // Sorted by path, not by Canvas ID
+        assert_eq!(paths(&cs101.new_files), ["CS101/files/a.pdf", "CS101/files/b.pdf"]);
+        assert_eq!(paths(&cs101.updated_files), ["CS101/files/week1.pdf"]);
+        assert_eq!(cs101.updated_files[0].size, 250);
+        assert!(cs101.unlocked_files.is_empty());
+        let ma1521 = &report.courses["MA1521"];
+        assert_eq!(paths(&ma1521.unlocked_files), ["MA1521/files/exam.pdf"]);
+        assert!(ma1521.new_files.is_empty());
+    }
+
+    #[test]
+    fn unchanged_sync_reports_nothing() {
+        let (_, after) = snapshots();
+        let report = Report::build(&after, &after, &[], &[], course_of);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn topics_and_failures_are_reported_by_course() {
+        let manifest = Manifest::default();
+        let topics = [
+            NewTopic {
+                course: "CS101".to_string(),
+                announcement: true,
+                title: "Midterm moved".to_string(),
+                posted_at: Some("2024-02-01T09:00:00Z".to_string()),
+            },
+            NewTopic {
+                course: "CS101".to_string(),
+                announcement: false,
+                title: "Week 3 questions".to_string(),
+                posted_at: None,
+            },
+        ];
+        let failures = [
+            Failure {
+                phase: Phase::Download,
+                course: Some("CS101".to_string()),
+                content: "download".to_string(),
+                url: Some("https://canvas.example.edu/files/9/download".to_string()),
+                path: Some("CS101/files/slides.pdf".into()),
+                error: vec!["Failed to download".to_string(), "timeout".to_string()],
+            },
+            Failure {
+                phase: Phase::Crawl,
+                course: None,
+                content: "courses".to_string(),
+                url: Some("https://canvas.example.edu/api/v1/courses".to_string()),
+                path: None,
+                error: vec!["unauthorized".to_string()],
+            },
+        ];
+        let report = Report::build(&manifest, &manifest, &topics, &failures, course_of);
+        let cs101 = &report.courses["CS101"];
+        assert_eq!(cs101.announcements.len(), 1);
+        assert_eq!(cs101.discussions[0].title, "Week 3 questions");
+        assert_eq!(cs101.failures[0].target.as_deref(), Some("CS101/files/slides.pdf"));
+        assert_eq!(cs101.failures[0].error, "Failed to download: timeout");
+        // Without a course, and without a path the URL is the target
+        assert_eq!(report.courses[""].failures[0].target.as_deref(), Some("https://canvas.example.edu/api/v1/courses"));
+
+        let markdown = report.markdown();
+        assert!(markdown.contains("\n## CS101\n"), "{markdown}");
+        assert!(markdown.contains("\n### New announcements\n\n- Midterm moved (2024-02-01T09:00:00Z)\n"), "{markdown}");
+        assert!(markdown.contains("\n### New discussions\n\n- Week 3 questions\n"), "{markdown}");
+        assert!(markdown.contains("- download `CS101/files/slides.pdf`: Failed to download: timeout\n"), "{markdown}");
+        assert!(markdown.contains("\n## Other\n"), "{markdown}");
+    }
+
+    #[test]
+    fn markdown_lists_files_with_their_size() {
+        let (before, after) = snapshots();
+        let markdown = Report::build(&before, &after, &[], &[], course_of).markdown();
+        assert!(markdown.starts_with("# Canvas changes "), "{markdown}");
+        let size = |bytes| indicatif::HumanBytes(bytes).to_string();
+        let new_files = format!("- `CS101/files/a.pdf` ({})\n- `CS101/files/b.pdf` ({})\n", size(20), size(10));
+        assert!(markdown.contains(&format!("\n### New files\n\n{new_files}")), "{markdown}");
+        let updated_files = format!("- `CS101/files/week1.pdf` ({})\n", size(250));
+        assert!(markdown.contains(&format!("\n### Updated files\n\n{updated_files}")), "{markdown}");
+        let unlocked_files = format!("\n### Previously locked, now available\n\n- `MA1521/files/exam.pdf` ({})\n", size(300));
+        assert!(markdown.contains(&unlocked_files), "{markdown}");
+        // Nothing to say about the unchanged syllabus
+        assert!(!markdown.contains("syllabus"), "{markdown}");
+    }
+
+    #[test]
+    fn json_report_leaves_out_empty_summaries() {
+        let (before, after) = snapshots();
+        let json = serde_json::to_value(Report::build(&before, &after, &[], &[], course_of)).expect("serialize report");
+        assert!(json.get("summaries").is_none());
+        assert_eq!(json["courses"]["CS101"]["new_files"][0]["path"], "CS101/files/a.pdf");
+    }
+}
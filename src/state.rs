@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+// Hidden folder inside the destination holding everything that is not course content
+pub const STATE_DIR: &str = ".canvasdl";
+pub const STATE_FILE: &str = "state.json";
+// In-progress downloads, unless --tmp-dir says otherwise
+pub const TMP_DIR: &str = "tmp";
+// Files left for the next run by --max-total-bytes
+pub const DEFERRED_FILE: &str = "deferred.json";
+// Downloads that failed, for --retry-failed
+pub const FAILED_FILE: &str = "failed.json";
+// Downloads of the last run not done yet, for --resume
+pub const QUEUE_FILE: &str = "queue.json";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    // Identity of the Canvas instance and user that produced this destination
+    pub canvas_url: Option<String>,
+    pub user_id: Option<u32>,
+    pub user_name: Option<String>,
+    // Destination-relative path --> Canvas updated_at of the downloaded copy
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    // Destination-relative path --> file saved under another name than on Canvas to avoid a collision
+    #[serde(default)]
+    pub renamed: BTreeMap<String, RenamedFile>,
+    // Mirror folder --> outcome of the last replication to it
+    #[serde(default)]
+    pub mirrors: BTreeMap<String, MirrorStatus>,
+    // "discussion/123" --> destination-relative folder, see content_folder()
+    #[serde(default)]
+    pub content_folders: BTreeMap<String, String>,
+    // Canvas course ID --> its folder, kept when the course code changes, see tracked_course_folder()
+    #[serde(default)]
+    pub course_folders: BTreeMap<String, CourseFolder>,
+    // Assignment ID --> Canvas due_at when last synced, to list changed due dates in the digest
+    #[serde(default)]
+    pub due_dates: BTreeMap<String, Option<String>>,
+    // Destination-relative path --> the downloaded copy, recorded with --verify-content
+    #[serde(default)]
+    pub content: BTreeMap<String, ContentRecord>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CourseFolder {
+    // Destination-relative
+    pub path: String,
+    // When the folder was recorded, a folder named after another one is kept
+    pub course_code: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ContentRecord {
+    pub size: u64,
+    // See crate::content_hash()
+    pub hash: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RenamedFile {
+    // Canvas file ID, or a hash of the URL for files without one
+    pub identity: String,
+    pub remote_name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MirrorStatus {
+    // Last successful replication
+    pub synced_at: Option<String>,
+    pub files_copied: usize,
+    pub error: Option<String>,
+    // State::content_folders as of the last replication, to follow renames
+    #[serde(default)]
+    pub content_folders: BTreeMap<String, String>,
+}
+
+pub fn state_dir(destination: &Path) -> PathBuf {
+    destination.join(STATE_DIR)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeferredFile {
+    // Destination-relative
+    pub path: String,
+    pub size: u64,
+    pub updated_at: String,
+}
+
+// Destination-relative paths of the files the last run deferred, empty when there are none
+pub fn load_deferred(destination: &Path) -> Vec<String> {
+    deferred_files(destination).into_iter().map(|file| file.path).collect()
+}
+
+pub fn deferred_files(destination: &Path) -> Vec<DeferredFile> {
+    std::fs::File::open(state_dir(destination).join(DEFERRED_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+// Removes the list once nothing is deferred anymore
+pub fn save_deferred(destination: &Path, deferred: &[crate::canvas::File]) -> Result<()> {
+    let deferred: Vec<DeferredFile> = deferred
+        .iter()
+        .map(|file| DeferredFile {
+            path: file.filepath.strip_prefix(destination).unwrap_or(&file.filepath).to_string_lossy().to_string(),
+            size: file.size,
+            updated_at: file.updated_at.clone(),
+        })
+        .collect();
+    save_deferred_files(destination, &deferred)
+}
+
+pub fn save_deferred_files(destination: &Path, deferred: &[DeferredFile]) -> Result<()> {
+    let path = state_dir(destination).join(DEFERRED_FILE);
+    if deferred.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(state_dir(destination))?;
+    crate::write_artifact(&path, serde_json::to_string_pretty(deferred)?.as_bytes())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FailedFile {
+    #[serde(flatten)]
+    pub file: crate::canvas::File,
+    // Destination-relative, File.filepath is not serialized
+    pub path: String,
+    #[serde(default)]
+    pub name_prefix: Option<u32>,
+    // Runs in a row the download failed in
+    pub attempts: u32,
+}
+
+// Empty when nothing failed or the list can not be read
+pub fn load_failed(destination: &Path) -> Vec<FailedFile> {
+    std::fs::File::open(state_dir(destination).join(FAILED_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+// Downloads that failed this run, with the attempts of the previous list counted on
+pub fn failed_files(destination: &Path, previous: &[FailedFile], failed: &[crate::canvas::File]) -> Vec<FailedFile> {
+    failed
+        .iter()
+        .map(|file| {
+            let path = file.filepath.strip_prefix(destination).unwrap_or(&file.filepath).to_string_lossy().to_string();
+            let attempts = previous.iter().find(|entry| entry.path == path).map_or(0, |entry| entry.attempts) + 1;
+            FailedFile { file: file.clone(), path, name_prefix: file.name_prefix, attempts }
+        })
+        .collect()
+}
+
+// Removes the list once nothing failed anymore
+pub fn save_failed(destination: &Path, failed: &[FailedFile]) -> Result<()> {
+    let path = state_dir(destination).join(FAILED_FILE);
+    if failed.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(state_dir(destination))?;
+    crate::write_artifact(&path, serde_json::to_string_pretty(failed)?.as_bytes())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueuedFile {
+    #[serde(flatten)]
+    pub file: crate::canvas::File,
+    // Destination-relative, File.filepath is not serialized
+    pub path: String,
+    #[serde(default)]
+    pub name_prefix: Option<u32>,
+}
+
+// Empty when the last run finished or the queue can not be read
+pub fn load_queue(destination: &Path) -> Vec<QueuedFile> {
+    std::fs::File::open(state_dir(destination).join(QUEUE_FILE))
+        .ok()
+        .and_then(|file| serde_json::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+pub fn queued_files(destination: &Path, files: &[crate::canvas::File]) -> Vec<QueuedFile> {
+    files
+        .iter()
+        .map(|file| QueuedFile {
+            file: file.clone(),
+            path: file.filepath.strip_prefix(destination).unwrap_or(&file.filepath).to_string_lossy().to_string(),
+            name_prefix: file.name_prefix,
+        })
+        .collect()
+}
+
+// Removes the queue once every download is done
+pub fn save_queue(destination: &Path, queue: &[QueuedFile]) -> Result<()> {
+    let path = state_dir(destination).join(QUEUE_FILE);
+    if queue.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("Could not remove {path:?}"))?;
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(state_dir(destination))?;
+    crate::write_artifact(&path, serde_json::to_string(queue)?.as_bytes())
+}
+
+// `path` with the folder `from` replaced by `to`, None when it is not inside `from`. All destination-relative
+pub fn moved_path(path: &str, from: &str, to: &str) -> Option<String> {
+    let inside = Path::new(path).strip_prefix(from).ok()?;
+    Some(Path::new(to).join(inside).to_string_lossy().to_string())
+}
+
+impl State {
+    // After the folder `from` was renamed to `to`, so downloads in it are still recognized
+    pub fn move_folder(&mut self, from: &str, to: &str) {
+        fn move_keys<T>(map: &mut BTreeMap<String, T>, from: &str, to: &str) {
+            let keys: Vec<String> = map.keys().filter(|key| moved_path(key, from, to).is_some()).cloned().collect();
+            for key in keys {
+                if let (Some(value), Some(new_key)) = (map.remove(&key), moved_path(&key, from, to)) {
+                    map.insert(new_key, value);
+                }
+            }
+        }
+        move_keys(&mut self.files, from, to);
+        move_keys(&mut self.renamed, from, to);
+        move_keys(&mut self.content, from, to);
+        for folder in self.content_folders.values_mut() {
+            if let Some(moved) = moved_path(folder, from, to) {
+                *folder = moved;
+            }
+        }
+    }
+
+    // Ok(None) when the destination has never been synced
+    pub fn load(destination: &Path) -> Result<Option<State>> {
+        let path = state_dir(destination).join(STATE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Could not open state file {path:?}"))?;
+        let state = serde_json::from_reader(file)
+            .with_context(|| format!("State file {path:?} is not valid json"))?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, destination: &Path) -> Result<()> {
+        let dir = state_dir(destination);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create state directory {dir:?}"))?;
+        let path = dir.join(STATE_FILE);
+        let body = serde_json::to_string_pretty(self)?;
+        crate::write_artifact(&path, body.as_bytes())
+    }
+}
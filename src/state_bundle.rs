@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::http_cache::{self, HttpCache};
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::state::{self, state_dir, DeferredFile, FailedFile, QueuedFile, State, STATE_FILE};
+
+// Tells an export apart from any other JSON file
+const FORMAT: &str = "canvas-downloader-state";
+// Bumped when an export can not be imported by earlier versions anymore
+const VERSION: u32 = 1;
+
+// What `state export` writes: everything a destination copied to another machine needs to sync on without
+// downloading again. Paths are destination-relative, so the copy can live anywhere
+#[derive(Deserialize, Serialize)]
+struct Bundle {
+    format: String,
+    version: u32,
+    // canvas-downloader version that exported it
+    exported_by: String,
+    exported_at: String,
+    state: Option<State>,
+    manifest: Manifest,
+    #[serde(default)]
+    failed: Vec<FailedFile>,
+    #[serde(default)]
+    deferred: Vec<DeferredFile>,
+    #[serde(default)]
+    queue: Vec<QueuedFile>,
+    // Cached Canvas listings by file name, see HttpCache::entries()
+    #[serde(default)]
+    http_cache: BTreeMap<String, http_cache::Entry>,
+}
+
+pub fn export(destination: &Path, file: &Path) -> Result<()> {
+    let state = State::load(destination)?;
+    let manifest = Manifest::load(destination);
+    if state.is_none() && manifest.iter().next().is_none() {
+        return Err(anyhow!(
+            "{} has not been synced, there is no state to export",
+            destination.to_string_lossy()
+        ));
+    }
+    let bundle = Bundle {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        exported_by: env!("CARGO_PKG_VERSION").to_string(),
+        exported_at: Local::now().to_rfc3339(),
+        state,
+        manifest,
+        failed: state::load_failed(destination),
+        deferred: state::deferred_files(destination),
+        queue: state::load_queue(destination),
+        http_cache: HttpCache::entries(destination)?,
+    };
+    crate::write_artifact(file, serde_json::to_string(&bundle)?.as_bytes())
+        .with_context(|| format!("Could not write export {file:?}"))?;
+    println!(
+        "Exported the state of {} ({} downloads) to {}",
+        destination.to_string_lossy(),
+        bundle.manifest.iter().count(),
+        file.to_string_lossy()
+    );
+    Ok(())
+}
+
+// Replaces the state of the destination with an export, only over an existing one with `force`
+pub fn import(destination: &Path, file: &Path, force: bool) -> Result<()> {
+    let body = std::fs::read_to_string(file).with_context(|| format!("Could not read export {file:?}"))?;
+    let bundle = parse(&body).with_context(|| format!("Could not import {file:?}"))?;
+    let dir = state_dir(destination);
+    if !force && (dir.join(STATE_FILE).exists() || dir.join(MANIFEST_FILE).exists()) {
+        return Err(anyhow!(
+            "{} has a sync state already. Pass --force to replace it with the imported one",
+            destination.to_string_lossy()
+        ));
+    }
+    // A run syncing meanwhile would overwrite the import with its own state
+    let _lock = crate::lock::acquire(destination, false)?;
+    match &bundle.state {
+        Some(state) => state.save(destination)?,
+        // A state.json left from before would not match the imported manifest
+        None => {
+            let state_path = dir.join(STATE_FILE);
+            if state_path.exists() {
+                std::fs::remove_file(&state_path).with_context(|| format!("Could not remove {state_path:?}"))?;
+            }
+        }
+    }
+    bundle.manifest.save(destination)?;
+    state::save_failed(destination, &bundle.failed)?;
+    state::save_deferred_files(destination, &bundle.deferred)?;
+    state::save_queue(destination, &bundle.queue)?;
+    let n_cached = HttpCache::restore(destination, &bundle.http_cache)?;
+    println!(
+        "Imported the state of {} downloads and {n_cached} cached listings, exported {} by canvas-downloader {}",
+        bundle.manifest.iter().count(),
+        bundle.exported_at,
+        bundle.exported_by
+    );
+    let missing = bundle
+        .manifest
+        .iter()
+        .filter(|(_, entry)| !entry.path.is_empty() && !destination.join(&entry.path).exists())
+        .count();
+    if missing > 0 {
+        eprintln!(
+            "Warning: {missing} downloaded file(s) are not in {}, the next sync downloads them again. Copy the \
+             destination folder over as well",
+            destination.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+// The format and version are checked first, an export of another schema fails with what to do about it
+fn parse(body: &str) -> Result<Bundle> {
+    let value: serde_json::Value = serde_json::from_str(body).context("Not valid JSON")?;
+    if value.get("format").and_then(serde_json::Value::as_str) != Some(FORMAT) {
+        return Err(anyhow!("Not a state export of canvas-downloader, create one with `state export <FILE>`"));
+    }
+    let version = value.get("version").and_then(serde_json::Value::as_u64).unwrap_or_default();
+    let exported_by = value.get("exported_by").and_then(serde_json::Value::as_str).unwrap_or("an unknown version");
+    if version != u64::from(VERSION) {
+        let advice = if version > u64::from(VERSION) {
+            "Update canvas-downloader here to import it"
+        } else {
+            "Export it again with this version of canvas-downloader"
+        };
+        return Err(anyhow!(
+            "The export is of state format {version} (canvas-downloader {exported_by}), this version reads format \
+             {VERSION}. {advice}"
+        ));
+    }
+    serde_json::from_value(value).context("The export is incomplete or damaged")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{file_fixture, process_options, TestDir};
+
+    const FILES: [(u32, &str, &str); 2] = [
+        (11, "CS101/files/syllabus.pdf", "2024-01-31T09:00:00.750Z"),
+        (12, "CS101/modules/1_Week 1/notes.pdf", "2024-02-01T10:00:00Z"),
+    ];
+
+    // A destination after a sync: the files, their state and manifest, a failed download and a cached listing
+    fn synced_destination(destination: &Path) {
+        let mut state = State { canvas_url: Some("https://canvas.example.edu".to_string()), ..State::default() };
+        let mut manifest = Manifest::default();
+        for (id, path, updated_at) in FILES {
+            let filepath = destination.join(path);
+            std::fs::create_dir_all(filepath.parent().expect("file has a folder")).expect("create course folder");
+            std::fs::write(&filepath, b"data").expect("write file");
+            state.files.insert(path.to_string(), updated_at.to_string());
+            manifest.record(id, path.to_string(), updated_at.to_string(), 4);
+        }
+        state.save(destination).expect("save state");
+        manifest.save(destination).expect("save manifest");
+        let failed = file_fixture(13, destination.join("CS101/files/slides.pdf"), "2024-02-02T08:00:00Z");
+        state::save_failed(destination, &state::failed_files(destination, &[], &[failed])).expect("save failed");
+        let entry = http_cache::Entry {
+            url: "https://canvas.example.edu/api/v1/courses/1/folders".to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            link: None,
+            content_type: Some("application/json".to_string()),
+            body: "[]".to_string(),
+        };
+        let cache = state_dir(destination).join(http_cache::HTTP_CACHE_DIR);
+        std::fs::create_dir_all(&cache).expect("create cache dir");
+        std::fs::write(cache.join("0123456789abcdef.json"), serde_json::to_string(&entry).expect("entry"))
+            .expect("write cache entry");
+    }
+
+    fn state_files(destination: &Path) -> Vec<serde_json::Value> {
+        let read = |path: std::path::PathBuf| {
+            std::fs::read_to_string(path).map_or(serde_json::Value::Null, |body| {
+                serde_json::from_str(&body).expect("state files are JSON")
+            })
+        };
+        let dir = state_dir(destination);
+        vec![
+            read(dir.join(STATE_FILE)),
+            read(dir.join(MANIFEST_FILE)),
+            read(dir.join(state::FAILED_FILE)),
+            read(dir.join(http_cache::HTTP_CACHE_DIR).join("0123456789abcdef.json")),
+        ]
+    }
+
+    // The destination folder copied over without .canvasdl, as on the other machine
+    fn copy_files(from: &Path, to: &Path) {
+        for (_, path, _) in FILES {
+            std::fs::create_dir_all(to.join(path).parent().expect("file has a folder")).expect("create folder");
+            std::fs::copy(from.join(path), to.join(path)).expect("copy file");
+        }
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let desktop = TestDir::new("rt-desktop");
+        let laptop = TestDir::new("rt-laptop");
+        let exports = TestDir::new("rt-out");
+        synced_destination(desktop.path());
+        let export_path = exports.join("state.json");
+        export(desktop.path(), &export_path).expect("export");
+        copy_files(desktop.path(), laptop.path());
+        import(laptop.path(), &export_path, false).expect("import");
+        assert_eq!(state_files(laptop.path()), state_files(desktop.path()));
+        // The lock of the import is released
+        assert!(!state_dir(laptop.path()).join(crate::lock::LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn nothing_pending_after_import() {
+        let desktop = TestDir::new("np-desktop");
+        let laptop = TestDir::new("np-laptop");
+        let exports = TestDir::new("np-out");
+        synced_destination(desktop.path());
+        let export_path = exports.join("state.json");
+        export(desktop.path(), &export_path).expect("export");
+        copy_files(desktop.path(), laptop.path());
+        import(laptop.path(), &export_path, false).expect("import");
+
+        // What filter_files() decides for Canvas listing the same versions again
+        let manifest = Manifest::load(laptop.path());
+        let pending = FILES.iter().filter(|(id, path, updated_at)| {
+            let filepath = laptop.join(path);
+            let recorded = manifest.lookup(*id).filter(|entry| entry.path == *path);
+            let recorded = recorded.map(|entry| entry.updated_at.as_str());
+            !filepath.exists()
+                || crate::newer_on_canvas(updated_at, recorded, || Ok(std::fs::metadata(&filepath)?.modified()?))
+                    .expect("comparable")
+        });
+        assert_eq!(pending.count(), 0);
+    }
+
+    #[test]
+    fn failed_downloads_round_trip_and_refilter() {
+        let destination = TestDir::new("failed");
+        let mut attachment = file_fixture(13, destination.join("CS101/files/7_slides.pdf"), "2024-02-02T08:00:00Z");
+        attachment.display_name = "slides.pdf".to_string();
+        attachment.name_prefix = Some(7);
+        let notes = file_fixture(14, destination.join("CS101/files/notes.pdf"), "2024-02-02T08:00:00Z");
+        let failed = state::failed_files(destination.path(), &[], &[attachment.clone(), notes.clone()]);
+        state::save_failed(destination.path(), &failed).expect("save failed");
+
+        let loaded = state::load_failed(destination.path());
+        let summary: Vec<(u32, &str, Option<u32>, u32)> =
+            loaded.iter().map(|failed| (failed.file.id, failed.path.as_str(), failed.name_prefix, failed.attempts)).collect();
+        assert_eq!(summary, [(13, "CS101/files/7_slides.pdf", Some(7), 1), (14, "CS101/files/notes.pdf", None, 1)]);
+
+        // Downloaded by a later sync, the notes are resolved
+        std::fs::create_dir_all(destination.join("CS101/files")).expect("create course folder");
+        std::fs::write(&notes.filepath, b"data").expect("write notes");
+        let options = process_options(destination.path(), "https://canvas.example.edu", &[]);
+        let listed = loaded.iter().map(|failed| (failed.path.as_str(), failed.file.clone(), failed.name_prefix));
+        let retried = crate::refilter_files(&options, listed);
+        assert_eq!(retried.iter().map(|file| &file.filepath).collect::<Vec<_>>(), [&attachment.filepath]);
+
+        // Failing again counts on, what succeeded leaves the list
+        let failed_again = state::failed_files(destination.path(), &loaded, &retried);
+        state::save_failed(destination.path(), &failed_again).expect("save failed");
+        let attempts: Vec<(u32, u32)> =
+            state::load_failed(destination.path()).iter().map(|failed| (failed.file.id, failed.attempts)).collect();
+        assert_eq!(attempts, [(13, 2)]);
+        state::save_failed(destination.path(), &[]).expect("remove failed");
+        assert!(!state_dir(destination.path()).join(state::FAILED_FILE).exists());
+    }
+
+    #[test]
+    fn import_refuses_other_formats_and_existing_state() {
+        let desktop = TestDir::new("rf-desktop");
+        let laptop = TestDir::new("rf-laptop");
+        let exports = TestDir::new("rf-out");
+        synced_destination(desktop.path());
+        let export_path = exports.join("state.json");
+        export(desktop.path(), &export_path).expect("export");
+
+        let export = std::fs::read_to_string(&export_path).expect("read export");
+        let newer = export.replace("\"version\":1", "\"version\":2");
+        assert_ne!(newer, export);
+        let e = parse(&newer).err().expect("newer format refused");
+        assert!(e.to_string().contains("Update canvas-downloader"), "{e}");
+        let e = parse("{\"files\": {}}").err().expect("other JSON refused");
+        assert!(e.to_string().contains("Not a state export"), "{e}");
+
+        synced_destination(laptop.path());
+        let e = import(laptop.path(), &export_path, false).expect_err("existing state kept");
+        assert!(e.to_string().contains("--force"), "{e}");
+    }
+
+    #[test]
+    fn forced_import_without_state_removes_the_old_one() {
+        let desktop = TestDir::new("fs-desktop");
+        let laptop = TestDir::new("fs-laptop");
+        let exports = TestDir::new("fs-out");
+        synced_destination(desktop.path());
+        std::fs::remove_file(state_dir(desktop.path()).join(STATE_FILE)).expect("remove state");
+        let export_path = exports.join("state.json");
+        export(desktop.path(), &export_path).expect("export");
+
+        synced_destination(laptop.path());
+        import(laptop.path(), &export_path, true).expect("import");
+        assert!(!state_dir(laptop.path()).join(STATE_FILE).exists());
+        assert!(state_dir(laptop.path()).join(MANIFEST_FILE).exists());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::Course;
+
+pub const REPORT_TXT: &str = "storage_report.txt";
+pub const REPORT_JSON: &str = "storage_report.json";
+const N_LARGEST_FILES: usize = 20;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct StorageReport {
+    pub generated_at: String,
+    pub courses: Vec<CourseUsage>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CourseUsage {
+    pub id: u32,
+    pub course_code: String,
+    pub path: PathBuf,
+    pub total_bytes: u64,
+    // "files", "videos", "submissions" or "json artifacts" --> bytes
+    pub bytes_by_type: BTreeMap<String, u64>,
+    pub largest_files: Vec<(PathBuf, u64)>,
+}
+
+// Bucket of a course-relative path. Assignment folders hold submissions next to the files linked in
+// assignment descriptions, both are counted as submissions
+fn content_type(relative_path: &Path) -> &'static str {
+    if relative_path.extension().is_some_and(|ext| ext == "json") {
+        return "json artifacts";
+    }
+    match relative_path.components().next().and_then(|c| c.as_os_str().to_str()) {
+        Some("videos") => "videos",
+        Some("assignments") => "submissions",
+        _ => "files",
+    }
+}
+
+// Every regular file below `folder`, without following symlinks out of the destination
+pub fn walk(folder: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<()> {
+    for entry in std::fs::read_dir(folder).with_context(|| format!("Could not read directory {folder:?}"))? {
+        let entry = entry?;
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if metadata.is_file() {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+pub fn course_usage(course: &Course, course_folder_path: &Path) -> Result<CourseUsage> {
+    let mut files = Vec::new();
+    if course_folder_path.exists() {
+        walk(course_folder_path, &mut files)?;
+    }
+    let mut usage = CourseUsage {
+        id: course.id,
+        course_code: course.course_code.clone(),
+        path: course_folder_path.to_path_buf(),
+        ..Default::default()
+    };
+    for (path, bytes) in &files {
+        let relative_path = path.strip_prefix(course_folder_path).unwrap_or(path);
+        *usage.bytes_by_type.entry(content_type(relative_path).to_string()).or_default() += bytes;
+        usage.total_bytes += bytes;
+    }
+    files.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+    files.truncate(N_LARGEST_FILES);
+    usage.largest_files = files;
+    Ok(usage)
+}
+
+// Writes the txt and json reports at the destination root, comparing against the previous json report
+pub fn write(destination: &Path, courses: &[(&Course, PathBuf)]) -> Result<PathBuf> {
+    let json_path = destination.join(REPORT_JSON);
+    let previous: Option<StorageReport> = std::fs::read_to_string(&json_path)
+        .ok()
+        .and_then(|body| serde_json::from_str(&body).ok());
+
+    let report = StorageReport {
+        generated_at: Local::now().to_rfc3339(),
+        courses: courses
+            .iter()
+            .map(|(course, course_folder_path)| course_usage(course, course_folder_path))
+            .collect::<Result<_>>()?,
+    };
+
+    let mut text = format!("Storage report, generated {}\n", report.generated_at);
+    if let Some(previous) = &previous {
+        text.push_str(&format!("Growth is relative to the report of {}\n", previous.generated_at));
+    }
+    for usage in &report.courses {
+        let previous_usage = previous
+            .as_ref()
+            .and_then(|previous| previous.courses.iter().find(|c| c.id == usage.id));
+        text.push_str(&format!(
+            "\n{} ({}): {}{}\n",
+            usage.course_code,
+            usage.path.to_string_lossy(),
+            indicatif::HumanBytes(usage.total_bytes),
+            growth(usage.total_bytes, previous_usage.map(|p| p.total_bytes))
+        ));
+        for (content_type, bytes) in &usage.bytes_by_type {
+            let previous_bytes = previous_usage.map(|p| p.bytes_by_type.get(content_type).copied().unwrap_or(0));
+            text.push_str(&format!(
+                "  {content_type:<16} {:>12}{}\n",
+                indicatif::HumanBytes(*bytes).to_string(),
+                growth(*bytes, previous_bytes)
+            ));
+        }
+        text.push_str("  Largest files:\n");
+        for (path, bytes) in &usage.largest_files {
+            text.push_str(&format!(
+                "    {:>12}  {}\n",
+                indicatif::HumanBytes(*bytes).to_string(),
+                path.to_string_lossy()
+            ));
+        }
+    }
+
+    crate::write_artifact(&json_path, serde_json::to_string_pretty(&report)?.as_bytes())?;
+    let txt_path = destination.join(REPORT_TXT);
+    crate::write_artifact(&txt_path, text.as_bytes())?;
+    Ok(txt_path)
+}
+
+fn growth(bytes: u64, previous_bytes: Option<u64>) -> String {
+    match previous_bytes {
+        Some(previous_bytes) if bytes >= previous_bytes => {
+            format!(" (+{})", indicatif::HumanBytes(bytes - previous_bytes))
+        }
+        Some(previous_bytes) => format!(" (-{})", indicatif::HumanBytes(previous_bytes - bytes)),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::Name;
+use serde_json::Value;
+
+use crate::canvas::{File, ProcessOptions};
+
+const STUDIO_HOST: &str = "instructuremedia.com";
+// Recorded next to the page or discussion that embeds them
+pub const EXTERNAL_LINKS_FILE: &str = "external_links.txt";
+
+// <iframe src="https://school.instructuremedia.com/embed/1f0c..."> in pages, announcements, ...
+pub fn embed_urls(html: &str) -> Vec<String> {
+    Document::from(html)
+        .find(Name("iframe"))
+        .filter_map(|n| n.attr("src"))
+        .filter(|src| {
+            Url::parse(src).is_ok_and(|url| {
+                url.host_str().is_some_and(|host| host.ends_with(STUDIO_HOST))
+                    && url.path().starts_with("/embed/")
+            })
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+// Best progressive MP4 and the captions of a Studio clip, queued into the course videos folder
+pub async fn process_embed(
+    (embed_url, path, videos_path): (String, PathBuf, PathBuf),
+    options: Arc<ProcessOptions>,
+) -> Result<()> {
+    options.metrics.record_request("studio");
+    let embed_html = options.client.get(&embed_url).send().await?.error_for_status()?.text().await?;
+    let config = embed_config(&embed_html)
+        .ok_or_else(|| anyhow!("Could not find the media config of Canvas Studio embed {embed_url}"))?;
+
+    let title = find_string(&config, "title").unwrap_or_else(|| "Canvas Studio clip".to_string());
+    let title = sanitize_filename::sanitize(title);
+    let updated_at = find_string(&config, "updated_at")
+        .or_else(|| find_string(&config, "created_at"))
+        .unwrap_or_else(|| Local::now().to_rfc3339());
+
+    let download_allowed = ["download_allowed", "can_download", "allow_download"]
+        .iter()
+        .find_map(|key| find_bool(&config, key))
+        .unwrap_or(true);
+    let mut sources = Vec::new();
+    collect_objects(&config, &mut sources, &|object| {
+        let url = object.get("url").or_else(|| object.get("src")).and_then(Value::as_str);
+        let is_mp4 = object.get("mime_type").or_else(|| object.get("content_type")).and_then(Value::as_str)
+            == Some("video/mp4")
+            || url.is_some_and(|url| url.split('?').next().unwrap_or(url).ends_with(".mp4"));
+        url.is_some() && is_mp4
+    });
+    let best_source = sources.iter().max_by_key(|source| {
+        ["height", "bitrate", "size"]
+            .iter()
+            .map(|key| source.get(key).and_then(Value::as_u64).unwrap_or(0))
+            .collect::<Vec<u64>>()
+    });
+    let Some(best_source) = best_source.filter(|_| download_allowed) else {
+        return record_external_link(&options, &path, &format!("{title} [Canvas Studio] - {embed_url} (download disabled)")).await;
+    };
+
+    let mut files = vec![studio_file(
+        format!("{title}.mp4"),
+        best_source,
+        &updated_at,
+    )];
+    let mut captions = Vec::new();
+    collect_objects(&config, &mut captions, &|object| {
+        object.get("srclang").is_some() && (object.get("url").or_else(|| object.get("src"))).is_some()
+    });
+    for caption in &captions {
+        let lang = caption.get("srclang").and_then(Value::as_str).unwrap_or("und");
+        let url = caption.get("url").or_else(|| caption.get("src")).and_then(Value::as_str).unwrap_or_default();
+        let ext = if url.split('?').next().unwrap_or(url).ends_with(".srt") { "srt" } else { "vtt" };
+        files.push(studio_file(format!("{title}.{lang}.{ext}"), caption, &updated_at));
+    }
+
+    options.create_folder(&videos_path)?;
+    let mut filtered_files = crate::filter_files(&options, &videos_path, files);
+    let mut lock = options.files_to_download.lock().await;
+    lock.append(&mut filtered_files);
+    Ok(())
+}
+
+fn studio_file(display_name: String, source: &Value, updated_at: &str) -> File {
+    File {
+        id: 0,
+        folder_id: 0,
+        display_name,
+        size: source.get("size").and_then(Value::as_u64).unwrap_or(0),
+        url: source
+            .get("url")
+            .or_else(|| source.get("src"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        updated_at: updated_at.to_string(),
+        locked_for_user: false,
+        filepath: PathBuf::new(),
+        source_context: None,
+        name_prefix: None,
+        created_at: None,
+        course_id: None,
+    }
+}
+
+// The embed page carries its media config as JSON in a script tag
+fn embed_config(embed_html: &str) -> Option<Value> {
+    Document::from(embed_html)
+        .find(Name("script"))
+        .filter_map(|n| {
+            let text = n.text();
+            let start = text.find('{')?;
+            let end = text.rfind('}')?;
+            serde_json::from_str::<Value>(text.get(start..=end)?).ok()
+        })
+        .find(|config| find_string(config, "title").is_some())
+}
+
+fn collect_objects<'a>(value: &'a Value, found: &mut Vec<&'a Value>, matches: &dyn Fn(&Value) -> bool) {
+    match value {
+        Value::Object(object) => {
+            if matches(value) {
+                found.push(value);
+            }
+            object.values().for_each(|value| collect_objects(value, found, matches));
+        }
+        Value::Array(items) => items.iter().for_each(|value| collect_objects(value, found, matches)),
+        _ => {}
+    }
+}
+
+fn find_string(value: &Value, key: &str) -> Option<String> {
+    let mut found = Vec::new();
+    collect_objects(value, &mut found, &|object| object.get(key).is_some_and(Value::is_string));
+    found.first().and_then(|object| object.get(key)).and_then(Value::as_str).map(str::to_string)
+}
+
+fn find_bool(value: &Value, key: &str) -> Option<bool> {
+    let mut found = Vec::new();
+    collect_objects(value, &mut found, &|object| object.get(key).is_some_and(Value::is_boolean));
+    found.first().and_then(|object| object.get(key)).and_then(Value::as_bool)
+}
+
+// One line per link, kept across runs without duplicates
+async fn record_external_link(options: &ProcessOptions, path: &Path, line: &str) -> Result<()> {
+    let links_path = path.join(EXTERNAL_LINKS_FILE);
+    let mut links = std::fs::read_to_string(&links_path).unwrap_or_default();
+    if links.lines().any(|existing| existing == line) {
+        return Ok(());
+    }
+    links.push_str(line);
+    links.push('\n');
+    options.write_artifact(&links_path, links.as_bytes()).await
+}
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::canvas::{Credentials, File, ProcessOptions};
+use crate::state::State;
+use crate::CommandLineOptions;
+
+static N_TEST_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+// Temporary folder of one test, removed again when dropped. Tests run in parallel, so every folder is numbered
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let n = N_TEST_DIRS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("canvasdl-test-{}-{n}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create test dir");
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// A file as listed by the Canvas files API, to be saved at `filepath`
+pub fn file_fixture(id: u32, filepath: impl AsRef<Path>, updated_at: &str) -> File {
+    let filepath = filepath.as_ref();
+    let mut file: File = serde_json::from_value(serde_json::json!({
+        "id": id,
+        "folder_id": 3,
+        "display_name": filepath.file_name().map(|name| name.to_string_lossy()).unwrap_or_default(),
+        "size": 1024,
+        "url": format!("https://canvas.example.edu/files/{id}/download"),
+        "updated_at": updated_at,
+        "locked_for_user": false,
+    }))
+    .expect("file fixture");
+    file.filepath = filepath.to_path_buf();
+    file
+}
+
+// Options of a run syncing into `destination` from the Canvas instance at `canvas_url`, usually a mock_server(), as if
+// started with `extra_args`
+pub fn process_options(destination: &Path, canvas_url: &str, extra_args: &[&str]) -> Arc<ProcessOptions> {
+    let destination = destination.to_string_lossy();
+    let argv = ["canvas-downloader", "-d", &destination].into_iter().chain(extra_args.iter().copied());
+    let args = CommandLineOptions::parse_from(argv);
+    let cred = Credentials {
+        canvas_url: canvas_url.to_string(),
+        canvas_token: "test-token".to_string(),
+        api_prefix: None,
+    };
+    let clients = crate::http_clients(&args).expect("http clients");
+    let user = serde_json::from_value(serde_json::json!({ "id": 1, "name": "Test Student" })).expect("user fixture");
+    let api_base = format!("{canvas_url}/api/v1");
+    let options = crate::process_options(&args, &cred, clients, api_base, user, State::default(), CancellationToken::new());
+    Arc::new(options.expect("process options"))
+}
+
+// What a mock_server() answers one request with
+pub enum Reply {
+    // Written, then the connection is closed
+    Close(Vec<u8>),
+    // Written, then the connection is held open without sending another byte
+    Stall(Vec<u8>),
+}
+
+// "HTTP/1.1 <status>" with `headers` ("Name: value\r\n" each) and `body`, on a connection of its own
+pub fn http_response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response =
+        format!("HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// Answers `n_requests` requests, one per connection, with what `reply` makes of each lowercased request head. Returns
+// its http://127.0.0.1:<port> URL and the request heads
+pub async fn mock_server(
+    n_requests: usize,
+    mut reply: impl FnMut(&str) -> Reply + Send + 'static,
+) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock server");
+    let address = listener.local_addr().expect("mock server address");
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for _ in 0..n_requests {
+            let (mut socket, _) = listener.accept().await.expect("accept request");
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.expect("read request");
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request).to_lowercase();
+            match reply(&request) {
+                Reply::Close(response) => {
+                    // The client may hang up first, eg after refusing the headers
+                    let _ = socket.write_all(&response).await;
+                }
+                Reply::Stall(response) => {
+                    let _ = socket.write_all(&response).await;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(std::time::Duration::from_secs(600)).await;
+                        drop(socket);
+                    });
+                }
+            }
+            requests.push(request);
+        }
+        requests
+    });
+    (format!("http://{address}"), server)
+}